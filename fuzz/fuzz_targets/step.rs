@@ -0,0 +1,23 @@
+//! `cargo +nightly fuzz run step`
+//!
+//! The first byte picks the node; the rest is its input, one message per
+//! line.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use whirlpool::{fuzz, BroadcastNode, CounterNode, EchoNode, KafkaNode, KvNode, TxnNode};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((node, data)) = data.split_first() else {
+        return;
+    };
+    match node % 6 {
+        0 => fuzz::step(|_| EchoNode::default(), data),
+        1 => fuzz::step(|_| BroadcastNode::default(), data),
+        2 => fuzz::step(|_| CounterNode::default(), data),
+        3 => fuzz::step(|_| KafkaNode::default(), data),
+        4 => fuzz::step(|_| TxnNode::default(), data),
+        _ => fuzz::step(|_| KvNode::default(), data),
+    }
+});
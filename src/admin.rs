@@ -0,0 +1,202 @@
+//! A tiny HTTP server for looking into a running node, e.g. mid-way
+//! through a Maelstrom run, without instrumenting its stderr:
+//!
+//! - `/state`: what the node holds, as [`Node::inspect`] has it;
+//! - `/topology`: the nodes it knows of and talks to, as
+//!   [`Node::topology`] has it;
+//! - `/metrics`: the [metrics] recorded so far, which it turns on;
+//! - `/prometheus`: the same in the Prometheus text format, see
+//!   [`crate::prometheus`];
+//! - `/pending-rpcs`: the [calls](crate::Rpc) still waiting for replies.
+//!
+//! Every other answer is JSON. `/state` and `/topology` are asked of the node by
+//! the main loop between two events, so it is never seen half-way through
+//! one, and are answered with `503` if it is too busy to say within
+//! [`ASK_TIMEOUT`]. Built with the `admin` feature, and started by the
+//! main loop when [`Config::admin`](crate::Config::admin) names an address.
+//!
+//! [`Node::inspect`]: crate::Node::inspect
+//! [`Node::topology`]: crate::Node::topology
+
+use crate::{metrics, prometheus, Event, Rpc};
+use anyhow::Context;
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// How long the node has to answer for `/state` or `/topology`.
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What is served, as listed for any other path.
+const PATHS: [&str; 5] = [
+    "/state",
+    "/topology",
+    "/metrics",
+    "/prometheus",
+    "/pending-rpcs",
+];
+
+/// How long a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+enum View {
+    State,
+    Topology,
+}
+
+/// A request for a view of the node, for the main loop to answer between
+/// events.
+#[derive(Debug, Clone)]
+pub struct Inspect {
+    view: View,
+    reply: mpsc::Sender<Value>,
+}
+
+impl Inspect {
+    /// Answers with the node's [`Node::inspect`](crate::Node::inspect) or
+    /// [`Node::topology`](crate::Node::topology), whichever was asked for.
+    pub fn answer(&self, state: impl FnOnce() -> Value, topology: impl FnOnce() -> Value) {
+        let view = match self.view {
+            View::State => state(),
+            View::Topology => topology(),
+        };
+        // The server may have stopped waiting.
+        let _ = self.reply.send(view);
+    }
+}
+
+/// Listens on `addr` and answers requests on a thread of its own, asking
+/// the main loop through `events` and looking at `rpc`'s pending calls.
+pub(crate) fn serve<P: Send + 'static>(
+    addr: &str,
+    events: mpsc::SyncSender<Event<P>>,
+    rpc: Option<Rpc<P>>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("admin on {addr}"))?;
+    crate::info!("admin on http://{}/", listener.local_addr()?);
+    metrics::enable();
+    thread::Builder::new().name("admin".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &events, rpc.as_ref()));
+            if let Err(e) = result {
+                crate::debug!("admin request failed: {e}");
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn respond<P>(
+    mut stream: TcpStream,
+    events: &mpsc::SyncSender<Event<P>>,
+    rpc: Option<&Rpc<P>>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Only the request line matters; the headers are read past.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let path = request.split(' ').nth(1).unwrap_or("/");
+    let (status, content_type, body) = match path {
+        "/prometheus" => (
+            "200 OK",
+            // The version of the text format, as Prometheus asks for it.
+            "text/plain; version=0.0.4",
+            prometheus::render(&metrics::snapshot()),
+        ),
+        _ => {
+            let (status, body) = match path {
+                "/state" => ask(events, View::State),
+                "/topology" => ask(events, View::Topology),
+                "/metrics" => ("200 OK", metrics_json(&metrics::snapshot())),
+                "/pending-rpcs" => ("200 OK", pending_json(rpc)),
+                _ => ("404 Not Found", json!({ "paths": PATHS })),
+            };
+            (status, "application/json", body.to_string())
+        }
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn ask<P>(events: &mpsc::SyncSender<Event<P>>, view: View) -> (&'static str, Value) {
+    let (reply, answer) = mpsc::channel();
+    let unavailable = |error: &str| ("503 Service Unavailable", json!({ "error": error }));
+    // A full queue means the node is behind already; don't add to it.
+    if events
+        .try_send(Event::Inspect(Inspect { view, reply }))
+        .is_err()
+    {
+        return unavailable("node is busy or stopped");
+    }
+    match answer.recv_timeout(ASK_TIMEOUT) {
+        Ok(value) => ("200 OK", value),
+        Err(_) => unavailable("node didn't answer in time"),
+    }
+}
+
+fn metrics_json(metrics: &metrics::Metrics) -> Value {
+    let latency: serde_json::Map<_, _> = metrics
+        .latency
+        .iter()
+        .map(|(kind, latency)| {
+            let us = |d: Duration| d.as_micros() as u64;
+            let histogram = json!({
+                "count": latency.count(),
+                "mean_us": us(latency.mean()),
+                "p50_us": us(latency.quantile(0.5)),
+                "p99_us": us(latency.quantile(0.99)),
+                "max_us": us(latency.max()),
+            });
+            (kind.clone(), histogram)
+        })
+        .collect();
+    let queues: serde_json::Map<_, _> = metrics
+        .queues
+        .iter()
+        .map(|(queue, depth)| {
+            let depth = json!({"depth": depth.depth(), "max": depth.max()});
+            (queue.clone(), depth)
+        })
+        .collect();
+    json!({
+        "received": metrics.received,
+        "sent": metrics.sent,
+        "bytes_sent": metrics.bytes_sent,
+        "retries": metrics.retries,
+        "evictions": metrics.evictions,
+        "latency": latency,
+        "queues": queues,
+    })
+}
+
+fn pending_json<P>(rpc: Option<&Rpc<P>>) -> Value {
+    let calls: Vec<_> = rpc
+        .map(Rpc::pending)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|call| {
+            json!({
+                "msg_id": call.msg_id,
+                "dest": call.dest,
+                "waited_ms": call.waited.as_millis() as u64,
+            })
+        })
+        .collect();
+    Value::Array(calls)
+}
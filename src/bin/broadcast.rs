@@ -0,0 +1,16 @@
+use whirlpool::{main_loop_with, storage::Persisted, BroadcastNode, CausalBroadcastNode, Config};
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    if config.broadcast_causal {
+        return main_loop_with(
+            CausalBroadcastNode::default().with_retry_limits(config.retry_limits),
+            &config,
+        );
+    }
+    let node = BroadcastNode::from_config(&config);
+    match &config.state_dir {
+        Some(dir) => main_loop_with(Persisted::configured(node, dir, &config), &config),
+        None => main_loop_with(node, &config),
+    }
+}
@@ -0,0 +1,5 @@
+use whirlpool::{main_loop, CounterNode};
+
+fn main() -> anyhow::Result<()> {
+    main_loop(CounterNode::default())
+}
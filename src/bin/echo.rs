@@ -0,0 +1,6 @@
+use whirlpool::{main_loop_with, Config, EchoNode};
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    main_loop_with(EchoNode::new(config.ids), &config)
+}
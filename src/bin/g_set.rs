@@ -0,0 +1,5 @@
+use whirlpool::{main_loop, GSetNode};
+
+fn main() -> anyhow::Result<()> {
+    main_loop(GSetNode::default())
+}
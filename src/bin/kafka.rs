@@ -0,0 +1,5 @@
+use whirlpool::{main_loop, KafkaNode};
+
+fn main() -> anyhow::Result<()> {
+    main_loop(KafkaNode::replicated())
+}
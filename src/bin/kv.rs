@@ -0,0 +1,18 @@
+use whirlpool::{
+    crdt::LwwMap, kv::KvStore, main_loop_with, Config, CrdtNode, QuorumKvNode, RaftNode,
+    ShardedKvNode,
+};
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    if config.kv_quorum.is_some() {
+        return main_loop_with(QuorumKvNode::from_config(&config), &config);
+    }
+    if config.kv_lww {
+        return main_loop_with(CrdtNode::<LwwMap>::default(), &config);
+    }
+    match config.kv_sharded {
+        true => main_loop_with(ShardedKvNode::default(), &config),
+        false => main_loop_with(RaftNode::<KvStore>::from_config(&config), &config),
+    }
+}
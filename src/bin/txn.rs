@@ -0,0 +1,18 @@
+use whirlpool::{main_loop_with, storage::Persisted, Config, TwoPhaseTxnNode, TxnNode};
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    if config.txn_2pc {
+        return match &config.state_dir {
+            Some(dir) => main_loop_with(TwoPhaseTxnNode::with_decision_log(dir), &config),
+            None => main_loop_with(TwoPhaseTxnNode::default(), &config),
+        };
+    }
+    match &config.state_dir {
+        Some(dir) => main_loop_with(
+            Persisted::configured(TxnNode::default(), dir, &config),
+            &config,
+        ),
+        None => main_loop_with(TxnNode::default(), &config),
+    }
+}
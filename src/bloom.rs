@@ -0,0 +1,70 @@
+//! Bloom filters, for telling a peer which values a node has in far less
+//! room than listing them.
+//!
+//! A filter never says a value it holds is missing, but may say one it
+//! doesn't hold is there, at about the false positive rate it was sized
+//! for. Each filter hashes with its own seed, so a value a filter wrongly
+//! claims is almost certainly caught by the next one, built with another.
+
+use crate::ring::hash;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+
+/// More hashes than this add little, so a filter from a peer asking for
+/// more is only probed with this many.
+const MAX_HASHES: u32 = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    seed: u64,
+    hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized to hold `capacity` items with about
+    /// `false_positives` of the items it doesn't hold reported as held.
+    pub fn new(capacity: usize, false_positives: f64, seed: u64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let capacity = capacity.max(1) as f64;
+        let bits = (-capacity * false_positives.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0);
+        let hashes = (bits / capacity * ln2)
+            .round()
+            .clamp(1.0, MAX_HASHES as f64);
+        Self {
+            seed,
+            hashes: hashes as u32,
+            bits: vec![0; (bits as usize).div_ceil(64)],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        if self.bits.is_empty() {
+            return;
+        }
+        for bit in self.positions(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `item` may have been inserted. An empty bit array is
+    /// taken to hold nothing.
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        !self.bits.is_empty()
+            && self
+                .positions(item)
+                .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits `item` sets, by double hashing: the `i`th is
+    /// `h1 + i * h2`, with `h1` and `h2` the halves of one 64-bit hash.
+    fn positions<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = usize> {
+        let h = hash(&(self.seed, item));
+        let (h1, h2) = (h & 0xffff_ffff, h >> 32 | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes.min(MAX_HASHES)))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len.max(1)) as usize)
+    }
+}
@@ -0,0 +1,1067 @@
+use crate::{
+    bloom::BloomFilter,
+    clock::VectorClock,
+    compress::{self, Encoding, Packed},
+    handshake::{Capability, Handshake},
+    heartbeat::Heartbeats,
+    payload::ReadValue,
+    swim::{FailureDetector, SwimConfig},
+    time,
+    timer::TimerWheel,
+    CacheLimits, Config, Membership, Message, MsgIdAllocator, Node, Payload, RetryQueue, RpcError,
+    TopologyStrategy,
+};
+use anyhow::bail;
+use rand::{
+    seq::{IteratorRandom, SliceRandom},
+    Rng,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// How often retries, heartbeats and failure detection are seen to, at
+/// most.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The most values a `sync_response` carries by default.
+pub const SYNC_CHUNK: usize = 1000;
+
+/// The false positive rate digests are sized for, in
+/// [`BroadcastMode::Digest`]. A value a digest wrongly claims is sent
+/// once a later digest, hashed differently, doesn't.
+const DIGEST_FALSE_POSITIVES: f64 = 0.01;
+
+/// How a [`BroadcastNode`] gets values to its neighbors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BroadcastMode {
+    /// Forward each new value once and forget about it.
+    Forward,
+    /// Forward each new value and re-send it until the neighbor acks, so
+    /// values survive dropped messages and partitions.
+    Reliable,
+    /// Collect new values and send them to each neighbor as one batched
+    /// `gossip` message per tick, re-sent until acked.
+    #[default]
+    Gossip,
+    /// Like `Gossip`, but only through a leader, the node with the lowest
+    /// id: the others send it their new values, and it batches up
+    /// everything new and fans it out to them, so each value costs one
+    /// message per node instead of one per link. Values stall while the
+    /// leader is unreachable, and the `topology` message is ignored.
+    Leader,
+    /// Nothing is pushed: each tick, every node sends its neighbors a
+    /// Bloom filter of the values it has seen, and each answers with the
+    /// values it has that the filter lacks. Costs two messages per link
+    /// per tick however many values there are, and values take a tick or
+    /// two per hop.
+    Digest,
+}
+
+impl FromStr for BroadcastMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "forward" => BroadcastMode::Forward,
+            "reliable" => BroadcastMode::Reliable,
+            "gossip" => BroadcastMode::Gossip,
+            "leader" => BroadcastMode::Leader,
+            "digest" => BroadcastMode::Digest,
+            _ => bail!("unknown broadcast mode {s}"),
+        })
+    }
+}
+
+/// How gossip rounds go: in [`BroadcastMode::Gossip`] and
+/// [`BroadcastMode::Leader`], the rounds that send out batches of new
+/// values, and in [`BroadcastMode::Digest`], those that send digests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipConfig {
+    /// How long between rounds.
+    pub interval: Duration,
+    /// How many neighbors, picked at random, each round goes to, if not
+    /// all of them. The others' values wait for a later round.
+    pub fanout: Option<usize>,
+    /// How far each interval may stray from `interval` at random, as a
+    /// fraction of it, so that nodes' rounds don't line up.
+    pub jitter: f64,
+    /// The most values one gossip carries; the rest wait for the next
+    /// round.
+    pub max_batch: Option<usize>,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: TICK,
+            fanout: None,
+            jitter: 0.0,
+            max_batch: None,
+        }
+    }
+}
+
+impl GossipConfig {
+    /// How often to tick to start rounds on time: every interval, or
+    /// with jitter every tenth of one, so rounds start within a tenth of
+    /// an interval of when they are due.
+    fn tick(&self) -> Duration {
+        let tick = match self.jitter > 0.0 {
+            true => (self.interval / 10).max(Duration::from_millis(1)),
+            false => self.interval,
+        };
+        tick.min(TICK)
+    }
+
+    /// The time to the next round, jittered.
+    fn next_interval(&self) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return self.interval;
+        }
+        let factor = time::rng().gen_range(1.0 - jitter..=1.0 + jitter);
+        self.interval.mul_f64(factor)
+    }
+}
+
+/// Serves the `broadcast` workload.
+///
+/// The cluster can change after `init`. A node whose `init` doesn't list
+/// it among `node_ids` is joining: it sends `join` to the lowest of those
+/// ids, its sponsor, which passes it on to the others and answers with
+/// the cluster and every value it has seen. A node sent `leave` naming
+/// itself tells the others to forget it. Either way, every node picks its
+/// neighbors again; with [`TopologyStrategy::Provided`], a joining node is
+/// linked to its sponsor, and a leaving one's neighbors to each other.
+#[derive(Debug, Default)]
+pub struct BroadcastNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub seen: HashSet<usize>,
+    mode: BroadcastMode,
+    topology: TopologyStrategy,
+    neighbors: Vec<String>,
+    /// The topology Maelstrom provided, kept up to date as nodes join
+    /// and leave.
+    provided: HashMap<String, Vec<String>>,
+    retries: RetryQueue,
+    /// Values each neighbor hasn't been sent yet, in [`BroadcastMode::Gossip`]
+    /// and [`BroadcastMode::Leader`].
+    outbox: HashMap<String, HashSet<usize>>,
+    /// How many values that originated on each node this one has, if
+    /// gossip is stamped with clocks or peers are pulled from; see
+    /// [`BroadcastNode::with_clock`] and [`BroadcastNode::with_pull`].
+    clock: Option<VectorClock>,
+    /// Whether gossip carries `clock`.
+    stamped: bool,
+    /// Those values, by the node they originated on, in order.
+    origins: HashMap<String, Vec<usize>>,
+    /// The other way round: where each of those values originated, and
+    /// its place among that node's values, counting from 1.
+    origin_of: HashMap<usize, (String, u64)>,
+    /// Tells which peers are dead; see
+    /// [`BroadcastNode::with_failure_detector`].
+    detector: Option<FailureDetector>,
+    /// Tells which peers went quiet; see [`BroadcastNode::with_heartbeats`].
+    heartbeats: Option<Heartbeats>,
+    /// How many bytes of values a gossip batch takes before it is packed;
+    /// see [`BroadcastNode::with_compression`].
+    compress_above: Option<usize>,
+    /// Peers whose gossip or `hello` said they read packed values.
+    packing_peers: HashSet<String>,
+    /// Learns what peers can do; see [`BroadcastNode::with_handshake`].
+    handshake: Option<Handshake>,
+    /// Whether to sync from a peer on `init`; see
+    /// [`BroadcastNode::with_sync`].
+    sync_on_init: bool,
+    /// The most values a `sync_response` from this node carries, if not
+    /// [`SYNC_CHUNK`].
+    sync_chunk: Option<usize>,
+    /// How often to pull from a peer, if at all.
+    pull: Option<Duration>,
+    gossip: GossipConfig,
+    /// When the next gossip round and pull are due.
+    timers: TimerWheel<Timer>,
+}
+
+/// What a [`BroadcastNode`] keeps a timer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timer {
+    Round,
+    Pull,
+}
+
+impl BroadcastNode {
+    pub fn new(mode: BroadcastMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// A node set up as `config` says: its mode, topology, gossip rounds,
+    /// retry limits, whether gossip carries a clock, whether it pulls, whether it
+    /// detects failures and whether it shakes hands with its peers.
+    pub fn from_config(config: &Config) -> Self {
+        let mut node = Self::new(config.broadcast_mode)
+            .with_topology(config.topology)
+            .with_gossip(config.gossip.clone())
+            .with_retry_limits(config.retry_limits);
+        if config.broadcast_clock {
+            node = node.with_clock();
+        }
+        if config.swim {
+            node = node.with_failure_detector(SwimConfig::default());
+        }
+        if let Some(interval) = config.heartbeat {
+            node = node.with_heartbeats(Heartbeats::new(interval));
+        }
+        if let Some(above) = config.gossip_compress_above {
+            node = node.with_compression(above);
+        }
+        if config.broadcast_sync {
+            node = node.with_sync(SYNC_CHUNK);
+        }
+        if let Some(interval) = config.broadcast_pull {
+            node = node.with_pull(interval);
+        }
+        if config.handshake || config.msgpack || config.gzip {
+            node = node.with_handshake(Handshake::new(config.capabilities()));
+        }
+        node
+    }
+
+    /// Picks neighbors with `topology` instead of the Maelstrom-provided
+    /// topology.
+    pub fn with_topology(mut self, topology: TopologyStrategy) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn with_gossip(mut self, gossip: GossipConfig) -> Self {
+        self.gossip = gossip;
+        self
+    }
+
+    /// Gives up on unacked messages as `limits` says, see
+    /// [`RetryQueue::with_limits`].
+    pub fn with_retry_limits(mut self, limits: CacheLimits) -> Self {
+        self.retries = std::mem::take(&mut self.retries).with_limits(limits);
+        self
+    }
+
+    /// Stamps gossip with a [`VectorClock`] counting the values that
+    /// originated on each node. A peer whose clock is behind the stamp asks
+    /// for what it is missing with a `catch_up`, so values lost on the way
+    /// are recovered without flooding. Neighbors get stamped gossip every
+    /// tick, even with nothing new to send.
+    pub fn with_clock(mut self) -> Self {
+        self.clock = Some(VectorClock::new());
+        self.stamped = true;
+        self
+    }
+
+    /// Has the node, once initialized, ask a random peer for every value it
+    /// is missing with a `sync_request`, as a node restarted from a
+    /// snapshot needs to. The peer answers in chunks of at most `chunk`
+    /// values, in ascending order, and each chunk is followed by a request
+    /// for the values above it. With a clock, see
+    /// [`BroadcastNode::with_clock`], the request carries it, and the
+    /// values it covers are left out.
+    pub fn with_sync(mut self, chunk: usize) -> Self {
+        self.sync_on_init = true;
+        self.sync_chunk = Some(chunk);
+        self
+    }
+
+    /// Pulls as well as pushes: every `interval`, sends a random live peer
+    /// a `catch_up` with this node's clock, the watermark of what it has,
+    /// and takes in the values the peer answers with. Values reach every
+    /// node eventually even if all pushes of them were lost. The clock is
+    /// kept as [`BroadcastNode::with_clock`] keeps it, but gossip is only
+    /// stamped with it if that was asked for too.
+    pub fn with_pull(mut self, interval: Duration) -> Self {
+        self.clock.get_or_insert_with(VectorClock::new);
+        self.pull = Some(interval);
+        self
+    }
+
+    /// Runs a SWIM [`FailureDetector`] alongside. Nothing is sent to peers
+    /// it declared dead until they are back, and while any neighbor is
+    /// dead, new values go to every live peer instead, so they still reach
+    /// the nodes only that neighbor would have passed them on to.
+    pub fn with_failure_detector(mut self, config: SwimConfig) -> Self {
+        self.detector = Some(FailureDetector::new(config));
+        self
+    }
+
+    pub fn failure_detector(&self) -> Option<&FailureDetector> {
+        self.detector.as_ref()
+    }
+
+    /// Sends peers `heartbeats` and holds back retries to any that went
+    /// quiet, rather than piling more messages on a peer that is down.
+    pub fn with_heartbeats(mut self, heartbeats: Heartbeats) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    pub fn heartbeats(&self) -> Option<&Heartbeats> {
+        self.heartbeats.as_ref()
+    }
+
+    /// Packs the values of gossip batches that would take more than
+    /// `above` bytes as a JSON array, for peers that can read them. Gossip
+    /// says which encodings its sender reads, and only nodes with
+    /// compression on say any, so other peers keep getting plain lists.
+    /// Peers that both this node's and their handshake say read
+    /// [`Capability::Gzip`] are sent the batch gzipped instead, and ones
+    /// that read [`Capability::MsgPack`] are sent MessagePack.
+    pub fn with_compression(mut self, above: usize) -> Self {
+        self.compress_above = Some(above);
+        self
+    }
+
+    /// Says hello to peers after `init` with `handshake`. With compression
+    /// on, it advertises [`Capability::PackedValues`] too, so peers can
+    /// send packed values before any gossip has said so.
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Some(handshake);
+        self
+    }
+
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.handshake.as_ref()
+    }
+
+    /// How many values that originated on each node this one has, if it
+    /// keeps count.
+    pub fn clock(&self) -> Option<&VectorClock> {
+        self.clock.as_ref()
+    }
+
+    /// Gossip carrying `messages` to `peer`, packed if there are enough of
+    /// them and the peer reads packed values.
+    fn gossip_to(&self, peer: &str, messages: Vec<usize>) -> Payload {
+        let accepts = match self.compress_above {
+            Some(_) => vec![Encoding::DeltaVarint],
+            None => Vec::new(),
+        };
+        // With a clock, values whose origin is known go with it, so the
+        // peer's clock counts them as this one's does.
+        let mut origins: HashMap<String, Vec<(u64, usize)>> = HashMap::new();
+        let messages = match &self.clock {
+            Some(_) => messages
+                .into_iter()
+                .filter(|message| {
+                    let Some((origin, at)) = self.origin_of.get(message) else {
+                        return true;
+                    };
+                    origins
+                        .entry(origin.clone())
+                        .or_default()
+                        .push((*at, *message));
+                    false
+                })
+                .collect(),
+            None => messages,
+        };
+        let encoding = match &self.handshake {
+            Some(handshake) if handshake.supports(peer, Capability::Gzip) => Some(Encoding::Gzip),
+            Some(handshake) if handshake.supports(peer, Capability::MsgPack) => {
+                Some(Encoding::MsgPack)
+            }
+            _ if self.packing_peers.contains(peer) => Some(Encoding::DeltaVarint),
+            _ => None,
+        };
+        let (messages, packed) = match (self.compress_above, encoding) {
+            (Some(above), Some(encoding)) if compress::json_len(&messages) > above => {
+                let packed = Packed::encode(encoding, &messages);
+                (Vec::new(), Some(packed))
+            }
+            _ => (messages, None),
+        };
+        Payload::Gossip {
+            messages,
+            clock: self.clock.clone().filter(|_| self.stamped),
+            packed,
+            origins,
+            accepts,
+        }
+    }
+
+    /// Asks `peer` for the values it has above `after`.
+    fn request_sync(
+        &mut self,
+        peer: &str,
+        after: Option<usize>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let request = Payload::SyncRequest {
+            clock: self.clock.clone(),
+            after,
+        };
+        let msg = Message::new(
+            &self.membership.node_id,
+            peer,
+            Some(self.msg_ids.next()),
+            request,
+        );
+        self.retries.send(msg, out)
+    }
+
+    /// The chunk of values to answer a `sync_request` with: those above
+    /// `after` that `clock` doesn't cover, ascending.
+    fn sync_chunk(&self, clock: Option<&VectorClock>, after: Option<usize>) -> Payload {
+        let covered: HashSet<usize> = match clock {
+            Some(clock) => self
+                .origins
+                .iter()
+                .flat_map(|(origin, values)| {
+                    let have = (clock.get(origin) as usize).min(values.len());
+                    values[..have].iter().copied()
+                })
+                .collect(),
+            None => HashSet::new(),
+        };
+        let mut values: Vec<usize> = self
+            .seen
+            .iter()
+            .copied()
+            .filter(|value| after.is_none_or(|after| *value > after) && !covered.contains(value))
+            .collect();
+        values.sort_unstable();
+        let chunk = self.sync_chunk.unwrap_or(SYNC_CHUNK).max(1);
+        let done = values.len() <= chunk;
+        values.truncate(chunk);
+        Payload::SyncResponse { values, done }
+    }
+
+    /// Sends a random live peer a `catch_up` with this node's clock, if
+    /// pulling.
+    fn pull(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let Some(clock) = &self.clock else {
+            return Ok(());
+        };
+        let (detector, heartbeats) = (&self.detector, &self.heartbeats);
+        let peer = self
+            .membership
+            .peers()
+            .filter(|peer| {
+                !detector.as_ref().is_some_and(|d| d.is_dead(peer))
+                    && heartbeats.as_ref().is_none_or(|h| h.is_alive(peer))
+            })
+            .choose(&mut time::rng());
+        let Some(peer) = peer else {
+            return Ok(());
+        };
+        let catch_up = Payload::CatchUp {
+            clock: clock.clone(),
+        };
+        Message::new(
+            &self.membership.node_id,
+            peer,
+            Some(self.msg_ids.next()),
+            catch_up,
+        )
+        .send(out)
+    }
+
+    /// [`GossipConfig::fanout`] of `peers` at random, or all of them.
+    fn fan_out(&self, mut peers: Vec<String>) -> Vec<String> {
+        if let Some(fanout) = self.gossip.fanout {
+            peers.shuffle(&mut time::rng());
+            peers.truncate(fanout);
+        }
+        peers
+    }
+
+    /// Sends live neighbors, as many as the fanout allows, a Bloom filter of the values seen, in
+    /// [`BroadcastMode::Digest`]. Digests aren't answered or retried; the
+    /// next round's make up for lost ones.
+    fn send_digests(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let seed = time::rng().gen();
+        let mut filter = BloomFilter::new(self.seen.len(), DIGEST_FALSE_POSITIVES, seed);
+        for message in &self.seen {
+            filter.insert(message);
+        }
+        for peer in self.fan_out(self.targets()) {
+            let digest = Payload::Digest {
+                filter: filter.clone(),
+            };
+            Message::new(&self.membership.node_id, &peer, None, digest).send(out)?;
+        }
+        Ok(())
+    }
+
+    /// Notes `message` as originating on `origin`, as its next value.
+    fn originated(&mut self, origin: &str, message: usize) {
+        let Some(clock) = &mut self.clock else {
+            return;
+        };
+        let values = self.origins.entry(origin.to_string()).or_default();
+        values.push(message);
+        let at = values.len() as u64;
+        clock.observe(origin, at);
+        self.origin_of.insert(message, (origin.to_string(), at));
+    }
+
+    /// Notes `message` as the `at`th value to originate on `origin`, if it
+    /// is the next one this node is missing from there. Out of order, it is
+    /// left to a `catch_up` to place.
+    fn received(&mut self, origin: &str, at: u64, message: usize) {
+        let have = self.origins.get(origin).map_or(0, Vec::len) as u64;
+        if self.clock.is_some() && at == have + 1 {
+            self.originated(origin, message);
+        }
+    }
+
+    /// The values this node has beyond `clock`.
+    fn missing_from(&self, clock: &VectorClock) -> HashMap<String, Vec<usize>> {
+        self.origins
+            .iter()
+            .filter_map(|(origin, values)| {
+                let missing = values.get(clock.get(origin) as usize..)?;
+                (!missing.is_empty()).then(|| (origin.clone(), missing.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Takes in a `catch_up_ok`: `values` by origin, ending at `clock`.
+    fn catch_up(
+        &mut self,
+        clock: &VectorClock,
+        values: &HashMap<String, Vec<usize>>,
+        from: &str,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        for (origin, values) in values {
+            let have = self.origins.get(origin).map_or(0, Vec::len);
+            let start = (clock.get(origin) as usize).saturating_sub(values.len());
+            // A gap means another response got here first; the next
+            // round of gossip will sort it out.
+            let Some(new) = have.checked_sub(start).and_then(|skip| values.get(skip..)) else {
+                continue;
+            };
+            for &message in new {
+                self.originated(origin, message);
+                if self.seen.insert(message) {
+                    self.forward(message, from, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn forward(
+        &mut self,
+        message: usize,
+        except: &str,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if self.mode == BroadcastMode::Digest {
+            // The next digest round takes it to the neighbors.
+            return Ok(());
+        }
+        let targets = self.targets();
+        if matches!(self.mode, BroadcastMode::Gossip | BroadcastMode::Leader) {
+            for peer in targets.iter().filter(|peer| *peer != except) {
+                self.outbox.entry(peer.clone()).or_default().insert(message);
+            }
+            return Ok(());
+        }
+        for peer in targets.iter().filter(|peer| *peer != except) {
+            let msg = Message::new(
+                &self.membership.node_id,
+                peer,
+                Some(self.msg_ids.next()),
+                Payload::Broadcast { message },
+            );
+            match self.mode {
+                BroadcastMode::Forward => msg.send(out)?,
+                _ => self.retries.send(msg, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The nodes this one forwards broadcasts to, taken from the last
+    /// `topology` message.
+    pub fn neighbors(&self) -> &[String] {
+        &self.neighbors
+    }
+
+    /// Where new values go: the neighbors, unless the failure detector
+    /// declared one of them dead, in which case every live peer.
+    fn targets(&self) -> Vec<String> {
+        let Some(detector) = &self.detector else {
+            return self.neighbors.clone();
+        };
+        if !self.neighbors.iter().any(|peer| detector.is_dead(peer)) {
+            return self.neighbors.clone();
+        }
+        let mut peers: Vec<_> = detector.live_peers().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// The node that fans out every value in [`BroadcastMode::Leader`]:
+    /// the lowest id, shorter ids first so that `n2` comes before `n10`.
+    pub fn leader(&self) -> Option<&str> {
+        self.membership
+            .node_ids
+            .iter()
+            .min_by_key(|id| (id.len(), *id))
+            .map(String::as_str)
+    }
+
+    /// Picks neighbors as the mode and topology strategy say.
+    fn pick_neighbors(&mut self) {
+        let me = &self.membership.node_id;
+        self.neighbors = match self.leader() {
+            Some(leader) if self.mode == BroadcastMode::Leader && leader == me => {
+                self.membership.peers().cloned().collect()
+            }
+            Some(leader) if self.mode == BroadcastMode::Leader => vec![leader.to_string()],
+            _ => self
+                .topology
+                .neighbors(me, &self.membership.node_ids, &self.provided),
+        };
+    }
+
+    /// Picks neighbors again after a node joined or left, and tells the
+    /// failure detector and heartbeats.
+    fn membership_changed(&mut self) {
+        self.pick_neighbors();
+        if let Some(detector) = &mut self.detector {
+            detector.set_membership(&self.membership);
+        }
+        if let Some(heartbeats) = &mut self.heartbeats {
+            heartbeats.set_membership(&self.membership);
+        }
+        if let Some(handshake) = &mut self.handshake {
+            handshake.set_membership(&self.membership);
+        }
+    }
+
+    /// Sends `payload` to every peer but `except`, until each acks.
+    fn tell_peers(
+        &mut self,
+        payload: Payload,
+        except: &str,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let peers: Vec<_> = self
+            .membership
+            .peers()
+            .filter(|peer| *peer != except)
+            .cloned()
+            .collect();
+        for peer in peers {
+            let msg = Message::new(
+                &self.membership.node_id,
+                &peer,
+                Some(self.msg_ids.next()),
+                payload.clone(),
+            );
+            self.retries.send(msg, out)?;
+        }
+        Ok(())
+    }
+
+    /// Handles `join` for `node`, from `src`: either `node` itself asking
+    /// us to sponsor it, or its sponsor.
+    fn join(&mut self, node: &str, src: &str, out: &mut impl Write) -> anyhow::Result<Payload> {
+        let sponsoring = node == src;
+        let sponsor = match sponsoring {
+            true => self.membership.node_id.clone(),
+            false => src.to_string(),
+        };
+        if self.membership.add(node) {
+            crate::info!("{node} joined, sponsored by {sponsor}");
+            link(&mut self.provided, &sponsor, node);
+            self.membership_changed();
+            if sponsoring {
+                self.tell_peers(Payload::Join { node: node.into() }, node, out)?;
+            }
+        }
+        if !sponsoring {
+            return Ok(Payload::JoinOk {
+                node_ids: Vec::new(),
+                messages: Vec::new(),
+            });
+        }
+        Ok(Payload::JoinOk {
+            node_ids: self.membership.node_ids.clone(),
+            messages: self.seen.iter().copied().collect(),
+        })
+    }
+
+    /// Handles `leave` for `node`: if it is us, tells everyone else,
+    /// otherwise forgets `node`.
+    fn leave(&mut self, node: &str, out: &mut impl Write) -> anyhow::Result<()> {
+        if node == self.membership.node_id {
+            crate::info!("leaving the cluster");
+            return self.tell_peers(Payload::Leave { node: node.into() }, node, out);
+        }
+        if self.membership.remove(node) {
+            crate::info!("{node} left");
+            unlink(&mut self.provided, node);
+            self.outbox.remove(node);
+            self.membership_changed();
+        }
+        Ok(())
+    }
+}
+
+/// Makes `a` and `b` neighbors in `topology`.
+fn link(topology: &mut HashMap<String, Vec<String>>, a: &str, b: &str) {
+    for (from, to) in [(a, b), (b, a)] {
+        let neighbors = topology.entry(from.to_string()).or_default();
+        if !neighbors.iter().any(|n| n == to) {
+            neighbors.push(to.to_string());
+        }
+    }
+}
+
+/// Removes `node` from `topology`, making its neighbors each other's so
+/// that the graph stays connected.
+fn unlink(topology: &mut HashMap<String, Vec<String>>, node: &str) {
+    let orphans = topology.remove(node).unwrap_or_default();
+    for neighbors in topology.values_mut() {
+        neighbors.retain(|n| n != node);
+    }
+    for (i, a) in orphans.iter().enumerate() {
+        for b in &orphans[i + 1..] {
+            link(topology, a, b);
+        }
+    }
+}
+
+impl Node for BroadcastNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        if let Some(heartbeats) = &mut self.heartbeats {
+            if heartbeats.observe(&input) {
+                return Ok(());
+            }
+        }
+        if let Some(detector) = &mut self.detector {
+            if detector.handle(&input, output)? {
+                return Ok(());
+            }
+        }
+        if let Some(handshake) = &mut self.handshake {
+            if handshake.handle(&input, output)? {
+                if handshake.supports(&input.src, Capability::PackedValues) {
+                    self.packing_peers.insert(input.src.clone());
+                }
+                return Ok(());
+            }
+        }
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                // A sponsor answers with the cluster; until then this node
+                // has no neighbors.
+                let joining = !self.membership.is_peer(node_id);
+                let sponsor = self.leader().filter(|_| joining).map(str::to_string);
+                if let Some(sponsor) = sponsor {
+                    self.membership.node_ids = vec![node_id.clone()];
+                    let join = Message::new(
+                        node_id,
+                        &sponsor,
+                        Some(self.msg_ids.next()),
+                        Payload::Join {
+                            node: node_id.clone(),
+                        },
+                    );
+                    self.retries.send(join, output)?;
+                }
+                self.pick_neighbors();
+                if self.sync_on_init && !joining {
+                    let peer = self.membership.peers().choose(&mut time::rng()).cloned();
+                    if let Some(peer) = peer {
+                        self.request_sync(&peer, None, output)?;
+                    }
+                }
+                if let Some(detector) = &mut self.detector {
+                    detector.init(&self.membership, self.msg_ids.clone());
+                }
+                if let Some(heartbeats) = &mut self.heartbeats {
+                    heartbeats.init(&self.membership);
+                }
+                if let Some(handshake) = &mut self.handshake {
+                    if self.compress_above.is_some() {
+                        handshake.advertise(Capability::PackedValues);
+                    }
+                    handshake.init(&self.membership, output)?;
+                }
+                if self.tick_interval().is_some() {
+                    self.timers.schedule(Duration::ZERO, Timer::Round);
+                }
+                if self.pull.is_some() {
+                    self.timers.schedule(Duration::ZERO, Timer::Pull);
+                }
+                Payload::InitOk
+            }
+            Payload::Broadcast { message } => {
+                // Already-seen values are acked but neither stored nor
+                // relayed again, which keeps forwarding from looping.
+                if self.seen.insert(*message) {
+                    if !self.membership.is_peer(&input.src) {
+                        let node_id = self.membership.node_id.clone();
+                        self.originated(&node_id, *message);
+                    }
+                    self.forward(*message, &input.src, output)?;
+                }
+                Payload::BroadcastOk
+            }
+            Payload::Gossip {
+                messages,
+                clock,
+                packed,
+                origins,
+                accepts,
+            } => {
+                if accepts.contains(&Encoding::DeltaVarint) {
+                    self.packing_peers.insert(input.src.clone());
+                }
+                let unpacked = match packed {
+                    Some(packed) => packed.decode()?,
+                    None => Vec::new(),
+                };
+                for (origin, values) in origins {
+                    let mut values = values.clone();
+                    values.sort_unstable();
+                    for (at, message) in values {
+                        self.received(origin, at, message);
+                        if self.seen.insert(message) {
+                            self.forward(message, &input.src, output)?;
+                        }
+                    }
+                }
+                for message in messages.iter().chain(&unpacked) {
+                    if self.seen.insert(*message) {
+                        self.forward(*message, &input.src, output)?;
+                    }
+                }
+                if let (Some(theirs), Some(ours)) = (clock, &self.clock) {
+                    if !ours.includes(theirs) {
+                        let catch_up = Payload::CatchUp {
+                            clock: ours.clone(),
+                        };
+                        Message::new(
+                            &self.membership.node_id,
+                            &input.src,
+                            Some(self.msg_ids.next()),
+                            catch_up,
+                        )
+                        .send(output)?;
+                    }
+                }
+                Payload::GossipOk
+            }
+            Payload::Digest { filter } => {
+                let missing: Vec<usize> = self
+                    .seen
+                    .iter()
+                    .copied()
+                    .filter(|message| !filter.contains(message))
+                    .collect();
+                if !missing.is_empty() {
+                    let gossip = self.gossip_to(&input.src, missing);
+                    Message::new(&self.membership.node_id, &input.src, None, gossip)
+                        .send(output)?;
+                }
+                return Ok(());
+            }
+            Payload::SyncRequest { clock, after } => self.sync_chunk(clock.as_ref(), *after),
+            Payload::SyncResponse { values, done } => {
+                self.seen.extend(values);
+                // A duplicate's follow-up was already asked for.
+                let first = self.retries.ack(&input);
+                if let (true, false, Some(&last)) = (first, done, values.last()) {
+                    self.request_sync(&input.src, Some(last), output)?;
+                }
+                return Ok(());
+            }
+            Payload::CatchUp { clock } => match &self.clock {
+                Some(ours) => Payload::CatchUpOk {
+                    clock: ours.clone(),
+                    values: self.missing_from(clock),
+                },
+                None => return Err(RpcError::not_supported("this node keeps no clock").into()),
+            },
+            Payload::CatchUpOk { clock, values } => {
+                return self.catch_up(clock, values, &input.src, output);
+            }
+            Payload::Read { .. } => Payload::ReadOk {
+                value: ReadValue::Messages {
+                    messages: self.seen.iter().copied().collect(),
+                },
+            },
+            Payload::Topology { topology } => {
+                self.provided = topology.clone();
+                self.pick_neighbors();
+                Payload::TopologyOk
+            }
+            Payload::Join { node } => self.join(node, &input.src, output)?,
+            Payload::JoinOk { node_ids, messages } => {
+                self.retries.ack(&input);
+                if !node_ids.is_empty() {
+                    self.membership.node_ids = node_ids.clone();
+                    self.seen.extend(messages);
+                    link(&mut self.provided, &input.src, &self.membership.node_id);
+                    self.membership_changed();
+                }
+                return Ok(());
+            }
+            Payload::Leave { node } => {
+                self.leave(node, output)?;
+                Payload::LeaveOk
+            }
+            Payload::LeaveOk => {
+                self.retries.ack(&input);
+                return Ok(());
+            }
+            Payload::BroadcastOk | Payload::GossipOk => {
+                self.retries.ack(&input);
+                return Ok(());
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(
+                    RpcError::not_supported("broadcast node cannot handle this message").into(),
+                )
+            }
+        };
+        // Nobody waits for a reply to a message without a msg_id, such as
+        // a gossip that only carries a clock.
+        if input.body.id.is_none() {
+            return Ok(());
+        }
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        match self.mode {
+            BroadcastMode::Forward
+                if self.clock.is_none()
+                    && self.detector.is_none()
+                    && self.heartbeats.is_none()
+                    && self.handshake.is_none() =>
+            {
+                None
+            }
+            _ => Some(self.gossip.tick()),
+        }
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    fn tick(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        if let Some(detector) = &mut self.detector {
+            detector.tick(output)?;
+        }
+        if let Some(heartbeats) = &mut self.heartbeats {
+            heartbeats.tick(output)?;
+        }
+        if let Some(handshake) = &mut self.handshake {
+            handshake.tick(output)?;
+        }
+        let (detector, heartbeats) = (&self.detector, &self.heartbeats);
+        self.retries.resend_due_unless(output, |peer| {
+            detector.as_ref().is_some_and(|d| d.is_dead(peer))
+                || heartbeats.as_ref().is_some_and(|h| !h.is_alive(peer))
+        })?;
+        // Ticks may come a little early; half a tick of slack keeps a
+        // round from slipping to the tick after.
+        let due = self.timers.expire(time::now() + self.gossip.tick() / 2);
+        if let (true, Some(interval)) = (due.contains(&Timer::Pull), self.pull) {
+            self.timers.schedule(interval, Timer::Pull);
+            self.pull(output)?;
+        }
+        if !due.contains(&Timer::Round) {
+            return Ok(());
+        }
+        self.timers
+            .schedule(self.gossip.next_interval(), Timer::Round);
+        let waiting = self
+            .outbox
+            .iter()
+            .filter(|(peer, pending)| {
+                !pending.is_empty() && !self.detector.as_ref().is_some_and(|d| d.is_dead(peer))
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in self.fan_out(waiting) {
+            let pending = self.outbox.entry(peer.clone()).or_default();
+            let messages: Vec<usize> = pending
+                .iter()
+                .take(self.gossip.max_batch.unwrap_or(usize::MAX))
+                .copied()
+                .collect();
+            for message in &messages {
+                pending.remove(message);
+            }
+            let gossip = self.gossip_to(&peer, messages);
+            let msg = Message::new(
+                &self.membership.node_id,
+                &peer,
+                Some(self.msg_ids.next()),
+                gossip,
+            );
+            self.retries.send(msg, output)?;
+        }
+        // Stamps alone are cheap to lose, so they aren't retried.
+        if self.stamped {
+            for peer in &self.neighbors {
+                let gossip = self.gossip_to(peer, Vec::new());
+                Message::new(&self.membership.node_id, peer, None, gossip).send(output)?;
+            }
+        }
+        if self.mode == BroadcastMode::Digest {
+            self.send_digests(output)?;
+        }
+        Ok(())
+    }
+
+    /// The values seen, in order, and how many are still on their way to
+    /// each neighbor.
+    fn inspect(&self) -> Value {
+        let mut seen: Vec<_> = self.seen.iter().collect();
+        seen.sort_unstable();
+        let outbox: HashMap<_, _> = self
+            .outbox
+            .iter()
+            .map(|(peer, values)| (peer, values.len()))
+            .collect();
+        json!({
+            "seen": seen,
+            "outbox": outbox,
+            "unacked": self.retries.len(),
+        })
+    }
+
+    fn topology(&self) -> Value {
+        json!({
+            "node_id": self.membership.node_id,
+            "node_ids": self.membership.node_ids,
+            "strategy": format!("{:?}", self.topology),
+            "neighbors": self.neighbors,
+        })
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,184 @@
+//! Causal broadcast: every node delivers a broadcast value only after the
+//! values its origin had delivered when it broadcast it.
+//!
+//! The origin sends each value straight to every peer, stamped with a
+//! vector clock of how many values from each node it had delivered, the
+//! new one included. A peer that receives a value before one it depends on
+//! holds it back until that one has been delivered, so receiving a value
+//! and delivering it are separate steps: [`CausalBroadcastNode::on_deliver`]
+//! hears of values in an order consistent with causality, whatever order
+//! the network brought them in.
+
+use crate::{
+    clock::VectorClock, payload::ReadValue, CacheLimits, Membership, Message, MsgIdAllocator, Node,
+    Payload, RetryQueue, RpcError,
+};
+use std::{fmt, io::Write, time::Instant};
+
+/// Called with the node a value originated on and the value, once it is
+/// delivered.
+pub type DeliverFn = Box<dyn FnMut(&str, usize)>;
+
+/// Serves the `broadcast` workload with causal delivery. `read` returns
+/// the values delivered so far, and `topology` is ignored, since values go
+/// to every peer.
+#[derive(Default)]
+pub struct CausalBroadcastNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    /// The values delivered, in the order they were.
+    delivered: Vec<usize>,
+    /// How many values from each node have been delivered.
+    clock: VectorClock,
+    /// Values received before some value they depend on, with the node
+    /// they originated on and their stamp.
+    held: Vec<(String, VectorClock, usize)>,
+    retries: RetryQueue,
+    on_deliver: Option<DeliverFn>,
+}
+
+impl fmt::Debug for CausalBroadcastNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CausalBroadcastNode")
+            .field("membership", &self.membership)
+            .field("delivered", &self.delivered)
+            .field("clock", &self.clock)
+            .field("held", &self.held)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CausalBroadcastNode {
+    /// Calls `deliver` with every value delivered from now on, in order,
+    /// those broadcast on this node included.
+    pub fn on_deliver(mut self, deliver: impl FnMut(&str, usize) + 'static) -> Self {
+        self.on_deliver = Some(Box::new(deliver));
+        self
+    }
+
+    /// Gives up on unacked values as `limits` says, see
+    /// [`RetryQueue::with_limits`].
+    pub fn with_retry_limits(mut self, limits: CacheLimits) -> Self {
+        self.retries = std::mem::take(&mut self.retries).with_limits(limits);
+        self
+    }
+
+    /// The values delivered, in the order they were.
+    pub fn delivered(&self) -> &[usize] {
+        &self.delivered
+    }
+
+    /// How many values were received but are waiting on others.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Delivers a client's `message` and sends it to every peer.
+    fn broadcast(&mut self, message: usize, out: &mut impl Write) -> anyhow::Result<()> {
+        let node_id = self.membership.node_id.clone();
+        self.clock.increment(&node_id);
+        self.deliver(&node_id, message);
+        for peer in self.membership.peers() {
+            let payload = Payload::CausalBroadcast {
+                message,
+                clock: self.clock.clone(),
+            };
+            let msg = Message::new(&node_id, peer, Some(self.msg_ids.next()), payload);
+            self.retries.send(msg, out)?;
+        }
+        Ok(())
+    }
+
+    /// Takes in `message` from `origin`, delivering it and anything held
+    /// back that it unblocks, unless it depends on values not delivered
+    /// yet.
+    fn receive(&mut self, origin: &str, clock: &VectorClock, message: usize) {
+        let seq = clock.get(origin);
+        let held = self
+            .held
+            .iter()
+            .any(|(node, stamp, _)| node == origin && stamp.get(node) == seq);
+        if seq <= self.clock.get(origin) || held {
+            return;
+        }
+        self.held.push((origin.to_string(), clock.clone(), message));
+        while let Some(ready) = self
+            .held
+            .iter()
+            .position(|(origin, stamp, _)| self.is_ready(origin, stamp))
+        {
+            let (origin, stamp, message) = self.held.swap_remove(ready);
+            self.clock.observe(&origin, stamp.get(&origin));
+            self.deliver(&origin, message);
+        }
+    }
+
+    /// Whether a value from `origin` stamped `stamp` is the next one from
+    /// it, and everything else it depends on has been delivered.
+    fn is_ready(&self, origin: &str, stamp: &VectorClock) -> bool {
+        stamp.iter().all(|(node, seq)| match node == origin {
+            true => seq == self.clock.get(node) + 1,
+            false => seq <= self.clock.get(node),
+        })
+    }
+
+    fn deliver(&mut self, origin: &str, message: usize) {
+        crate::debug!("delivering {message} from {origin}");
+        self.delivered.push(message);
+        if let Some(deliver) = &mut self.on_deliver {
+            deliver(origin, message);
+        }
+    }
+}
+
+impl Node for CausalBroadcastNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                Payload::InitOk
+            }
+            Payload::Broadcast { message } => {
+                self.broadcast(*message, output)?;
+                Payload::BroadcastOk
+            }
+            Payload::CausalBroadcast { message, clock } => {
+                self.receive(&input.src, clock, *message);
+                Payload::CausalBroadcastOk
+            }
+            Payload::CausalBroadcastOk => {
+                self.retries.ack(&input);
+                return Ok(());
+            }
+            Payload::Read { .. } => Payload::ReadOk {
+                value: ReadValue::Messages {
+                    messages: self.delivered.clone(),
+                },
+            },
+            Payload::Topology { .. } => Payload::TopologyOk,
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(RpcError::not_supported(
+                    "causal broadcast node cannot handle this message",
+                )
+                .into())
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.retries.next_due()
+    }
+
+    fn tick(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        self.retries.resend_due(output)?;
+        Ok(())
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,131 @@
+//! Hybrid logical clocks: wall-clock milliseconds with a logical counter
+//! for events within the same millisecond, or while the local clock is
+//! behind one we heard from.
+//!
+//! Like a Lamport clock, a receipt is always stamped later than the send,
+//! however skewed the two nodes' clocks are; unlike one, timestamps stay
+//! within the skew of real time, so "latest" means roughly what a client
+//! would expect. That makes them good versions for last-writer-wins
+//! updates, see [`Versioned::stamped`](crate::services::Versioned::stamped).
+
+use crate::time;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+/// A point in hybrid logical time. Ordered by wall time, then by the
+/// logical counter.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct HlcTimestamp {
+    /// Milliseconds since the Unix epoch.
+    pub wall: u64,
+    pub logical: u16,
+}
+
+impl HlcTimestamp {
+    /// Packs the timestamp into one number with the same order: 48 bits of
+    /// wall time, then 16 of logical counter.
+    pub fn to_u64(self) -> u64 {
+        self.wall << 16 | u64::from(self.logical)
+    }
+
+    pub fn from_u64(packed: u64) -> Self {
+        Self {
+            wall: packed >> 16,
+            logical: packed as u16,
+        }
+    }
+
+    /// The next timestamp after this one at the same wall time, or at the
+    /// next millisecond once the counter is used up.
+    fn next(self) -> Self {
+        match self.logical.checked_add(1) {
+            Some(logical) => Self { logical, ..self },
+            None => Self {
+                wall: self.wall + 1,
+                logical: 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.wall, self.logical)
+    }
+}
+
+/// A hybrid logical clock. Clones share the clock, like
+/// [`LamportClock`](super::LamportClock).
+#[derive(Clone)]
+pub struct HybridClock {
+    last: Arc<Mutex<HlcTimestamp>>,
+    wall: Arc<dyn Fn() -> u64 + Send + Sync>,
+}
+
+impl fmt::Debug for HybridClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HybridClock")
+            .field("last", &self.last())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HybridClock {
+    fn default() -> Self {
+        Self::with_wall_clock(|| {
+            time::system_now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        })
+    }
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A clock that reads wall time, in milliseconds, from `wall` rather
+    /// than the system clock; for simulating skew.
+    pub fn with_wall_clock(wall: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        Self {
+            last: Arc::default(),
+            wall: Arc::new(wall),
+        }
+    }
+
+    /// The last timestamp handed out or observed, without moving the clock.
+    pub fn last(&self) -> HlcTimestamp {
+        *self.last.lock().unwrap()
+    }
+
+    /// Timestamps a local event, such as a send or a write.
+    pub fn now(&self) -> HlcTimestamp {
+        self.advance(None)
+    }
+
+    /// Timestamps receiving a message stamped `remote`: later than both
+    /// `remote` and anything this clock has handed out before.
+    pub fn observe(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.advance(Some(remote))
+    }
+
+    fn advance(&self, remote: Option<HlcTimestamp>) -> HlcTimestamp {
+        let wall = (self.wall)();
+        let mut last = self.last.lock().unwrap();
+        let latest = remote.map_or(*last, |remote| remote.max(*last));
+        *last = if wall > latest.wall {
+            HlcTimestamp { wall, logical: 0 }
+        } else {
+            latest.next()
+        };
+        *last
+    }
+}
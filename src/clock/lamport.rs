@@ -0,0 +1,94 @@
+//! Lamport clocks: a single counter that moves forward on every send and
+//! receive, so that a message is always stamped later than everything its
+//! sender had seen when sending it.
+//!
+//! A node that wants its messages stamped uses [`Stamped<P>`] as its payload
+//! type: the stamp travels as a `lamport` field next to `P`'s own, and
+//! messages without one (from Maelstrom clients, say) still parse.
+
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// A Lamport clock. Clones share the counter, like
+/// [`MsgIdAllocator`](crate::MsgIdAllocator).
+#[derive(Debug, Clone, Default)]
+pub struct LamportClock(Arc<AtomicU64>);
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current time, without moving the clock.
+    pub fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Counts a local event, such as a send, and returns its time.
+    pub fn tick(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Counts receiving a message stamped `time` and returns the time of
+    /// the receipt, which is later than both `time` and anything before.
+    pub fn observe(&self, time: u64) -> u64 {
+        self.0.fetch_max(time, Ordering::SeqCst);
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// A tick made unique across the cluster by `node_id`, for a total
+    /// order of events such as last-writer-wins updates.
+    pub fn timestamp(&self, node_id: &str) -> Timestamp {
+        Timestamp {
+            time: self.tick(),
+            node: node_id.to_string(),
+        }
+    }
+
+    /// Stamps `msg` with a tick and sends it.
+    pub fn send<P: Serialize>(&self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()> {
+        let Message { src, dest, body } = msg;
+        let payload = Stamped {
+            lamport: Some(self.tick()),
+            payload: body.payload,
+        };
+        let mut msg = Message::new(src, dest, body.id, payload);
+        msg.body.in_reply_to = body.in_reply_to;
+        msg.send(out)
+    }
+
+    /// Observes `msg`'s stamp, if it has one, and returns it unwrapped.
+    pub fn receive<P>(&self, msg: Message<Stamped<P>>) -> Message<P> {
+        if let Some(time) = msg.body.payload.lamport {
+            self.observe(time);
+        }
+        let Message { src, dest, body } = msg;
+        let mut msg = Message::new(src, dest, body.id, body.payload.payload);
+        msg.body.in_reply_to = body.in_reply_to;
+        msg
+    }
+}
+
+/// A Lamport time with the id of the node it was taken on, which breaks
+/// ties: timestamps are totally ordered, by time and then by node.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub time: u64,
+    pub node: String,
+}
+
+/// A payload `P` with the sender's Lamport time alongside.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stamped<P> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    #[serde(flatten)]
+    pub payload: P,
+}
@@ -0,0 +1,10 @@
+//! Logical clocks, for ordering events across nodes without trusting their
+//! wall clocks.
+
+pub mod hlc;
+pub mod lamport;
+pub mod vector;
+
+pub use hlc::{HlcTimestamp, HybridClock};
+pub use lamport::{LamportClock, Stamped, Timestamp};
+pub use vector::VectorClock;
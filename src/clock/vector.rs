@@ -0,0 +1,92 @@
+//! Vector clocks: one counter per node, which together say which events a
+//! node has seen, and so whether one state happened before another or the
+//! two are concurrent.
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Counters keyed by node id. Missing nodes count as zero, and serialize
+/// as a plain JSON object: `{"n1": 3, "n2": 1}`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Counts an event on `node` and returns its new counter.
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let counter = self.0.entry(node.to_string()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Raises `node`'s counter to `counter`, if it is lower.
+    pub fn observe(&mut self, node: &str, counter: u64) {
+        if counter > self.get(node) {
+            self.0.insert(node.to_string(), counter);
+        }
+    }
+
+    /// Takes the pointwise maximum with `other`.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &counter) in &other.0 {
+            self.observe(node, counter);
+        }
+    }
+
+    /// `Less` if `self` happened before `other`, `Greater` if after,
+    /// `Equal` if they are the same and `None` if they are concurrent.
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            let next = match self.get(node).cmp(&other.get(node)) {
+                Ordering::Equal => continue,
+                next => next,
+            };
+            if ordering != Ordering::Equal && ordering != next {
+                return None;
+            }
+            ordering = next;
+        }
+        Some(ordering)
+    }
+
+    /// Whether `self` has seen every event `other` has.
+    pub fn includes(&self, other: &VectorClock) -> bool {
+        other
+            .iter()
+            .all(|(node, counter)| self.get(node) >= counter)
+    }
+
+    /// Whether neither clock happened before the other.
+    pub fn concurrent(&self, other: &VectorClock) -> bool {
+        self.compare(other).is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0
+            .iter()
+            .map(|(node, &counter)| (node.as_str(), counter))
+    }
+}
+
+impl PartialEq for VectorClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.compare(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for VectorClock {}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.compare(other)
+    }
+}
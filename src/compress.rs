@@ -0,0 +1,179 @@
+//! Encodings for the broadcast values gossip carries, for batches big
+//! enough that a JSON array of them gets costly.
+//!
+//! A batch can go [gzip]ped, which keeps it as it is, order
+//! and duplicates included, and takes the runs of close values a batch
+//! tends to hold down to a fraction of their JSON. Peers that can't read
+//! gzip get it packed instead: sorted, with the gaps between consecutive
+//! values written as variable-length integers, a byte or so per value
+//! against three to six in JSON, but losing order and duplicates, which
+//! broadcast doesn't need. Batches can also go as
+//! [MessagePack](crate::msgpack), which keeps their order but saves less.
+//! The bytes travel as a base64 string, with an [`Encoding`] marking how
+//! to read them back.
+//!
+//! Only peers that said they can read an encoding are sent it; see
+//! [`crate::BroadcastNode::with_compression`].
+
+use crate::{gzip, msgpack, ErrorCode, RpcError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    /// The values sorted, each written as its gap from the one before as
+    /// a LEB128 varint, then base64-encoded. Order and duplicates are lost.
+    DeltaVarint,
+    /// The values as a MessagePack array, then base64-encoded. Only sent
+    /// to peers whose handshake said they read it, since nodes from before
+    /// it can't parse gossip naming it.
+    #[serde(rename = "msgpack")]
+    MsgPack,
+    /// The values as a JSON array, gzipped, then base64-encoded. Only sent
+    /// to peers whose handshake said they read it, as with MessagePack.
+    Gzip,
+}
+
+/// Values packed in an [`Encoding`], sent in place of a plain list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Packed {
+    pub encoding: Encoding,
+    pub data: String,
+}
+
+impl Packed {
+    pub fn encode(encoding: Encoding, values: &[usize]) -> Self {
+        let data = match encoding {
+            Encoding::DeltaVarint => to_base64(&delta_varint(values)),
+            Encoding::MsgPack => to_base64(&msgpack::encode(&Value::from(values))),
+            Encoding::Gzip => {
+                let json = serde_json::to_vec(values).expect("integers serialize");
+                to_base64(&gzip::compress(&json))
+            }
+        };
+        Self { encoding, data }
+    }
+
+    pub fn decode(&self) -> Result<Vec<usize>, RpcError> {
+        let bytes = from_base64(&self.data).ok_or_else(|| malformed("data is not base64"))?;
+        match self.encoding {
+            Encoding::DeltaVarint => from_delta_varint(&bytes),
+            Encoding::MsgPack => {
+                let Value::Array(values) = msgpack::decode(&bytes)? else {
+                    return Err(malformed("not an array"));
+                };
+                values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_u64()
+                            .and_then(|value| usize::try_from(value).ok())
+                            .ok_or_else(|| malformed("value is not an unsigned integer"))
+                    })
+                    .collect()
+            }
+            Encoding::Gzip => serde_json::from_slice(&gzip::decompress(&bytes)?)
+                .map_err(|_| malformed("not an array of unsigned integers")),
+        }
+    }
+}
+
+/// How many bytes `values` take as a JSON array.
+pub fn json_len(values: &[usize]) -> usize {
+    let digits: usize = values
+        .iter()
+        .map(|value| value.checked_ilog10().unwrap_or(0) as usize + 1)
+        .sum();
+    2 + digits + values.len().saturating_sub(1)
+}
+
+fn delta_varint(values: &[usize]) -> Vec<u8> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut bytes = Vec::with_capacity(sorted.len());
+    let mut last = 0;
+    for value in sorted {
+        let mut gap = (value - last) as u64;
+        last = value;
+        while gap >= 0x80 {
+            bytes.push(gap as u8 | 0x80);
+            gap >>= 7;
+        }
+        bytes.push(gap as u8);
+    }
+    bytes
+}
+
+fn from_delta_varint(bytes: &[u8]) -> Result<Vec<usize>, RpcError> {
+    let mut values = Vec::new();
+    let (mut last, mut gap, mut shift) = (0usize, 0u64, 0);
+    for &byte in bytes {
+        if shift > 63 {
+            return Err(malformed("gap overflows"));
+        }
+        gap |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            last = usize::try_from(gap)
+                .ok()
+                .and_then(|gap| last.checked_add(gap))
+                .ok_or_else(|| malformed("value overflows"))?;
+            values.push(last);
+            (gap, shift) = (0, 0);
+        }
+    }
+    if shift > 0 {
+        return Err(malformed("data ends mid-value"));
+    }
+    Ok(values)
+}
+
+fn malformed(text: &str) -> RpcError {
+    RpcError::new(
+        ErrorCode::MalformedRequest,
+        format!("packed values: {text}"),
+    )
+}
+
+pub(crate) fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3f] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+pub(crate) fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for chunk in text.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = BASE64.iter().position(|&d| d == c)? as u32;
+            n = n << 6 | digit;
+        }
+        n <<= 6 * padding as u32;
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
@@ -0,0 +1,528 @@
+//! Runtime knobs, read from `WHIRLPOOL_*` environment variables so they can
+//! be changed between Maelstrom runs without recompiling.
+
+use crate::{
+    handshake::Capability,
+    ids::IdScheme,
+    kv::quorum::QuorumConfig,
+    log::{self, Level},
+    metrics,
+    output::FlushPolicy,
+    prometheus,
+    ratelimit::RateLimits,
+    record,
+    socket::{self, SocketConfig},
+    trace, BroadcastMode, GossipConfig, TopologyStrategy,
+};
+use anyhow::{bail, Context};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `WHIRLPOOL_BROADCAST_MODE`: `forward`, `reliable`, `gossip`,
+    /// `leader` or `digest`.
+    pub broadcast_mode: BroadcastMode,
+    /// `WHIRLPOOL_BROADCAST_CLOCK`: `true` to stamp gossip with vector
+    /// clocks, see [`crate::BroadcastNode::with_clock`].
+    pub broadcast_clock: bool,
+    /// `WHIRLPOOL_BROADCAST_SYNC`: `true` for broadcast nodes to ask a
+    /// peer for what they are missing when they start, see
+    /// [`crate::BroadcastNode::with_sync`].
+    pub broadcast_sync: bool,
+    /// `WHIRLPOOL_BROADCAST_PULL_MS`: how often broadcast nodes pull from
+    /// a random peer, if at all, see [`crate::BroadcastNode::with_pull`].
+    pub broadcast_pull: Option<Duration>,
+    /// `WHIRLPOOL_BROADCAST_CAUSAL`: `true` to deliver broadcast values in
+    /// causal order instead, see [`crate::causal`].
+    pub broadcast_causal: bool,
+    /// `WHIRLPOOL_SWIM`: `true` to detect failed peers with SWIM and route
+    /// broadcasts around them, see [`crate::swim`].
+    pub swim: bool,
+    /// `WHIRLPOOL_HEARTBEAT_MS`: how often broadcast nodes send peers a
+    /// heartbeat, if at all, see [`crate::heartbeat`].
+    pub heartbeat: Option<Duration>,
+    /// `WHIRLPOOL_HANDSHAKE`: `true` for broadcast and Raft nodes to tell
+    /// peers their protocol version and capabilities after `init`, see
+    /// [`crate::handshake`].
+    pub handshake: bool,
+    /// `WHIRLPOOL_MSGPACK`: `true` for broadcast and Raft nodes to send
+    /// peers that read it MessagePack in packed gossip values and
+    /// snapshots, see [`crate::msgpack`]. Implies `WHIRLPOOL_HANDSHAKE`,
+    /// which is how peers tell.
+    pub msgpack: bool,
+    /// `WHIRLPOOL_GZIP`: `true` for broadcast and Raft nodes to send peers
+    /// that read it packed gossip values and snapshots gzipped, see
+    /// [`crate::gzip`]. Implies `WHIRLPOOL_HANDSHAKE`, which is how peers
+    /// tell.
+    pub gzip: bool,
+    /// `WHIRLPOOL_GOSSIP_INTERVAL_MS`, `WHIRLPOOL_GOSSIP_FANOUT`,
+    /// `WHIRLPOOL_GOSSIP_JITTER` and `WHIRLPOOL_GOSSIP_MAX_BATCH`: how
+    /// broadcast gossip rounds go, see [`GossipConfig`]. A fanout or batch
+    /// of 0 means no limit.
+    pub gossip: GossipConfig,
+    /// `WHIRLPOOL_GOSSIP_COMPRESS_ABOVE`: how many bytes of values a gossip
+    /// batch takes before it is packed, if ever, see [`crate::compress`].
+    pub gossip_compress_above: Option<usize>,
+    /// `WHIRLPOOL_TOPOLOGY`: see [`TopologyStrategy`]'s `FromStr` impl.
+    pub topology: TopologyStrategy,
+    /// `WHIRLPOOL_IDS`: `uuid`, `uuid-v7`, `ulid`, `counter` or `snowflake`,
+    /// the ids `generate` returns.
+    pub ids: IdScheme,
+    /// `WHIRLPOOL_UNKNOWN_MESSAGES`: `ignore`, `log` or `reply`.
+    pub unknown_messages: UnknownPolicy,
+    /// `WHIRLPOOL_VALIDATE`: `true` to turn away messages sent to another
+    /// node, client requests without a `msg_id` and unexpected replies,
+    /// see [`crate::validate`].
+    pub validate: bool,
+    /// `WHIRLPOOL_STRICT`: `true` to turn away messages with fields their
+    /// type doesn't have, instead of ignoring the fields, see
+    /// [`crate::validate::unknown_fields`].
+    pub strict: bool,
+    /// `WHIRLPOOL_PROXY_UPSTREAM`: a node to pass messages of unknown
+    /// types on to instead, relaying its replies back, see
+    /// [`crate::proxy`].
+    pub proxy_upstream: Option<String>,
+    /// `WHIRLPOOL_TCP_LISTEN`, `WHIRLPOOL_TCP_PEERS` and
+    /// `WHIRLPOOL_TCP_NODE_ID`: an address to take messages on instead of
+    /// stdin and stdout, where the nodes of the cluster listen, as
+    /// `n1=host:port,...`, and this node's id to start with an `init`, see
+    /// [`crate::tcp`].
+    pub tcp: Option<SocketConfig>,
+    /// `WHIRLPOOL_UNIX_LISTEN`, `WHIRLPOOL_UNIX_PEERS` and
+    /// `WHIRLPOOL_UNIX_NODE_ID`: the same, with unix socket paths for
+    /// addresses, see [`crate::unix`].
+    pub unix: Option<SocketConfig>,
+    /// `WHIRLPOOL_UDP_LISTEN`, `WHIRLPOOL_UDP_PEERS` and
+    /// `WHIRLPOOL_UDP_NODE_ID`: the same over UDP, see [`crate::udp`].
+    pub udp: Option<SocketConfig>,
+    /// `WHIRLPOOL_UDP_LOSS`: the share of datagrams to drop on purpose,
+    /// from 0 to 1.
+    pub udp_loss: f64,
+    /// `WHIRLPOOL_WS_LISTEN`, `WHIRLPOOL_WS_PEERS` and
+    /// `WHIRLPOOL_WS_NODE_ID`: the same over WebSocket, see [`crate::ws`].
+    /// Only one of `tcp`, `unix`, `udp` and `ws` can be set.
+    pub ws: Option<SocketConfig>,
+    /// `WHIRLPOOL_LOG`: `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: Level,
+    /// `WHIRLPOOL_TRACE_FILE`: where to append a JSON line per handled
+    /// message, see [`trace`](mod@crate::trace).
+    pub trace_file: Option<PathBuf>,
+    /// `WHIRLPOOL_RECORD_FILE`: where to append every message read and
+    /// written, for [`crate::record::replay`].
+    pub record_file: Option<PathBuf>,
+    /// `WHIRLPOOL_METRICS`: `true` to record [`crate::metrics`] and print
+    /// them on shutdown.
+    pub metrics: bool,
+    /// `WHIRLPOOL_PROMETHEUS_FILE`: where to write [`crate::metrics`] in
+    /// the Prometheus text format, for node_exporter's textfile collector,
+    /// see [`crate::prometheus`].
+    pub prometheus_file: Option<PathBuf>,
+    /// `WHIRLPOOL_PROMETHEUS_INTERVAL_MS`: how often to rewrite it.
+    pub prometheus_interval: Duration,
+    /// `WHIRLPOOL_OTEL_ENDPOINT`: an OTLP/HTTP collector such as
+    /// `http://localhost:4318` to export handler spans to, carrying traces
+    /// across nodes. Needs the `otel` feature, see `whirlpool::otel`.
+    pub otel_endpoint: Option<String>,
+    /// `WHIRLPOOL_ADMIN`: an address such as `127.0.0.1:9000` to serve
+    /// the node's state, metrics (as JSON, or for Prometheus) and pending
+    /// calls on over HTTP. Needs the `admin` feature, see
+    /// `whirlpool::admin`.
+    pub admin: Option<String>,
+    /// `WHIRLPOOL_FLUSH_BYTES` and `WHIRLPOOL_FLUSH_DELAY_MS`: when buffered
+    /// output is written out, see [`FlushPolicy`].
+    pub flush: FlushPolicy,
+    /// `WHIRLPOOL_REPLY_PRIORITY`: `false` to write messages in the order
+    /// they were sent, instead of letting replies to clients overtake
+    /// gossip when output backs up, see [`crate::output`].
+    pub reply_priority: bool,
+    /// `WHIRLPOOL_RATE_PER_DEST` and `WHIRLPOOL_RATE_GLOBAL`: how many
+    /// messages a second may go to each other node, and to all of them
+    /// together, as `rate` or `rate,burst`, see [`crate::ratelimit`].
+    pub rate_limits: RateLimits,
+    /// `WHIRLPOOL_DEDUP`: `true` to handle each request at most once,
+    /// answering retries from a cache, see [`crate::dedup`].
+    pub dedup: bool,
+    /// `WHIRLPOOL_DEDUP_TTL_MS` and `WHIRLPOOL_DEDUP_MAX`: how long, and
+    /// how many, requests the dedup cache remembers, a minute and 100000
+    /// by default. 0 means no limit.
+    pub dedup_limits: CacheLimits,
+    /// `WHIRLPOOL_RETRY_TTL_MS` and `WHIRLPOOL_RETRY_MAX`: how long a
+    /// message is retried before it is given up on, and how many may wait
+    /// for an ack, forever and 100000 by default. 0 means no limit.
+    pub retry_limits: CacheLimits,
+    /// `WHIRLPOOL_QUEUE_CAPACITY`: how many events, or chunks of output,
+    /// may queue up between the reader, handler and writer threads.
+    pub queue_capacity: usize,
+    /// `WHIRLPOOL_OVERLOAD`: `block` or `reject`.
+    pub overload: OverloadPolicy,
+    /// `WHIRLPOOL_SIGNALS`: whether to shut down on SIGTERM and SIGINT as on
+    /// end of input, see [`crate::signal`]. The handlers are process-wide,
+    /// so only [`Config::from_env`], which [`main_loop`](crate::main_loop)
+    /// runs with, turns this on by default.
+    pub signals: bool,
+    /// `WHIRLPOOL_STATE_DIR`: where nodes that support it keep snapshots of
+    /// their state, see [`crate::storage`].
+    pub state_dir: Option<PathBuf>,
+    /// `WHIRLPOOL_SNAPSHOT_INTERVAL_MS`: how often those snapshots are
+    /// taken.
+    pub snapshot_interval: Duration,
+    /// `WHIRLPOOL_WAL`: `true` to also log every mutation between
+    /// snapshots, see [`crate::wal`].
+    pub wal: bool,
+    /// `WHIRLPOOL_TXN_2PC`: `true` to spread `txn-rw-register` registers
+    /// over the cluster and commit with two-phase commit, see
+    /// [`crate::txn::two_phase`].
+    pub txn_2pc: bool,
+    /// `WHIRLPOOL_KV_SHARDED`: `true` to spread `lin-kv` keys over the
+    /// cluster instead of replicating them with Raft, see
+    /// [`crate::kv::sharded`].
+    pub kv_sharded: bool,
+    /// `WHIRLPOOL_KV_QUORUM`: `n,w,r`, such as `3,2,2`, to replicate
+    /// `lin-kv` keys to `n` nodes with quorum reads and writes instead, see
+    /// [`crate::kv::quorum`].
+    pub kv_quorum: Option<QuorumConfig>,
+    /// `WHIRLPOOL_KV_ANTI_ENTROPY_MS`: how often quorum replicas sync with
+    /// a peer, 1000 by default, or 0 not to.
+    pub kv_anti_entropy: Option<Duration>,
+    /// `WHIRLPOOL_KV_HINTED_HANDOFF`: `true` for quorum writes to leave
+    /// hints for replicas that don't acknowledge them in time.
+    pub kv_hinted_handoff: bool,
+    /// `WHIRLPOOL_KV_LWW`: `true` to serve `lin-kv` from a last-writer-wins
+    /// map on every node instead, see [`crate::crdt::lww`].
+    pub kv_lww: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            broadcast_mode: BroadcastMode::default(),
+            broadcast_clock: false,
+            broadcast_sync: false,
+            broadcast_pull: None,
+            broadcast_causal: false,
+            swim: false,
+            heartbeat: None,
+            handshake: false,
+            msgpack: false,
+            gzip: false,
+            gossip: GossipConfig::default(),
+            gossip_compress_above: None,
+            topology: TopologyStrategy::default(),
+            ids: IdScheme::default(),
+            unknown_messages: UnknownPolicy::default(),
+            validate: false,
+            strict: false,
+            proxy_upstream: None,
+            tcp: None,
+            unix: None,
+            udp: None,
+            udp_loss: 0.0,
+            ws: None,
+            log_level: Level::default(),
+            trace_file: None,
+            record_file: None,
+            metrics: false,
+            prometheus_file: None,
+            prometheus_interval: Duration::from_secs(10),
+            otel_endpoint: None,
+            admin: None,
+            flush: FlushPolicy::default(),
+            reply_priority: true,
+            rate_limits: RateLimits::default(),
+            dedup: false,
+            dedup_limits: CacheLimits {
+                ttl: Some(Duration::from_secs(60)),
+                max_entries: Some(100_000),
+            },
+            retry_limits: CacheLimits {
+                ttl: None,
+                max_entries: Some(100_000),
+            },
+            queue_capacity: 1024,
+            overload: OverloadPolicy::default(),
+            signals: false,
+            state_dir: None,
+            snapshot_interval: Duration::from_secs(1),
+            wal: false,
+            txn_2pc: false,
+            kv_sharded: false,
+            kv_quorum: None,
+            kv_anti_entropy: Some(Duration::from_secs(1)),
+            kv_hinted_handoff: false,
+            kv_lww: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        let tcp = socket_from_env("WHIRLPOOL_TCP")?;
+        let unix = socket_from_env("WHIRLPOOL_UNIX")?;
+        let udp = socket_from_env("WHIRLPOOL_UDP")?;
+        let ws = socket_from_env("WHIRLPOOL_WS")?;
+        let transports = [&tcp, &unix, &udp, &ws];
+        if transports.iter().filter(|t| t.is_some()).count() > 1 {
+            bail!("only one of WHIRLPOOL_{{TCP,UNIX,UDP,WS}}_LISTEN can be set");
+        }
+        Ok(Self {
+            broadcast_mode: env_or("WHIRLPOOL_BROADCAST_MODE", defaults.broadcast_mode)?,
+            broadcast_clock: env_or("WHIRLPOOL_BROADCAST_CLOCK", defaults.broadcast_clock)?,
+            broadcast_sync: env_or("WHIRLPOOL_BROADCAST_SYNC", defaults.broadcast_sync)?,
+            broadcast_pull: match env_or("WHIRLPOOL_BROADCAST_PULL_MS", 0)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            broadcast_causal: env_or("WHIRLPOOL_BROADCAST_CAUSAL", defaults.broadcast_causal)?,
+            swim: env_or("WHIRLPOOL_SWIM", defaults.swim)?,
+            heartbeat: match env_or("WHIRLPOOL_HEARTBEAT_MS", 0)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            handshake: env_or("WHIRLPOOL_HANDSHAKE", defaults.handshake)?,
+            msgpack: env_or("WHIRLPOOL_MSGPACK", defaults.msgpack)?,
+            gzip: env_or("WHIRLPOOL_GZIP", defaults.gzip)?,
+            gossip: GossipConfig {
+                interval: Duration::from_millis(env_or(
+                    "WHIRLPOOL_GOSSIP_INTERVAL_MS",
+                    defaults.gossip.interval.as_millis() as u64,
+                )?),
+                fanout: match env_or("WHIRLPOOL_GOSSIP_FANOUT", 0)? {
+                    0 => None,
+                    fanout => Some(fanout),
+                },
+                jitter: env_or("WHIRLPOOL_GOSSIP_JITTER", defaults.gossip.jitter)?,
+                max_batch: match env_or("WHIRLPOOL_GOSSIP_MAX_BATCH", 0)? {
+                    0 => None,
+                    max => Some(max),
+                },
+            },
+            gossip_compress_above: match env_or("WHIRLPOOL_GOSSIP_COMPRESS_ABOVE", 0)? {
+                0 => None,
+                bytes => Some(bytes),
+            },
+            topology: env_or("WHIRLPOOL_TOPOLOGY", defaults.topology)?,
+            ids: env_or("WHIRLPOOL_IDS", defaults.ids)?,
+            unknown_messages: env_or("WHIRLPOOL_UNKNOWN_MESSAGES", defaults.unknown_messages)?,
+            validate: env_or("WHIRLPOOL_VALIDATE", defaults.validate)?,
+            strict: env_or("WHIRLPOOL_STRICT", defaults.strict)?,
+            proxy_upstream: std::env::var("WHIRLPOOL_PROXY_UPSTREAM").ok(),
+            tcp,
+            unix,
+            udp,
+            udp_loss: env_or("WHIRLPOOL_UDP_LOSS", defaults.udp_loss)?,
+            ws,
+            log_level: env_or("WHIRLPOOL_LOG", defaults.log_level)?,
+            trace_file: std::env::var_os("WHIRLPOOL_TRACE_FILE").map(PathBuf::from),
+            record_file: std::env::var_os("WHIRLPOOL_RECORD_FILE").map(PathBuf::from),
+            metrics: env_or("WHIRLPOOL_METRICS", defaults.metrics)?,
+            prometheus_file: std::env::var_os("WHIRLPOOL_PROMETHEUS_FILE").map(PathBuf::from),
+            prometheus_interval: Duration::from_millis(env_or(
+                "WHIRLPOOL_PROMETHEUS_INTERVAL_MS",
+                defaults.prometheus_interval.as_millis() as u64,
+            )?),
+            otel_endpoint: std::env::var("WHIRLPOOL_OTEL_ENDPOINT").ok(),
+            admin: std::env::var("WHIRLPOOL_ADMIN").ok(),
+            flush: FlushPolicy {
+                max_bytes: env_or("WHIRLPOOL_FLUSH_BYTES", defaults.flush.max_bytes)?,
+                max_delay: Duration::from_millis(env_or(
+                    "WHIRLPOOL_FLUSH_DELAY_MS",
+                    defaults.flush.max_delay.as_millis() as u64,
+                )?),
+            },
+            reply_priority: env_or("WHIRLPOOL_REPLY_PRIORITY", defaults.reply_priority)?,
+            rate_limits: RateLimits {
+                per_dest: std::env::var("WHIRLPOOL_RATE_PER_DEST")
+                    .ok()
+                    .map(|rate| rate.parse().context("parsing WHIRLPOOL_RATE_PER_DEST"))
+                    .transpose()?,
+                global: std::env::var("WHIRLPOOL_RATE_GLOBAL")
+                    .ok()
+                    .map(|rate| rate.parse().context("parsing WHIRLPOOL_RATE_GLOBAL"))
+                    .transpose()?,
+            },
+            dedup: env_or("WHIRLPOOL_DEDUP", defaults.dedup)?,
+            dedup_limits: CacheLimits::from_env("WHIRLPOOL_DEDUP", defaults.dedup_limits)?,
+            retry_limits: CacheLimits::from_env("WHIRLPOOL_RETRY", defaults.retry_limits)?,
+            queue_capacity: env_or("WHIRLPOOL_QUEUE_CAPACITY", defaults.queue_capacity)?,
+            overload: env_or("WHIRLPOOL_OVERLOAD", defaults.overload)?,
+            signals: env_or("WHIRLPOOL_SIGNALS", true)?,
+            state_dir: std::env::var_os("WHIRLPOOL_STATE_DIR").map(PathBuf::from),
+            snapshot_interval: Duration::from_millis(env_or(
+                "WHIRLPOOL_SNAPSHOT_INTERVAL_MS",
+                defaults.snapshot_interval.as_millis() as u64,
+            )?),
+            wal: env_or("WHIRLPOOL_WAL", defaults.wal)?,
+            txn_2pc: env_or("WHIRLPOOL_TXN_2PC", defaults.txn_2pc)?,
+            kv_sharded: env_or("WHIRLPOOL_KV_SHARDED", defaults.kv_sharded)?,
+            kv_quorum: std::env::var("WHIRLPOOL_KV_QUORUM")
+                .ok()
+                .map(|quorum| quorum.parse().context("parsing WHIRLPOOL_KV_QUORUM"))
+                .transpose()?,
+            kv_anti_entropy: match env_or("WHIRLPOOL_KV_ANTI_ENTROPY_MS", 1000)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            kv_hinted_handoff: env_or("WHIRLPOOL_KV_HINTED_HANDOFF", defaults.kv_hinted_handoff)?,
+            kv_lww: env_or("WHIRLPOOL_KV_LWW", defaults.kv_lww)?,
+        })
+    }
+
+    /// Sets up the process-wide parts: the log level, the trace and record
+    /// files, metrics and their Prometheus file, and exporting spans.
+    pub fn apply(&self) -> anyhow::Result<()> {
+        log::set_level(self.log_level);
+        if let Some(path) = &self.trace_file {
+            trace::init(path)?;
+        }
+        if let Some(path) = &self.record_file {
+            record::init(path)?;
+        }
+        if self.metrics {
+            metrics::enable();
+        }
+        if let Some(path) = &self.prometheus_file {
+            prometheus::init(path, self.prometheus_interval)?;
+        }
+        if let Some(endpoint) = &self.otel_endpoint {
+            #[cfg(feature = "otel")]
+            crate::otel::init(endpoint)?;
+            #[cfg(not(feature = "otel"))]
+            crate::warn!("not exporting spans to {endpoint}: built without the otel feature");
+        }
+        Ok(())
+    }
+
+    /// The capabilities broadcast and Raft nodes advertise in their
+    /// handshake: the encodings `WHIRLPOOL_MSGPACK` and `WHIRLPOOL_GZIP`
+    /// turn on.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let msgpack = self.msgpack.then_some(Capability::MsgPack);
+        let gzip = self.gzip.then_some(Capability::Gzip);
+        msgpack.into_iter().chain(gzip).collect()
+    }
+}
+
+/// What the main loop does with a message whose payload doesn't parse as the
+/// node's payload type, e.g. a `type` it has never heard of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownPolicy {
+    /// Drop the message silently.
+    Ignore,
+    /// Drop the message and note it on stderr.
+    Log,
+    /// Answer with a `not-supported` error (code 10), like a node does for
+    /// a known message it can't handle.
+    #[default]
+    Reply,
+}
+
+impl FromStr for UnknownPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "ignore" => UnknownPolicy::Ignore,
+            "log" => UnknownPolicy::Log,
+            "reply" => UnknownPolicy::Reply,
+            _ => bail!("unknown policy for unknown messages {s}"),
+        })
+    }
+}
+
+/// Bounds on a cache that would otherwise keep growing over a long run,
+/// such as the [dedup](crate::dedup) cache or a [`crate::RetryQueue`].
+/// Entries older than `ttl` are evicted, and the oldest ones once there
+/// are more than `max_entries`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub ttl: Option<Duration>,
+    pub max_entries: Option<usize>,
+}
+
+impl CacheLimits {
+    /// Whether an entry made at `at` has outlived the TTL by `now`.
+    pub fn expired(&self, at: Instant, now: Instant) -> bool {
+        self.ttl
+            .is_some_and(|ttl| now.saturating_duration_since(at) >= ttl)
+    }
+
+    /// Whether a cache holding `len` entries is over its size bound.
+    pub fn over(&self, len: usize) -> bool {
+        self.max_entries.is_some_and(|max| len > max)
+    }
+
+    /// Reads `{prefix}_TTL_MS` and `{prefix}_MAX`, where 0 means no limit.
+    fn from_env(prefix: &str, defaults: Self) -> anyhow::Result<Self> {
+        let ttl = env_or(
+            &format!("{prefix}_TTL_MS"),
+            defaults.ttl.map_or(0, |ttl| ttl.as_millis() as u64),
+        )?;
+        let max = env_or(&format!("{prefix}_MAX"), defaults.max_entries.unwrap_or(0))?;
+        Ok(Self {
+            ttl: (ttl > 0).then(|| Duration::from_millis(ttl)),
+            max_entries: (max > 0).then_some(max),
+        })
+    }
+}
+
+/// What the reader does with a message when the handler's queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Wait for room, which stops reading (and resolving RPC replies)
+    /// until the handler catches up.
+    #[default]
+    Block,
+    /// Answer requests with a `temporarily-unavailable` error (code 11)
+    /// and drop everything else.
+    Reject,
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "block" => OverloadPolicy::Block,
+            "reject" => OverloadPolicy::Reject,
+            _ => bail!("unknown overload policy {s}"),
+        })
+    }
+}
+
+fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Into<anyhow::Error>,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(Into::into)
+            .with_context(|| format!("parsing {name}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// `<prefix>_LISTEN`, `<prefix>_PEERS` and `<prefix>_NODE_ID`, if the first
+/// is set.
+fn socket_from_env(prefix: &str) -> anyhow::Result<Option<SocketConfig>> {
+    let Ok(listen) = std::env::var(format!("{prefix}_LISTEN")) else {
+        return Ok(None);
+    };
+    let peers = match std::env::var(format!("{prefix}_PEERS")) {
+        Ok(peers) => {
+            socket::parse_peers(&peers).with_context(|| format!("parsing {prefix}_PEERS"))?
+        }
+        Err(_) => Default::default(),
+    };
+    Ok(Some(SocketConfig {
+        listen,
+        peers,
+        node_id: std::env::var(format!("{prefix}_NODE_ID")).ok(),
+    }))
+}
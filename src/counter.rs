@@ -0,0 +1,106 @@
+use crate::{
+    payload::{AddValue, ReadValue},
+    services::SeqKv,
+    ErrorCode, Membership, Message, MsgIdAllocator, Node, Payload, Rpc, RpcError,
+};
+use anyhow::Context;
+use serde_json::Value;
+use std::io::Write;
+
+/// The `seq-kv` key holding the counter.
+const COUNTER_KEY: &str = "counter";
+
+/// Serves the `g-counter` and `pn-counter` workloads. The counter lives in `seq-kv` so every
+/// node reads and updates the same value.
+#[derive(Debug)]
+pub struct CounterNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    rpc: Rpc,
+    kv: Option<SeqKv>,
+}
+
+impl Default for CounterNode {
+    fn default() -> Self {
+        let msg_ids = MsgIdAllocator::new();
+        Self {
+            membership: Membership::default(),
+            rpc: Rpc::new(msg_ids.clone()),
+            msg_ids,
+            kv: None,
+        }
+    }
+}
+
+impl CounterNode {
+    fn kv(&self) -> anyhow::Result<&SeqKv> {
+        self.kv.as_ref().context("counter used before init")
+    }
+
+    /// Applies `f` to the counter and returns the value it replaced.
+    fn update(
+        &self,
+        f: impl Fn(i64) -> anyhow::Result<i64>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<i64> {
+        let previous = self.kv()?.client().update(
+            COUNTER_KEY.into(),
+            Value::from(0),
+            |value| {
+                let value = value.as_i64().context("counter is not a number")?;
+                Ok(f(value)?.into())
+            },
+            out,
+        )?;
+        previous.as_i64().context("counter is not a number")
+    }
+}
+
+impl Node for CounterNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                self.kv = Some(SeqKv::new(node_id, self.rpc.clone()));
+                Payload::InitOk
+            }
+            Payload::Add {
+                value: AddValue::Delta { delta },
+                ..
+            } => {
+                self.update(
+                    |value| {
+                        value.checked_add(*delta).ok_or_else(|| {
+                            RpcError::new(ErrorCode::Abort, "counter would overflow").into()
+                        })
+                    },
+                    output,
+                )?;
+                Payload::AddOk
+            }
+            Payload::Read { .. } => {
+                // seq-kv may serve stale reads; a CAS that leaves the value
+                // unchanged only succeeds against the latest one.
+                let value = self.update(Ok, output)?;
+                Payload::ReadOk {
+                    value: ReadValue::Value {
+                        value: Value::from(value),
+                    },
+                }
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(
+                    RpcError::not_supported("counter node cannot handle this message").into(),
+                )
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+}
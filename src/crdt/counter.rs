@@ -0,0 +1,103 @@
+//! A counter that can be incremented and decremented: every node counts
+//! its own increments and decrements, and merging keeps the higher count
+//! for each node.
+
+use super::{Crdt, Replicated};
+use crate::{
+    payload::{AddValue, ReadValue},
+    Payload, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Serves the `g-counter` and `pn-counter` workloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    /// How much each node has added.
+    increments: BTreeMap<String, u64>,
+    /// How much each node has taken away.
+    decrements: BTreeMap<String, u64>,
+}
+
+impl PnCounter {
+    pub fn value(&self) -> i64 {
+        let total = |counts: &BTreeMap<String, u64>| -> i128 {
+            counts.values().map(|&count| i128::from(count)).sum()
+        };
+        let value = total(&self.increments) - total(&self.decrements);
+        value.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+    }
+
+    /// Adds `delta` on `node`.
+    pub fn add(&mut self, node: &str, delta: i64) {
+        let delta = self.add_delta(node, delta);
+        self.merge(&delta);
+    }
+
+    /// The delta of adding `delta` on `node`: `node`'s new count.
+    fn add_delta(&self, node: &str, delta: i64) -> Self {
+        let counts = match delta >= 0 {
+            true => &self.increments,
+            false => &self.decrements,
+        };
+        let count = counts
+            .get(node)
+            .copied()
+            .unwrap_or(0)
+            .saturating_add(delta.unsigned_abs());
+        let counts = BTreeMap::from([(node.to_string(), count)]);
+        match delta >= 0 {
+            true => Self {
+                increments: counts,
+                ..Self::default()
+            },
+            false => Self {
+                decrements: counts,
+                ..Self::default()
+            },
+        }
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        for (ours, theirs) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (node, &count) in theirs {
+                let ours = ours.entry(node.clone()).or_default();
+                *ours = (*ours).max(count);
+            }
+        }
+    }
+}
+
+impl Replicated for PnCounter {
+    fn apply(
+        &mut self,
+        node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        match request {
+            Payload::Add {
+                value: AddValue::Delta { delta },
+                ..
+            } => {
+                let delta = self.add_delta(node_id, *delta);
+                self.merge(&delta);
+                Ok((Payload::AddOk, Some(delta)))
+            }
+            Payload::Read { .. } => {
+                let value = ReadValue::Value {
+                    value: Value::from(self.value()),
+                };
+                Ok((Payload::ReadOk { value }, None))
+            }
+            _ => Err(RpcError::not_supported(
+                "counter cannot handle this message",
+            )),
+        }
+    }
+}
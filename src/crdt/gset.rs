@@ -0,0 +1,94 @@
+//! A grow-only set: elements can be added but never removed, and merging
+//! takes the union.
+
+use super::{element_of, Crdt, CrdtNode, Replicated};
+use crate::{
+    payload::{AddValue, ReadValue},
+    Payload, RpcError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt::Debug};
+
+/// The node the `g-set` binary runs: a [`GSet`] of Maelstrom's integer
+/// elements on every node, gossiped to the others.
+pub type GSetNode = CrdtNode<GSet<i64>>;
+
+/// Serves the `g-set` workload: `add` adds an `element`, and `read`
+/// returns every element seen so far.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord> GSet<T> {
+    /// Adds `element`, returning whether it is new.
+    pub fn add(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// The elements, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+}
+
+impl<T> Crdt for GSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+}
+
+impl<T> Replicated for GSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn apply(
+        &mut self,
+        _node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        match request {
+            Payload::Add {
+                value: AddValue::Element { element },
+                ..
+            } => {
+                let mut delta = Self::default();
+                delta.add(element_of(element)?);
+                self.merge(&delta);
+                Ok((Payload::AddOk, Some(delta)))
+            }
+            Payload::Read { .. } => {
+                let value = serde_json::to_value(&self.elements).expect("elements serialize");
+                let value = ReadValue::Value { value };
+                Ok((Payload::ReadOk { value }, None))
+            }
+            _ => Err(RpcError::not_supported(
+                "g-set node cannot handle this message",
+            )),
+        }
+    }
+}
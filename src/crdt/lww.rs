@@ -0,0 +1,157 @@
+//! A map of last-writer-wins registers: every key keeps the value of the
+//! write with the latest stamp, wherever it was made.
+//!
+//! Stamps come from a [`HybridClock`], with the writing node's id breaking
+//! ties, so every replica picks the same winner. Merging observes the
+//! stamps it takes in, so a write made after seeing another one is always
+//! stamped later, however skewed the nodes' clocks are.
+
+use super::{Crdt, Replicated};
+use crate::{
+    clock::{HlcTimestamp, HybridClock},
+    payload::ReadValue,
+    Payload, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// When a write was made, and where.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    pub time: HlcTimestamp,
+    pub node: String,
+}
+
+/// A value and the stamp of the write that set it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister {
+    pub stamp: Stamp,
+    pub value: Value,
+}
+
+/// Serves the KV workloads from a replica that takes writes on its own,
+/// so reads may return stale values, and `cas` compares against this
+/// replica's value only.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LwwMap {
+    /// By the JSON encoding of their key, since keys may be any JSON value.
+    registers: BTreeMap<String, LwwRegister>,
+    #[serde(skip)]
+    clock: HybridClock,
+}
+
+/// Maps are equal if they hold the same registers, whatever their clocks.
+impl PartialEq for LwwMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.registers == other.registers
+    }
+}
+
+impl LwwMap {
+    /// A map stamping writes with `clock`.
+    pub fn with_clock(clock: HybridClock) -> Self {
+        Self {
+            registers: BTreeMap::new(),
+            clock,
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&LwwRegister> {
+        self.registers.get(&key.to_string())
+    }
+
+    /// Sets `key` to `value` as written now on `node`.
+    pub fn set(&mut self, node: &str, key: &Value, value: Value) -> Stamp {
+        let stamp = Stamp {
+            time: self.clock.now(),
+            node: node.to_string(),
+        };
+        let register = LwwRegister {
+            stamp: stamp.clone(),
+            value,
+        };
+        self.registers.insert(key.to_string(), register);
+        stamp
+    }
+
+    /// A map of just `key`'s register, the delta of setting it.
+    fn delta_of(&self, key: &Value) -> Self {
+        let key = key.to_string();
+        let register = self.registers.get(&key).cloned();
+        Self {
+            registers: register
+                .map(|register| (key, register))
+                .into_iter()
+                .collect(),
+            clock: HybridClock::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.registers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+}
+
+impl Crdt for LwwMap {
+    fn merge(&mut self, other: &Self) {
+        for (key, theirs) in &other.registers {
+            self.clock.observe(theirs.stamp.time);
+            match self.registers.get_mut(key) {
+                Some(ours) if ours.stamp >= theirs.stamp => {}
+                Some(ours) => *ours = theirs.clone(),
+                None => {
+                    self.registers.insert(key.clone(), theirs.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Replicated for LwwMap {
+    fn apply(
+        &mut self,
+        node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        let missing =
+            |key: &Value| RpcError::key_does_not_exist(format!("key {key} does not exist"));
+        match request {
+            Payload::Read { key: Some(key), .. } => {
+                let register = self.get(key).ok_or_else(|| missing(key))?;
+                let value = ReadValue::Value {
+                    value: register.value.clone(),
+                };
+                Ok((Payload::ReadOk { value }, None))
+            }
+            Payload::Write { key, value, .. } => {
+                self.set(node_id, key, value.clone());
+                Ok((Payload::WriteOk, Some(self.delta_of(key))))
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                match self.get(key) {
+                    Some(register) if register.value != *from => {
+                        return Err(RpcError::precondition_failed(format!(
+                            "expected {from}, but had {}",
+                            register.value
+                        )))
+                    }
+                    None if !create_if_not_exists => return Err(missing(key)),
+                    _ => {}
+                }
+                self.set(node_id, key, to.clone());
+                Ok((Payload::CasOk, Some(self.delta_of(key))))
+            }
+            _ => Err(RpcError::not_supported("not a key-value operation")),
+        }
+    }
+}
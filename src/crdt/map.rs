@@ -0,0 +1,239 @@
+//! A map of CRDTs under string keys, so that one node can serve several
+//! of them at once.
+//!
+//! A key holds a [`PnCounter`], an [`OrSet`] or a last-writer-wins
+//! register, whichever the first request for it needs: `add` with a
+//! `delta` makes a counter, `add` or `remove` with an `element` a set, and
+//! `write` or `cas` a register. Merging two maps merges what is under each
+//! key. If two nodes start the same key off as different types at once,
+//! every replica keeps the same one of them: a register over a set over a
+//! counter.
+
+use super::{
+    lww::{LwwRegister, Stamp},
+    Crdt, OrSet, PnCounter, Replicated,
+};
+use crate::{
+    clock::HybridClock,
+    payload::{AddValue, ReadValue},
+    ErrorCode, Payload, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// What a [`CrdtMap`] holds under a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Nested {
+    Counter(PnCounter),
+    Set(OrSet<i64>),
+    Register(LwwRegister),
+}
+
+impl Nested {
+    /// Which of two values of different types under the same key wins.
+    fn rank(&self) -> u8 {
+        match self {
+            Nested::Counter(_) => 0,
+            Nested::Set(_) => 1,
+            Nested::Register(_) => 2,
+        }
+    }
+
+    fn merge(&mut self, other: &Nested) {
+        match (self, other) {
+            (Nested::Counter(ours), Nested::Counter(theirs)) => ours.merge(theirs),
+            (Nested::Set(ours), Nested::Set(theirs)) => ours.merge(theirs),
+            (Nested::Register(ours), Nested::Register(theirs)) => {
+                if theirs.stamp > ours.stamp {
+                    *ours = theirs.clone();
+                }
+            }
+            (ours, theirs) => {
+                if theirs.rank() > ours.rank() {
+                    *ours = theirs.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Serves counters, sets and registers, each request naming its key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CrdtMap {
+    entries: BTreeMap<String, Nested>,
+    /// Stamps register writes.
+    #[serde(skip)]
+    clock: HybridClock,
+}
+
+/// Maps are equal if they hold the same values, whatever their clocks.
+impl PartialEq for CrdtMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl CrdtMap {
+    pub fn get(&self, key: &str) -> Option<&Nested> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// A map of just `nested` under `key`.
+    fn delta(key: &str, nested: Nested) -> Self {
+        Self {
+            entries: BTreeMap::from([(key.to_string(), nested)]),
+            clock: HybridClock::default(),
+        }
+    }
+
+    /// Serves a register `request` on `key`.
+    fn apply_register(
+        &mut self,
+        node_id: &str,
+        key: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        let current = match self.entries.get(key) {
+            Some(Nested::Register(register)) => Some(&register.value),
+            Some(other) => return Err(mismatch(key, other)),
+            None => None,
+        };
+        let (value, reply) = match request {
+            Payload::Write { value, .. } => (value, Payload::WriteOk),
+            Payload::Cas {
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => {
+                match current {
+                    Some(current) if current != from => {
+                        return Err(RpcError::precondition_failed(format!(
+                            "expected {from}, but had {current}"
+                        )))
+                    }
+                    None if !create_if_not_exists => return Err(missing(key)),
+                    _ => {}
+                }
+                (to, Payload::CasOk)
+            }
+            _ => unreachable!("only writes and cas go to registers"),
+        };
+        let register = Nested::Register(LwwRegister {
+            stamp: Stamp {
+                time: self.clock.now(),
+                node: node_id.to_string(),
+            },
+            value: value.clone(),
+        });
+        self.entries.insert(key.to_string(), register.clone());
+        Ok((reply, Some(Self::delta(key, register))))
+    }
+}
+
+impl Crdt for CrdtMap {
+    fn merge(&mut self, other: &Self) {
+        for (key, theirs) in &other.entries {
+            if let Nested::Register(register) = theirs {
+                self.clock.observe(register.stamp.time);
+            }
+            match self.entries.get_mut(key) {
+                Some(ours) => ours.merge(theirs),
+                None => {
+                    self.entries.insert(key.clone(), theirs.clone());
+                }
+            }
+        }
+    }
+}
+
+impl Replicated for CrdtMap {
+    fn apply(
+        &mut self,
+        node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        let key = match request {
+            Payload::Add { key: Some(key), .. }
+            | Payload::Remove { key: Some(key), .. }
+            | Payload::Read { key: Some(key), .. }
+            | Payload::Write { key, .. }
+            | Payload::Cas { key, .. } => key.as_str().ok_or_else(|| {
+                RpcError::new(
+                    ErrorCode::MalformedRequest,
+                    format!("key {key} is not a string"),
+                )
+            })?,
+            _ => {
+                return Err(RpcError::not_supported(
+                    "not a request for a key of the map",
+                ))
+            }
+        };
+        let wanted = match request {
+            Payload::Read { .. } => {
+                let nested = self.entries.get(key).ok_or_else(|| missing(key))?;
+                let value = match nested {
+                    Nested::Counter(counter) => Value::from(counter.value()),
+                    Nested::Set(set) => Value::from(set.iter().copied().collect::<Vec<_>>()),
+                    Nested::Register(register) => register.value.clone(),
+                };
+                let value = ReadValue::Value { value };
+                return Ok((Payload::ReadOk { value }, None));
+            }
+            Payload::Write { .. } | Payload::Cas { .. } => {
+                return self.apply_register(node_id, key, request);
+            }
+            Payload::Add {
+                value: AddValue::Delta { .. },
+                ..
+            } => Nested::Counter(PnCounter::default()),
+            _ => Nested::Set(OrSet::default()),
+        };
+        if let Some(other) = self.entries.get(key) {
+            if other.rank() != wanted.rank() {
+                return Err(mismatch(key, other));
+            }
+        }
+        let existed = self.entries.contains_key(key);
+        let mut nested = self.entries.remove(key).unwrap_or(wanted);
+        let applied = match &mut nested {
+            Nested::Counter(counter) => counter
+                .apply(node_id, request)
+                .map(|(reply, delta)| (reply, delta.map(Nested::Counter))),
+            Nested::Set(set) => set
+                .apply(node_id, request)
+                .map(|(reply, delta)| (reply, delta.map(Nested::Set))),
+            Nested::Register(_) => unreachable!("registers are served above"),
+        };
+        if applied.is_ok() || existed {
+            self.entries.insert(key.to_string(), nested);
+        }
+        let (reply, delta) = applied?;
+        Ok((reply, delta.map(|delta| Self::delta(key, delta))))
+    }
+}
+
+fn missing(key: &str) -> RpcError {
+    RpcError::key_does_not_exist(format!("key {key} does not exist"))
+}
+
+/// The error for a request that doesn't fit the type of `nested`.
+fn mismatch(key: &str, nested: &Nested) -> RpcError {
+    let kind = match nested {
+        Nested::Counter(_) => "a counter",
+        Nested::Set(_) => "a set",
+        Nested::Register(_) => "a register",
+    };
+    RpcError::precondition_failed(format!("key {key} holds {kind}"))
+}
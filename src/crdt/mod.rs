@@ -0,0 +1,194 @@
+//! Conflict-free replicated data types: state that every replica updates
+//! on its own and merges with the others' in any order, converging once
+//! they have all seen the same updates.
+//!
+//! [`CrdtNode`] serves a workload from any [`Replicated`] CRDT, applying
+//! clients' requests to its own replica and gossiping the updates to every
+//! peer periodically. Each update comes with a delta, a small state holding
+//! only what it changed, and a gossip round sends each peer just the
+//! deltas it hasn't acknowledged yet, merged into one, rather than the
+//! whole state. Peers are sent the whole state every
+//! [`FULL_STATE_EVERY`] rounds anyway, which repairs anything lost, such
+//! as deltas that never made it to a peer that has since restarted.
+//!
+//! Every node gossips with every other, so a node only sends its own
+//! updates, and forgets their deltas once all its peers have them.
+
+pub mod counter;
+pub mod gset;
+pub mod lww;
+pub mod map;
+pub mod orset;
+
+pub use counter::PnCounter;
+pub use gset::{GSet, GSetNode};
+pub use lww::LwwMap;
+pub use map::CrdtMap;
+pub use orset::OrSet;
+
+use crate::{ErrorCode, Membership, Message, MsgIdAllocator, Node, Payload, RpcError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    io::Write,
+    time::Duration,
+};
+
+/// How often a [`CrdtNode`] sends its updates to its peers.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Every how many gossip rounds peers are sent the whole state.
+pub const FULL_STATE_EVERY: u64 = 10;
+
+/// A state-based CRDT.
+pub trait Crdt: Debug + Clone + Default + Serialize + DeserializeOwned {
+    /// Takes in everything in `other`. Merging has to be commutative,
+    /// associative and idempotent, so that replicas can merge each other's
+    /// states in any order and any number of times.
+    fn merge(&mut self, other: &Self);
+}
+
+/// A CRDT that serves a Maelstrom workload.
+pub trait Replicated: Crdt {
+    /// Applies a client's `request` to `node_id`'s replica, and returns the
+    /// reply and, if the request changed the replica, the delta: a state
+    /// holding just the change, which has the same effect merged into any
+    /// other replica.
+    fn apply(
+        &mut self,
+        node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError>;
+}
+
+/// A client's `element`, as the type a set holds.
+fn element_of<T: DeserializeOwned>(element: &Value) -> Result<T, RpcError> {
+    serde_json::from_value(element.clone())
+        .map_err(|err| RpcError::new(ErrorCode::MalformedRequest, err.to_string()))
+}
+
+/// Serves a workload from a replica of `C` on every node.
+#[derive(Debug, Default)]
+pub struct CrdtNode<C> {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub state: C,
+    /// How many updates this node has made.
+    seq: u64,
+    /// The deltas of this node's updates that some peer hasn't
+    /// acknowledged, by their place among the updates.
+    deltas: VecDeque<(u64, C)>,
+    /// How many of this node's updates each peer has acknowledged.
+    acked: HashMap<String, u64>,
+    rounds: u64,
+}
+
+impl<C: Replicated> CrdtNode<C> {
+    /// How many deltas are kept for peers yet to acknowledge them.
+    pub fn pending_deltas(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// What to send `peer` this round, if anything: the whole state, or
+    /// the deltas it hasn't acknowledged.
+    fn gossip_for(&self, peer: &str, full: bool) -> Option<C> {
+        if full {
+            return Some(self.state.clone());
+        }
+        let acked = self.acked.get(peer).copied().unwrap_or(0);
+        let mut pending = self
+            .deltas
+            .iter()
+            .filter(|(seq, _)| *seq > acked)
+            .peekable();
+        pending.peek()?;
+        let mut group = C::default();
+        for (_, delta) in pending {
+            group.merge(delta);
+        }
+        Some(group)
+    }
+
+    /// Notes that `peer` has this node's first `seq` updates, and forgets
+    /// the deltas every peer has.
+    fn acknowledged(&mut self, peer: &str, seq: u64) {
+        let acked = self.acked.entry(peer.to_string()).or_default();
+        *acked = (*acked).max(seq);
+        let everyone = self
+            .membership
+            .peers()
+            .map(|peer| self.acked.get(peer).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(self.seq);
+        while self.deltas.front().is_some_and(|(seq, _)| *seq <= everyone) {
+            self.deltas.pop_front();
+        }
+    }
+}
+
+impl<C: Replicated> Node for CrdtNode<C> {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                Payload::InitOk
+            }
+            Payload::Merge { state, seq } => {
+                let state: C = serde_json::from_value(state.clone())
+                    .map_err(|err| RpcError::new(ErrorCode::MalformedRequest, err.to_string()))?;
+                self.state.merge(&state);
+                Payload::MergeOk { seq: *seq }
+            }
+            Payload::MergeOk { seq } => {
+                self.acknowledged(&input.src, *seq);
+                return Ok(());
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            request => {
+                let (reply, delta) = self.state.apply(&self.membership.node_id, request)?;
+                if let Some(delta) = delta {
+                    self.seq += 1;
+                    if self.membership.peers().next().is_some() {
+                        self.deltas.push_back((self.seq, delta));
+                    }
+                }
+                reply
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(GOSSIP_INTERVAL)
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.rounds += 1;
+        let full = self.rounds.is_multiple_of(FULL_STATE_EVERY);
+        for peer in self.membership.peers() {
+            let Some(state) = self.gossip_for(peer, full) else {
+                continue;
+            };
+            let payload = Payload::Merge {
+                state: serde_json::to_value(state)?,
+                seq: self.seq,
+            };
+            Message::new(
+                &self.membership.node_id,
+                peer,
+                Some(self.msg_ids.next()),
+                payload,
+            )
+            .send(out)?;
+        }
+        Ok(())
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,161 @@
+//! An observed-remove set: elements can be removed as well as added, and
+//! an add concurrent with a remove of the same element wins.
+//!
+//! Every add tags the element with a tag unique to it, the adding node and
+//! how many adds that node has made. A remove takes away only the tags the
+//! remover has seen, keeping them as tombstones so that merging doesn't
+//! bring them back; an element is in the set while it has a tag that
+//! wasn't removed.
+
+use super::{element_of, Crdt, Replicated};
+use crate::{
+    payload::{AddValue, ReadValue},
+    Payload, RpcError,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+};
+
+/// Identifies one add: the `seq`th made on `node`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Tag {
+    pub node: String,
+    pub seq: u64,
+}
+
+/// Serves a set workload like `g-set`'s, with `remove` as well.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet<T: Ord> {
+    /// The elements with their tags that weren't removed.
+    entries: BTreeSet<(T, Tag)>,
+    removed: BTreeSet<Tag>,
+    /// How many adds each node has made.
+    seqs: BTreeMap<String, u64>,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            seqs: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    /// Adds `element` on `node`, with a new tag.
+    pub fn add(&mut self, node: &str, element: T) {
+        let delta = self.add_delta(node, element);
+        self.join(&delta);
+    }
+
+    /// Removes `element` as far as this replica has seen it added,
+    /// returning whether it was in the set.
+    pub fn remove(&mut self, element: &T) -> bool {
+        let delta = self.remove_delta(element);
+        self.join(&delta);
+        !delta.removed.is_empty()
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries.iter().any(|(other, _)| other == element)
+    }
+
+    /// The elements, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut last = None;
+        self.entries.iter().filter_map(move |(element, _)| {
+            let new = last != Some(element);
+            last = Some(element);
+            new.then_some(element)
+        })
+    }
+
+    /// The delta of adding `element` on `node`: the element with a new tag.
+    fn add_delta(&self, node: &str, element: T) -> Self {
+        let seq = self.seqs.get(node).copied().unwrap_or(0) + 1;
+        let tag = Tag {
+            node: node.to_string(),
+            seq,
+        };
+        Self {
+            entries: BTreeSet::from([(element, tag)]),
+            removed: BTreeSet::new(),
+            seqs: BTreeMap::from([(node.to_string(), seq)]),
+        }
+    }
+
+    /// The delta of removing `element`: its tags, as tombstones.
+    fn remove_delta(&self, element: &T) -> Self {
+        let removed = self
+            .entries
+            .iter()
+            .filter(|(other, _)| other == element)
+            .map(|(_, tag)| tag.clone())
+            .collect();
+        Self {
+            removed,
+            ..Self::default()
+        }
+    }
+
+    fn join(&mut self, other: &Self) {
+        self.removed.extend(other.removed.iter().cloned());
+        self.entries.extend(other.entries.iter().cloned());
+        let removed = &self.removed;
+        self.entries.retain(|(_, tag)| !removed.contains(tag));
+        for (node, seq) in &other.seqs {
+            let ours = self.seqs.entry(node.clone()).or_default();
+            *ours = (*ours).max(*seq);
+        }
+    }
+}
+
+impl<T> Crdt for OrSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        self.join(other);
+    }
+}
+
+impl<T> Replicated for OrSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn apply(
+        &mut self,
+        node_id: &str,
+        request: &Payload,
+    ) -> Result<(Payload, Option<Self>), RpcError> {
+        match request {
+            Payload::Add {
+                value: AddValue::Element { element },
+                ..
+            } => {
+                let delta = self.add_delta(node_id, element_of(element)?);
+                self.join(&delta);
+                Ok((Payload::AddOk, Some(delta)))
+            }
+            Payload::Remove { element, .. } => {
+                let delta = self.remove_delta(&element_of(element)?);
+                self.join(&delta);
+                let changed = !delta.removed.is_empty();
+                Ok((Payload::RemoveOk, changed.then_some(delta)))
+            }
+            Payload::Read { .. } => {
+                let elements: Vec<&T> = self.iter().collect();
+                let value = serde_json::to_value(elements).expect("elements serialize");
+                let value = ReadValue::Value { value };
+                Ok((Payload::ReadOk { value }, None))
+            }
+            _ => Err(RpcError::not_supported(
+                "or-set node cannot handle this message",
+            )),
+        }
+    }
+}
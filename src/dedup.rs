@@ -0,0 +1,222 @@
+//! Answering retried requests from a cache instead of handling them again.
+//!
+//! A client or peer that gives up waiting for a reply sends its request
+//! again with the same `msg_id`, and a node that handles it twice applies
+//! it twice: a counter adds the delta again, say. [`Deduped`] wraps a node
+//! and remembers each request it passes on by sender and `msg_id`, along
+//! with the node's reply once it sends one, whether while handling the
+//! request or later, as nodes that wait on their peers do. A duplicate of
+//! an answered request gets the same reply again, and one of a request
+//! still waiting for its reply is dropped, since that reply is coming.
+//!
+//! Errors the main loop sends for a handler that failed aren't the node's
+//! own replies, so they aren't cached, and the request is handled again
+//! if it is retried. Failed requests change nothing, so that is safe.
+//!
+//! Requests are forgotten once they are older than the cache's
+//! [`CacheLimits::ttl`], and the oldest ones once it holds more than
+//! [`CacheLimits::max_entries`], so a retry that comes later than that is
+//! handled again.
+
+use crate::{metrics, time, CacheLimits, Message, MsgIdAllocator, Node, Rpc};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Serves `N`, handling each request at most once.
+#[derive(Debug)]
+pub struct Deduped<N> {
+    node: N,
+    /// Requests passed on to the node, by sender and `msg_id`, with the
+    /// reply once it has sent one, as written.
+    replies: HashMap<(String, usize), Option<Vec<u8>>>,
+    /// The keys of `replies`, oldest first, with when they were added.
+    order: VecDeque<(Instant, (String, usize))>,
+    limits: CacheLimits,
+}
+
+/// As much of a sent message as it takes to tell what it replies to.
+#[derive(Deserialize)]
+struct Sent {
+    dest: String,
+    body: SentBody,
+}
+
+#[derive(Deserialize)]
+struct SentBody {
+    in_reply_to: Option<usize>,
+}
+
+impl<N> Deduped<N> {
+    pub fn new(node: N) -> Self {
+        Self {
+            node,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+            limits: CacheLimits::default(),
+        }
+    }
+
+    /// Forgets requests as `limits` says; by default none are.
+    pub fn with_limits(mut self, limits: CacheLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.node
+    }
+
+    /// How many requests are remembered.
+    pub fn len(&self) -> usize {
+        self.replies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+
+    /// Forgets the requests that have outlived the TTL, and the oldest
+    /// ones while there are too many.
+    fn evict(&mut self, now: Instant) {
+        let mut evicted = 0;
+        while let Some((at, key)) = self.order.pop_front() {
+            if !self.limits.expired(at, now) && !self.limits.over(self.order.len() + 1) {
+                self.order.push_front((at, key));
+                break;
+            }
+            self.replies.remove(&key);
+            evicted += 1;
+        }
+        metrics::record_evictions("dedup", evicted);
+    }
+
+    /// Keeps the replies in `sent` to requests waiting for one.
+    fn capture(&mut self, sent: &[u8]) {
+        for line in sent.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            let Ok(Sent { dest, body }) = serde_json::from_slice(line) else {
+                continue;
+            };
+            let Some(in_reply_to) = body.in_reply_to else {
+                continue;
+            };
+            if let Some(reply @ None) = self.replies.get_mut(&(dest, in_reply_to)) {
+                let mut line = line.to_vec();
+                line.push(b'\n');
+                *reply = Some(line);
+            }
+        }
+    }
+}
+
+impl<P, N: Node<P>> Node<P> for Deduped<N> {
+    fn handle(&mut self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()> {
+        // Replies, and messages nobody waits on a reply to, pass straight
+        // through.
+        let key = match (msg.body.id, msg.body.in_reply_to) {
+            (Some(msg_id), None) => (msg.src.clone(), msg_id),
+            _ => {
+                let mut out = Tee::new(out);
+                let result = self.node.handle(msg, &mut out);
+                self.capture(&out.copy);
+                return result;
+            }
+        };
+        let now = time::now();
+        self.evict(now);
+        match self.replies.get(&key) {
+            Some(Some(reply)) => {
+                crate::debug!("answering duplicate {} from {} again", key.1, key.0);
+                return Ok(out.write_all(reply)?);
+            }
+            Some(None) => {
+                crate::debug!("dropping duplicate {} from {}", key.1, key.0);
+                return Ok(());
+            }
+            None => {}
+        }
+        self.replies.insert(key.clone(), None);
+        self.order.push_back((now, key.clone()));
+        if self.limits.over(self.order.len()) {
+            self.evict(now);
+        }
+        let mut out = Tee::new(out);
+        let result = self.node.handle(msg, &mut out);
+        if result.is_err() {
+            self.replies.remove(&key);
+            self.order.pop_back();
+        }
+        self.capture(&out.copy);
+        result
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.node.tick_interval()
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.evict(time::now());
+        let mut out = Tee::new(out);
+        let result = self.node.tick(&mut out);
+        self.capture(&out.copy);
+        result
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.node.next_timer()
+    }
+
+    fn rpc(&self) -> Option<Rpc<P>> {
+        self.node.rpc()
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.node.msg_ids()
+    }
+
+    fn shutdown(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut out = Tee::new(out);
+        let result = self.node.shutdown(&mut out);
+        self.capture(&out.copy);
+        result
+    }
+
+    fn inspect(&self) -> serde_json::Value {
+        self.node.inspect()
+    }
+
+    fn topology(&self) -> serde_json::Value {
+        self.node.topology()
+    }
+}
+
+/// Passes everything written on to `out`, flushes included, keeping a
+/// copy.
+struct Tee<'a, W> {
+    out: &'a mut W,
+    copy: Vec<u8>,
+}
+
+impl<'a, W> Tee<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Self {
+            out,
+            copy: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Tee<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(data)?;
+        self.copy.extend_from_slice(&data[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{
+    ids::{IdGenerator, IdScheme, UuidV4},
+    Membership, Message, MsgIdAllocator, Node, Payload, RpcError,
+};
+use std::io::Write;
+
+/// Serves the `echo` and `unique-ids` workloads.
+#[derive(Debug)]
+pub struct EchoNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    ids: Box<dyn IdGenerator>,
+}
+
+impl Default for EchoNode {
+    fn default() -> Self {
+        Self::with_generator(UuidV4)
+    }
+}
+
+impl EchoNode {
+    /// A node that generates ids with one of the built-in schemes.
+    pub fn new(ids: IdScheme) -> Self {
+        Self::from_boxed(ids.generator())
+    }
+
+    /// A node that generates ids with `ids`.
+    pub fn with_generator(ids: impl IdGenerator + 'static) -> Self {
+        Self::from_boxed(Box::new(ids))
+    }
+
+    fn from_boxed(ids: Box<dyn IdGenerator>) -> Self {
+        Self {
+            membership: Membership::default(),
+            msg_ids: MsgIdAllocator::default(),
+            ids,
+        }
+    }
+}
+
+impl Node for EchoNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                let index = node_ids.iter().position(|id| id == node_id).unwrap_or(0);
+                self.ids.init(node_id, index);
+                Payload::InitOk
+            }
+            Payload::Echo { echo } => Payload::EchoOk { echo: echo.clone() },
+            Payload::Generate => Payload::GenerateOk {
+                id: self.ids.next_id(),
+            },
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(RpcError::not_supported("echo node cannot handle this message").into())
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
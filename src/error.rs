@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The standard Maelstrom error codes, see
+/// <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(u32),
+}
+
+impl ErrorCode {
+    /// Whether Maelstrom treats this error as definite, i.e. the operation
+    /// is known not to have taken place.
+    pub fn is_definite(self) -> bool {
+        !matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Other(_)
+        )
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            other => ErrorCode::Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+/// An error that should be reported back to the sender as a Maelstrom
+/// `error` message rather than aborting the node.
+///
+/// Handlers return it through `anyhow`, e.g.
+/// `return Err(RpcError::not_supported("cas").into())`, and `main_loop`
+/// turns it into the reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl RpcError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn not_supported(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotSupported, text)
+    }
+
+    pub fn precondition_failed(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PreconditionFailed, text)
+    }
+
+    pub fn key_does_not_exist(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::KeyDoesNotExist, text)
+    }
+
+    pub fn txn_conflict(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::TxnConflict, text)
+    }
+
+    pub fn crash(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Crash, text)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}: {}", u32::from(self.code), self.text)
+    }
+}
+
+impl std::error::Error for RpcError {}
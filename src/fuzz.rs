@@ -0,0 +1,47 @@
+//! An entry point for fuzzing nodes, driven by `cargo fuzz` from `fuzz/`.
+//!
+//! [`step`] treats its input as a stream of lines, the way Maelstrom's
+//! stdin arrives, and puts each one through the same parsing and handling
+//! as the main loop. Input the main loop would reject or exit on is fine;
+//! what the fuzzer is after is a panic, such as an overflow in a handler's
+//! arithmetic.
+//!
+//! The node runs in a one-node [`Sim`], so requests it makes to the KV
+//! services are answered instead of timing out.
+
+use crate::{handle_unknown, parse_event, sim::Sim, Event, Node, Replier, UnknownPolicy};
+use std::{io, time::Duration};
+
+/// Starts a node built by `make` as `n0` and feeds it every line of `data`
+/// that parses as a message, until it has seen them all or returns an
+/// error the main loop would exit on.
+pub fn step<N: Node>(make: impl FnMut(&str) -> N, data: &[u8]) {
+    let Ok(mut sim) = Sim::with_seed(1, make, 0) else {
+        return;
+    };
+    sim.network().delay = Duration::ZERO..Duration::ZERO;
+    for line in data.split(|b| *b == b'\n') {
+        let Ok(line) = std::str::from_utf8(line) else {
+            continue;
+        };
+        match parse_event(line) {
+            Ok(Event::Message(msg)) => sim.inject(msg),
+            Ok(Event::Unknown(msg)) => {
+                let replier = Replier::default();
+                let _ = handle_unknown(&msg, UnknownPolicy::Reply, &replier, &mut io::sink());
+                continue;
+            }
+            Ok(Event::Tick | Event::Shutdown) | Err(_) => continue,
+            #[cfg(feature = "admin")]
+            Ok(Event::Inspect(_)) => continue,
+            #[cfg(feature = "async")]
+            Ok(Event::Wake) => continue,
+        }
+        // Only what is due right now, so a node that keeps messaging
+        // itself can't keep the fuzzer here forever.
+        if sim.run_for(Duration::ZERO).is_err() {
+            break;
+        }
+        sim.take_replies();
+    }
+}
@@ -0,0 +1,804 @@
+//! gzip, for the bulk data nodes send each other: packed gossip values and
+//! Raft snapshots, which are JSON full of repeats that DEFLATE takes down
+//! to a fraction of its size.
+//!
+//! Compressing finds repeats within the last 32 KiB with a hash chain,
+//! putting a repeat off by a byte when a longer one starts there, and
+//! writes everything as one block, in Huffman codes fit to what it holds
+//! or the fixed ones, whichever comes out shorter. Decompressing
+//! reads any gzip member, stored, fixed and dynamic blocks alike, checks
+//! its CRC-32 and length, and gives up past [`MAX_LEN`] bytes so a small
+//! message can't make a node allocate without bound.
+//!
+//! As with [MessagePack](crate::msgpack), messages themselves stay JSON;
+//! gzip only travels inside them, base64-encoded, and only to peers whose
+//! [handshake](crate::handshake) said they read it.
+
+use crate::{ErrorCode, RpcError};
+
+/// The most bytes a member may decompress to.
+pub const MAX_LEN: usize = 64 << 20;
+
+/// How far back repeats are looked for.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried for a repeat.
+const MAX_CHAIN: usize = 64;
+/// Repeats at least this long are taken without looking for a longer one
+/// a byte on.
+const MAX_LAZY: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths come in, in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// How long the header [`compress`] writes is.
+const HEADER_LEN: usize = 10;
+
+/// `data` as a gzip member.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // No file name, modification time or extra flags; made on an unknown OS.
+    let header: [u8; HEADER_LEN] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let tokens = deflate(data);
+    let fixed = block(&tokens, &header, Codes::fixed());
+    let dynamic = block(&tokens, &header, Codes::fit(&tokens));
+    let mut out = match fixed.len() <= dynamic.len() {
+        true => fixed,
+        false => dynamic,
+    };
+    // Data with few repeats comes out longer than it went in; it is sent
+    // as it is instead, in stored blocks of at most 64 KiB.
+    let blocks = data.len().div_ceil(0xffff).max(1);
+    if out.len() > HEADER_LEN + data.len() + 5 * blocks {
+        out.truncate(HEADER_LEN);
+        store(data, &mut out);
+    }
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// The data in the gzip member `bytes`, all of them.
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, RpcError> {
+    let body = skip_header(bytes)?;
+    let mut reader = BitReader {
+        bytes: body,
+        bits: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored(&mut reader, &mut out)?,
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                inflate(&mut reader, &lengths, &distances, &mut out)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut reader)?;
+                inflate(&mut reader, &lengths, &distances, &mut out)?
+            }
+            _ => return Err(malformed("reserved block type")),
+        }
+        if last {
+            break;
+        }
+    }
+    let trailer = reader.rest();
+    let [c0, c1, c2, c3, l0, l1, l2, l3] = trailer else {
+        return Err(malformed(match trailer.len() < 8 {
+            true => "data ends before the trailer",
+            false => "trailing bytes",
+        }));
+    };
+    if u32::from_le_bytes([*c0, *c1, *c2, *c3]) != crc32(&out) {
+        return Err(malformed("CRC mismatch"));
+    }
+    if u32::from_le_bytes([*l0, *l1, *l2, *l3]) != out.len() as u32 {
+        return Err(malformed("length mismatch"));
+    }
+    Ok(out)
+}
+
+/// Writes `data` as stored blocks.
+fn store(data: &[u8], out: &mut Vec<u8>) {
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        // Whether it is the last, and stored, in the three bits before the
+        // byte boundary stored blocks start on.
+        out.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+}
+
+/// What a block holds: bytes, and repeats of earlier ones.
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Repeat { len: usize, dist: usize },
+}
+
+impl Token {
+    /// The symbol of the literal/length alphabet it starts with, and for
+    /// a repeat, its distance's symbol.
+    fn symbols(self) -> (usize, Option<usize>) {
+        match self {
+            Token::Literal(byte) => (usize::from(byte), None),
+            Token::Repeat { len, dist } => (257 + len_index(len), Some(dist_index(dist))),
+        }
+    }
+}
+
+fn len_index(len: usize) -> usize {
+    let i = LEN_BASE.iter().rposition(|&base| usize::from(base) <= len);
+    i.expect("matches are at least 3 long")
+}
+
+fn dist_index(dist: usize) -> usize {
+    let i = DIST_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= dist);
+    i.expect("distances are at least 1")
+}
+
+/// Finds repeats in `data`, as the literals and back-references to write.
+fn deflate(data: &[u8]) -> Vec<Token> {
+    let mut chains = Chains {
+        head: vec![usize::MAX; 1 << HASH_BITS],
+        prev: vec![usize::MAX; WINDOW],
+    };
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let found = chains.longest_match(data, at);
+        chains.insert(data, at);
+        let longer_next = |len| {
+            len < MAX_LAZY
+                && chains
+                    .longest_match(data, at + 1)
+                    .is_some_and(|(next, _)| next > len)
+        };
+        match found {
+            Some((len, dist)) if !longer_next(len) => {
+                tokens.push(Token::Repeat { len, dist });
+                for repeated in at + 1..at + len {
+                    chains.insert(data, repeated);
+                }
+                at += len;
+            }
+            _ => {
+                tokens.push(Token::Literal(data[at]));
+                at += 1;
+            }
+        }
+    }
+    tokens
+}
+
+/// `header`, then `tokens` as the only block, in `codes`.
+fn block(tokens: &[Token], header: &[u8], codes: Codes) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: header.to_vec(),
+        bits: 0,
+        count: 0,
+    };
+    writer.bits(1, 1);
+    codes.write_header(&mut writer);
+    for &token in tokens {
+        writer.token(token, &codes);
+    }
+    writer.code(&codes.literals, 256);
+    writer.finish()
+}
+
+/// The Huffman codes a block is written in.
+struct Codes {
+    literals: Code,
+    distances: Code,
+    /// For fit codes, how the lengths of the two go in the block header.
+    header: Option<LengthsHeader>,
+}
+
+impl Codes {
+    fn fixed() -> Self {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        Self {
+            literals: Code::new(lengths.to_vec()),
+            distances: Code::new(vec![5; 30]),
+            header: None,
+        }
+    }
+
+    /// Codes fit to how often `tokens` use each symbol.
+    fn fit(tokens: &[Token]) -> Self {
+        let mut literals = vec![0u32; 286];
+        let mut distances = vec![0u32; 30];
+        literals[256] = 1;
+        for &token in tokens {
+            let (literal, distance) = token.symbols();
+            literals[literal] += 1;
+            if let Some(distance) = distance {
+                distances[distance] += 1;
+            }
+        }
+        let literals = code_lengths(&literals, 15);
+        let mut distances = code_lengths(&distances, 15);
+        if distances.iter().all(|&len| len == 0) {
+            // There has to be a distance code, even if nothing uses it.
+            distances[0] = 1;
+        }
+        let header = LengthsHeader::new(&literals, &distances);
+        Self {
+            literals: Code::new(literals),
+            distances: Code::new(distances),
+            header: Some(header),
+        }
+    }
+
+    /// The block type, and for fit codes, their lengths.
+    fn write_header(&self, writer: &mut BitWriter) {
+        let Some(header) = &self.header else {
+            return writer.bits(1, 2);
+        };
+        writer.bits(2, 2);
+        writer.bits(header.literals as u32 - 257, 5);
+        writer.bits(header.distances as u32 - 1, 5);
+        let lengths = &header.code.lengths;
+        let count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| lengths[symbol] != 0)
+            .map_or(0, |last| last + 1)
+            .max(4);
+        writer.bits(count as u32 - 4, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..count] {
+            writer.bits(lengths[symbol].into(), 3);
+        }
+        for &(symbol, extra) in &header.runs {
+            writer.code(&header.code, symbol);
+            match symbol {
+                16 => writer.bits(extra.into(), 2),
+                17 => writer.bits(extra.into(), 3),
+                18 => writer.bits(extra.into(), 7),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The code lengths of a dynamic block's codes, run-length encoded as
+/// its header has them.
+struct LengthsHeader {
+    /// How many literal/length and distance codes there are.
+    literals: usize,
+    distances: usize,
+    /// Each length as a symbol of the code length alphabet, and the extra
+    /// bits of a repeat.
+    runs: Vec<(usize, u8)>,
+    /// The code the symbols are written in.
+    code: Code,
+}
+
+impl LengthsHeader {
+    fn new(literals: &[u8], distances: &[u8]) -> Self {
+        let used = |lengths: &[u8], min| {
+            let last = lengths.iter().rposition(|&len| len != 0);
+            last.map_or(0, |last| last + 1).max(min)
+        };
+        let (literals, distances) = (
+            &literals[..used(literals, 257)],
+            &distances[..used(distances, 1)],
+        );
+        let lengths = [literals, distances].concat();
+        let mut runs = Vec::new();
+        let mut at = 0;
+        while at < lengths.len() {
+            let len = lengths[at];
+            let run = lengths[at..].iter().take_while(|&&l| l == len).count();
+            match (len, run) {
+                (0, 11..) => {
+                    let run = run.min(138);
+                    runs.push((18, (run - 11) as u8));
+                    at += run;
+                }
+                (0, 3..) => {
+                    runs.push((17, (run - 3) as u8));
+                    at += run;
+                }
+                (_, 4..) => {
+                    // The length once, then repeats of it.
+                    let repeats = (run - 1).min(6);
+                    runs.push((usize::from(len), 0));
+                    runs.push((16, (repeats - 3) as u8));
+                    at += 1 + repeats;
+                }
+                _ => {
+                    runs.push((usize::from(len), 0));
+                    at += 1;
+                }
+            }
+        }
+        let mut counts = vec![0u32; 19];
+        for &(symbol, _) in &runs {
+            counts[symbol] += 1;
+        }
+        Self {
+            literals: literals.len(),
+            distances: distances.len(),
+            runs,
+            code: Code::new(code_lengths(&counts, 7)),
+        }
+    }
+}
+
+/// A canonical Huffman code, as each symbol's code and its length.
+struct Code {
+    codes: Vec<u32>,
+    lengths: Vec<u8>,
+}
+
+impl Code {
+    fn new(lengths: Vec<u8>) -> Self {
+        let mut counts = [0u32; 16];
+        for &len in &lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        let mut next = [0u32; 16];
+        for len in 1..16 {
+            next[len] = (next[len - 1] + counts[len - 1]) << 1;
+        }
+        let codes = lengths
+            .iter()
+            .map(|&len| {
+                let code = next[usize::from(len)];
+                next[usize::from(len)] += 1;
+                code
+            })
+            .collect();
+        Self { codes, lengths }
+    }
+}
+
+/// The lengths of a Huffman code for symbols used `counts` times, none
+/// longer than `limit`. Unused symbols get no code, and a lone used one a
+/// code of one bit.
+fn code_lengths(counts: &[u32], limit: u8) -> Vec<u8> {
+    let mut counts = counts.to_vec();
+    loop {
+        let lengths = huffman_lengths(&counts);
+        if lengths.iter().all(|&len| len <= limit) {
+            return lengths;
+        }
+        // Evening out the counts shortens the longest codes; halving them
+        // until they fit costs a little over the optimal code.
+        for count in counts.iter_mut().filter(|count| **count > 0) {
+            *count = (*count).div_ceil(2);
+        }
+    }
+}
+
+fn huffman_lengths(counts: &[u32]) -> Vec<u8> {
+    use std::{cmp::Reverse, collections::BinaryHeap};
+
+    let mut lengths = vec![0u8; counts.len()];
+    let mut heap: BinaryHeap<_> = (0..counts.len())
+        .filter(|&symbol| counts[symbol] > 0)
+        .map(|symbol| Reverse((u64::from(counts[symbol]), symbol)))
+        .collect();
+    if heap.len() == 1 {
+        let Reverse((_, symbol)) = heap.pop().expect("just counted");
+        lengths[symbol] = 1;
+        return lengths;
+    }
+    // Nodes past the symbols are merged pairs; each node's parent, to
+    // walk up from the symbols to the root.
+    let mut parent = vec![usize::MAX; counts.len()];
+    while heap.len() > 1 {
+        let Reverse((a, left)) = heap.pop().expect("at least two");
+        let Reverse((b, right)) = heap.pop().expect("at least two");
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[left] = node;
+        parent[right] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        let mut node = symbol;
+        while parent[node] != usize::MAX {
+            node = parent[node];
+            *len += 1;
+        }
+    }
+    lengths
+}
+
+/// The positions seen so far, chained by the hash of the three bytes
+/// starting at each.
+struct Chains {
+    /// The latest position with each hash.
+    head: Vec<usize>,
+    /// The position before each, by position modulo the window.
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn hash(data: &[u8], at: usize) -> usize {
+        let key = u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0]);
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], at: usize) {
+        if at + MIN_MATCH <= data.len() {
+            let hash = Self::hash(data, at);
+            self.prev[at % WINDOW] = self.head[hash];
+            self.head[hash] = at;
+        }
+    }
+
+    /// The longest repeat of what starts at `at` within the window, as its
+    /// length and distance back, if one is long enough to be worth it.
+    fn longest_match(&self, data: &[u8], at: usize) -> Option<(usize, usize)> {
+        if at + MIN_MATCH > data.len() {
+            return None;
+        }
+        let max = (data.len() - at).min(MAX_MATCH);
+        let (mut best_len, mut best_dist) = (0, 0);
+        let mut candidate = self.head[Self::hash(data, at)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || at - candidate > WINDOW {
+                break;
+            }
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[at..at + max])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best_len {
+                (best_len, best_dist) = (len, at - candidate);
+                if len == max {
+                    break;
+                }
+            }
+            // A slot since reused by a later position may lead forward.
+            let next = self.prev[candidate % WINDOW];
+            if next == usize::MAX || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        (best_len >= MIN_MATCH).then_some((best_len, best_dist))
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `count` bits of `value`, least significant first.
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes `symbol` in `code`. Huffman codes go most significant bit
+    /// first.
+    fn code(&mut self, code: &Code, symbol: usize) {
+        let len = u32::from(code.lengths[symbol]);
+        self.bits(code.codes[symbol].reverse_bits() >> (32 - len), len);
+    }
+
+    fn token(&mut self, token: Token, codes: &Codes) {
+        let (literal, distance) = token.symbols();
+        self.code(&codes.literals, literal);
+        if let (Token::Repeat { len, dist }, Some(distance)) = (token, distance) {
+            let i = literal - 257;
+            self.bits((len - usize::from(LEN_BASE[i])) as u32, LEN_EXTRA[i].into());
+            self.code(&codes.distances, distance);
+            let extra = dist - usize::from(DIST_BASE[distance]);
+            self.bits(extra as u32, DIST_EXTRA[distance].into());
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// The rest of `bytes` after a gzip header.
+fn skip_header(bytes: &[u8]) -> Result<&[u8], RpcError> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    let [0x1f, 0x8b, method, flags, _, _, _, _, _, _, rest @ ..] = bytes else {
+        return Err(malformed("not gzip"));
+    };
+    if *method != 8 {
+        return Err(malformed("not DEFLATE"));
+    }
+    if flags & 0xe0 != 0 {
+        return Err(malformed("reserved flags set"));
+    }
+    let mut rest = rest;
+    if flags & FEXTRA != 0 {
+        let [l0, l1, ..] = rest else {
+            return Err(malformed("data ends in the header"));
+        };
+        let len = usize::from(u16::from_le_bytes([*l0, *l1]));
+        rest = skip(rest, 2 + len)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = rest.iter().position(|&b| b == 0);
+            let end = end.ok_or_else(|| malformed("data ends in the header"))?;
+            rest = skip(rest, end + 1)?;
+        }
+    }
+    if flags & FHCRC != 0 {
+        rest = skip(rest, 2)?;
+    }
+    Ok(rest)
+}
+
+fn skip(bytes: &[u8], len: usize) -> Result<&[u8], RpcError> {
+    bytes
+        .get(len..)
+        .ok_or_else(|| malformed("data ends in the header"))
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    /// Reads `count` bits, least significant first.
+    fn bits(&mut self, count: u32) -> Result<u32, RpcError> {
+        while self.count < count {
+            let [byte, rest @ ..] = self.bytes else {
+                return Err(malformed("data ends mid-block"));
+            };
+            self.bits |= u64::from(*byte) << self.count;
+            self.bytes = rest;
+            self.count += 8;
+        }
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drops what is left of the current byte.
+    fn align(&mut self) {
+        self.bits >>= self.count % 8;
+        self.count -= self.count % 8;
+    }
+
+    /// The bytes after the current one. Bytes are only taken as their bits
+    /// are needed, so none but the current one are ever buffered.
+    fn rest(mut self) -> &'a [u8] {
+        self.align();
+        self.bytes
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and
+/// the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, RpcError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(malformed("over-subscribed code"));
+            }
+        }
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&symbol| lengths[usize::from(symbol)] != 0)
+            .collect();
+        symbols.sort_by_key(|&symbol| lengths[usize::from(symbol)]);
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<u16, RpcError> {
+        // Codes of each length follow on from the shorter ones', so the
+        // code read so far is a symbol once it falls within its length's.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(malformed("no such code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    let lengths = Huffman::new(&lengths).expect("the fixed code is complete");
+    let distances = Huffman::new(&[5; 30]).expect("the fixed code is not over-subscribed");
+    (lengths, distances)
+}
+
+fn dynamic_codes(reader: &mut BitReader<'_>) -> Result<(Huffman, Huffman), RpcError> {
+    let literals = reader.bits(5)? as usize + 257;
+    let distances = reader.bits(5)? as usize + 1;
+    let code_lengths = reader.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(malformed("too many codes"));
+    }
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code.decode(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let previous = lengths
+                    .last()
+                    .ok_or_else(|| malformed("nothing to repeat"))?;
+                (*previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err(malformed("code lengths overrun"));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths[256] == 0 {
+        return Err(malformed("no end of block code"));
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literals);
+    Ok((
+        Huffman::new(literal_lengths)?,
+        Huffman::new(distance_lengths)?,
+    ))
+}
+
+fn stored(reader: &mut BitReader<'_>, out: &mut Vec<u8>) -> Result<(), RpcError> {
+    reader.align();
+    let len = reader.bits(16)?;
+    if reader.bits(16)? != !len & 0xffff {
+        return Err(malformed("stored block length mismatch"));
+    }
+    if out.len() + len as usize > MAX_LEN {
+        return Err(malformed("too long"));
+    }
+    for _ in 0..len {
+        out.push(reader.bits(8)? as u8);
+    }
+    Ok(())
+}
+
+fn inflate(
+    reader: &mut BitReader<'_>,
+    lengths: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>,
+) -> Result<(), RpcError> {
+    loop {
+        let symbol = lengths.decode(reader)?;
+        let len = match symbol {
+            0..=255 => {
+                out.push(symbol as u8);
+                continue;
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let i = usize::from(symbol - 257);
+                usize::from(LEN_BASE[i]) + reader.bits(LEN_EXTRA[i].into())? as usize
+            }
+            _ => return Err(malformed("no such length")),
+        };
+        let i = usize::from(distances.decode(reader)?);
+        if i >= DIST_BASE.len() {
+            return Err(malformed("no such distance"));
+        }
+        let dist = usize::from(DIST_BASE[i]) + reader.bits(DIST_EXTRA[i].into())? as usize;
+        if dist > out.len() {
+            return Err(malformed("distance reaches before the start"));
+        }
+        if out.len() + len > MAX_LEN {
+            return Err(malformed("too long"));
+        }
+        // Byte by byte, since a repeat may overlap what it writes.
+        let from = out.len() - dist;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+    }
+}
+
+/// The CRC-32 of `data`, as gzip has it.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[usize::from(crc as u8 ^ byte)] ^ crc >> 8
+    })
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xedb8_8320 ^ crc >> 1,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn malformed(text: &str) -> RpcError {
+    RpcError::new(ErrorCode::MalformedRequest, format!("gzip: {text}"))
+}
@@ -0,0 +1,279 @@
+//! Telling peers which version of the node-to-node protocol this node
+//! speaks and what it can do beyond the baseline, so that nodes built from
+//! different versions of this crate can share a cluster.
+//!
+//! After `init`, a [`Handshake`] sends every peer a `hello` carrying
+//! [`PROTOCOL_VERSION`] and this node's [`Capability`]s, and resends it
+//! every interval until the peer answers with a `hello_ok` carrying its
+//! own, or says hello itself and is answered. Both are one-way messages,
+//! so the exchange needs no `msg_id`s and leaves [`Rpc`](crate::Rpc)
+//! calls alone. The version is exchanged once per peer rather than
+//! stamped on every gossip, sync or Raft message.
+//!
+//! Versions only ever add capabilities, so any two nodes can talk the
+//! baseline every version speaks; anything beyond it is only used with a
+//! peer that advertised it too, see [`Handshake::supports`]. A peer from
+//! before handshakes, which answers `hello` with an error or not at all, is
+//! taken to speak version 0, the baseline, once it has been asked
+//! [`MAX_ATTEMPTS`] times. One that never answered is still said hello to
+//! now and then, less and less often, in case it was only cut off.
+//! Capabilities a later version adds read as [`Capability::Unknown`] here
+//! and are never used.
+
+use crate::{time, ErrorCode, Membership, Message, Payload};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// The version of the node-to-node protocol this crate speaks. Bumped
+/// whenever a [`Capability`] is added.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// How many `hello`s a peer gets before it is taken to speak the baseline.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// How many times longer than the interval the wait between `hello`s to a
+/// peer that doesn't answer grows to, at most.
+const MAX_BACKOFF: u32 = 64;
+
+/// Something a node can do beyond the baseline protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    /// Reads gossip values packed as [`crate::compress`] describes.
+    PackedValues,
+    /// Reads [MessagePack](crate::msgpack), in gossip values and in Raft
+    /// snapshots. Since version 2.
+    #[serde(rename = "msgpack")]
+    MsgPack,
+    /// Reads [gzip](crate::gzip), in gossip values and in Raft snapshots.
+    /// Since version 3.
+    Gzip,
+    /// One this version doesn't know of.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What a peer said about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Peer {
+    /// A peer that never said, taken to speak only the baseline.
+    fn baseline() -> Self {
+        Self {
+            version: 0,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+/// A peer yet to answer.
+#[derive(Debug, Clone)]
+struct Pending {
+    attempts: u32,
+    next_hello: Instant,
+}
+
+/// The handshake with every peer, and what each said.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    capabilities: Vec<Capability>,
+    interval: Duration,
+    membership: Membership,
+    /// Peers that said hello or answered one, or are taken to speak the
+    /// baseline.
+    peers: HashMap<String, Peer>,
+    /// Peers yet to answer.
+    pending: HashMap<String, Pending>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Handshake {
+    /// Advertises `capabilities`, resending `hello` every 100ms.
+    pub fn new(capabilities: impl IntoIterator<Item = Capability>) -> Self {
+        Self {
+            capabilities: capabilities.into_iter().collect(),
+            interval: Duration::from_millis(100),
+            membership: Membership::default(),
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Advertises `capability` too, unless it already is.
+    pub fn advertise(&mut self, capability: Capability) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+    }
+
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Says hello to the peers announced by `init`.
+    pub fn init(&mut self, membership: &Membership, out: &mut impl Write) -> anyhow::Result<()> {
+        self.set_membership(membership);
+        self.tick(out)
+    }
+
+    /// Follows nodes joining or leaving after `init`. Peers that joined are
+    /// said hello to on the next tick.
+    pub fn set_membership(&mut self, membership: &Membership) {
+        self.membership = membership.clone();
+        self.peers.retain(|peer, _| membership.is_peer(peer));
+        self.pending.retain(|peer, _| membership.is_peer(peer));
+        let now = time::now();
+        for peer in membership.peers() {
+            if !self.peers.contains_key(peer) {
+                self.pending.entry(peer.clone()).or_insert(Pending {
+                    attempts: 0,
+                    next_hello: now,
+                });
+            }
+        }
+    }
+
+    /// Takes in `msg` if it is part of the handshake, answering a `hello`.
+    /// Returns whether it was, and needs no further handling.
+    pub fn handle(&mut self, msg: &Message, out: &mut impl Write) -> anyhow::Result<bool> {
+        match &msg.body.payload {
+            Payload::Hello {
+                version,
+                capabilities,
+            } => {
+                self.said(&msg.src, *version, capabilities);
+                let hello_ok = Payload::HelloOk {
+                    version: PROTOCOL_VERSION,
+                    capabilities: self.capabilities.clone(),
+                };
+                Message::new(&self.membership.node_id, &msg.src, None, hello_ok).send(out)?;
+                Ok(true)
+            }
+            Payload::HelloOk {
+                version,
+                capabilities,
+            } => {
+                self.said(&msg.src, *version, capabilities);
+                Ok(true)
+            }
+            Payload::Error {
+                code: ErrorCode::NotSupported,
+                ..
+            } if self.pending.contains_key(&msg.src) => {
+                crate::debug!("{} doesn't say hello, speaking the baseline", msg.src);
+                self.pending.remove(&msg.src);
+                self.peers.insert(msg.src.clone(), Peer::baseline());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn said(&mut self, peer: &str, version: u32, capabilities: &[Capability]) {
+        if !self.membership.is_peer(peer) {
+            return;
+        }
+        self.pending.remove(peer);
+        let capabilities = capabilities
+            .iter()
+            .copied()
+            .filter(|capability| *capability != Capability::Unknown)
+            .collect();
+        self.peers.insert(
+            peer.to_string(),
+            Peer {
+                version,
+                capabilities,
+            },
+        );
+    }
+
+    /// Says hello again to peers yet to answer that are due one, taking
+    /// those asked [`MAX_ATTEMPTS`] times to speak the baseline until they
+    /// do answer.
+    pub fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let now = time::now();
+        for (peer, pending) in &mut self.pending {
+            if now < pending.next_hello {
+                continue;
+            }
+            if pending.attempts == MAX_ATTEMPTS {
+                crate::debug!("{peer} doesn't answer hello, speaking the baseline");
+                self.peers.insert(peer.clone(), Peer::baseline());
+            }
+            pending.attempts += 1;
+            let backoff = 2u32
+                .saturating_pow(pending.attempts.saturating_sub(MAX_ATTEMPTS))
+                .min(MAX_BACKOFF);
+            pending.next_hello = now + self.interval * backoff;
+            let hello = Payload::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: self.capabilities.clone(),
+            };
+            Message::new(&self.membership.node_id, peer, None, hello).send(out)?;
+        }
+        Ok(())
+    }
+
+    /// What `peer` said about itself, once it has answered or is taken to
+    /// speak the baseline.
+    pub fn peer(&self, peer: &str) -> Option<&Peer> {
+        self.peers.get(peer)
+    }
+
+    /// The protocol version `peer` speaks, if known yet.
+    pub fn version(&self, peer: &str) -> Option<u32> {
+        self.peer(peer).map(|peer| peer.version)
+    }
+
+    /// Whether both this node and `peer` have `capability`, so it may be
+    /// used with `peer`. Not until `peer` has said so.
+    pub fn supports(&self, peer: &str, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+            && self
+                .peer(peer)
+                .is_some_and(|peer| peer.capabilities.contains(&capability))
+    }
+
+    /// The capabilities this node shares with every peer, the feature set
+    /// the whole cluster can use. Empty until every peer has answered.
+    pub fn common(&self) -> Vec<Capability> {
+        if !self.is_settled() {
+            return Vec::new();
+        }
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(|capability| {
+                self.membership
+                    .peers()
+                    .all(|peer| self.supports(peer, *capability))
+            })
+            .collect()
+    }
+
+    /// Whether every peer has answered, or is taken to speak the baseline.
+    pub fn is_settled(&self) -> bool {
+        self.membership
+            .peers()
+            .all(|peer| self.peers.contains_key(peer))
+    }
+}
@@ -0,0 +1,109 @@
+//! Peer liveness from periodic heartbeats: a lighter alternative to
+//! [`swim`](crate::swim) that needs no probes, only a `heartbeat` to every
+//! peer every interval.
+//!
+//! Any message from a peer counts as a sign of life, heartbeat or not. A
+//! peer nothing was heard from for longer than the timeout is considered
+//! down, so that retries can hold off, see
+//! [`RetryQueue::resend_due_unless`](crate::RetryQueue::resend_due_unless).
+
+use crate::{time, Membership, Message, Payload};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// When each peer was last heard from, and when to send the next beat.
+#[derive(Debug, Clone)]
+pub struct Heartbeats {
+    interval: Duration,
+    timeout: Duration,
+    membership: Membership,
+    last_seen: HashMap<String, Instant>,
+    next_beat: Option<Instant>,
+}
+
+impl Default for Heartbeats {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100))
+    }
+}
+
+impl Heartbeats {
+    /// Beats every `interval`, and considers peers down after five missed
+    /// beats.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            timeout: interval * 5,
+            membership: Membership::default(),
+            last_seen: HashMap::new(),
+            next_beat: None,
+        }
+    }
+
+    /// Considers peers down after nothing was heard from them for
+    /// `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Starts tracking the peers announced by `init`, as if each had just
+    /// been heard from.
+    pub fn init(&mut self, membership: &Membership) {
+        self.membership = membership.clone();
+        let now = time::now();
+        self.last_seen = membership.peers().map(|peer| (peer.clone(), now)).collect();
+    }
+
+    /// Follows nodes joining or leaving after `init`. Peers that joined are
+    /// treated as just heard from.
+    pub fn set_membership(&mut self, membership: &Membership) {
+        self.membership = membership.clone();
+        let now = time::now();
+        self.last_seen.retain(|peer, _| membership.is_peer(peer));
+        for peer in membership.peers() {
+            self.last_seen.entry(peer.clone()).or_insert(now);
+        }
+    }
+
+    /// Notes that `msg` arrived. Returns whether it was a heartbeat, which
+    /// needs no further handling.
+    pub fn observe(&mut self, msg: &Message) -> bool {
+        if let Some(seen) = self.last_seen.get_mut(&msg.src) {
+            *seen = time::now();
+        }
+        msg.body.payload == Payload::Heartbeat
+    }
+
+    /// When something last arrived from `node`, or `None` if it isn't a
+    /// peer.
+    pub fn last_seen(&self, node: &str) -> Option<Instant> {
+        self.last_seen.get(node).copied()
+    }
+
+    /// Whether `node` is a peer heard from within the timeout.
+    pub fn is_alive(&self, node: &str) -> bool {
+        self.last_seen(node)
+            .is_some_and(|seen| time::elapsed(seen) < self.timeout)
+    }
+
+    /// Sends every peer a heartbeat if one is due.
+    pub fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let now = time::now();
+        if self.next_beat.is_some_and(|at| now < at) {
+            return Ok(());
+        }
+        self.next_beat = Some(now + self.interval);
+        for peer in self.membership.peers() {
+            Message::new(&self.membership.node_id, peer, None, Payload::Heartbeat).send(out)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Unique ids for the `unique-ids` workload.
+//!
+//! Every scheme here is an [`IdGenerator`] and needs no coordination
+//! between nodes: random ones rely on enough random bits, the others on
+//! each node having a slice of the id space to itself, derived from its id
+//! or its index in `init`'s `node_ids`. Which one an
+//! [`EchoNode`](crate::EchoNode) uses is fixed when it is built, by
+//! [`IdScheme`] or [`EchoNode::with_generator`](crate::EchoNode::with_generator).
+
+use anyhow::bail;
+use rand::Rng;
+use serde_json::Value;
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// A source of cluster-wide unique ids.
+pub trait IdGenerator: Debug + Send {
+    /// Called on `init`, before the first [`IdGenerator::next_id`], with
+    /// this node's id and its index in the cluster.
+    fn init(&mut self, _node_id: &str, _index: usize) {}
+
+    fn next_id(&mut self) -> Value;
+}
+
+/// The built-in generators, by name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    /// [`UuidV4`].
+    #[default]
+    Uuid,
+    /// [`UuidV7`].
+    UuidV7,
+    /// [`Ulid`].
+    Ulid,
+    /// [`NodeCounter`].
+    Counter,
+    /// [`Snowflake`].
+    Snowflake,
+}
+
+impl IdScheme {
+    pub fn generator(self) -> Box<dyn IdGenerator> {
+        match self {
+            IdScheme::Uuid => Box::new(UuidV4),
+            IdScheme::UuidV7 => Box::new(UuidV7),
+            IdScheme::Ulid => Box::new(Ulid),
+            IdScheme::Counter => Box::<NodeCounter>::default(),
+            IdScheme::Snowflake => Box::new(Snowflake::new(0)),
+        }
+    }
+}
+
+impl FromStr for IdScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "uuid" | "uuid-v4" => IdScheme::Uuid,
+            "uuid-v7" => IdScheme::UuidV7,
+            "ulid" => IdScheme::Ulid,
+            "counter" => IdScheme::Counter,
+            "snowflake" => IdScheme::Snowflake,
+            _ => bail!("unknown id scheme {s}"),
+        })
+    }
+}
+
+/// Random UUIDv4 strings: 122 random bits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV4;
+
+impl IdGenerator for UuidV4 {
+    fn next_id(&mut self) -> Value {
+        Uuid::new_v4().to_string().into()
+    }
+}
+
+/// UUIDv7 strings: a millisecond timestamp followed by 74 random bits, so
+/// ids sort roughly by creation time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV7;
+
+impl IdGenerator for UuidV7 {
+    fn next_id(&mut self) -> Value {
+        let mut bytes: [u8; 16] = rand::thread_rng().gen();
+        bytes[..6].copy_from_slice(&unix_ms().to_be_bytes()[2..]);
+        bytes[6] = 0x70 | (bytes[6] & 0x0f);
+        bytes[8] = 0x80 | (bytes[8] & 0x3f);
+        Uuid::from_bytes(bytes).to_string().into()
+    }
+}
+
+/// ULIDs: a 48-bit millisecond timestamp and 80 random bits, as 26
+/// characters of Crockford base32.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ulid;
+
+impl IdGenerator for Ulid {
+    fn next_id(&mut self) -> Value {
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        let random: u128 = rand::thread_rng().gen::<u128>() >> 48;
+        let mut bits = u128::from(unix_ms()) << 80 | random;
+        let mut id = [0u8; 26];
+        for c in id.iter_mut().rev() {
+            *c = ALPHABET[(bits & 0x1f) as usize];
+            bits >>= 5;
+        }
+        String::from_utf8_lossy(&id).into_owned().into()
+    }
+}
+
+/// `<node id>-<n>` strings, counting up from 0. Node ids are unique, so
+/// the ids are too, but only for as long as the node doesn't restart.
+#[derive(Debug, Clone, Default)]
+pub struct NodeCounter {
+    node_id: String,
+    next: u64,
+}
+
+impl IdGenerator for NodeCounter {
+    fn init(&mut self, node_id: &str, _index: usize) {
+        self.node_id = node_id.to_string();
+    }
+
+    fn next_id(&mut self) -> Value {
+        let id = format!("{}-{}", self.node_id, self.next);
+        self.next += 1;
+        id.into()
+    }
+}
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+
+/// Where [`Snowflake`] timestamps start: 2023-01-01T00:00:00Z. 41 bits of
+/// milliseconds from there last until 2092.
+const EPOCH_MS: u64 = 1_672_531_200_000;
+
+/// Twitter-style ids: milliseconds since 2023-01-01 in the top 41 bits,
+/// then 10 bits of node index and a 12-bit sequence number within the
+/// millisecond. Ids from one generator are strictly increasing, and
+/// generators with different node indexes never collide.
+#[derive(Debug, Clone)]
+pub struct Snowflake {
+    node: u64,
+    last_ms: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    /// `node` is this node's index in the cluster, e.g. its position in
+    /// `init`'s `node_ids`; only its low 10 bits are used.
+    pub fn new(node: usize) -> Self {
+        Self {
+            node: node as u64 & ((1 << NODE_BITS) - 1),
+            last_ms: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        // A clock that went backwards mustn't hand out old ids again.
+        let mut now = unix_ms().max(EPOCH_MS).max(self.last_ms);
+        if now == self.last_ms {
+            self.sequence = (self.sequence + 1) & ((1 << SEQUENCE_BITS) - 1);
+            if self.sequence == 0 {
+                // 4096 ids this millisecond already; wait for the next.
+                while now <= self.last_ms {
+                    thread::sleep(Duration::from_micros(100));
+                    now = unix_ms();
+                }
+            }
+        } else {
+            self.sequence = 0;
+        }
+        self.last_ms = now;
+        (now - EPOCH_MS) << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | self.sequence
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn init(&mut self, _node_id: &str, index: usize) {
+        *self = Snowflake::new(index);
+    }
+
+    fn next_id(&mut self) -> Value {
+        self.next_u64().into()
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,78 @@
+//! Where incoming messages come from.
+//!
+//! The main loop pulls raw JSON messages from an [`InputSource`] on a
+//! thread of its own. [`stdin`] is what Maelstrom uses; [`JsonStream`]
+//! reads any other byte stream, such as a file to replay, and
+//! [`Messages`] and `mpsc::Receiver<String>` feed messages built in-process
+//! without spawning anything.
+
+use crate::Message;
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::{de::IoRead, value::RawValue, StreamDeserializer};
+use std::{
+    collections::VecDeque,
+    io::{BufReader, Read, Stdin},
+    sync::mpsc,
+};
+
+pub trait InputSource: Send + 'static {
+    /// The next message, still as JSON, or `None` once the source is
+    /// exhausted.
+    fn next_message(&mut self) -> Option<anyhow::Result<String>>;
+}
+
+/// Concatenated (usually newline-delimited) JSON messages read from `R`.
+pub struct JsonStream<R: Read> {
+    inputs: StreamDeserializer<'static, IoRead<R>, Box<RawValue>>,
+}
+
+impl<R: Read> JsonStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            inputs: serde_json::Deserializer::from_reader(reader).into_iter(),
+        }
+    }
+}
+
+impl<R: Read + Send + 'static> InputSource for JsonStream<R> {
+    fn next_message(&mut self) -> Option<anyhow::Result<String>> {
+        let input = self.inputs.next()?;
+        Some(
+            input
+                .map(|raw| Box::<str>::from(raw).into())
+                .context("Maelstrom input could not be deserialized"),
+        )
+    }
+}
+
+/// The process's stdin, as Maelstrom drives it.
+pub fn stdin() -> JsonStream<BufReader<Stdin>> {
+    JsonStream::new(BufReader::new(std::io::stdin()))
+}
+
+/// A fixed list of messages, delivered in order.
+#[derive(Debug, Clone, Default)]
+pub struct Messages(VecDeque<String>);
+
+impl Messages {
+    pub fn new<P: Serialize>(msgs: impl IntoIterator<Item = Message<P>>) -> anyhow::Result<Self> {
+        msgs.into_iter()
+            .map(|msg| serde_json::to_string(&msg).context("serialize message"))
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+impl InputSource for Messages {
+    fn next_message(&mut self) -> Option<anyhow::Result<String>> {
+        self.0.pop_front().map(Ok)
+    }
+}
+
+/// Messages sent from another thread; exhausted once every sender is gone.
+impl InputSource for mpsc::Receiver<String> {
+    fn next_message(&mut self) -> Option<anyhow::Result<String>> {
+        self.recv().ok().map(Ok)
+    }
+}
@@ -0,0 +1,294 @@
+//! The Kafka-style replicated log workload (Gossip Glomers challenge 5).
+
+use crate::{
+    services::{is_error_code, KvClient, LIN_KV},
+    ErrorCode, Membership, Message, MsgIdAllocator, Node, Payload, Rpc, RpcError,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+
+/// Offsets keyed by log name.
+pub type Offsets = HashMap<String, usize>;
+
+/// `[offset, msg]` pairs keyed by log name, as returned by `poll_ok`.
+pub type Records = HashMap<String, Vec<(usize, i64)>>;
+
+/// How many records `poll` returns per log at most.
+const POLL_LIMIT: usize = 100;
+
+/// Append-only logs plus the offsets clients have committed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Logs {
+    logs: HashMap<String, Vec<i64>>,
+    committed: Offsets,
+}
+
+impl Logs {
+    /// Appends `msg` to `key`'s log and returns its offset.
+    pub fn append(&mut self, key: &str, msg: i64) -> usize {
+        let log = self.logs.entry(key.to_string()).or_default();
+        log.push(msg);
+        log.len() - 1
+    }
+
+    /// Records starting at each requested offset.
+    pub fn poll(&self, offsets: &Offsets) -> Records {
+        offsets
+            .iter()
+            .filter_map(|(key, &from)| {
+                let log = self.logs.get(key)?;
+                let records = log
+                    .iter()
+                    .enumerate()
+                    .skip(from)
+                    .take(POLL_LIMIT)
+                    .map(|(offset, &msg)| (offset, msg))
+                    .collect();
+                Some((key.clone(), records))
+            })
+            .collect()
+    }
+
+    /// Commits offsets, never moving a committed offset backwards.
+    pub fn commit(&mut self, offsets: &Offsets) {
+        for (key, &offset) in offsets {
+            let committed = self.committed.entry(key.clone()).or_default();
+            *committed = (*committed).max(offset);
+        }
+    }
+
+    pub fn committed(&self, keys: &[String]) -> Offsets {
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), *self.committed.get(key)?)))
+            .collect()
+    }
+}
+
+/// Logs kept in Maelstrom's `lin-kv` service so that every node sees the
+/// same offsets.
+///
+/// Records live under `entry/<key>/<offset>`, and an offset is taken by
+/// creating its entry with CAS, so it is never handed out without its
+/// record: a failed append leaves nothing behind for the next one to trip
+/// over. `next/<key>` only hints at where the free offsets start, and is
+/// raised after each append. Committed offsets live under
+/// `committed/<key>`. Records never change once written, so they are cached
+/// locally after the first read.
+#[derive(Debug)]
+pub struct LinKvLogs {
+    kv: KvClient,
+    cache: HashMap<String, BTreeMap<usize, i64>>,
+}
+
+impl LinKvLogs {
+    pub fn new(kv: KvClient) -> Self {
+        Self {
+            kv,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn append(&mut self, key: &str, msg: i64, out: &mut impl Write) -> anyhow::Result<usize> {
+        let mut offset = match self.kv.read(format!("next/{key}").into(), out) {
+            Ok(next) => next.as_u64().context("next offset is not a number")? as usize,
+            Err(e) if is_error_code(&e, ErrorCode::KeyDoesNotExist) => 0,
+            Err(e) => return Err(e),
+        };
+        // Records are numbers, so `null` is never there to compare with:
+        // the CAS only succeeds by creating the entry.
+        loop {
+            let entry = format!("entry/{key}/{offset}");
+            match self
+                .kv
+                .cas(entry.into(), Value::Null, msg.into(), true, out)
+            {
+                Ok(()) => break,
+                Err(e) if is_error_code(&e, ErrorCode::PreconditionFailed) => offset += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        self.cache
+            .entry(key.to_string())
+            .or_default()
+            .insert(offset, msg);
+        // The record is in; a hint left behind only costs later appends a
+        // few more tries.
+        let raised = self.kv.update(
+            format!("next/{key}").into(),
+            Value::from(0),
+            |next| {
+                let next = next.as_u64().context("next offset is not a number")?;
+                Ok(next.max(offset as u64 + 1).into())
+            },
+            out,
+        );
+        if let Err(e) = raised {
+            crate::warn!("raising next/{key} past {offset}: {e}");
+        }
+        Ok(offset)
+    }
+
+    /// Records starting at each requested offset. Entries are only ever
+    /// created at the lowest free offset, so a log's records stop at the
+    /// first missing one.
+    pub fn poll(&mut self, offsets: &Offsets, out: &mut impl Write) -> anyhow::Result<Records> {
+        let mut records = Records::new();
+        for (key, &from) in offsets {
+            let mut found = Vec::new();
+            for offset in from..from.saturating_add(POLL_LIMIT) {
+                match self.record(key, offset, out)? {
+                    Some(msg) => found.push((offset, msg)),
+                    None => break,
+                }
+            }
+            if !found.is_empty() {
+                records.insert(key.clone(), found);
+            }
+        }
+        Ok(records)
+    }
+
+    fn record(
+        &mut self,
+        key: &str,
+        offset: usize,
+        out: &mut impl Write,
+    ) -> anyhow::Result<Option<i64>> {
+        if let Some(msg) = self.cache.get(key).and_then(|log| log.get(&offset)) {
+            return Ok(Some(*msg));
+        }
+        let msg = match self.kv.read(format!("entry/{key}/{offset}").into(), out) {
+            Ok(msg) => msg.as_i64().context("record is not a number")?,
+            Err(e) if is_error_code(&e, ErrorCode::KeyDoesNotExist) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.cache
+            .entry(key.to_string())
+            .or_default()
+            .insert(offset, msg);
+        Ok(Some(msg))
+    }
+
+    /// Commits offsets, never moving a committed offset backwards.
+    pub fn commit(&mut self, offsets: &Offsets, out: &mut impl Write) -> anyhow::Result<()> {
+        for (key, &offset) in offsets {
+            self.kv.update(
+                format!("committed/{key}").into(),
+                Value::from(0),
+                |committed| {
+                    let committed = committed.as_u64().context("offset is not a number")?;
+                    Ok(committed.max(offset as u64).into())
+                },
+                out,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn committed(&self, keys: &[String], out: &mut impl Write) -> anyhow::Result<Offsets> {
+        let mut offsets = Offsets::new();
+        for key in keys {
+            match self.kv.read(format!("committed/{key}").into(), out) {
+                Ok(offset) => {
+                    let offset = offset.as_u64().context("offset is not a number")?;
+                    offsets.insert(key.clone(), offset as usize);
+                }
+                Err(e) if is_error_code(&e, ErrorCode::KeyDoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+/// Serves the `kafka` workload, either from this node's memory (single
+/// node only) or replicated through `lin-kv`.
+#[derive(Debug)]
+pub struct KafkaNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub logs: Logs,
+    rpc: Rpc,
+    replicated: bool,
+    lin_kv: Option<LinKvLogs>,
+}
+
+impl Default for KafkaNode {
+    fn default() -> Self {
+        let msg_ids = MsgIdAllocator::new();
+        Self {
+            membership: Membership::default(),
+            rpc: Rpc::new(msg_ids.clone()),
+            msg_ids,
+            logs: Logs::default(),
+            replicated: false,
+            lin_kv: None,
+        }
+    }
+}
+
+impl KafkaNode {
+    /// A node that keeps its logs in `lin-kv`, for multi-node clusters.
+    pub fn replicated() -> Self {
+        Self {
+            replicated: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Node for KafkaNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                if self.replicated {
+                    let kv = KvClient::new(LIN_KV, node_id, self.rpc.clone());
+                    self.lin_kv = Some(LinKvLogs::new(kv));
+                }
+                Payload::InitOk
+            }
+            Payload::Send { key, msg } => Payload::SendOk {
+                offset: match &mut self.lin_kv {
+                    Some(lin_kv) => lin_kv.append(key, *msg, output)?,
+                    None => self.logs.append(key, *msg),
+                },
+            },
+            Payload::Poll { offsets } => Payload::PollOk {
+                msgs: match &mut self.lin_kv {
+                    Some(lin_kv) => lin_kv.poll(offsets, output)?,
+                    None => self.logs.poll(offsets),
+                },
+            },
+            Payload::CommitOffsets { offsets } => {
+                match &mut self.lin_kv {
+                    Some(lin_kv) => lin_kv.commit(offsets, output)?,
+                    None => self.logs.commit(offsets),
+                }
+                Payload::CommitOffsetsOk
+            }
+            Payload::ListCommittedOffsets { keys } => Payload::ListCommittedOffsetsOk {
+                offsets: match &self.lin_kv {
+                    Some(lin_kv) => lin_kv.committed(keys, output)?,
+                    None => self.logs.committed(keys),
+                },
+            },
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(RpcError::not_supported("kafka node cannot handle this message").into())
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+}
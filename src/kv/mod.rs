@@ -0,0 +1,112 @@
+//! A key-value store speaking the same protocol as Maelstrom's KV services,
+//! for serving the `lin-kv` workload, on its own or replicated with
+//! [`RaftNode`](crate::RaftNode), or [sharded] over the cluster.
+
+pub mod quorum;
+pub mod sharded;
+
+pub use quorum::QuorumKvNode;
+pub use sharded::ShardedKvNode;
+
+use crate::{payload::ReadValue, Membership, Message, MsgIdAllocator, Node, Payload, RpcError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, io::Write};
+
+/// Values keyed by the JSON encoding of their key, since keys may be any
+/// JSON value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KvStore {
+    entries: HashMap<String, Value>,
+}
+
+impl KvStore {
+    pub fn read(&self, key: &Value) -> Result<Value, RpcError> {
+        self.entries
+            .get(&key.to_string())
+            .cloned()
+            .ok_or_else(|| RpcError::key_does_not_exist(format!("key {key} does not exist")))
+    }
+
+    pub fn write(&mut self, key: &Value, value: Value) {
+        self.entries.insert(key.to_string(), value);
+    }
+
+    pub fn cas(
+        &mut self,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), RpcError> {
+        match self.entries.get_mut(&key.to_string()) {
+            Some(current) if current == from => *current = to,
+            Some(current) => {
+                return Err(RpcError::precondition_failed(format!(
+                    "expected {from}, but had {current}"
+                )))
+            }
+            None if create_if_not_exists => self.write(key, to),
+            None => {
+                return Err(RpcError::key_does_not_exist(format!(
+                    "key {key} does not exist"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a `read`, `write` or `cas` request and returns the reply.
+    pub fn apply(&mut self, payload: &Payload) -> Result<Payload, RpcError> {
+        Ok(match payload {
+            Payload::Read { key: Some(key), .. } => Payload::ReadOk {
+                value: ReadValue::Value {
+                    value: self.read(key)?,
+                },
+            },
+            Payload::Write { key, value, .. } => {
+                self.write(key, value.clone());
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                self.cas(key, from, to.clone(), *create_if_not_exists)?;
+                Payload::CasOk
+            }
+            _ => return Err(RpcError::not_supported("not a key-value operation")),
+        })
+    }
+}
+
+/// Serves the `lin-kv` workload from a single node's memory, which is only
+/// linearizable with a cluster of one.
+#[derive(Debug, Default)]
+pub struct KvNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub store: KvStore,
+}
+
+impl Node for KvNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                Payload::InitOk
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            payload => self.store.apply(payload)?,
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,845 @@
+//! Dynamo-style quorum replication.
+//!
+//! Every key is stored on `n` replicas, the first `n` nodes for it along a
+//! [`HashRing`] of the cluster, with a version from the [`HybridClock`] of
+//! the node that wrote it. Whichever node a client asks coordinates the
+//! request: a `write` goes to every replica and succeeds once `w` of them
+//! stored it, and a `read` asks every replica and answers with the newest
+//! version among the first `r` to reply. With `r + w > n` every read hears
+//! from at least one replica that has the latest acknowledged write. A
+//! `read` or `write` can ask for a different [`Consistency`] than the
+//! configured one.
+//!
+//! A read that finds replicas behind the newest version it saw writes that
+//! version back to them (read repair), without holding up the answer to
+//! the client. That includes replicas that answer after the client was
+//! answered, for as long as the read would have waited for them, so
+//! frequently read keys converge even if some replicas missed writes.
+//!
+//! In the background, every replica periodically compares a
+//! [`MerkleTree`] of the keys it shares with one of its peers against the
+//! peer's (anti-entropy). They walk down from the roots to the leaves that
+//! differ and exchange only the entries under those, so replicas catch up
+//! on keys nobody reads, after a long partition, for a few messages more
+//! than the entries they missed.
+//!
+//! With hinted handoff, a write that a replica hasn't acknowledged after
+//! a while is kept by the coordinator as a hint for that replica and
+//! counted as acknowledged by it (a sloppy quorum), so writes succeed even
+//! while replicas are cut off from the coordinator. The coordinator keeps offering the
+//! replica its hints until it takes them. Until then the write may be on
+//! fewer than `w` replicas, and reads can miss it.
+//!
+//! A `cas` reads as above, compares, and then writes its new value with a
+//! later version. It is not atomic: two concurrent `cas` on the same key
+//! may both succeed, and the later version wins.
+
+use crate::{
+    clock::{HlcTimestamp, HybridClock},
+    payload::ReadValue,
+    services::Versioned,
+    time, Config, ErrorCode, HashRing, Membership, MerkleTree, Message, MsgIdAllocator, Node,
+    Payload, Rpc, RpcCall, RpcError,
+};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::Write,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// How long a coordinator waits for enough replicas to answer before
+/// giving up with a `timeout` error.
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a write waits for a replica before leaving it a hint instead,
+/// with hinted handoff.
+const HINT_AFTER: Duration = Duration::from_millis(200);
+
+/// How long to wait for a replica to take its hints before offering them
+/// again.
+const HANDOFF_RETRY: Duration = Duration::from_millis(500);
+
+/// How many replicas a `read` or `write` waits for, instead of the
+/// configured `r` or `w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    One,
+    /// A majority of the replicas.
+    Quorum,
+    All,
+}
+
+impl Consistency {
+    /// How many of `n` replicas this is.
+    pub fn of(self, n: usize) -> usize {
+        match self {
+            Consistency::One => 1,
+            Consistency::Quorum => n / 2 + 1,
+            Consistency::All => n,
+        }
+    }
+}
+
+/// How many replicas each key has, and how many of them writes and reads
+/// wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuorumConfig {
+    /// `n`.
+    pub replicas: usize,
+    /// `w`.
+    pub write: usize,
+    /// `r`.
+    pub read: usize,
+}
+
+impl Default for QuorumConfig {
+    fn default() -> Self {
+        Self {
+            replicas: 3,
+            write: 2,
+            read: 2,
+        }
+    }
+}
+
+impl FromStr for QuorumConfig {
+    type Err = anyhow::Error;
+
+    /// Parses `n,w,r`, such as `3,2,2`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("quorum {s}"))?;
+        let &[replicas, write, read] = numbers.as_slice() else {
+            bail!("quorum {s} is not n,w,r");
+        };
+        if replicas == 0 || !(1..=replicas).contains(&write) || !(1..=replicas).contains(&read) {
+            bail!("quorum {s} needs 1 <= w, r <= n");
+        }
+        Ok(Self {
+            replicas,
+            write,
+            read,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Kind {
+    Read,
+    Write,
+    Cas {
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+}
+
+/// Where a request stands: waiting for enough replicas to answer a read,
+/// or to acknowledge a write.
+#[derive(Debug)]
+enum Phase {
+    /// What each replica that answered has.
+    Reading(Vec<(String, Option<Versioned<Value>>)>),
+    Writing {
+        acks: usize,
+        versioned: Versioned<Value>,
+    },
+}
+
+impl Phase {
+    /// Counts `replica`'s answer towards the phase.
+    fn record(&mut self, replica: &str, answer: Payload) {
+        match (self, answer) {
+            (Phase::Reading(answers), Payload::ReplicaReadOk { value }) => {
+                answers.push((replica.to_string(), value))
+            }
+            (Phase::Writing { acks, .. }, Payload::ReplicaWriteOk) => *acks += 1,
+            (_, other) => crate::warn!("unexpected answer from replica: {other:?}"),
+        }
+    }
+
+    fn answered(&self) -> usize {
+        match self {
+            Phase::Reading(answers) => answers.len(),
+            Phase::Writing { acks, .. } => *acks,
+        }
+    }
+}
+
+/// A client request this node is coordinating.
+#[derive(Debug)]
+struct Request {
+    client: Message<()>,
+    key: Value,
+    kind: Kind,
+    phase: Phase,
+    /// How many replicas have to answer this phase.
+    needed: usize,
+    /// The replicas yet to answer.
+    calls: Vec<(String, RpcCall)>,
+    started: Instant,
+    phase_started: Instant,
+}
+
+/// A replica that hadn't answered a read by the time it was done with,
+/// to repair if it turns out to be behind `newest`.
+#[derive(Debug)]
+struct Straggler {
+    replica: String,
+    key: Value,
+    newest: Versioned<Value>,
+    call: RpcCall,
+    started: Instant,
+}
+
+/// Writes held for a replica that didn't acknowledge them.
+#[derive(Debug, Default)]
+struct Hints {
+    entries: Vec<(Value, Versioned<Value>)>,
+    /// The handoff in flight: how many of the entries it carries, and when
+    /// it was sent.
+    handoff: Option<(usize, RpcCall, Instant)>,
+}
+
+/// Serves the KV workloads from `n` replicas per key, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct QuorumKvNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    config: QuorumConfig,
+    rpc: Rpc,
+    clock: HybridClock,
+    ring: HashRing,
+    /// The keys this node is a replica of, by their JSON encoding.
+    store: HashMap<String, Versioned<Value>>,
+    requests: Vec<Request>,
+    stragglers: Vec<Straggler>,
+    /// How often to start a round of anti-entropy, if at all.
+    anti_entropy: Option<Duration>,
+    last_sync: Instant,
+    /// The index among our peers of the one to sync with next.
+    next_peer: usize,
+    hinted_handoff: bool,
+    /// By the replica they are for.
+    hints: HashMap<String, Hints>,
+}
+
+impl Default for QuorumKvNode {
+    fn default() -> Self {
+        Self::new(QuorumConfig::default())
+    }
+}
+
+impl QuorumKvNode {
+    pub fn new(config: QuorumConfig) -> Self {
+        let msg_ids = MsgIdAllocator::new();
+        Self {
+            membership: Membership::default(),
+            rpc: Rpc::new(msg_ids.clone()),
+            msg_ids,
+            config,
+            clock: HybridClock::new(),
+            ring: HashRing::default(),
+            store: HashMap::new(),
+            requests: Vec::new(),
+            stragglers: Vec::new(),
+            anti_entropy: None,
+            last_sync: time::now(),
+            next_peer: 0,
+            hinted_handoff: false,
+            hints: HashMap::new(),
+        }
+    }
+
+    /// A node set up as `config` says: with its quorum, or the default one,
+    /// and anti-entropy.
+    pub fn from_config(config: &Config) -> Self {
+        let mut node = Self::new(config.kv_quorum.unwrap_or_default());
+        if let Some(interval) = config.kv_anti_entropy {
+            node = node.with_anti_entropy(interval);
+        }
+        if config.kv_hinted_handoff {
+            node = node.with_hinted_handoff();
+        }
+        node
+    }
+
+    /// Syncs with a peer every `interval`, going round them in turn.
+    pub fn with_anti_entropy(mut self, interval: Duration) -> Self {
+        self.anti_entropy = Some(interval);
+        self
+    }
+
+    /// Leaves hints for replicas that are slow to acknowledge writes.
+    pub fn with_hinted_handoff(mut self) -> Self {
+        self.hinted_handoff = true;
+        self
+    }
+
+    /// How many writes this node holds as hints for other replicas.
+    pub fn hinted(&self) -> usize {
+        self.hints.values().map(|hints| hints.entries.len()).sum()
+    }
+
+    /// The nodes that store `key`, fewer than `n` in a smaller cluster.
+    pub fn replicas_of(&self, key: &Value) -> Vec<&str> {
+        self.ring.owners_of(&key.to_string(), self.config.replicas)
+    }
+
+    /// This node's copy of `key`, if it is a replica and has one.
+    pub fn get(&self, key: &Value) -> Option<&Versioned<Value>> {
+        self.store.get(&key.to_string())
+    }
+
+    /// Stores `versioned` unless this replica already has a later version.
+    /// Equal versions are broken by the value, so that replicas agree.
+    fn put(&mut self, key: &Value, versioned: Versioned<Value>) {
+        self.clock
+            .observe(HlcTimestamp::from_u64(versioned.version));
+        let newest = self
+            .store
+            .entry(key.to_string())
+            .or_insert(versioned.clone());
+        if later(&versioned, newest) {
+            *newest = versioned;
+        }
+    }
+
+    /// Starts a phase of `request`: asks every replica to read or store
+    /// `key`, answering for this node straight away if it is one.
+    fn start(
+        &mut self,
+        mut request: Request,
+        write: Option<Versioned<Value>>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let replicas: Vec<String> = self
+            .replicas_of(&request.key)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        request.needed = request.needed.min(replicas.len());
+        request.calls.clear();
+        request.phase = match &write {
+            Some(versioned) => Phase::Writing {
+                acks: 0,
+                versioned: versioned.clone(),
+            },
+            None => Phase::Reading(Vec::new()),
+        };
+        request.phase_started = time::now();
+        let payload = match write {
+            Some(versioned) => Payload::ReplicaWrite {
+                key: request.key.clone(),
+                value: versioned.value,
+                version: versioned.version,
+            },
+            None => Payload::ReplicaRead {
+                key: request.key.clone(),
+            },
+        };
+        for replica in replicas {
+            if replica == self.membership.node_id {
+                let answer = self.replicate(&payload).expect("a replica request");
+                request.phase.record(&replica, answer);
+                continue;
+            }
+            let call = self
+                .rpc
+                .call(&self.membership.node_id, &replica, payload.clone(), out)?;
+            request.calls.push((replica, call));
+        }
+        self.advance(request, out)
+    }
+
+    /// Serves `payload` as a replica, if it is a `replica_read` or
+    /// `replica_write`.
+    fn replicate(&mut self, payload: &Payload) -> Option<Payload> {
+        Some(match payload {
+            Payload::ReplicaRead { key } => Payload::ReplicaReadOk {
+                value: self.get(key).cloned(),
+            },
+            Payload::ReplicaWrite {
+                key,
+                value,
+                version,
+            } => {
+                let versioned = Versioned {
+                    version: *version,
+                    value: value.clone(),
+                };
+                self.put(key, versioned);
+                Payload::ReplicaWriteOk
+            }
+            Payload::Handoff { entries } => {
+                for (key, versioned) in entries {
+                    self.put(key, versioned.clone());
+                }
+                Payload::HandoffOk
+            }
+            _ => return None,
+        })
+    }
+
+    /// Takes in the replicas' answers to `request` that have come in, and
+    /// moves it on once there are enough, or it took too long.
+    fn advance(&mut self, mut request: Request, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut i = 0;
+        while i < request.calls.len() {
+            let Some(reply) = request.calls[i].1.wait_timeout(Duration::ZERO) else {
+                i += 1;
+                continue;
+            };
+            let (replica, _) = request.calls.swap_remove(i);
+            request.phase.record(&replica, reply.body.payload);
+        }
+        if self.hinted_handoff && time::elapsed(request.phase_started) >= HINT_AFTER {
+            self.hint(&mut request);
+        }
+        let answered = request.phase.answered();
+        if answered >= request.needed {
+            return self.complete(request, out);
+        }
+        if time::elapsed(request.started) >= QUORUM_TIMEOUT {
+            let text = format!("{answered} of {} replicas answered", request.needed);
+            let err = RpcError::new(ErrorCode::Timeout, text);
+            let msg_id = Some(self.msg_ids.next());
+            return request
+                .client
+                .into_error(&self.membership.node_id, msg_id, err)
+                .send(out);
+        }
+        self.requests.push(request);
+        Ok(())
+    }
+
+    /// Takes the write of `request` for the replicas yet to acknowledge it,
+    /// as hints, acknowledging it for them.
+    fn hint(&mut self, request: &mut Request) {
+        let Phase::Writing { acks, versioned } = &mut request.phase else {
+            return;
+        };
+        for (replica, _) in request.calls.drain(..) {
+            crate::debug!("keeping {} as a hint for {replica}", request.key);
+            let hints = self.hints.entry(replica).or_default();
+            hints.entries.push((request.key.clone(), versioned.clone()));
+            *acks += 1;
+        }
+    }
+
+    /// Offers each replica its hints, one handoff at a time, and forgets
+    /// those it took.
+    fn hand_off(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        for (replica, hints) in &mut self.hints {
+            if let Some((count, call, sent_at)) = &hints.handoff {
+                match call.wait_timeout(Duration::ZERO) {
+                    Some(reply) if reply.body.payload == Payload::HandoffOk => {
+                        hints.entries.drain(..*count);
+                    }
+                    _ if time::elapsed(*sent_at) < HANDOFF_RETRY => continue,
+                    _ => {}
+                }
+                hints.handoff = None;
+            }
+            if hints.entries.is_empty() {
+                continue;
+            }
+            let payload = Payload::Handoff {
+                entries: hints.entries.clone(),
+            };
+            let call = self
+                .rpc
+                .call(&self.membership.node_id, replica, payload, out)?;
+            hints.handoff = Some((hints.entries.len(), call, time::now()));
+        }
+        self.hints.retain(|_, hints| !hints.entries.is_empty());
+        Ok(())
+    }
+
+    /// Finishes a phase of `request` that enough replicas answered.
+    fn complete(&mut self, mut request: Request, out: &mut impl Write) -> anyhow::Result<()> {
+        let newest = match &mut request.phase {
+            Phase::Writing { .. } => {
+                let payload = match request.kind {
+                    Kind::Cas { .. } => Payload::CasOk,
+                    _ => Payload::WriteOk,
+                };
+                return request
+                    .client
+                    .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+                    .send(out);
+            }
+            Phase::Reading(answers) => {
+                let answers = std::mem::take(answers);
+                let newest = answers
+                    .iter()
+                    .filter_map(|(_, answer)| answer.as_ref())
+                    .reduce(|a, b| if later(b, a) { b } else { a })
+                    .cloned();
+                if let Some(newest) = &newest {
+                    let late = std::mem::take(&mut request.calls);
+                    self.repair(&request.key, newest, answers, late, out)?;
+                }
+                newest
+            }
+        };
+        let key = &request.key;
+        let result = match (&request.kind, newest) {
+            (Kind::Read, Some(newest)) => Ok(Payload::ReadOk {
+                value: ReadValue::Value {
+                    value: newest.value,
+                },
+            }),
+            (Kind::Cas { from, to, .. }, Some(newest)) if newest.value == *from => {
+                let version = self.clock.observe(HlcTimestamp::from_u64(newest.version));
+                let write = Versioned {
+                    version: version.to_u64(),
+                    value: to.clone(),
+                };
+                request.needed = self.config.write;
+                return self.start(request, Some(write), out);
+            }
+            (Kind::Cas { from, .. }, Some(newest)) => Err(RpcError::precondition_failed(format!(
+                "expected {from}, but had {}",
+                newest.value
+            ))),
+            (
+                Kind::Cas {
+                    to,
+                    create_if_not_exists: true,
+                    ..
+                },
+                None,
+            ) => {
+                let write = Versioned::stamped(&self.clock, to.clone());
+                request.needed = self.config.write;
+                return self.start(request, Some(write), out);
+            }
+            (_, None) => Err(RpcError::key_does_not_exist(format!(
+                "key {key} does not exist"
+            ))),
+            (Kind::Write, Some(_)) => unreachable!("writes don't read"),
+        };
+        let msg_id = Some(self.msg_ids.next());
+        match result {
+            Ok(payload) => request
+                .client
+                .into_reply(&self.membership.node_id, msg_id, payload)
+                .send(out),
+            Err(err) => request
+                .client
+                .into_error(&self.membership.node_id, msg_id, err)
+                .send(out),
+        }
+    }
+
+    /// Writes `newest` back to the replicas whose `answers` are behind it,
+    /// and keeps an eye on the `late` ones.
+    fn repair(
+        &mut self,
+        key: &Value,
+        newest: &Versioned<Value>,
+        answers: Vec<(String, Option<Versioned<Value>>)>,
+        late: Vec<(String, RpcCall)>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        for (replica, answer) in answers {
+            if behind(answer.as_ref(), newest) {
+                self.repair_replica(&replica, key, newest, out)?;
+            }
+        }
+        let now = time::now();
+        self.stragglers
+            .extend(late.into_iter().map(|(replica, call)| Straggler {
+                replica,
+                key: key.clone(),
+                newest: newest.clone(),
+                call,
+                started: now,
+            }));
+        Ok(())
+    }
+
+    fn repair_replica(
+        &mut self,
+        replica: &str,
+        key: &Value,
+        newest: &Versioned<Value>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        crate::debug!("repairing {key} on {replica}");
+        if replica == self.membership.node_id {
+            self.put(key, newest.clone());
+            return Ok(());
+        }
+        let payload = Payload::ReplicaWrite {
+            key: key.clone(),
+            value: newest.value.clone(),
+            version: newest.version,
+        };
+        // Nobody waits for the answer; a repair that gets lost is made
+        // again by the next read.
+        self.send(replica, payload, out)
+    }
+
+    fn send(&self, dest: &str, payload: Payload, out: &mut impl Write) -> anyhow::Result<()> {
+        let msg_id = Some(self.msg_ids.next());
+        Message::new(&self.membership.node_id, dest, msg_id, payload).send(out)
+    }
+
+    /// Repairs the stragglers that answered with an older version.
+    fn check_stragglers(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        for straggler in std::mem::take(&mut self.stragglers) {
+            let Some(reply) = straggler.call.wait_timeout(Duration::ZERO) else {
+                if time::elapsed(straggler.started) < QUORUM_TIMEOUT {
+                    self.stragglers.push(straggler);
+                }
+                continue;
+            };
+            let Payload::ReplicaReadOk { value } = reply.body.payload else {
+                continue;
+            };
+            let Straggler {
+                replica,
+                key,
+                newest,
+                ..
+            } = &straggler;
+            if behind(value.as_ref(), newest) {
+                self.repair_replica(replica, key, newest, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// This node's entries for the keys `peer` is a replica of too.
+    fn shared_with<'a>(
+        &'a self,
+        peer: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a Versioned<Value>)> {
+        self.store.iter().filter(move |(key, _)| {
+            let replicas = self.ring.owners_of(key.as_str(), self.config.replicas);
+            replicas.contains(&peer)
+        })
+    }
+
+    /// The tree of the entries `peer` should have too.
+    fn tree_with(&self, peer: &str) -> MerkleTree {
+        let mut tree = MerkleTree::default();
+        for (key, versioned) in self.shared_with(peer) {
+            tree.insert(key, &(versioned.version, versioned.value.to_string()));
+        }
+        tree
+    }
+
+    /// Starts a round of anti-entropy with the next peer, if one is due.
+    fn sync(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let Some(interval) = self.anti_entropy else {
+            return Ok(());
+        };
+        if time::elapsed(self.last_sync) < interval {
+            return Ok(());
+        }
+        self.last_sync = time::now();
+        let peers: Vec<&String> = self.membership.peers().collect();
+        if peers.is_empty() {
+            return Ok(());
+        }
+        let peer = peers[self.next_peer % peers.len()].clone();
+        self.next_peer = self.next_peer.wrapping_add(1);
+        let root = self.tree_with(&peer).root();
+        let nodes = vec![(0, root)];
+        self.send(&peer, Payload::MerkleDigest { nodes }, out)
+    }
+
+    /// Answers the `nodes` of `peer`'s tree that differ from ours: with our
+    /// children of them, and our entries under those that are leaves.
+    fn compare(
+        &mut self,
+        peer: &str,
+        nodes: &[(usize, u64)],
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let tree = self.tree_with(peer);
+        let (leaves, inner): (Vec<usize>, Vec<usize>) = tree
+            .diff(nodes)
+            .into_iter()
+            .partition(|&node| tree.is_leaf(node));
+        let children: Vec<(usize, u64)> = inner
+            .into_iter()
+            .filter_map(|node| tree.children(node))
+            .flatten()
+            .map(|child| (child, tree.get(child).expect("a child of the tree")))
+            .collect();
+        if !children.is_empty() {
+            self.send(peer, Payload::MerkleDigest { nodes: children }, out)?;
+        }
+        if !leaves.is_empty() {
+            let entries = self
+                .shared_with(peer)
+                .filter(|(key, _)| leaves.contains(&tree.leaf_of(*key)))
+                .map(|(key, versioned)| (decode(key), versioned.clone()))
+                .collect();
+            let nodes = leaves;
+            self.send(peer, Payload::MerkleKeys { nodes, entries }, out)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the `entries` `peer` sent that are newer than ours, and sends
+    /// back ours under the leaves `nodes` that `peer` is behind on.
+    fn merge(
+        &mut self,
+        peer: &str,
+        nodes: &[usize],
+        entries: &[(Value, Versioned<Value>)],
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        for (key, versioned) in entries {
+            if self
+                .replicas_of(key)
+                .contains(&self.membership.node_id.as_str())
+            {
+                self.put(key, versioned.clone());
+            }
+        }
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        let theirs: HashMap<String, &Versioned<Value>> = entries
+            .iter()
+            .map(|(key, versioned)| (key.to_string(), versioned))
+            .collect();
+        let tree = MerkleTree::default();
+        let entries: Vec<_> = self
+            .shared_with(peer)
+            .filter(|(key, _)| nodes.contains(&tree.leaf_of(*key)))
+            .filter(|(key, ours)| behind(theirs.get(*key).copied(), ours))
+            .map(|(key, versioned)| (decode(key), versioned.clone()))
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let nodes = Vec::new();
+        self.send(peer, Payload::MerkleKeys { nodes, entries }, out)
+    }
+
+    /// Starts coordinating a client's request.
+    fn coordinate(&mut self, input: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let client = input.header();
+        let (key, kind, needed, write) = match input.body.payload {
+            Payload::Read {
+                key: Some(key),
+                consistency,
+            } => {
+                let needed = consistency.map_or(self.config.read, |c| c.of(self.config.replicas));
+                (key, Kind::Read, needed, None)
+            }
+            Payload::Write {
+                key,
+                value,
+                consistency,
+            } => {
+                let needed = consistency.map_or(self.config.write, |c| c.of(self.config.replicas));
+                let write = Versioned::stamped(&self.clock, value);
+                (key, Kind::Write, needed, Some(write))
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let kind = Kind::Cas {
+                    from,
+                    to,
+                    create_if_not_exists,
+                };
+                (key, kind, self.config.read, None)
+            }
+            _ => return Err(RpcError::not_supported("not a key-value operation").into()),
+        };
+        let request = Request {
+            client,
+            key,
+            kind,
+            phase: Phase::Reading(Vec::new()),
+            needed,
+            calls: Vec::new(),
+            started: time::now(),
+            phase_started: time::now(),
+        };
+        self.start(request, write, out)
+    }
+}
+
+/// A key as stored, back from its JSON encoding.
+fn decode(key: &str) -> Value {
+    serde_json::from_str(key).expect("keys are stored as JSON")
+}
+
+/// Whether a replica that has `answer` is missing `newest`.
+fn behind(answer: Option<&Versioned<Value>>, newest: &Versioned<Value>) -> bool {
+    answer.is_none_or(|answer| later(newest, answer))
+}
+
+/// Whether `a` supersedes `b`.
+fn later(a: &Versioned<Value>, b: &Versioned<Value>) -> bool {
+    (a.version, a.value.to_string()) > (b.version, b.value.to_string())
+}
+
+impl Node for QuorumKvNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        if let Some(answer) = self.replicate(&input.body.payload) {
+            return input
+                .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), answer)
+                .send(output);
+        }
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                self.ring = HashRing::new(node_ids);
+                Payload::InitOk
+            }
+            Payload::MerkleDigest { nodes } => return self.compare(&input.src, nodes, output),
+            Payload::MerkleKeys { nodes, entries } => {
+                return self.merge(&input.src, nodes, entries, output)
+            }
+            // Answers from replicas a request stopped waiting for.
+            Payload::ReplicaReadOk { .. } | Payload::ReplicaWriteOk | Payload::HandoffOk => {
+                return Ok(())
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => return self.coordinate(input, output),
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(10))
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        for request in std::mem::take(&mut self.requests) {
+            self.advance(request, out)?;
+        }
+        self.check_stragglers(out)?;
+        self.hand_off(out)?;
+        self.sync(out)
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+}
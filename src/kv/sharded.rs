@@ -0,0 +1,152 @@
+//! Keys spread over the cluster instead of replicated to every node.
+//!
+//! Every key belongs to one node, its owner on a [`HashRing`] of the
+//! cluster, which serves it from its own [`KvStore`]. A node asked about a
+//! key it doesn't own passes the request on to the owner through its
+//! [`Rpc`] and relays the answer, errors included, to the client, so
+//! clients may talk to any node. Nothing is replicated: a key is
+//! unavailable while its owner is unreachable.
+
+use super::KvStore;
+use crate::{
+    time, ErrorCode, HashRing, Membership, Message, MsgIdAllocator, Node, Payload, Rpc, RpcCall,
+    RpcError,
+};
+use serde_json::Value;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for an owner to answer a request passed on to it. The
+/// client is left to time out after that.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client request passed on to the key's owner.
+#[derive(Debug)]
+struct Forwarded {
+    client: Message<()>,
+    sent_at: Instant,
+    call: RpcCall,
+}
+
+/// Serves the `lin-kv` workload with each key kept only by its owner.
+#[derive(Debug)]
+pub struct ShardedKvNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    /// The keys this node owns.
+    pub store: KvStore,
+    rpc: Rpc,
+    ring: HashRing,
+    forwarded: Vec<Forwarded>,
+}
+
+impl Default for ShardedKvNode {
+    fn default() -> Self {
+        let msg_ids = MsgIdAllocator::new();
+        Self {
+            membership: Membership::default(),
+            rpc: Rpc::new(msg_ids.clone()),
+            msg_ids,
+            store: KvStore::default(),
+            ring: HashRing::default(),
+            forwarded: Vec::new(),
+        }
+    }
+}
+
+impl ShardedKvNode {
+    /// The node that owns `key`, hashed by its JSON encoding like the
+    /// store does.
+    pub fn owner_of(&self, key: &Value) -> Option<&str> {
+        self.ring.owner_of(&key.to_string())
+    }
+
+    /// Relays the answers to forwarded requests that have come in, and
+    /// gives up on those that took too long.
+    fn collect_replies(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut i = 0;
+        while i < self.forwarded.len() {
+            let forwarded = &self.forwarded[i];
+            match forwarded.call.wait_timeout(Duration::ZERO) {
+                Some(reply) => {
+                    let Forwarded { client, .. } = self.forwarded.swap_remove(i);
+                    client
+                        .into_reply(
+                            &self.membership.node_id,
+                            Some(self.msg_ids.next()),
+                            reply.body.payload,
+                        )
+                        .send(out)?;
+                }
+                None if time::elapsed(forwarded.sent_at) >= FORWARD_TIMEOUT => {
+                    self.forwarded.swap_remove(i);
+                }
+                None => i += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node for ShardedKvNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let key = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                self.ring = HashRing::new(node_ids);
+                return input
+                    .into_reply(
+                        &self.membership.node_id,
+                        Some(self.msg_ids.next()),
+                        Payload::InitOk,
+                    )
+                    .send(output);
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            Payload::Read { key: Some(key), .. }
+            | Payload::Write { key, .. }
+            | Payload::Cas { key, .. } => key,
+            _ => return Err(RpcError::not_supported("not a key-value operation").into()),
+        };
+        let Some(owner) = self.owner_of(key).map(str::to_string) else {
+            let text = "not initialized yet";
+            return Err(RpcError::new(ErrorCode::TemporarilyUnavailable, text).into());
+        };
+        if owner == self.membership.node_id {
+            let payload = self.store.apply(&input.body.payload)?;
+            return input
+                .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+                .send(output);
+        }
+        // Every node builds the same ring, so a peer only sends us keys we
+        // own.
+        if self.membership.is_peer(&input.src) {
+            let text = format!("{owner} owns {key}, not {}", self.membership.node_id);
+            return Err(RpcError::new(ErrorCode::TemporarilyUnavailable, text).into());
+        }
+        let client = input.header();
+        let call = self
+            .rpc
+            .call(&self.membership.node_id, &owner, input.body.payload, output)?;
+        self.forwarded.push(Forwarded {
+            client,
+            sent_at: time::now(),
+            call,
+        });
+        Ok(())
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(10))
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.collect_replies(out)
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+}
@@ -1,156 +1,906 @@
-use anyhow::{bail, Context};
+use crate::payload::Payload;
+use anyhow::Context;
 use std::{
-    collections::HashMap,
-    io::{StdoutLock, Write},
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
-use crate::payload::Payload;
 
-mod payload;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod bloom;
+pub mod broadcast;
+pub mod causal;
+pub mod clock;
+pub mod compress;
+pub mod config;
+pub mod counter;
+pub mod crdt;
+pub mod dedup;
+pub mod echo;
+pub mod error;
+pub mod fuzz;
+pub mod gzip;
+pub mod handshake;
+pub mod heartbeat;
+pub mod ids;
+pub mod input;
+pub mod kafka;
+pub mod kv;
+pub mod log;
+pub mod merkle;
+pub mod metrics;
+pub mod middleware;
+pub mod msgpack;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod output;
+pub mod paxos;
+pub mod payload;
+pub mod pool;
+pub mod prometheus;
+pub mod proxy;
+pub mod raft;
+pub mod ratelimit;
+pub mod record;
+pub mod retry;
+pub mod ring;
+pub mod router;
+pub mod rpc;
+#[cfg(feature = "async")]
+pub mod runtime;
+pub mod services;
+pub mod signal;
+pub mod sim;
+pub mod socket;
+pub mod storage;
+pub mod swim;
+pub mod tcp;
+pub mod testing;
+pub mod time;
+pub mod timer;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+pub mod txn;
+pub mod udp;
+#[cfg(unix)]
+pub mod unix;
+pub mod validate;
+pub mod wal;
+pub mod ws;
+
+pub use broadcast::{BroadcastMode, BroadcastNode, GossipConfig};
+pub use causal::CausalBroadcastNode;
+pub use config::{CacheLimits, Config, OverloadPolicy, UnknownPolicy};
+pub use counter::CounterNode;
+pub use crdt::{CrdtNode, GSetNode};
+pub use echo::EchoNode;
+pub use error::{ErrorCode, RpcError};
+pub use kafka::KafkaNode;
+pub use kv::{KvNode, QuorumKvNode, ShardedKvNode};
+pub use merkle::MerkleTree;
+pub use paxos::PaxosNode;
+pub use raft::RaftNode;
+pub use retry::{Backoff, RetryQueue};
+pub use ring::HashRing;
+pub use router::Router;
+pub use rpc::{PendingCall, Rpc, RpcCall};
+pub use topology::TopologyStrategy;
+pub use txn::{TwoPhaseTxnNode, TxnNode};
 
-use serde::{Deserialize, Serialize};
-use serde_json;
-use uuid::Uuid;
+use dedup::Deduped;
+use input::InputSource;
+use output::Outbox;
+use proxy::Proxy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use signal::Signal;
+use trace::Span;
+use validate::Validator;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Message {
-    src: String,
-    dest: String,
-    body: Body,
+/// A Maelstrom message. `P` is the type of the body's payload, which
+/// defaults to the built-in [`Payload`]; any internally tagged
+/// (`#[serde(tag = "type")]`) enum works.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message<P = Payload> {
+    pub src: String,
+    pub dest: String,
+    pub body: Body<P>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Body {
-    #[serde(rename = "msg_id")]
-    id: Option<usize>,
-    in_reply_to: Option<usize>,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Body<P = Payload> {
+    #[serde(rename = "msg_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<usize>,
+    /// The span the message was sent from, as a W3C `traceparent`, for
+    /// traces to carry on across nodes; see [`trace`](mod@crate::trace).
+    /// Filled in as the message is sent, unless set already.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
     #[serde(flatten)]
-    payload: Payload,
-}
-
-
-pub struct EchoNode {
-    pub id: usize,
-    pub value: usize,
-    pub known: HashMap<String, Vec<usize>>,
-}
-
-impl EchoNode {
-    pub fn step(&mut self, input: Message, output: &mut StdoutLock) -> anyhow::Result<()> {
-        match input.body.payload {
-            Payload::Add { delta } => {
-                self.value += delta;
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::AddOk,
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to Read")?;
-                output.write_all(b"\n").context("write trailing line")?;
+    pub payload: P,
+}
+
+impl<P> Message<P> {
+    pub fn new(
+        src: impl Into<String>,
+        dest: impl Into<String>,
+        msg_id: Option<usize>,
+        payload: P,
+    ) -> Message<P> {
+        Message {
+            src: src.into(),
+            dest: dest.into(),
+            body: Body {
+                id: msg_id,
+                in_reply_to: None,
+                traceparent: None,
+                payload,
+            },
+        }
+    }
+
+    /// Builds a reply from `src`, normally the node's own id from init, to
+    /// whoever sent this message, with `in_reply_to` set to this message's
+    /// `msg_id`.
+    pub fn into_reply<Q>(&self, src: &str, msg_id: Option<usize>, payload: Q) -> Message<Q> {
+        Message {
+            src: src.to_string(),
+            dest: self.src.clone(),
+            body: Body {
+                id: msg_id,
+                in_reply_to: self.body.id,
+                traceparent: None,
+                payload,
+            },
+        }
+    }
+
+    /// Builds an `error` reply from `src` to this message. Error replies
+    /// always use the built-in [`Payload`], whatever `P` is.
+    pub fn into_error(&self, src: &str, msg_id: Option<usize>, err: RpcError) -> Message {
+        self.into_reply(
+            src,
+            msg_id,
+            Payload::Error {
+                code: err.code,
+                text: err.text,
+            },
+        )
+    }
+
+    /// Just the addressing of this message, for replying after the payload
+    /// has been consumed.
+    pub(crate) fn header(&self) -> Message<()> {
+        Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                traceparent: None,
+                payload: (),
+            },
+        }
+    }
+
+    /// Writes this message as a single line of JSON.
+    pub fn send(&self, out: &mut impl Write) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        let observed = log::enabled(log::Level::Debug) || metrics::enabled();
+        let kind = observed.then(|| trace::payload_kind(&self.body.payload));
+        if let Some(kind) = &kind {
+            log::message("send", &self.src, &self.dest, kind, self.body.id);
+        }
+        let mut out = metrics::Counting::new(out);
+        match trace::traceparent() {
+            Some(traceparent) if self.body.traceparent.is_none() => {
+                let mut msg = serde_json::to_value(self).context("serialize message")?;
+                msg["body"]["traceparent"] = traceparent.into();
+                serde_json::to_writer(&mut out, &msg)
             }
-            Payload::Broadcast { .. } => {
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::BroadcastOk,
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to Read")?;
-                output.write_all(b"\n").context("write trailing line")?;
+            _ => serde_json::to_writer(&mut out, self),
+        }
+        .context("serialize message")?;
+        out.write_all(b"\n").context("write trailing new line")?;
+        if let (Some(kind), true) = (&kind, metrics::enabled()) {
+            metrics::record_sent(kind, out.bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Hands out `msg_id`s for outgoing messages. Ids are unique and strictly
+/// increasing for the lifetime of the allocator; clones share the counter.
+#[derive(Debug, Clone, Default)]
+pub struct MsgIdAllocator(Arc<AtomicUsize>);
+
+impl MsgIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next(&self) -> usize {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Sends the `error` replies the main loop makes on the node's behalf, such
+/// as for an [`RpcError`] its handler returned: from the node's id, as
+/// announced by `init`, under a `msg_id` from the node's allocator. Clones
+/// share what they have learnt.
+#[derive(Debug, Clone, Default)]
+pub struct Replier {
+    node_id: Arc<OnceLock<String>>,
+    msg_ids: MsgIdAllocator,
+}
+
+impl Replier {
+    /// `msg_ids` should be the node's, see [`Node::msg_ids`].
+    pub fn new(msg_ids: MsgIdAllocator) -> Self {
+        Self {
+            node_id: Arc::default(),
+            msg_ids,
+        }
+    }
+
+    /// A replier for the node `node_id`, which has already had its `init`.
+    pub fn named(node_id: &str, msg_ids: MsgIdAllocator) -> Self {
+        let replier = Self::new(msg_ids);
+        let _ = replier.node_id.set(node_id.to_string());
+        replier
+    }
+
+    /// Learns the node's id from `json`, if it is the `init` message.
+    pub fn learn(&self, json: &str) {
+        if self.node_id.get().is_some() {
+            return;
+        }
+        if let Some(init) = Init::parse(json) {
+            if let Some(node_id) = init.node_id {
+                let _ = self.node_id.set(node_id);
             }
-            Payload::Topology { topology } => {
-                self.known = topology;
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::TopologyOk,
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to topology")?;
-                output.write_all(b"\n").context("write trailing line")?;
+        }
+    }
+
+    /// The node's id, once it is known.
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.get().map(String::as_str)
+    }
+
+    /// Builds an `error` reply to `request`. Before `init` there is no id to
+    /// send it from but the one `request` was sent to.
+    pub fn error<P>(&self, request: &Message<P>, err: RpcError) -> Message {
+        let src = self.node_id().unwrap_or(&request.dest);
+        request.into_error(src, Some(self.msg_ids.next()), err)
+    }
+}
+
+/// This node's identity and the cluster it belongs to, as announced by
+/// `init`.
+#[derive(Debug, Clone, Default)]
+pub struct Membership {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+}
+
+impl Membership {
+    pub fn init(&mut self, node_id: &str, node_ids: &[String]) {
+        self.node_id = node_id.to_string();
+        self.node_ids = node_ids.to_vec();
+    }
+
+    /// Every other node in the cluster.
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.node_ids.iter().filter(move |id| **id != self.node_id)
+    }
+
+    /// Whether `id` names a cluster node rather than a client or service.
+    pub fn is_peer(&self, id: &str) -> bool {
+        self.node_ids.iter().any(|node| node == id)
+    }
+
+    /// Adds `node`, which joined after `init`. Returns whether it is new.
+    pub fn add(&mut self, node: &str) -> bool {
+        if self.is_peer(node) {
+            return false;
+        }
+        self.node_ids.push(node.to_string());
+        true
+    }
+
+    /// Removes `node`, which left the cluster. Returns whether it was a
+    /// member.
+    pub fn remove(&mut self, node: &str) -> bool {
+        let before = self.node_ids.len();
+        self.node_ids.retain(|id| id != node);
+        self.node_ids.len() < before
+    }
+}
+
+/// Everything the main loop can wake up for.
+#[derive(Debug, Clone)]
+pub enum Event<P = Payload> {
+    /// A message read from the input.
+    Message(Message<P>),
+    /// A message read from the input whose payload isn't a `P`, kept as raw
+    /// JSON. What happens to it is up to [`Config::unknown_messages`].
+    Unknown(Message<serde_json::Value>),
+    /// Fired every [`Node::tick_interval`], for periodic work such as gossip,
+    /// and once [`Node::next_timer`] has passed.
+    Tick,
+    /// The input was closed; no further messages will arrive. The main loop
+    /// calls [`Node::shutdown`], cancels the node's pending RPC calls and
+    /// flushes what is left of its output.
+    Shutdown,
+    /// A request from the [admin] server for a look at the node,
+    /// answered between two other events.
+    #[cfg(feature = "admin")]
+    Inspect(admin::Inspect),
+    /// A handler the [async runtime](runtime) is running can get on, now
+    /// that what it was waiting for is here.
+    #[cfg(feature = "async")]
+    Wake,
+}
+
+/// A Maelstrom workload. Implementors receive every inbound message in order
+/// and write their replies (newline-delimited JSON) to `out`.
+pub trait Node<P = Payload> {
+    fn handle(&mut self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()>;
+
+    /// How often [`Node::tick`] should run. `None` disables ticks.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn tick(&mut self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// When [`Node::tick`] should next run, on top of every
+    /// [`Node::tick_interval`], such as the next deadline in a
+    /// [`timer::TimerWheel`]. Asked after every event.
+    fn next_timer(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The node's outstanding requests, if it makes any. Replies to them are
+    /// routed to the waiting [`RpcCall`] instead of to [`Node::handle`].
+    fn rpc(&self) -> Option<Rpc<P>> {
+        None
+    }
+
+    /// Where the node gets its `msg_id`s from, for the replies the main loop
+    /// sends on its behalf to take theirs from too. That of its
+    /// [`Node::rpc`] unless implemented.
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.rpc().map(|rpc| rpc.msg_ids())
+    }
+
+    /// Runs once the input is closed, before the main loop returns, for
+    /// last words such as saving state. No ticks come after it, whatever
+    /// timers are pending, and whatever is written to `out` is still sent.
+    fn shutdown(&mut self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// What the node holds, as JSON, for a look from outside such as the
+    /// admin server's `/state`. `null` unless implemented.
+    fn inspect(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// The nodes this one knows of and talks to, as JSON, for the admin
+    /// server's `/topology`. `null` unless implemented.
+    fn topology(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
+
+/// Feeds `node` with messages from stdin, plus ticks if it asks for them,
+/// until stdin is closed. Runtime behaviour is taken from
+/// [`Config::from_env`], including whether to talk over [TCP](tcp),
+/// [UDP](udp), [WebSocket](ws) or a [unix socket](socket) instead.
+pub fn main_loop<P, N>(node: N) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: Node<P>,
+{
+    main_loop_with(node, &Config::from_env()?)
+}
+
+/// Like [`main_loop`], with explicit runtime configuration. With
+/// [`Config::dedup`], the node is wrapped in a [`dedup::Deduped`], and
+/// with [`Config::tcp`], [`Config::udp`], [`Config::ws`] or
+/// [`Config::unix`], it talks over TCP, UDP, WebSocket or a unix socket
+/// instead of stdin and stdout.
+pub fn main_loop_with<P, N>(node: N, config: &Config) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: Node<P>,
+{
+    // Before `run` applies the rest, for listening to be logged as asked.
+    crate::log::set_level(config.log_level);
+    if let Some(tcp) = &config.tcp {
+        let (input, out) = tcp::listen(tcp)?;
+        return run_configured(node, config, input, out);
+    }
+    if let Some(udp) = &config.udp {
+        let (input, out) = udp::listen(udp, udp::UdpOptions::from_config(config))?;
+        return run_configured(node, config, input, out);
+    }
+    if let Some(ws) = &config.ws {
+        let (input, out) = ws::listen(ws)?;
+        return run_configured(node, config, input, out);
+    }
+    if let Some(socket) = &config.unix {
+        #[cfg(unix)]
+        {
+            let (input, out) = unix::listen(socket)?;
+            return run_configured(node, config, input, out);
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("can't listen on {}: no unix sockets here", socket.listen);
+    }
+    run_configured(node, config, input::stdin(), std::io::stdout())
+}
+
+/// [`run`], with `node` wrapped in a [`dedup::Deduped`] if `config` says so.
+fn run_configured<P, N, W>(
+    node: N,
+    config: &Config,
+    input: impl InputSource,
+    out: W,
+) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: Node<P>,
+    W: Write + Send,
+{
+    match config.dedup {
+        true => run(
+            Deduped::new(node).with_limits(config.dedup_limits),
+            config,
+            input,
+            out,
+        ),
+        false => run(node, config, input, out),
+    }
+}
+
+/// Like [`main_loop_with`], reading messages from `input` and writing to
+/// `out` instead of stdin and stdout. See [`input`] and [`transport`] for
+/// what else can be plugged in.
+///
+/// Reading, handling and writing each get a thread of their own, connected
+/// by bounded channels, so a slow handler doesn't hold up reading (and
+/// resolving RPC replies) and a slow reader of `out` doesn't hold up
+/// handling until the channels fill up.
+pub fn run<P, N, W>(
+    mut node: N,
+    config: &Config,
+    input: impl InputSource,
+    out: W,
+) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: Node<P>,
+    W: Write + Send,
+{
+    config.apply()?;
+
+    let reader = thread::scope(|scope| {
+        let (mut out, writer) = output::spawn_writer(scope, out, config);
+        let replier = Replier::new(node.msg_ids().unwrap_or_default());
+        let ((_, events), reader) = spawn_event_sources(
+            input,
+            node.tick_interval(),
+            node.rpc(),
+            &replier,
+            config,
+            out.clone(),
+        )?;
+        let mut handled = 0;
+        let mut handle_events = || -> anyhow::Result<()> {
+            while let Some(event) = next_event(&events, node.next_timer()) {
+                handled += 1;
+                match event {
+                    Event::Message(input) => {
+                        let span = Span::message(&input);
+                        let request = input.header();
+                        let result = node.handle(input, &mut out);
+                        reply_on_rpc_error(result, &request, &replier, &mut out)?;
+                        span.finish();
+                    }
+                    Event::Unknown(input) => {
+                        handle_unknown(&input, config.unknown_messages, &replier, &mut out)?
+                    }
+                    Event::Tick => {
+                        let span = Span::tick();
+                        node.tick(&mut out).context("Node tick function failed")?;
+                        span.finish();
+                    }
+                    Event::Shutdown => {
+                        node.shutdown(&mut out)
+                            .context("Node shutdown function failed")?;
+                        break;
+                    }
+                    #[cfg(feature = "admin")]
+                    Event::Inspect(inspect) => {
+                        inspect.answer(|| node.inspect(), || node.topology())
+                    }
+                    #[cfg(feature = "async")]
+                    Event::Wake => {}
+                }
+                out.flush().context("handing output to the writer")?;
             }
-            Payload::Read { .. } => {
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::ReadOk {
-                            value: self.value.clone(),
-                        },
-                    },
-                };
-
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to Read")?;
-                output.write_all(b"\n").context("write trailing line")?;
+            Ok(())
+        };
+        let result = handle_events();
+        let cancelled = cancel_pending(node.rpc());
+        reader.report_stop(handled, cancelled, || node.inspect());
+        let closed = out.close();
+        // If the writer failed, its error explains any the handler hit.
+        writer.join().expect("writer thread panicked")?;
+        result?;
+        closed.context("closing output")?;
+        Ok::<_, anyhow::Error>(reader)
+    })?;
+
+    // A node stopped by a signal leaves its counters behind, enabled or not.
+    if crate::metrics::enabled() || reader.signalled() {
+        crate::metrics::report();
+    }
+    crate::prometheus::dump();
+    crate::trace::flush();
+
+    reader.join().context("reading input")
+}
+
+/// Fails the calls still waiting in `rpc`, as no replies will be read for
+/// them. Returns how many there were.
+pub(crate) fn cancel_pending<P>(rpc: Option<Rpc<P>>) -> usize {
+    let Some(rpc) = rpc else {
+        return 0;
+    };
+    let pending = rpc.outstanding();
+    rpc.cancel_all(RpcError::crash("node is shutting down"));
+    pending
+}
+
+/// The thread reading the input, and the signal that stopped the main loop
+/// before the input ran out, if one did.
+pub(crate) struct Reader {
+    thread: JoinHandle<anyhow::Result<()>>,
+    signal: Arc<OnceLock<Signal>>,
+    /// Stops watching for signals once the main loop is done with.
+    _watch: Option<signal::Watch>,
+}
+
+impl Reader {
+    /// Logs how the main loop stopped, if a signal stopped it, after
+    /// handling `handled` events and cancelling `cancelled` calls, and
+    /// writes the node's [`state`](Node::inspect) to stderr, as it won't be
+    /// seen again.
+    pub(crate) fn report_stop(
+        &self,
+        handled: u64,
+        cancelled: usize,
+        state: impl FnOnce() -> serde_json::Value,
+    ) {
+        if let Some(signal) = self.signal.get() {
+            crate::info!(
+                "stopped by {signal}: {handled} events handled, \
+                 {cancelled} pending calls cancelled"
+            );
+            eprintln!("state {}", state());
+        }
+    }
+
+    /// Whether a signal stopped the main loop.
+    pub(crate) fn signalled(&self) -> bool {
+        self.signal.get().is_some()
+    }
+
+    /// Waits for the reader to finish, unless a signal stopped the main
+    /// loop while it was still blocked on the input, which is left to end
+    /// with the process.
+    pub(crate) fn join(self) -> anyhow::Result<()> {
+        if self.signal.get().is_some() && !self.thread.is_finished() {
+            return Ok(());
+        }
+        self.thread.join().expect("input thread panicked")
+    }
+}
+
+/// The queue of events for the main loop, and a sender of its own into it.
+pub(crate) type EventQueue<P> = (mpsc::SyncSender<Event<P>>, mpsc::Receiver<Event<P>>);
+
+/// Starts the reader thread for `input` and, if `tick_interval` is set, the
+/// tick thread, feeding a queue of [`Config::queue_capacity`] events. The
+/// reader sends [`Event::Shutdown`] once `input` is exhausted and its
+/// handle yields any error it hit along the way. With
+/// [`Config::proxy_upstream`], messages of unknown types are passed on
+/// there by the reader, see [`proxy`], and with [`Config::validate`] it
+/// turns away malformed ones, see [`validate`], as it does ones with
+/// unknown fields with [`Config::strict`]. With [`Config::signals`],
+/// SIGTERM and SIGINT send one too. Replies to calls pending
+/// in `rpc` are delivered straight to their callers. With
+/// [`OverloadPolicy::Reject`], requests that don't fit in the queue are
+/// answered on `out` straight away. Error replies go through `replier`,
+/// which learns the node's id from `init`. With [`Config::admin`], the
+/// admin server is started too.
+pub(crate) fn spawn_event_sources<P>(
+    mut input: impl InputSource,
+    tick_interval: Option<Duration>,
+    rpc: Option<Rpc<P>>,
+    replier: &Replier,
+    config: &Config,
+    mut out: Outbox,
+) -> anyhow::Result<(EventQueue<P>, Reader)>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+{
+    let (tx, rx) = mpsc::sync_channel(config.queue_capacity);
+
+    let signal = Arc::new(OnceLock::new());
+    let mut watch = None;
+    if config.signals {
+        let (tx, caught) = (tx.clone(), Arc::clone(&signal));
+        let watching = signal::on_next(move |signal| {
+            let _ = caught.set(signal);
+            // Once the main loop is gone there is nothing left to stop.
+            let stopping = tx.send(Event::Shutdown).is_ok();
+            if stopping {
+                crate::warn!("caught {signal}, shutting down");
             }
-            Payload::Generate { .. } => {
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::GenerateOk {
-                            id: Uuid::new_v4().to_string(),
-                        },
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to init")?;
-                output.write_all(b"\n").context("write trailing new line")?;
+            stopping
+        });
+        watch = Some(watching.context("installing signal handlers")?);
+    }
+
+    if let Some(addr) = &config.admin {
+        #[cfg(feature = "admin")]
+        admin::serve(addr, tx.clone(), rpc.clone())?;
+        #[cfg(not(feature = "admin"))]
+        crate::warn!("not serving admin on {addr}: built without the admin feature");
+    }
+
+    let input_tx = tx.clone();
+    let overload = config.overload;
+    let mut proxy = config.proxy_upstream.clone().map(Proxy::new);
+    let replier = replier.clone();
+    let mut validator = config
+        .validate
+        .then(|| Validator::new(rpc.is_some()).with_replier(replier.clone()));
+    let strict = config.strict;
+    let reader = thread::spawn(move || -> anyhow::Result<()> {
+        let result = std::iter::from_fn(|| input.next_message()).try_for_each(|json| {
+            let json = json?;
+            record::inbound(&json);
+            replier.learn(&json);
+            if let Some(proxy) = &mut proxy {
+                if proxy.relay(&json, &mut out)? {
+                    return Ok(());
+                }
             }
-            Payload::Init { .. } => {
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::InitOk,
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to init")?;
-                output.write_all(b"\n").context("write trailing new line")?;
+            let event = match parse_event(&json)? {
+                Event::Message(input) if strict => {
+                    validate::admit_strictly(&json, &input, &replier, &mut out)?
+                        .then_some(Event::Message(input))
+                }
+                event => Some(event),
+            };
+            let event = match (event, &rpc) {
+                (Some(Event::Message(input)), Some(rpc)) => rpc.resolve(input).map(Event::Message),
+                (event, _) => event,
+            };
+            let event = match (event, &mut validator) {
+                (Some(Event::Message(input)), Some(validator)) => validator
+                    .admit(&json, &input, &mut out)?
+                    .then_some(Event::Message(input)),
+                (Some(Event::Unknown(input)), Some(validator)) => validator
+                    .admit(&json, &input, &mut out)?
+                    .then_some(Event::Unknown(input)),
+                (event, _) => event,
+            };
+            let event = match event {
+                Some(Event::Unknown(input)) => match &mut proxy {
+                    Some(proxy) if input.src != proxy.upstream() => {
+                        proxy.forward(input, &mut out)?;
+                        None
+                    }
+                    _ => Some(Event::Unknown(input)),
+                },
+                event => event,
+            };
+            // The main loop only hangs up once it is done, so a failed send
+            // just means there is nobody left to read for. Events are
+            // counted in before they go, as the main loop may take them
+            // out before this thread gets to run again.
+            let Some(event) = event else {
+                return Ok(());
+            };
+            metrics::record_queued("events");
+            match overload {
+                OverloadPolicy::Block => {
+                    if input_tx.send(event).is_err() {
+                        metrics::record_dequeued("events");
+                    }
+                }
+                OverloadPolicy::Reject => match input_tx.try_send(event) {
+                    Ok(()) => {}
+                    Err(mpsc::TrySendError::Full(event)) => {
+                        metrics::record_dequeued("events");
+                        reject(event, &replier, &mut out)?
+                    }
+                    Err(mpsc::TrySendError::Disconnected(_)) => metrics::record_dequeued("events"),
+                },
             }
-            Payload::Echo { echo } => {
-                let reply = Message {
-                    src: input.dest,
-                    dest: input.src,
-                    body: Body {
-                        id: Some(self.id),
-                        in_reply_to: input.body.id,
-                        payload: Payload::EchoOk { echo },
-                    },
-                };
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to init")?;
-                output.write_all(b"\n").context("write trailing new line")?;
+            Ok(())
+        });
+        let _ = input_tx.send(Event::Shutdown);
+        result
+    });
+
+    if let Some(interval) = tick_interval {
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if tx.send(Event::Tick).is_err() {
+                break;
             }
-            Payload::EchoOk { .. } => bail!("recieved init_ok Message"),
-            Payload::InitOk { .. } => {}
-            Payload::GenerateOk { .. } => bail!("recieved generate_ok Message"),
-            Payload::ReadOk { .. } => bail!("recieved read_ok Message"),
-            Payload::BroadcastOk { .. } => bail!("recieved BroadcastOk Message"),
-            Payload::TopologyOk { .. } => bail!("recieved TopologyOk Message"),
-            Payload::AddOk { .. } => bail!("recieved AddOk Message"),
+        });
+    }
+
+    Ok((
+        (tx, rx),
+        Reader {
+            thread: reader,
+            signal,
+            _watch: watch,
+        },
+    ))
+}
+
+/// Waits for the next event, or until `timer`, if it is set, making a
+/// [`Event::Tick`] of it. `None` once every event source is gone.
+pub(crate) fn next_event<P>(
+    events: &mpsc::Receiver<Event<P>>,
+    timer: Option<Instant>,
+) -> Option<Event<P>> {
+    let event = match timer {
+        None => events.recv().ok(),
+        Some(timer) => match events.recv_timeout(timer.saturating_duration_since(Instant::now())) {
+            Ok(event) => Some(event),
+            Err(mpsc::RecvTimeoutError::Timeout) => Some(Event::Tick),
+            Err(mpsc::RecvTimeoutError::Disconnected) => None,
+        },
+    };
+    if let Some(event) = &event {
+        dequeued(event);
+    }
+    event
+}
+
+/// Records `event` as taken off the queue, if it was read from the input.
+pub(crate) fn dequeued<P>(event: &Event<P>) {
+    if let Event::Message(_) | Event::Unknown(_) = event {
+        metrics::record_dequeued("events");
+    }
+}
+
+/// Turns `event` away because the handler is overloaded.
+fn reject<P>(event: Event<P>, replier: &Replier, out: &mut Outbox) -> anyhow::Result<()> {
+    let Event::Message(msg) = event else {
+        return Ok(());
+    };
+    debug!("overloaded, rejecting message from {}", msg.src);
+    if msg.body.id.is_some() && msg.body.in_reply_to.is_none() {
+        let err = RpcError::new(ErrorCode::TemporarilyUnavailable, "node is overloaded");
+        replier.error(&msg, err).send(out)?;
+        out.flush().context("handing output to the writer")?;
+    }
+    Ok(())
+}
+
+/// Parses one message of input as a `Message<P>`, falling back to
+/// [`Event::Unknown`] if it is a well-formed message with a payload `P`
+/// doesn't recognise.
+fn parse_event<P: DeserializeOwned>(json: &str) -> anyhow::Result<Event<P>> {
+    if log::enabled(log::Level::Debug) {
+        if let Ok(msg) = serde_json::from_str::<Message<MessageKind>>(json) {
+            let kind = &msg.body.payload.kind;
+            log::message("recv", &msg.src, &msg.dest, kind, msg.body.id);
         }
-        self.id += 1;
-        Ok(())
     }
+    let err = match serde_json::from_str(json) {
+        Ok(msg) => return Ok(Event::Message(msg)),
+        Err(err) => err,
+    };
+    match serde_json::from_str(json) {
+        Ok(msg) => Ok(Event::Unknown(msg)),
+        Err(_) => Err(err).context("Maelstrom input could not be deserialized"),
+    }
+}
+
+/// Just the `type` of a payload, for logging.
+#[derive(Deserialize)]
+struct MessageKind {
+    #[serde(rename = "type", default)]
+    kind: String,
+}
+
+/// What `init` says, for looking inside it whatever the node's payload
+/// type is.
+#[derive(Deserialize)]
+pub(crate) struct Init {
+    #[serde(rename = "type")]
+    kind: String,
+    pub(crate) node_id: Option<String>,
+    #[serde(default)]
+    pub(crate) node_ids: Vec<String>,
+}
+
+impl Init {
+    /// `json`'s body, if it is an `init` message.
+    pub(crate) fn parse(json: &str) -> Option<Self> {
+        let msg = serde_json::from_str::<Message<Init>>(json).ok()?;
+        (msg.body.payload.kind == "init").then_some(msg.body.payload)
+    }
+}
+
+/// Deals with a message whose payload couldn't be parsed, according to
+/// `policy`, answering through `replier`.
+pub(crate) fn handle_unknown(
+    msg: &Message<serde_json::Value>,
+    policy: UnknownPolicy,
+    replier: &Replier,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let kind = msg.body.payload.get("type").and_then(|kind| kind.as_str());
+    match policy {
+        UnknownPolicy::Ignore => {}
+        UnknownPolicy::Log => warn!(
+            "ignoring unrecognised {} message from {}",
+            kind.unwrap_or("untyped"),
+            msg.src
+        ),
+        UnknownPolicy::Reply if msg.body.id.is_some() => {
+            let text = format!("unrecognised message type {}", kind.unwrap_or("(none)"));
+            replier
+                .error(msg, RpcError::not_supported(text))
+                .send(out)?;
+        }
+        UnknownPolicy::Reply => {}
+    }
+    Ok(())
+}
+
+/// Turns an [`RpcError`] returned by a handler into an `error` reply to
+/// `request`, sent through `replier`. Any other error is passed through.
+pub(crate) fn reply_on_rpc_error(
+    result: anyhow::Result<()>,
+    request: &Message<()>,
+    replier: &Replier,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let Err(e) = result else {
+        return Ok(());
+    };
+    let err = e
+        .downcast::<RpcError>()
+        .context("Node handle function failed")?;
+    if request.body.id.is_some() {
+        replier.error(request, err).send(out)?;
+    }
+    Ok(())
 }
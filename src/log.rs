@@ -0,0 +1,144 @@
+//! Minimal leveled logging to stderr, which Maelstrom collects into each
+//! node's log file. Stdout is reserved for protocol traffic, so nothing
+//! here ever touches it.
+//!
+//! Lines look like `0.042 DEBUG recv src=c1 dest=n1 type=echo msg_id=1`:
+//! seconds since the process started, the level, then the message. Use the
+//! [`error!`](crate::error!), [`warn!`](crate::warn!), [`info!`](crate::info!),
+//! [`debug!`](crate::debug!) and [`trace!`](crate::trace!) macros.
+
+use anyhow::bail;
+use std::{
+    fmt,
+    io::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// Log nothing at all.
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    /// Adds a line for every message received and sent.
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "off" => Level::Off,
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => bail!("unknown log level {s}"),
+        })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Changes the most verbose level that gets written. Takes effect
+/// immediately, on every thread.
+pub fn set_level(level: Level) {
+    START.get_or_init(Instant::now);
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= self::level()
+}
+
+/// Writes one line at `level`. Prefer the macros, which skip formatting
+/// when the level is disabled.
+pub fn write(level: Level, args: fmt::Arguments<'_>) {
+    let elapsed = START.get_or_init(Instant::now).elapsed().as_secs_f64();
+    // There is nowhere left to report a failure to write to stderr.
+    let _ = writeln!(std::io::stderr().lock(), "{elapsed:.3} {level} {args}");
+}
+
+/// Logs a message's addressing and type. `dir` is `recv` or `send`.
+pub(crate) fn message(dir: &str, src: &str, dest: &str, kind: &str, msg_id: Option<usize>) {
+    match msg_id {
+        Some(id) => crate::debug!("{dir} src={src} dest={dest} type={kind} msg_id={id}"),
+        None => crate::debug!("{dir} src={src} dest={dest} type={kind}"),
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
@@ -1,24 +1,172 @@
-use anyhow::Context;
-use std::collections::HashMap;
-use whirlpool::{EchoNode, Message};
+use anyhow::{bail, Context};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process::Command;
+#[cfg(unix)]
+use whirlpool::unix::Supervisor;
+use whirlpool::{
+    crdt::{CrdtMap, LwwMap, OrSet},
+    kv::KvStore,
+    main_loop_with, record,
+    storage::Persisted,
+    BroadcastNode, CausalBroadcastNode, Config, CounterNode, CrdtNode, EchoNode, GSetNode,
+    KafkaNode, QuorumKvNode, RaftNode, ShardedKvNode, TwoPhaseTxnNode, TxnNode,
+};
 
-fn main() -> anyhow::Result<()> {
-    let stdin = std::io::stdin().lock();
-    let inputs = serde_json::Deserializer::from_reader(stdin).into_iter::<Message>();
+const USAGE: &str = "\
+usage: whirlpool [--workload <workload>]
+       whirlpool replay <recording> [--node <id>] [--workload <workload>]
+       whirlpool cluster <nodes> [--dir <dir>] [--workload <workload>]
+
+workloads: echo (default), unique-ids, broadcast, g-counter, pn-counter,
+           kafka, txn-rw-register, lin-kv, g-set, or-set, crdt-map
+
+replay feeds the messages a node received, as recorded with
+WHIRLPOOL_RECORD_FILE, to a fresh node and prints how its replies differ
+from the recorded ones. --node picks the node if several were recorded.
+
+cluster runs that many nodes, n0 onwards, as child processes talking over
+unix sockets in --dir (the temp directory by default), where clients can
+connect to them, until one exits.";
+
+#[derive(Debug, Default)]
+struct Args {
+    workload: Option<String>,
+    /// The recording to replay, for `replay`.
+    replay: Option<PathBuf>,
+    node: Option<String>,
+    /// How many nodes to run, for `cluster`.
+    cluster: Option<usize>,
+    dir: Option<PathBuf>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "replay").is_some() {
+        let path = args.next().context("replay needs a recording")?;
+        parsed.replay = Some(path.into());
+    } else if args.next_if(|arg| arg == "cluster").is_some() {
+        let nodes = args.next().context("cluster needs a number of nodes")?;
+        parsed.cluster = Some(nodes.parse().context("number of nodes")?);
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            "-w" | "--workload" => {
+                parsed.workload = Some(args.next().context("--workload needs a value")?);
+            }
+            "--node" if parsed.replay.is_some() => {
+                parsed.node = Some(args.next().context("--node needs a value")?);
+            }
+            "--dir" if parsed.cluster.is_some() => {
+                parsed.dir = Some(args.next().context("--dir needs a value")?.into());
+            }
+            _ => match arg.strip_prefix("--workload=") {
+                Some(value) => parsed.workload = Some(value.to_string()),
+                None => bail!("unexpected argument {arg}\n\n{USAGE}"),
+            },
+        }
+    }
+    Ok(parsed)
+}
 
-    let mut stdout = std::io::stdout().lock();
+/// Calls `$run` with the node for `$workload`, followed by `$args`.
+macro_rules! with_node {
+    ($workload:expr, $config:expr, $run:path $(, $args:expr)*) => {
+        match $workload {
+            None | Some("echo") | Some("unique-ids") => $run(EchoNode::new($config.ids) $(, $args)*),
+            Some("broadcast") if $config.broadcast_causal => {
+                let node = CausalBroadcastNode::default().with_retry_limits($config.retry_limits);
+                $run(node $(, $args)*)
+            }
+            Some("broadcast") => {
+                let node = BroadcastNode::from_config(&$config);
+                match &$config.state_dir {
+                    Some(dir) => $run(Persisted::configured(node, dir, &$config) $(, $args)*),
+                    None => $run(node $(, $args)*),
+                }
+            }
+            Some("g-counter") | Some("pn-counter") => $run(CounterNode::default() $(, $args)*),
+            Some("kafka") => $run(KafkaNode::replicated() $(, $args)*),
+            Some("txn-rw-register") if $config.txn_2pc => match &$config.state_dir {
+                Some(dir) => $run(TwoPhaseTxnNode::with_decision_log(dir) $(, $args)*),
+                None => $run(TwoPhaseTxnNode::default() $(, $args)*),
+            },
+            Some("txn-rw-register") => match &$config.state_dir {
+                Some(dir) => $run(Persisted::configured(TxnNode::default(), dir, &$config) $(, $args)*),
+                None => $run(TxnNode::default() $(, $args)*),
+            },
+            Some("lin-kv") => match $config.kv_quorum {
+                Some(_) => $run(QuorumKvNode::from_config(&$config) $(, $args)*),
+                None if $config.kv_lww => $run(CrdtNode::<LwwMap>::default() $(, $args)*),
+                None if $config.kv_sharded => $run(ShardedKvNode::default() $(, $args)*),
+                None => $run(RaftNode::<KvStore>::from_config(&$config) $(, $args)*),
+            },
+            Some("g-set") => $run(GSetNode::default() $(, $args)*),
+            Some("or-set") => $run(CrdtNode::<OrSet<i64>>::default() $(, $args)*),
+            Some("crdt-map") => $run(CrdtNode::<CrdtMap>::default() $(, $args)*),
+            Some(other) => bail!("unknown workload {other}\n\n{USAGE}"),
+        }
+    };
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    let args = parse_args(std::env::args().skip(1))?;
+    let workload = args.workload.as_deref();
+    if let Some(nodes) = args.cluster {
+        return cluster(nodes, args.dir, workload);
+    }
+    let Some(path) = &args.replay else {
+        return with_node!(workload, config, main_loop_with, &config);
+    };
 
-    let mut state = EchoNode {
-        id: 0,
-        value: 0,
-        known: HashMap::new(),
+    let entries = record::load(path)?;
+    let node_id = match args.node {
+        Some(node) => node,
+        None => record::only_node(&entries)?,
     };
-    for input in inputs {
-        let input = input.context("Maelstrom input could not be deserialized")?;
-        state
-            .step(input, &mut stdout)
-            .context("Node step function failed")?;
+    let replayed = with_node!(
+        workload,
+        config,
+        record::replay,
+        &config,
+        &entries,
+        &node_id
+    )?;
+    let diff = record::diff(record::sent(&entries, &node_id), &replayed)?;
+    if diff.is_empty() {
+        eprintln!("replayed {} messages, no differences", replayed.len());
+        return Ok(());
     }
+    print!("{diff}");
+    bail!(
+        "{} recorded messages were not sent, {} sent messages were not recorded",
+        diff.missing.len(),
+        diff.unexpected.len()
+    )
+}
+
+#[cfg(unix)]
+fn cluster(nodes: usize, dir: Option<PathBuf>, workload: Option<&str>) -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    let dir = dir.unwrap_or_else(std::env::temp_dir);
+    let mut supervisor = Supervisor::launch(&dir, nodes, |_| {
+        let mut command = Command::new(&exe);
+        command.args(workload.map(|workload| format!("--workload={workload}")));
+        command
+    })?;
+    for (node, path) in supervisor.sockets() {
+        eprintln!("{node} listening on {path}");
+    }
+    supervisor.wait()
+}
 
-    Ok(())
+#[cfg(not(unix))]
+fn cluster(_nodes: usize, _dir: Option<PathBuf>, _workload: Option<&str>) -> anyhow::Result<()> {
+    bail!("cluster needs unix sockets, which aren't here")
 }
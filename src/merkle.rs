@@ -0,0 +1,99 @@
+//! Merkle trees, for finding the keys two replicas disagree on without
+//! sending all of them.
+//!
+//! Keys are hashed into a fixed number of leaves, `2^depth` of them, and
+//! each leaf holds the XOR of the hashes of its entries, so entries can be
+//! inserted in any order. Every node above hashes its two children. Two
+//! trees over the same entries have the same root; where they differ,
+//! comparing children from the root down leads to the leaves that do, and
+//! only the entries under those need to be exchanged.
+//!
+//! Hashes are the ones [`crate::ring`] uses, so trees built by different
+//! nodes of the same build can be compared.
+
+use crate::ring::hash;
+use std::hash::Hash;
+
+/// How deep trees are by default: 256 leaves.
+pub const DEFAULT_DEPTH: u32 = 8;
+
+/// A Merkle tree of a fixed depth, its nodes numbered from the root in
+/// breadth-first order: node `i`'s children are `2i + 1` and `2i + 2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    depth: u32,
+    nodes: Vec<u64>,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEPTH)
+    }
+}
+
+impl MerkleTree {
+    /// An empty tree with `2^depth` leaves.
+    pub fn new(depth: u32) -> Self {
+        let mut tree = Self {
+            depth,
+            nodes: vec![0; (1 << (depth + 1)) - 1],
+        };
+        for node in (0..tree.first_leaf()).rev() {
+            tree.nodes[node] = tree.hash_children(node);
+        }
+        tree
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[0]
+    }
+
+    /// The hash at `node`, if the tree has such a node.
+    pub fn get(&self, node: usize) -> Option<u64> {
+        self.nodes.get(node).copied()
+    }
+
+    pub fn is_leaf(&self, node: usize) -> bool {
+        (self.first_leaf()..self.nodes.len()).contains(&node)
+    }
+
+    /// The children of `node`, unless it is a leaf.
+    pub fn children(&self, node: usize) -> Option<[usize; 2]> {
+        (node < self.first_leaf()).then(|| [2 * node + 1, 2 * node + 2])
+    }
+
+    /// The leaf `key` goes under.
+    pub fn leaf_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let bucket = hash(key).checked_shr(64 - self.depth).unwrap_or(0);
+        self.first_leaf() + bucket as usize
+    }
+
+    /// Adds `key`'s entry, `value`, to its leaf, and rehashes the nodes
+    /// above it.
+    pub fn insert<K: Hash + ?Sized, V: Hash + ?Sized>(&mut self, key: &K, value: &V) {
+        let mut node = self.leaf_of(key);
+        self.nodes[node] ^= hash(&(key, value));
+        while node > 0 {
+            node = (node - 1) / 2;
+            self.nodes[node] = self.hash_children(node);
+        }
+    }
+
+    /// Those of the `nodes` of another tree, with their hashes, whose
+    /// hashes differ from this tree's.
+    pub fn diff(&self, nodes: &[(usize, u64)]) -> Vec<usize> {
+        nodes
+            .iter()
+            .filter(|&&(node, hash)| self.get(node).is_some_and(|ours| ours != hash))
+            .map(|&(node, _)| node)
+            .collect()
+    }
+
+    fn first_leaf(&self) -> usize {
+        (1 << self.depth) - 1
+    }
+
+    fn hash_children(&self, node: usize) -> u64 {
+        hash(&(self.nodes[2 * node + 1], self.nodes[2 * node + 2]))
+    }
+}
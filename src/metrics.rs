@@ -0,0 +1,273 @@
+//! Process-wide counters for tuning msgs-per-op: messages received and sent
+//! per payload type, bytes written, retries, cache evictions, how long
+//! handlers take, and how deep the main loop's queues get.
+//!
+//! Recording is off until [`enable`] is called (`WHIRLPOOL_METRICS=true`),
+//! since finding a message's type costs an extra serialization. The main
+//! loop prints a [`report`] to stderr on shutdown; call it from anywhere to
+//! get one on demand. [`crate::prometheus`] exports them for dashboards.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Upper bounds of the latency buckets, in microseconds. Anything slower
+/// lands in a final overflow bucket.
+const BUCKETS_US: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000, 1_000_000,
+];
+
+/// A latency histogram with fixed buckets.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS_US.len() + 1],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(BUCKETS_US.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((self.total.as_nanos() / n as u128) as u64),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The time spent across everything recorded.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// How many latencies landed in each bucket, by its upper bound, with
+    /// `None` for the overflow bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        let bounds = BUCKETS_US.iter().map(|us| Some(Duration::from_micros(*us)));
+        bounds.chain([None]).zip(self.buckets.iter().copied())
+    }
+
+    /// The upper bound of the bucket holding the `q`th quantile, e.g.
+    /// `quantile(0.99)`. Values in the overflow bucket report [`Self::max`].
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (self.count as f64 * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return match BUCKETS_US.get(bucket) {
+                    Some(bound) => Duration::from_micros(*bound).min(self.max),
+                    None => self.max,
+                };
+            }
+        }
+        self.max
+    }
+}
+
+/// How many items a queue holds, from how many went in and came out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueDepth {
+    queued: u64,
+    dequeued: u64,
+    max: u64,
+}
+
+impl QueueDepth {
+    pub fn depth(&self) -> u64 {
+        // Items can be taken out before their going in is recorded.
+        self.queued.saturating_sub(self.dequeued)
+    }
+
+    /// The deepest it has been.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+}
+
+/// Everything recorded so far.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub received: BTreeMap<String, u64>,
+    pub sent: BTreeMap<String, u64>,
+    pub bytes_sent: u64,
+    pub retries: u64,
+    /// Entries evicted for age or room, per cache: `dedup` or `retry`.
+    pub evictions: BTreeMap<String, u64>,
+    /// Time spent in the handler, per payload type. Ticks count as `tick`.
+    pub latency: BTreeMap<String, Histogram>,
+    /// The main loop's queues: `events`, the messages read and not yet
+    /// handled, and `output`, the chunks handed to the writer and not yet
+    /// written.
+    pub queues: BTreeMap<String, QueueDepth>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            received: BTreeMap::new(),
+            sent: BTreeMap::new(),
+            bytes_sent: 0,
+            retries: 0,
+            evictions: BTreeMap::new(),
+            latency: BTreeMap::new(),
+            queues: BTreeMap::new(),
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "metrics received={} sent={} bytes_sent={} retries={}",
+            self.received.values().sum::<u64>(),
+            self.sent.values().sum::<u64>(),
+            self.bytes_sent,
+            self.retries,
+        )?;
+        for (kind, n) in &self.sent {
+            writeln!(f, "metrics sent type={kind} count={n}")?;
+        }
+        for (cache, n) in &self.evictions {
+            writeln!(f, "metrics evicted cache={cache} count={n}")?;
+        }
+        for (queue, depth) in &self.queues {
+            writeln!(f, "metrics queue name={queue} max={}", depth.max())?;
+        }
+        for (kind, latency) in &self.latency {
+            writeln!(
+                f,
+                "metrics handled type={kind} count={} mean={:?} p50<={:?} p99<={:?} max={:?}",
+                latency.count(),
+                latency.mean(),
+                latency.quantile(0.5),
+                latency.quantile(0.99),
+                latency.max(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn with<T>(f: impl FnOnce(&mut Metrics) -> T) -> T {
+    // A panic elsewhere doesn't make the counters themselves inconsistent.
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut metrics)
+}
+
+pub(crate) fn record_handled(kind: &str, latency: Duration) {
+    with(|m| {
+        if kind != "tick" {
+            *m.received.entry(kind.to_string()).or_default() += 1;
+        }
+        m.latency
+            .entry(kind.to_string())
+            .or_default()
+            .record(latency);
+    })
+}
+
+pub(crate) fn record_sent(kind: &str, bytes: usize) {
+    with(|m| {
+        *m.sent.entry(kind.to_string()).or_default() += 1;
+        m.bytes_sent += bytes as u64;
+    })
+}
+
+pub(crate) fn record_retries(n: usize) {
+    if enabled() {
+        with(|m| m.retries += n as u64)
+    }
+}
+
+pub(crate) fn record_evictions(cache: &str, n: usize) {
+    if enabled() && n > 0 {
+        with(|m| *m.evictions.entry(cache.to_string()).or_default() += n as u64)
+    }
+}
+
+pub(crate) fn record_queued(queue: &str) {
+    if enabled() {
+        with(|m| {
+            let queue = m.queues.entry(queue.to_string()).or_default();
+            queue.queued += 1;
+            queue.max = queue.max.max(queue.depth());
+        })
+    }
+}
+
+pub(crate) fn record_dequeued(queue: &str) {
+    if enabled() {
+        with(|m| m.queues.entry(queue.to_string()).or_default().dequeued += 1)
+    }
+}
+
+pub fn snapshot() -> Metrics {
+    with(|m| m.clone())
+}
+
+/// Writes the current [`snapshot`] to stderr.
+pub fn report() {
+    eprint!("{}", snapshot());
+}
+
+/// Passes writes through to `W`, counting the bytes.
+pub(crate) struct Counting<W> {
+    inner: W,
+    pub bytes: usize,
+}
+
+impl<W: std::io::Write> Counting<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, bytes: 0 }
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
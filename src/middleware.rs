@@ -0,0 +1,134 @@
+//! Wrapping a node's handler in code that runs around every message, for
+//! concerns that cut across all of its message types: logging, timing,
+//! checking who a request is from.
+//!
+//! A [`Middleware`] gets each message before the node does, along with a
+//! [`Next`] that passes it on. It may do something first, pass the message
+//! on (or a changed one), do something after, or answer it itself and not
+//! pass it on at all. [`Layered`] puts one in front of a node; layers stack,
+//! and the outermost one sees each message first. Closures of the right
+//! shape are middleware too:
+//!
+//! ```
+//! use std::io::Write;
+//! use whirlpool::{middleware::{Layered, Next}, EchoNode, Message, RpcError};
+//!
+//! let node = Layered::new(
+//!     EchoNode::default(),
+//!     |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+//!         if msg.src.starts_with("intruder") {
+//!             return Err(RpcError::crash("go away").into());
+//!         }
+//!         next.run(msg, out)
+//!     },
+//! );
+//! ```
+//!
+//! Only [`Node::handle`] is wrapped; ticks and everything else go straight
+//! to the node.
+
+use crate::{Message, MsgIdAllocator, Node, Payload, Rpc};
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Runs around the handling of every message by the node it is layered
+/// on.
+pub trait Middleware<P = Payload> {
+    fn around(
+        &mut self,
+        msg: Message<P>,
+        out: &mut dyn Write,
+        next: Next<'_, P>,
+    ) -> anyhow::Result<()>;
+}
+
+impl<P, F> Middleware<P> for F
+where
+    F: FnMut(Message<P>, &mut dyn Write, Next<'_, P>) -> anyhow::Result<()>,
+{
+    fn around(
+        &mut self,
+        msg: Message<P>,
+        out: &mut dyn Write,
+        next: Next<'_, P>,
+    ) -> anyhow::Result<()> {
+        self(msg, out, next)
+    }
+}
+
+type Handle<'a, P> = &'a mut dyn FnMut(Message<P>, &mut dyn Write) -> anyhow::Result<()>;
+
+/// The rest of the chain: the layers inside this one, then the node.
+pub struct Next<'a, P = Payload> {
+    handle: Handle<'a, P>,
+}
+
+impl<P> Next<'_, P> {
+    /// Passes `msg` on, with whatever the rest of the chain writes going to
+    /// `out`.
+    pub fn run(self, msg: Message<P>, out: &mut dyn Write) -> anyhow::Result<()> {
+        (self.handle)(msg, out)
+    }
+}
+
+/// `N` with `M` running around its handler.
+#[derive(Debug)]
+pub struct Layered<N, M> {
+    node: N,
+    middleware: M,
+}
+
+impl<N, M> Layered<N, M> {
+    pub fn new(node: N, middleware: M) -> Self {
+        Self { node, middleware }
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.node
+    }
+}
+
+impl<P, N: Node<P>, M: Middleware<P>> Node<P> for Layered<N, M> {
+    fn handle(&mut self, msg: Message<P>, mut out: &mut impl Write) -> anyhow::Result<()> {
+        let node = &mut self.node;
+        let mut handle = |msg, mut out: &mut dyn Write| node.handle(msg, &mut out);
+        let next = Next {
+            handle: &mut handle,
+        };
+        self.middleware.around(msg, &mut out, next)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.node.tick_interval()
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.node.tick(out)
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.node.next_timer()
+    }
+
+    fn rpc(&self) -> Option<Rpc<P>> {
+        self.node.rpc()
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.node.msg_ids()
+    }
+
+    fn shutdown(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.node.shutdown(out)
+    }
+
+    fn inspect(&self) -> serde_json::Value {
+        self.node.inspect()
+    }
+
+    fn topology(&self) -> serde_json::Value {
+        self.node.topology()
+    }
+}
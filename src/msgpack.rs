@@ -0,0 +1,248 @@
+//! MessagePack, a binary encoding of the same values as JSON, for bulk data
+//! nodes send each other: packed gossip values and Raft snapshots. It
+//! takes integers and short strings in a byte or two fewer than JSON, and
+//! needs no escaping or number parsing to read back.
+//!
+//! Messages themselves stay JSON, since Maelstrom reads them; MessagePack
+//! only travels inside them, base64-encoded, and only to peers whose
+//! [handshake](crate::handshake) said they read it. Only what JSON can
+//! hold is written or read: no binary strings or extension types, and map
+//! keys are strings.
+
+use crate::{ErrorCode, RpcError};
+use serde_json::{Map, Number, Value};
+
+/// How deeply arrays and maps may nest, as in `serde_json`.
+const MAX_DEPTH: usize = 128;
+
+/// `value` as MessagePack.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write(value, &mut bytes);
+    bytes
+}
+
+/// The value `bytes` holds, all of them.
+pub fn decode(bytes: &[u8]) -> Result<Value, RpcError> {
+    let mut reader = Reader { bytes, depth: 0 };
+    let value = reader.value()?;
+    if !reader.bytes.is_empty() {
+        return Err(malformed("trailing bytes"));
+    }
+    Ok(value)
+}
+
+fn write(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => write_number(number, out),
+        Value::String(string) => {
+            write_len(string.len(), [0xa0, 0xd9, 0xda, 0xdb], 32, out);
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(values) => {
+            write_len(values.len(), [0x90, 0, 0xdc, 0xdd], 16, out);
+            for value in values {
+                write(value, out);
+            }
+        }
+        Value::Object(fields) => {
+            write_len(fields.len(), [0x80, 0, 0xde, 0xdf], 16, out);
+            for (key, value) in fields {
+                write(&Value::String(key.clone()), out);
+                write(value, out);
+            }
+        }
+    }
+}
+
+fn write_number(number: &Number, out: &mut Vec<u8>) {
+    if let Some(n) = number.as_u64() {
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    } else if let Some(n) = number.as_i64() {
+        // Negative, or it would have been a u64.
+        match n {
+            -32..=-1 => out.push(n as u8),
+            -0x80..=-33 => out.extend_from_slice(&[0xd0, n as u8]),
+            -0x8000..=-0x81 => {
+                out.push(0xd1);
+                out.extend_from_slice(&(n as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                out.push(0xd2);
+                out.extend_from_slice(&(n as i32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xd3);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    } else {
+        out.push(0xcb);
+        out.extend_from_slice(&number.as_f64().unwrap_or_default().to_be_bytes());
+    }
+}
+
+/// Writes the header of a string, array or map of `len`, with `markers`
+/// being its fixed-size marker, to be or-ed with lengths below `fixed`,
+/// then those of its 8, 16 and 32-bit lengths; arrays and maps have no
+/// 8-bit one.
+fn write_len(len: usize, markers: [u8; 4], fixed: usize, out: &mut Vec<u8>) {
+    match len {
+        _ if len < fixed => out.push(markers[0] | len as u8),
+        0..=0xff if markers[1] != 0 => out.extend_from_slice(&[markers[1], len as u8]),
+        0..=0xffff => {
+            out.push(markers[2]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(markers[3]);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn value(&mut self) -> Result<Value, RpcError> {
+        let marker = self.take::<1>()?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map(usize::from(marker & 0x0f))?,
+            0x90..=0x9f => self.array(usize::from(marker & 0x0f))?,
+            0xa0..=0xbf => self.string(usize::from(marker & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f32::from_be_bytes(self.take()?).into())?,
+            0xcb => float(f64::from_be_bytes(self.take()?))?,
+            0xcc => Value::from(u8::from_be_bytes(self.take()?)),
+            0xcd => Value::from(u16::from_be_bytes(self.take()?)),
+            0xce => Value::from(u32::from_be_bytes(self.take()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.take()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.take()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.take()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.take()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.take()?)),
+            0xd9 => {
+                let len = u8::from_be_bytes(self.take()?);
+                self.string(usize::from(len))?
+            }
+            0xda => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.string(usize::from(len))?
+            }
+            0xdb => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.string(len as usize)?
+            }
+            0xdc => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.array(usize::from(len))?
+            }
+            0xdd => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.array(len as usize)?
+            }
+            0xde => {
+                let len = u16::from_be_bytes(self.take()?);
+                self.map(usize::from(len))?
+            }
+            0xdf => {
+                let len = u32::from_be_bytes(self.take()?);
+                self.map(len as usize)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            _ => return Err(malformed(&format!("unsupported type {marker:#04x}"))),
+        })
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], RpcError> {
+        let bytes = self.slice(N)?;
+        Ok(bytes.try_into().expect("slice of N bytes"))
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&[u8], RpcError> {
+        if self.bytes.len() < len {
+            return Err(malformed("data ends mid-value"));
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self, len: usize) -> Result<Value, RpcError> {
+        let bytes = self.slice(len)?;
+        let string = std::str::from_utf8(bytes).map_err(|_| malformed("string is not UTF-8"))?;
+        Ok(Value::String(string.to_string()))
+    }
+
+    fn array(&mut self, len: usize) -> Result<Value, RpcError> {
+        self.nest(|reader| {
+            // Every value takes a byte at least, which bounds what a
+            // corrupt length can make us allocate.
+            let mut values = Vec::with_capacity(len.min(reader.bytes.len()));
+            for _ in 0..len {
+                values.push(reader.value()?);
+            }
+            Ok(Value::Array(values))
+        })
+    }
+
+    fn map(&mut self, len: usize) -> Result<Value, RpcError> {
+        self.nest(|reader| {
+            let mut fields = Map::new();
+            for _ in 0..len {
+                let Value::String(key) = reader.value()? else {
+                    return Err(malformed("map key is not a string"));
+                };
+                fields.insert(key, reader.value()?);
+            }
+            Ok(Value::Object(fields))
+        })
+    }
+
+    fn nest(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<Value, RpcError>,
+    ) -> Result<Value, RpcError> {
+        if self.depth == MAX_DEPTH {
+            return Err(malformed("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = read(self);
+        self.depth -= 1;
+        value
+    }
+}
+
+fn float(f: f64) -> Result<Value, RpcError> {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .ok_or_else(|| malformed("float is not finite"))
+}
+
+fn malformed(text: &str) -> RpcError {
+    RpcError::new(ErrorCode::MalformedRequest, format!("msgpack: {text}"))
+}
@@ -0,0 +1,310 @@
+//! Exporting [spans](mod@crate::trace) over OTLP, so traces of a request can be
+//! followed across nodes in Jaeger or anything else that takes OTLP.
+//!
+//! Messages sent while a message is handled carry the handling span in
+//! their body as a W3C `traceparent`, and the span of the node handling
+//! them in turn becomes its child, in the same trace. Messages that come
+//! without one, such as requests from clients, and ticks, start traces of
+//! their own.
+//!
+//! Spans are batched and sent as OTLP/HTTP JSON to `/v1/traces` under the
+//! endpoint set up with [`init`] (`WHIRLPOOL_OTEL_ENDPOINT`), e.g.
+//! `http://localhost:4318` for a Jaeger all-in-one. Each node shows up as
+//! a service of its own. Plain HTTP only. Built with the `otel` feature.
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Write},
+    sync::{mpsc, OnceLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How long finished spans are held, at most, before they are sent.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// How many finished spans are sent at once, at most.
+const MAX_BATCH: usize = 512;
+
+/// How long a collector may take to answer, and [`flush`] may wait.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `SPAN_KIND_INTERNAL` and `SPAN_KIND_SERVER`, as OTLP numbers them.
+const INTERNAL: u8 = 1;
+const SERVER: u8 = 2;
+
+static EXPORTER: OnceLock<mpsc::Sender<Command>> = OnceLock::new();
+
+/// The node spans are from, for the ones of ticks, which aren't addressed.
+static NODE: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: Cell<Option<SpanContext>> = const { Cell::new(None) };
+}
+
+/// What identifies a span: the trace it is part of, and itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+impl SpanContext {
+    /// Reads a W3C `traceparent`: `00-<trace id>-<span id>-<flags>`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let (Some("00"), Some(trace_id), Some(span_id), Some(_flags), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return None;
+        };
+        if trace_id.len() != 32 || span_id.len() != 16 {
+            return None;
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+        };
+        // All-zero ids are invalid, as the spec has it.
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+
+    /// This span as a sampled W3C `traceparent`.
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+
+    /// A new span in `parent`'s trace, or in a new trace without one.
+    fn child_of(parent: Option<SpanContext>) -> Self {
+        Self {
+            trace_id: parent.map_or_else(|| rand::random::<u128>().max(1), |p| p.trace_id),
+            span_id: rand::random::<u64>().max(1),
+        }
+    }
+}
+
+/// The span being handled on this thread, if spans are exported.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(Cell::get)
+}
+
+/// Makes `context` the span being handled on this thread.
+#[cfg(feature = "async")]
+pub(crate) fn set_current(context: Option<SpanContext>) {
+    CURRENT.with(|current| current.set(context));
+}
+
+pub(crate) fn enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+enum Command {
+    /// A finished span, and the node it is from.
+    Export(String, Value),
+    /// Send what is held now, and say so.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Exports spans to the OTLP/HTTP collector at `endpoint` from now on.
+/// Only the first call has any effect.
+pub fn init(endpoint: &str) -> anyhow::Result<()> {
+    let Some(rest) = endpoint.strip_prefix("http://") else {
+        bail!("OTLP endpoint {endpoint} isn't http://host:port");
+    };
+    let (addr, base) = match rest.split_once('/') {
+        Some((addr, base)) => (addr.to_string(), format!("/{}", base.trim_end_matches('/'))),
+        None => (rest.to_string(), String::new()),
+    };
+    let path = format!("{base}/v1/traces");
+    let (tx, commands) = mpsc::channel();
+    if EXPORTER.set(tx).is_err() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("otel".into())
+        .spawn(move || export(commands, &addr, &path))
+        .context("starting the OTLP exporter")?;
+    Ok(())
+}
+
+/// Sends the spans finished so far, waiting for the collector to take them,
+/// for a while.
+pub fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let (done, flushed) = mpsc::channel();
+    if exporter.send(Command::Flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(TIMEOUT);
+    }
+}
+
+/// A span being handled, current on its thread until it is finished or
+/// dropped.
+pub(crate) struct Active {
+    context: SpanContext,
+    parent: Option<SpanContext>,
+    node: Option<String>,
+    previous: Option<SpanContext>,
+}
+
+/// Starts a span, a child of `traceparent` if it is one, for handling a
+/// message to `node`, or a tick. `None` unless spans are exported.
+pub(crate) fn start(traceparent: Option<&str>, node: Option<&str>) -> Option<Active> {
+    if !enabled() {
+        return None;
+    }
+    let parent = traceparent.and_then(SpanContext::parse);
+    let context = SpanContext::child_of(parent);
+    let previous = CURRENT.with(|current| current.replace(Some(context)));
+    if let Some(node) = node {
+        NODE.get_or_init(|| node.to_string());
+    }
+    Some(Active {
+        context,
+        parent,
+        node: node.map(str::to_string),
+        previous,
+    })
+}
+
+impl Active {
+    #[cfg(feature = "async")]
+    pub(crate) fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    /// Hands the span, started at `started_at` and taking `duration`, to
+    /// the exporter.
+    pub(crate) fn finish(
+        self,
+        name: &str,
+        src: &str,
+        msg_id: Option<usize>,
+        started_at: SystemTime,
+        duration: Duration,
+    ) {
+        let start = started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let node = self.node_name().to_string();
+        let mut attributes = vec![attribute("whirlpool.node", &node)];
+        if !src.is_empty() {
+            attributes.push(attribute("whirlpool.src", src));
+        }
+        if let Some(msg_id) = msg_id {
+            let value = json!({"intValue": msg_id.to_string()});
+            attributes.push(json!({"key": "whirlpool.msg_id", "value": value}));
+        }
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.context.trace_id),
+            "spanId": format!("{:016x}", self.context.span_id),
+            "name": name,
+            "kind": if self.node.is_some() { SERVER } else { INTERNAL },
+            "startTimeUnixNano": start.as_nanos().to_string(),
+            "endTimeUnixNano": (start + duration).as_nanos().to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = format!("{:016x}", parent.span_id).into();
+        }
+        if let Some(exporter) = EXPORTER.get() {
+            let _ = exporter.send(Command::Export(node, span));
+        }
+    }
+
+    fn node_name(&self) -> &str {
+        self.node
+            .as_deref()
+            .or(NODE.get().map(String::as_str))
+            .unwrap_or("whirlpool")
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+/// Batches spans from `commands` and posts them to `path` on `addr` until
+/// every sender is gone.
+fn export(commands: mpsc::Receiver<Command>, addr: &str, path: &str) {
+    let mut batch = Vec::new();
+    loop {
+        let (flushed, stop) = match commands.recv_timeout(BATCH_DELAY) {
+            Ok(Command::Export(node, span)) => {
+                batch.push((node, span));
+                if batch.len() < MAX_BATCH {
+                    continue;
+                }
+                (None, false)
+            }
+            Ok(Command::Flush(done)) => (Some(done), false),
+            Err(mpsc::RecvTimeoutError::Timeout) => (None, false),
+            Err(mpsc::RecvTimeoutError::Disconnected) => (None, true),
+        };
+        if !batch.is_empty() {
+            let request = request(std::mem::take(&mut batch));
+            if let Err(e) = post(addr, path, &request.to_string()) {
+                crate::warn!("exporting spans to {addr}: {e}");
+            }
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+        if stop {
+            break;
+        }
+    }
+}
+
+/// An `ExportTraceServiceRequest` for `spans`, with each node as a
+/// service.
+fn request(spans: Vec<(String, Value)>) -> Value {
+    let mut by_node: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (node, span) in spans {
+        by_node.entry(node).or_default().push(span);
+    }
+    let resource_spans: Vec<_> = by_node
+        .into_iter()
+        .map(|(node, spans)| {
+            json!({
+                "resource": {"attributes": [attribute("service.name", &node)]},
+                "scopeSpans": [{"scope": {"name": "whirlpool"}, "spans": spans}],
+            })
+        })
+        .collect();
+    json!({ "resourceSpans": resource_spans })
+}
+
+fn post(addr: &str, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = crate::tcp::dial(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector answered {}",
+            status.trim_end()
+        ))),
+    }
+}
@@ -0,0 +1,259 @@
+//! The writer stage of the main loop, which coalesces outgoing messages
+//! into fewer, larger writes.
+//!
+//! Handlers write into an outbox, which hands what they wrote to the
+//! writer thread in one chunk per handled event, over a bounded channel.
+//! The writer collects chunks in an [`Output`] buffer and passes it on in
+//! one write when it runs out of chunks to take, when the buffer grows past
+//! [`FlushPolicy::max_bytes`], or when its oldest byte has waited
+//! [`FlushPolicy::max_delay`]. Anything that blocks waiting for a reply
+//! must flush first, as [`Rpc::call`](crate::Rpc::call) does.
+//!
+//! Messages to other nodes, gossip and replication, are bulk: with
+//! [`Config::reply_priority`] the writer sets them aside while more chunks
+//! are waiting, up to [`FlushPolicy::max_bytes`] of them, so replies to
+//! clients, and calls to services, taken after them still go out first.
+//! Messages to one destination keep their order either way.
+//!
+//! With [`Config::rate_limits`] set, chunks pass through a [`Limiter`] on
+//! the way, and the writer wakes up to write the messages it held back once
+//! their tokens come.
+
+use crate::{metrics, ratelimit::Limiter, Config};
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    io::{self, Write},
+    sync::mpsc,
+    thread::{Scope, ScopedJoinHandle},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    /// Flush once this many bytes are buffered.
+    pub max_bytes: usize,
+    /// Flush once the oldest buffered byte is this old, even if the main
+    /// loop is still busy.
+    pub max_delay: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024,
+            max_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+/// A write buffer in front of `W` that flushes according to a
+/// [`FlushPolicy`].
+pub struct Output<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    policy: FlushPolicy,
+    /// When the first byte currently in `buf` was written.
+    since: Option<Instant>,
+}
+
+impl<W: Write> Output<W> {
+    pub fn new(inner: W, policy: FlushPolicy) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(policy.max_bytes),
+            policy,
+            since: None,
+        }
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        self.since = None;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let since = *self.since.get_or_insert_with(Instant::now);
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= self.policy.max_bytes || since.elapsed() >= self.policy.max_delay {
+            self.flush_buf()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for Output<W> {
+    fn drop(&mut self) {
+        // Nobody is left to hear about a failure at this point.
+        let _ = self.flush();
+    }
+}
+
+/// A handler's side of the writer thread: buffers until flushed, then
+/// hands the writer everything as one chunk, so output from different
+/// handlers never interleaves. Clones start with an empty buffer.
+pub(crate) struct Outbox {
+    buf: Vec<u8>,
+    tx: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl Clone for Outbox {
+    fn clone(&self) -> Self {
+        Self {
+            buf: Vec::new(),
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl Outbox {
+    /// Tells the writer to finish once it has written everything handed to
+    /// it so far, even if other clones are still around.
+    pub(crate) fn close(mut self) -> io::Result<()> {
+        self.flush()?;
+        // An empty chunk is the signal; flushing never sends one.
+        let _ = self.tx.send(Vec::new());
+        Ok(())
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            // Counted in first, as the writer may take it out before
+            // this thread gets to run again.
+            metrics::record_queued("output");
+            if self.tx.send(std::mem::take(&mut self.buf)).is_err() {
+                metrics::record_dequeued("output");
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts the writer thread on `scope`. It runs until [`Outbox::close`] is
+/// called or every [`Outbox`] cloned from the returned one is gone; its
+/// handle yields any error it hit writing to `out`.
+pub(crate) fn spawn_writer<'scope, W>(
+    scope: &'scope Scope<'scope, '_>,
+    out: W,
+    config: &Config,
+) -> (Outbox, ScopedJoinHandle<'scope, anyhow::Result<()>>)
+where
+    W: Write + Send + 'scope,
+{
+    let (tx, chunks) = mpsc::sync_channel::<Vec<u8>>(config.queue_capacity);
+    let policy = config.flush;
+    let mut limiter = config
+        .rate_limits
+        .is_enabled()
+        .then(|| Limiter::new(config.rate_limits));
+    let reply_priority = config.reply_priority;
+    let writer = scope.spawn(move || -> anyhow::Result<()> {
+        let mut out = Output::new(out, policy);
+        // Messages to other nodes held back for those to anyone else.
+        let mut bulk = Vec::new();
+        loop {
+            let chunk = match chunks.try_recv() {
+                Ok(chunk) => chunk,
+                // Nothing else to coalesce with right now.
+                Err(mpsc::TryRecvError::Empty) => {
+                    emit(&mut out, &std::mem::take(&mut bulk))?;
+                    out.flush().context("flushing output")?;
+                    let now = Instant::now();
+                    let next = limiter.as_ref().and_then(|l| l.next_release(now));
+                    match next {
+                        None => match chunks.recv() {
+                            Ok(chunk) => chunk,
+                            Err(_) => break,
+                        },
+                        Some(at) => match chunks.recv_timeout(at.saturating_duration_since(now)) {
+                            Ok(chunk) => chunk,
+                            Err(mpsc::RecvTimeoutError::Timeout) => {
+                                if let Some(limiter) = &mut limiter {
+                                    emit(&mut out, &limiter.release(Instant::now()))?;
+                                }
+                                continue;
+                            }
+                            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                        },
+                    }
+                }
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            metrics::record_dequeued("output");
+            let chunk = match &mut limiter {
+                Some(limiter) => limiter.admit(&chunk, Instant::now()),
+                None => chunk,
+            };
+            if !reply_priority {
+                emit(&mut out, &chunk)?;
+                continue;
+            }
+            for line in chunk.split_inclusive(|&b| b == b'\n') {
+                match to_node(line).is_some() {
+                    true => bulk.extend_from_slice(line),
+                    false => emit(&mut out, line)?,
+                }
+            }
+            if bulk.len() >= policy.max_bytes {
+                emit(&mut out, &std::mem::take(&mut bulk))?;
+            }
+        }
+        emit(&mut out, &bulk)?;
+        if let Some(limiter) = &mut limiter {
+            emit(&mut out, &limiter.drain())?;
+        }
+        out.flush().context("flushing output")
+    });
+    (
+        Outbox {
+            buf: Vec::new(),
+            tx,
+        },
+        writer,
+    )
+}
+
+/// Writes `chunk` to `out`, recording it first.
+fn emit<W: Write>(out: &mut Output<W>, chunk: &[u8]) -> anyhow::Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    crate::record::outbound(chunk);
+    out.write_all(chunk).context("writing output")
+}
+
+/// Only the destination of a message.
+#[derive(Deserialize)]
+struct Dest {
+    dest: String,
+}
+
+/// The destination of `line`, a message as written, if it is another
+/// node, as Maelstrom names them, rather than a client or a service.
+pub(crate) fn to_node(line: &[u8]) -> Option<String> {
+    let Dest { dest } = serde_json::from_slice(line.trim_ascii_end()).ok()?;
+    let index = dest.strip_prefix('n')?;
+    (!index.is_empty() && index.bytes().all(|b| b.is_ascii_digit())).then_some(dest)
+}
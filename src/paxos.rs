@@ -0,0 +1,343 @@
+//! Single-decree Paxos: the cluster agrees on one value, whoever proposes
+//! what. Meant for teaching, and for comparing against [`raft`](crate::raft).
+//!
+//! Every [`PaxosNode`] plays all three roles:
+//!
+//! - as **proposer**, it takes a client's `propose` and runs rounds for it:
+//!   `prepare` with a ballot higher than any it has seen, and once a
+//!   majority `promise`s to ignore lower ballots, `accept` with the value
+//!   accepted under the highest ballot among the promises, or the client's
+//!   own if there is none. A round that stalls is retried with a higher
+//!   ballot after a randomized timeout, so duelling proposers settle down.
+//! - as **acceptor**, it promises and accepts as long as nothing with a
+//!   higher ballot came first, and tells every learner what it accepted.
+//! - as **learner**, it counts `accepted`s, and once a majority accepted
+//!   one ballot, that ballot's value is chosen; waiting clients get
+//!   `propose_ok` with it.
+//!
+//! Messages to the node itself are handled in place rather than sent.
+
+use crate::{
+    payload::ReadValue, time, Membership, Message, MsgIdAllocator, Node, Payload, RpcError,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// How long a proposer waits for a round to succeed before retrying it
+/// with a higher ballot.
+const ROUND_TIMEOUT: Range<Duration> = Duration::from_millis(100)..Duration::from_millis(300);
+
+/// A proposal number, unique across the cluster: ordered by round, with
+/// the proposer's id breaking ties.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: String,
+}
+
+/// A value an acceptor accepted, and under which ballot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub ballot: Ballot,
+    pub value: Value,
+}
+
+#[derive(Debug, Default)]
+pub struct Acceptor {
+    /// The highest ballot promised; lower ones are ignored.
+    promised: Option<Ballot>,
+    accepted: Option<Proposal>,
+}
+
+impl Acceptor {
+    /// Phase 1b: promises to ignore anything below `ballot`, unless a
+    /// higher ballot was promised already.
+    pub fn prepare(&mut self, ballot: &Ballot) -> Payload {
+        let promised = self.promised.as_ref().is_none_or(|p| ballot > p);
+        if promised {
+            self.promised = Some(ballot.clone());
+        }
+        Payload::Promise {
+            ballot: self.promised.clone().unwrap_or_else(|| ballot.clone()),
+            promised,
+            accepted: self.accepted.clone(),
+        }
+    }
+
+    /// Phase 2b: accepts `value` unless a higher ballot was promised.
+    pub fn accept(&mut self, ballot: &Ballot, value: &Value) -> Option<Proposal> {
+        if self.promised.as_ref().is_some_and(|p| ballot < p) {
+            return None;
+        }
+        self.promised = Some(ballot.clone());
+        let proposal = Proposal {
+            ballot: ballot.clone(),
+            value: value.clone(),
+        };
+        self.accepted = Some(proposal.clone());
+        Some(proposal)
+    }
+}
+
+#[derive(Debug)]
+enum Phase {
+    Idle,
+    /// Waiting for a majority of promises, which are collected here.
+    Preparing(HashMap<String, Option<Proposal>>),
+    Accepting,
+}
+
+#[derive(Debug)]
+pub struct Proposer {
+    phase: Phase,
+    ballot: Option<Ballot>,
+    /// The highest round seen in any ballot, ours or not.
+    highest_round: u64,
+    /// What to propose if no value was accepted yet.
+    value: Option<Value>,
+    deadline: Instant,
+}
+
+impl Default for Proposer {
+    fn default() -> Self {
+        Self {
+            phase: Phase::Idle,
+            ballot: None,
+            highest_round: 0,
+            value: None,
+            deadline: time::now(),
+        }
+    }
+}
+
+impl Proposer {
+    fn observe(&mut self, ballot: &Ballot) {
+        self.highest_round = self.highest_round.max(ballot.round);
+    }
+
+    fn is_idle(&self) -> bool {
+        matches!(self.phase, Phase::Idle)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Learner {
+    /// Who accepted each ballot.
+    accepted: HashMap<Ballot, HashSet<String>>,
+    chosen: Option<Value>,
+}
+
+impl Learner {
+    /// Counts `acceptor`'s acceptance of `proposal`. Returns the value if
+    /// this made it chosen.
+    fn learn(&mut self, acceptor: &str, proposal: Proposal, majority: usize) -> Option<Value> {
+        if self.chosen.is_some() {
+            return None;
+        }
+        let acceptors = self.accepted.entry(proposal.ballot).or_default();
+        acceptors.insert(acceptor.to_string());
+        if acceptors.len() < majority {
+            return None;
+        }
+        self.accepted.clear();
+        self.chosen = Some(proposal.value.clone());
+        Some(proposal.value)
+    }
+}
+
+/// Serves `propose` and `read` for a single value decided by Paxos.
+#[derive(Debug, Default)]
+pub struct PaxosNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub acceptor: Acceptor,
+    pub proposer: Proposer,
+    pub learner: Learner,
+    /// Clients waiting for the value to be chosen.
+    waiting: Vec<Message<()>>,
+}
+
+impl PaxosNode {
+    /// The chosen value, once this node has learned it.
+    pub fn chosen(&self) -> Option<&Value> {
+        self.learner.chosen.as_ref()
+    }
+
+    fn majority(&self) -> usize {
+        self.membership.node_ids.len() / 2 + 1
+    }
+
+    /// Sends `payload` to every node, handling our own copy in place.
+    fn broadcast(&mut self, payload: Payload, out: &mut impl Write) -> anyhow::Result<()> {
+        for peer in self.membership.peers() {
+            Message::new(&self.membership.node_id, peer, None, payload.clone()).send(out)?;
+        }
+        let me = self.membership.node_id.clone();
+        let msg = Message::new(&me, &me, None, payload);
+        self.handle_peer(msg, out)
+    }
+
+    /// Starts a new round with a ballot higher than any seen.
+    fn start_round(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let ballot = Ballot {
+            round: self.proposer.highest_round + 1,
+            node: self.membership.node_id.clone(),
+        };
+        crate::debug!("starting paxos round {}", ballot.round);
+        self.proposer.observe(&ballot);
+        self.proposer.ballot = Some(ballot.clone());
+        self.proposer.phase = Phase::Preparing(HashMap::new());
+        let timeout = time::rng().gen_range(ROUND_TIMEOUT);
+        self.proposer.deadline = time::now() + timeout;
+        self.broadcast(Payload::Prepare { ballot }, out)
+    }
+
+    fn promised(
+        &mut self,
+        acceptor: &str,
+        ballot: Ballot,
+        accepted: Option<Proposal>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let majority = self.majority();
+        let Phase::Preparing(promises) = &mut self.proposer.phase else {
+            return Ok(());
+        };
+        if self.proposer.ballot.as_ref() != Some(&ballot) {
+            return Ok(());
+        }
+        promises.insert(acceptor.to_string(), accepted);
+        if promises.len() < majority {
+            return Ok(());
+        }
+        // A value some acceptor already accepted may have been chosen, so
+        // it has to be proposed again instead of ours.
+        let value = promises
+            .values()
+            .flatten()
+            .max_by(|a, b| a.ballot.cmp(&b.ballot))
+            .map(|proposal| proposal.value.clone())
+            .or_else(|| self.proposer.value.clone())
+            .unwrap_or_default();
+        self.proposer.phase = Phase::Accepting;
+        self.broadcast(Payload::Accept { ballot, value }, out)
+    }
+
+    /// Handles Paxos messages between nodes.
+    fn handle_peer(&mut self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let me = self.membership.node_id.clone();
+        match msg.body.payload {
+            Payload::Prepare { ballot } => {
+                self.proposer.observe(&ballot);
+                let promise = self.acceptor.prepare(&ballot);
+                if msg.src == me {
+                    let reply = Message::new(&me, &me, None, promise);
+                    return self.handle_peer(reply, out);
+                }
+                Message::new(&me, &msg.src, None, promise).send(out)?;
+            }
+            Payload::Promise {
+                ballot,
+                promised: true,
+                accepted,
+            } => self.promised(&msg.src, ballot, accepted, out)?,
+            Payload::Promise { ballot, .. } => self.proposer.observe(&ballot),
+            Payload::Accept { ballot, value } => {
+                self.proposer.observe(&ballot);
+                if let Some(Proposal { ballot, value }) = self.acceptor.accept(&ballot, &value) {
+                    self.broadcast(Payload::Accepted { ballot, value }, out)?;
+                }
+            }
+            Payload::Accepted { ballot, value } => {
+                let majority = self.majority();
+                let proposal = Proposal { ballot, value };
+                if let Some(value) = self.learner.learn(&msg.src, proposal, majority) {
+                    crate::info!("paxos chose {value}");
+                    self.proposer.phase = Phase::Idle;
+                    for client in std::mem::take(&mut self.waiting) {
+                        let payload = Payload::ProposeOk {
+                            value: value.clone(),
+                        };
+                        client
+                            .into_reply(
+                                &self.membership.node_id,
+                                Some(self.msg_ids.next()),
+                                payload,
+                            )
+                            .send(out)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Node for PaxosNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                Payload::InitOk
+            }
+            Payload::Propose { value } => {
+                if let Some(chosen) = self.chosen() {
+                    Payload::ProposeOk {
+                        value: chosen.clone(),
+                    }
+                } else {
+                    self.waiting.push(input.header());
+                    self.proposer.value.get_or_insert_with(|| value.clone());
+                    if self.proposer.is_idle() {
+                        self.start_round(output)?;
+                    }
+                    return Ok(());
+                }
+            }
+            Payload::Read { key: None, .. } => match self.chosen() {
+                Some(value) => Payload::ReadOk {
+                    value: ReadValue::Value {
+                        value: value.clone(),
+                    },
+                },
+                None => return Err(RpcError::key_does_not_exist("nothing chosen yet").into()),
+            },
+            Payload::Prepare { .. }
+            | Payload::Promise { .. }
+            | Payload::Accept { .. }
+            | Payload::Accepted { .. } => return self.handle_peer(input, output),
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => {
+                return Err(RpcError::not_supported("paxos node cannot handle this message").into())
+            }
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(20))
+    }
+
+    /// Retries a round that took too long, as long as a client is waiting.
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let stalled = !self.proposer.is_idle() && time::now() >= self.proposer.deadline;
+        if stalled && self.chosen().is_none() && !self.waiting.is_empty() {
+            self.start_round(out)?;
+        }
+        Ok(())
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
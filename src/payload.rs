@@ -1,12 +1,29 @@
-use crate::HashMap;
-use serde::{ Serialize, Deserialize};
+use crate::bloom::BloomFilter;
+use crate::clock::VectorClock;
+use crate::compress::{Encoding, Packed};
+use crate::error::ErrorCode;
+use crate::handshake::Capability;
+use crate::kafka::{Offsets, Records};
+use crate::kv::quorum::Consistency;
+use crate::paxos::{Ballot, Proposal};
+use crate::raft::LogEntry;
+use crate::services::Versioned;
+use crate::swim::MemberUpdate;
+use crate::txn::Op;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum Payload {
     Add {
-        delta: usize,
+        #[serde(flatten)]
+        value: AddValue,
+        /// Which of the CRDTs of a [`crate::crdt::CrdtMap`] to add to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Value>,
     },
     AddOk,
     Echo {
@@ -21,20 +38,370 @@ pub enum Payload {
     },
     InitOk,
     Generate,
+    /// Any JSON value will do, as long as it is unique.
     GenerateOk {
-        id: String,
+        id: Value,
     },
     Broadcast {
         message: usize,
     },
     BroadcastOk,
-    Read,
+    Gossip {
+        messages: Vec<usize>,
+        /// The sender's clock, if it tracks one, so the receiver can tell
+        /// whether it is missing values and send a `catch_up`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+        /// More values, packed, when there are enough of them for that to
+        /// pay; see [`crate::compress`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        packed: Option<Packed>,
+        /// More values, from a sender that keeps a clock, by the node they
+        /// originated on, each with its place among that node's values,
+        /// counting from 1, for the receiver's clock to count them too.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        origins: HashMap<String, Vec<(u64, usize)>>,
+        /// The encodings the sender can read values packed in.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        accepts: Vec<Encoding>,
+    },
+    GossipOk,
+    /// Asks a peer for the broadcast values it has beyond `clock`.
+    CatchUp {
+        clock: VectorClock,
+    },
+    /// The values the requester was missing, by the node they originated
+    /// on, and the responder's clock after them.
+    CatchUpOk {
+        clock: VectorClock,
+        values: HashMap<String, Vec<usize>>,
+    },
+    /// Workloads read without a key; the KV services and workloads read
+    /// one key.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Value>,
+        /// Overrides how many replicas answer, for
+        /// [`QuorumKvNode`](crate::kv::quorum::QuorumKvNode).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consistency: Option<Consistency>,
+    },
     ReadOk {
-        value: usize,
+        #[serde(flatten)]
+        value: ReadValue,
     },
+    Write {
+        key: Value,
+        value: Value,
+        /// Overrides how many replicas acknowledge, for
+        /// [`QuorumKvNode`](crate::kv::quorum::QuorumKvNode).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consistency: Option<Consistency>,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
     TopologyOk,
     Topology {
-        topology: HashMap<String, Vec<usize>>,
+        topology: HashMap<String, Vec<String>>,
+    },
+    Send {
+        key: String,
+        msg: i64,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: Offsets,
+    },
+    PollOk {
+        msgs: Records,
+    },
+    CommitOffsets {
+        offsets: Offsets,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: Offsets,
+    },
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    /// Raft: a candidate asks for this node's vote in `term`. Only granted
+    /// if the candidate's log is at least as up to date as the voter's.
+    RequestVote {
+        term: u64,
+        candidate_id: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+    },
+    /// Raft: the leader of `term` replicating `entries`, which follow the
+    /// entry at `prev_log_index`. Empty ones serve as heartbeats.
+    AppendEntries {
+        term: u64,
+        leader_id: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// On success, `match_index` is the last entry the follower now shares
+    /// with the leader; otherwise, a hint at where their logs may match.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// Raft: the part of the leader's snapshot starting at byte `offset`,
+    /// for a follower that needs entries compacted into it. The snapshot
+    /// covers the log up to `last_included_index`.
+    InstallSnapshot {
+        term: u64,
+        leader_id: String,
+        last_included_index: u64,
+        last_included_term: u64,
+        offset: usize,
+        data: String,
+        /// How the snapshot is encoded, if not as JSON; see
+        /// [`crate::raft::snapshot`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+        done: bool,
+    },
+    /// `offset` is how much of the snapshot the follower has, and so where
+    /// the next chunk starts.
+    InstallSnapshotOk {
+        term: u64,
+        offset: usize,
+    },
+    /// Paxos: a client proposing `value`. The reply carries the value that
+    /// was chosen, which need not be this one.
+    Propose {
+        value: Value,
+    },
+    ProposeOk {
+        value: Value,
+    },
+    /// Paxos phase 1a: a proposer asking acceptors to ignore ballots below
+    /// `ballot`.
+    Prepare {
+        ballot: Ballot,
+    },
+    /// If not `promised`, `ballot` is the higher one the acceptor promised
+    /// instead. `accepted` is the last proposal it accepted, if any.
+    Promise {
+        ballot: Ballot,
+        promised: bool,
+        accepted: Option<Proposal>,
+    },
+    /// Paxos phase 2a: a proposer asking acceptors to accept `value`.
+    Accept {
+        ballot: Ballot,
+        value: Value,
+    },
+    /// An acceptor telling the learners it accepted `value`.
+    Accepted {
+        ballot: Ballot,
+        value: Value,
+    },
+    /// Two-phase commit: a coordinator asking a participant to run `txn`,
+    /// its share of a transaction, and vote on committing it.
+    TxnPrepare {
+        txn_id: String,
+        txn: Vec<Op>,
+    },
+    /// A participant's vote. If it is to `commit`, `txn` holds the ops as
+    /// run, with the values read.
+    TxnVote {
+        txn_id: String,
+        commit: bool,
+        txn: Vec<Op>,
+    },
+    TxnCommit {
+        txn_id: String,
+    },
+    TxnAbort {
+        txn_id: String,
     },
+    /// A participant acknowledging `txn_commit` or `txn_abort`.
+    TxnDone {
+        txn_id: String,
+    },
+    /// SWIM: a failure detector probing a peer. Every SWIM message carries
+    /// the latest membership `updates`.
+    Ping {
+        updates: Vec<MemberUpdate>,
+    },
+    PingOk {
+        updates: Vec<MemberUpdate>,
+    },
+    /// Asks a peer to probe `target` on our behalf.
+    PingReq {
+        target: String,
+        updates: Vec<MemberUpdate>,
+    },
+    /// `target` answered the probe made for a `ping_req`.
+    PingReqOk {
+        target: String,
+    },
+    /// A sign of life, sent to every peer periodically; see
+    /// [`crate::heartbeat`]. Not answered.
+    Heartbeat,
+    /// `node` joining the cluster after `init`. The new node sends it to a
+    /// sponsor, which passes it on to every other node.
+    Join {
+        node: String,
+    },
+    /// The sponsor's answer to the new node carries the cluster, itself
+    /// included, and the broadcast values seen so far. Other nodes answer
+    /// with neither.
+    JoinOk {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        node_ids: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        messages: Vec<usize>,
+    },
+    /// `node` leaving the cluster. Sent to the node itself, it tells every
+    /// other node.
+    Leave {
+        node: String,
+    },
+    LeaveOk,
+    /// Quorum replication: a coordinator asking a replica for its copy of
+    /// `key`.
+    ReplicaRead {
+        key: Value,
+    },
+    ReplicaReadOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<Versioned<Value>>,
+    },
+    /// A coordinator asking a replica to store `value` as of `version`,
+    /// unless it has a later one.
+    ReplicaWrite {
+        key: Value,
+        value: Value,
+        version: u64,
+    },
+    ReplicaWriteOk,
+    /// Anti-entropy between replicas: the hashes of some nodes of the
+    /// sender's [`crate::MerkleTree`] of the keys both it and the receiver
+    /// replicate. The receiver answers those that differ with its own
+    /// children of them, or with `merkle_keys` for leaves.
+    MerkleDigest {
+        nodes: Vec<(usize, u64)>,
+    },
+    /// The sender's entries under the leaves `nodes`. The receiver stores
+    /// those newer than its own and, unless `nodes` is empty, answers with
+    /// its entries under them that the sender is missing or behind on.
+    MerkleKeys {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        nodes: Vec<usize>,
+        entries: Vec<(Value, Versioned<Value>)>,
+    },
+    /// Writes a coordinator kept as hints for the receiving replica while
+    /// it was unreachable, to store unless it has later versions.
+    Handoff {
+        entries: Vec<(Value, Versioned<Value>)>,
+    },
+    HandoffOk,
+    /// Takes `element` out of a set, for [`crate::crdt::OrSet`].
+    Remove {
+        element: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<Value>,
+    },
+    RemoveOk,
+    /// A replica's whole CRDT state, or a delta of its updates, for the
+    /// receiver to merge into its own; see [`crate::crdt`]. Either way it
+    /// covers the sender's first `seq` updates.
+    Merge {
+        state: Value,
+        seq: u64,
+    },
+    MergeOk {
+        seq: u64,
+    },
+    /// A broadcast value sent by the node it originated on, stamped with
+    /// how many values from each node it had delivered, this one included;
+    /// see [`crate::causal`].
+    CausalBroadcast {
+        message: usize,
+        clock: VectorClock,
+    },
+    CausalBroadcastOk,
+    /// A Bloom filter of the broadcast values the sender has seen, for the
+    /// receiver to answer with a `gossip` of the ones it lacks. Not
+    /// answered otherwise.
+    Digest {
+        filter: BloomFilter,
+    },
+    /// Asks a peer for the broadcast values it has, above `after` if
+    /// given, leaving out those `clock` says the sender has.
+    SyncRequest {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<usize>,
+    },
+    /// One chunk of the values asked for, ascending. Unless `done`, the
+    /// next starts above the last of these.
+    SyncResponse {
+        values: Vec<usize>,
+        done: bool,
+    },
+    /// The protocol version and capabilities of the sender, to be answered
+    /// with the receiver's in a `hello_ok`; see [`crate::handshake`].
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    HelloOk {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+/// The body of an `add`, which differs between workloads.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AddValue {
+    /// `g-counter` and `pn-counter`: negative deltas decrement.
+    Delta { delta: i64 },
+    /// `g-set`: an element to add to the set.
+    Element { element: Value },
+}
+
+/// The body of a `read_ok`, which differs between workloads.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ReadValue {
+    /// `broadcast`: every value seen so far.
+    Messages { messages: Vec<usize> },
+    /// `g-counter` and the KV services: a single value.
+    Value { value: Value },
 }
- 
@@ -0,0 +1,235 @@
+//! Handling messages on several threads at once.
+//!
+//! [`run_pool`] is the multi-threaded counterpart of [`run`](crate::run).
+//! Messages are sharded over `workers` threads by a hash of their `src`,
+//! so messages from any one peer or client are still handled in the order
+//! they arrived. Everything the workers write goes through the single
+//! writer thread, one handled event at a time.
+//!
+//! Since several threads handle messages at the same time, nodes implement
+//! [`SharedNode`], which takes `&self`. Any [`Node`] can be wrapped in a
+//! `Mutex` to get one, at the cost of handling one message at a time again.
+//! Ticks only come every [`SharedNode::tick_interval`], as
+//! [`Node::next_timer`] isn't asked.
+
+use crate::{
+    cancel_pending, dequeued, handle_unknown, input::InputSource, output, reply_on_rpc_error,
+    spawn_event_sources, trace::Span, Config, Event, Message, MsgIdAllocator, Node, Payload,
+    Replier, Rpc,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// A [`Node`] that can handle several messages concurrently.
+pub trait SharedNode<P = Payload>: Send + Sync + 'static {
+    fn handle(&self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()>;
+
+    /// See [`Node::tick_interval`].
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn tick(&self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// See [`Node::rpc`].
+    fn rpc(&self) -> Option<Rpc<P>> {
+        None
+    }
+
+    /// See [`Node::msg_ids`].
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.rpc().map(|rpc| rpc.msg_ids())
+    }
+
+    /// See [`Node::shutdown`]. Runs once every worker is done.
+    fn shutdown(&self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// See [`Node::inspect`].
+    fn inspect(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// See [`Node::topology`].
+    fn topology(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
+
+impl<P, N> SharedNode<P> for Mutex<N>
+where
+    N: Node<P> + Send + 'static,
+{
+    fn handle(&self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()> {
+        self.lock().unwrap().handle(msg, out)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.lock().unwrap().tick_interval()
+    }
+
+    fn tick(&self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.lock().unwrap().tick(out)
+    }
+
+    fn rpc(&self) -> Option<Rpc<P>> {
+        self.lock().unwrap().rpc()
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.lock().unwrap().msg_ids()
+    }
+
+    fn shutdown(&self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.lock().unwrap().shutdown(out)
+    }
+
+    fn inspect(&self) -> serde_json::Value {
+        self.lock().unwrap().inspect()
+    }
+
+    fn topology(&self) -> serde_json::Value {
+        self.lock().unwrap().topology()
+    }
+}
+
+fn shard(src: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    src.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// Feeds `node` with messages from `input` on `workers` threads, writing
+/// to `out`, until `input` is exhausted. Ticks are handled by the first
+/// worker.
+pub fn run_pool<P, N, W>(
+    node: N,
+    workers: usize,
+    config: &Config,
+    input: impl InputSource,
+    out: W,
+) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: SharedNode<P>,
+    W: Write + Send,
+{
+    config.apply()?;
+    let node = Arc::new(node);
+
+    let reader = thread::scope(|scope| {
+        let (mut out, writer) = output::spawn_writer(scope, out, config);
+        let replier = Replier::new(node.msg_ids().unwrap_or_default());
+        let ((_, events), reader) = spawn_event_sources(
+            input,
+            node.tick_interval(),
+            node.rpc(),
+            &replier,
+            config,
+            out.clone(),
+        )?;
+
+        let mut queues = Vec::new();
+        let mut workers_done = Vec::new();
+        for _ in 0..workers.max(1) {
+            let (tx, rx) = mpsc::sync_channel::<Event<P>>(config.queue_capacity);
+            let node = Arc::clone(&node);
+            let mut out = out.clone();
+            let replier = replier.clone();
+            queues.push(tx);
+            workers_done.push(scope.spawn(move || -> anyhow::Result<()> {
+                for event in rx {
+                    match event {
+                        Event::Message(input) => {
+                            let span = Span::message(&input);
+                            let request = input.header();
+                            let result = node.handle(input, &mut out);
+                            reply_on_rpc_error(result, &request, &replier, &mut out)?;
+                            span.finish();
+                        }
+                        Event::Tick => {
+                            let span = Span::tick();
+                            node.tick(&mut out).context("Node tick function failed")?;
+                            span.finish();
+                        }
+                        Event::Unknown(_) | Event::Shutdown => {}
+                        #[cfg(feature = "admin")]
+                        Event::Inspect(_) => {}
+                        #[cfg(feature = "async")]
+                        Event::Wake => {}
+                    }
+                    out.flush().context("handing output to the writer")?;
+                }
+                Ok(())
+            }));
+        }
+
+        let mut handled = 0;
+        let mut dispatch = || -> anyhow::Result<()> {
+            for event in &events {
+                handled += 1;
+                dequeued(&event);
+                let worker = match &event {
+                    Event::Message(msg) => shard(&msg.src, queues.len()),
+                    Event::Tick => 0,
+                    Event::Unknown(msg) => {
+                        handle_unknown(msg, config.unknown_messages, &replier, &mut out)?;
+                        out.flush().context("handing output to the writer")?;
+                        continue;
+                    }
+                    Event::Shutdown => break,
+                    #[cfg(feature = "admin")]
+                    Event::Inspect(inspect) => {
+                        inspect.answer(|| node.inspect(), || node.topology());
+                        continue;
+                    }
+                    #[cfg(feature = "async")]
+                    Event::Wake => continue,
+                };
+                // A worker only hangs up after failing; its error is
+                // collected below.
+                if queues[worker].send(event).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let dispatched = dispatch();
+        drop(queues);
+        let worked = workers_done
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("worker thread panicked"));
+        let result = worked.and(dispatched).and_then(|()| {
+            node.shutdown(&mut out)
+                .context("Node shutdown function failed")
+        });
+        let cancelled = cancel_pending(node.rpc());
+        reader.report_stop(handled, cancelled, || node.inspect());
+        let closed = out.close();
+        // If the writer failed, its error explains any the others hit.
+        writer.join().expect("writer thread panicked")?;
+        result?;
+        closed.context("closing output")?;
+        Ok::<_, anyhow::Error>(reader)
+    })?;
+
+    // A node stopped by a signal leaves its counters behind, enabled or not.
+    if crate::metrics::enabled() || reader.signalled() {
+        crate::metrics::report();
+    }
+    crate::prometheus::dump();
+    crate::trace::flush();
+
+    reader.join().context("reading input")
+}
@@ -0,0 +1,179 @@
+//! [`metrics`] in the Prometheus text format, for dashboarding long soak
+//! tests: messages received and sent per payload type, bytes sent,
+//! retries, evictions, handler latencies as histograms and the depths of
+//! the main loop's queues.
+//!
+//! Prometheus can scrape them from the [admin](crate::Config::admin)
+//! server's `/prometheus`, or, with [`init`], they are written to a file
+//! every so often for node_exporter's textfile collector to pick up:
+//!
+//! ```text
+//! # TYPE whirlpool_messages_received_total counter
+//! whirlpool_messages_received_total{type="broadcast"} 1042
+//! ...
+//! ```
+
+use crate::metrics::{self, Metrics};
+use anyhow::Context;
+use std::{
+    fmt::{self, Write as _},
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+static FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Enables [`metrics`] and writes them to `path` every `interval` from now
+/// on, and once more when the main loop returns. Each write replaces the
+/// file whole, so it is never read half-written. Only the first call has
+/// any effect.
+pub fn init(path: &Path, interval: Duration) -> anyhow::Result<()> {
+    if FILE.set(path.to_path_buf()).is_err() {
+        return Ok(());
+    }
+    metrics::enable();
+    write(path).with_context(|| format!("writing metrics to {}", path.display()))?;
+    thread::Builder::new()
+        .name("prometheus".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            dump();
+        })?;
+    Ok(())
+}
+
+/// Writes the metrics to the file set up with [`init`], if there is one.
+pub fn dump() {
+    let Some(path) = FILE.get() else {
+        return;
+    };
+    if let Err(e) = write(path) {
+        crate::warn!("writing metrics to {}: {e}", path.display());
+    }
+}
+
+fn write(path: &Path) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, render(&metrics::snapshot()))?;
+    fs::rename(&tmp, path)
+}
+
+/// `metrics` in the Prometheus text exposition format.
+pub fn render(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    family(
+        &mut out,
+        "messages_received_total",
+        "counter",
+        "Messages handled, by payload type.",
+    );
+    for (kind, n) in &metrics.received {
+        sample(&mut out, "messages_received_total", &[("type", kind)], *n);
+    }
+    family(
+        &mut out,
+        "messages_sent_total",
+        "counter",
+        "Messages sent, by payload type.",
+    );
+    for (kind, n) in &metrics.sent {
+        sample(&mut out, "messages_sent_total", &[("type", kind)], *n);
+    }
+    family(
+        &mut out,
+        "bytes_sent_total",
+        "counter",
+        "Bytes of messages sent.",
+    );
+    sample(&mut out, "bytes_sent_total", &[], metrics.bytes_sent);
+    family(
+        &mut out,
+        "retries_total",
+        "counter",
+        "Messages resent for want of an ack.",
+    );
+    sample(&mut out, "retries_total", &[], metrics.retries);
+    family(
+        &mut out,
+        "evictions_total",
+        "counter",
+        "Entries evicted for age or room, by cache.",
+    );
+    for (cache, n) in &metrics.evictions {
+        sample(&mut out, "evictions_total", &[("cache", cache)], *n);
+    }
+
+    family(
+        &mut out,
+        "handler_seconds",
+        "histogram",
+        "Time spent in the handler, by payload type.",
+    );
+    for (kind, latency) in &metrics.latency {
+        let mut seen = 0;
+        for (bound, n) in latency.buckets() {
+            seen += n;
+            let le = bound.map_or("+Inf".to_string(), |b| b.as_secs_f64().to_string());
+            let labels = [("type", kind.as_str()), ("le", le.as_str())];
+            sample(&mut out, "handler_seconds_bucket", &labels, seen);
+        }
+        let labels = [("type", kind.as_str())];
+        let sum = latency.total().as_secs_f64();
+        sample(&mut out, "handler_seconds_sum", &labels, sum);
+        sample(&mut out, "handler_seconds_count", &labels, latency.count());
+    }
+
+    family(
+        &mut out,
+        "queue_depth",
+        "gauge",
+        "Items waiting in the main loop's queues.",
+    );
+    for (queue, depth) in &metrics.queues {
+        sample(&mut out, "queue_depth", &[("queue", queue)], depth.depth());
+    }
+    family(
+        &mut out,
+        "queue_depth_max",
+        "gauge",
+        "The most items the main loop's queues have held.",
+    );
+    for (queue, depth) in &metrics.queues {
+        sample(
+            &mut out,
+            "queue_depth_max",
+            &[("queue", queue)],
+            depth.max(),
+        );
+    }
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP whirlpool_{name} {help}");
+    let _ = writeln!(out, "# TYPE whirlpool_{name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+    let _ = write!(out, "whirlpool_{name}");
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// `value` escaped as a label value has to be.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
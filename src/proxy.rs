@@ -0,0 +1,130 @@
+//! Passing messages a node doesn't understand on to another node, for
+//! layered deployments where a thin front node handles some workloads
+//! itself and leaves the rest to the node behind it.
+//!
+//! With [`Config::proxy_upstream`](crate::Config::proxy_upstream) set, the
+//! reader thread sends every message whose payload isn't one of the node's
+//! types, which would otherwise go to
+//! [`Config::unknown_messages`](crate::Config::unknown_messages), to the
+//! upstream node instead, from this node and under a `msg_id` of the
+//! proxy's own. The upstream's reply to it, whatever its type, is sent
+//! back to the original sender as this node's reply to the original
+//! request, without the node ever seeing either.
+//!
+//! The proxy's `msg_id`s start at [`FIRST_MSG_ID`], far above any a node
+//! hands out itself, so its replies can't be mistaken for the node's.
+//! Messages from the upstream itself are never sent back to it.
+
+use crate::{time, Message};
+use anyhow::Context;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// The first `msg_id` a [`Proxy`] uses. Still exact as a JSON number in
+/// any language.
+pub const FIRST_MSG_ID: usize = 1 << 48;
+
+/// How long a forwarded request waits for the upstream's reply before it
+/// is forgotten.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request passed on to the upstream, waiting for its reply.
+#[derive(Debug)]
+struct Forwarded {
+    client: String,
+    msg_id: usize,
+    sent_at: Instant,
+}
+
+/// Forwards messages to an upstream node and relays its replies back.
+#[derive(Debug)]
+pub struct Proxy {
+    upstream: String,
+    next_id: usize,
+    /// Forwarded requests, by the `msg_id` they were forwarded under.
+    pending: HashMap<usize, Forwarded>,
+}
+
+impl Proxy {
+    pub fn new(upstream: impl Into<String>) -> Self {
+        Self {
+            upstream: upstream.into(),
+            next_id: FIRST_MSG_ID,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    /// Sends `msg` on to the upstream, to relay its reply back to `msg`'s
+    /// sender once it comes.
+    pub fn forward(&mut self, msg: Message<Value>, out: &mut impl Write) -> anyhow::Result<()> {
+        let now = time::now();
+        self.pending
+            .retain(|_, forwarded| now.duration_since(forwarded.sent_at) < REPLY_TIMEOUT);
+        let msg_id = msg.body.id.map(|msg_id| {
+            let id = self.next_id();
+            self.pending.insert(
+                id,
+                Forwarded {
+                    client: msg.src.clone(),
+                    msg_id,
+                    sent_at: now,
+                },
+            );
+            id
+        });
+        crate::debug!("forwarding message from {} to {}", msg.src, self.upstream);
+        let mut forwarded = Message::new(&msg.dest, &self.upstream, msg_id, msg.body.payload);
+        forwarded.body.in_reply_to = msg.body.in_reply_to;
+        forwarded.send(out)?;
+        out.flush().context("handing output to the writer")
+    }
+
+    /// Relays `json` back to whoever sent the request it replies to, if it
+    /// is the upstream's reply to a forwarded one. Returns whether it was.
+    pub fn relay(&mut self, json: &str, out: &mut impl Write) -> anyhow::Result<bool> {
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+        let Ok(reply) = serde_json::from_str::<Message<Value>>(json) else {
+            return Ok(false);
+        };
+        if reply.src != self.upstream {
+            return Ok(false);
+        }
+        let Some(forwarded) = reply
+            .body
+            .in_reply_to
+            .and_then(|id| self.pending.remove(&id))
+        else {
+            return Ok(false);
+        };
+        let mut relayed = Message::new(
+            &reply.dest,
+            forwarded.client,
+            Some(self.next_id()),
+            reply.body.payload,
+        );
+        relayed.body.in_reply_to = Some(forwarded.msg_id);
+        relayed.send(out)?;
+        out.flush().context("handing output to the writer")?;
+        Ok(true)
+    }
+
+    /// How many forwarded requests are waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+}
@@ -0,0 +1,133 @@
+//! The replicated log.
+
+use crate::Payload;
+use serde::{Deserialize, Serialize};
+
+/// A command for the state machine, tagged with the term of the leader
+/// that appended it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: Payload,
+}
+
+/// Log entries, numbered from 1. Entries up to [`Log::snapshot_index`] have
+/// been compacted into a snapshot and only the last one's term is kept;
+/// index 0 stands for the empty log and has term 0, so every entry has a
+/// predecessor to check.
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    snapshot_index: u64,
+    snapshot_term: u64,
+    entries: Vec<LogEntry>,
+}
+
+impl Log {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last entry covered by a snapshot, or 0 if none is.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// How many entries are held in memory.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, or `None` if it is past the end or
+    /// compacted away.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&LogEntry> {
+        let i = index.checked_sub(self.snapshot_index + 1)?;
+        self.entries.get(usize::try_from(i).ok()?)
+    }
+
+    /// Up to `limit` entries starting at `index`, which must be after the
+    /// snapshot.
+    pub fn entries_from(&self, index: u64, limit: usize) -> Vec<LogEntry> {
+        let start = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Appends an entry and returns its index.
+    pub fn append(&mut self, entry: LogEntry) -> u64 {
+        self.entries.push(entry);
+        self.last_index()
+    }
+
+    /// Whether the entry at `index` has term `term`: if so, Raft's log
+    /// matching property says the logs agree up to there.
+    pub fn matches(&self, index: u64, term: u64) -> bool {
+        self.term_at(index) == Some(term)
+    }
+
+    /// Stores `entries` as the ones following `prev_index`, which must
+    /// [match](Log::matches). Existing entries are only cut off where they
+    /// conflict with the new ones, so a stale, shorter request never
+    /// truncates entries a later one already added.
+    pub fn merge(&mut self, prev_index: u64, entries: Vec<LogEntry>) {
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self
+                    .entries
+                    .truncate((index - self.snapshot_index - 1) as usize),
+                None => {}
+            }
+            self.entries.push(entry);
+        }
+    }
+
+    /// Drops the entries up to `index`, which a snapshot now covers.
+    pub fn compact(&mut self, index: u64) {
+        let Some(term) = self.term_at(index) else {
+            return;
+        };
+        if index <= self.snapshot_index {
+            return;
+        }
+        self.entries.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// Makes the log start after a snapshot received from the leader,
+    /// which covers up to `index` in `term`. Entries following it are kept
+    /// if the log agrees with the snapshot, and dropped otherwise.
+    pub fn reset(&mut self, index: u64, term: u64) {
+        if self.matches(index, term) {
+            return self.compact(index);
+        }
+        self.entries.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+}
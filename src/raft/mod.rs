@@ -0,0 +1,863 @@
+//! Raft consensus, for serving linearizable workloads from a cluster.
+//!
+//! [`RaftNode`] elects a leader: every node starts as a follower and waits
+//! a randomized election timeout for a heartbeat. If none comes it becomes
+//! a candidate for the next term and asks its peers for their votes with
+//! `request_vote`; a majority makes it the leader, which then sends
+//! `append_entries` often enough that nobody else times out. A node that
+//! hears of a later term, in a request or a reply, steps down to follower
+//! of that term.
+//!
+//! Client requests become commands in the leader's [log](log::Log), which
+//! `append_entries` replicates to the followers. Once a majority stores an
+//! entry it is committed, and every node applies it to its
+//! [`StateMachine`]; the leader answers the client with the result.
+//! Followers pass client requests on to the leader they know of, and relay
+//! its answer. To keep memory bounded, applied entries are regularly
+//! compacted into a [snapshot], which the leader sends followers
+//! that fall too far behind.
+//!
+//! Requests go out through the node's [`Rpc`], and replies are collected
+//! from the outstanding calls as the node handles messages and ticks, so
+//! nothing ever blocks waiting for a peer.
+
+pub mod log;
+pub mod snapshot;
+
+pub use log::{Log, LogEntry};
+pub use snapshot::Snapshot;
+
+use crate::{
+    compress::Encoding,
+    handshake::Handshake,
+    kv::KvStore,
+    time,
+    timer::{TimerId, TimerWheel},
+    Config, ErrorCode, Membership, Message, MsgIdAllocator, Node, Payload, Rpc, RpcCall, RpcError,
+};
+use anyhow::bail;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// How often a [`RaftNode`] collects replies. Its timers tick it on time
+/// through [`Node::next_timer`].
+const TICK: Duration = Duration::from_millis(10);
+
+/// The most entries one `append_entries` carries.
+const MAX_ENTRIES: usize = 64;
+
+/// How long a follower waits for the leader to answer a client request it
+/// passed on. The client is left to time out after that.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a [`RaftNode`] replicates: committed commands are applied in log
+/// order, exactly once on each node.
+pub trait StateMachine {
+    /// Applies a committed command and returns the reply for the client
+    /// that submitted it.
+    fn apply(&mut self, command: &Payload) -> Result<Payload, RpcError>;
+
+    /// The machine's whole state, for compacting the log. Machines that
+    /// return `None` keep every entry.
+    fn snapshot(&self) -> Option<Value> {
+        None
+    }
+
+    /// Replaces the machine's state with one taken by
+    /// [`StateMachine::snapshot`].
+    fn restore(&mut self, _snapshot: Value) -> anyhow::Result<()> {
+        bail!("this state machine does not support snapshots")
+    }
+}
+
+impl StateMachine for KvStore {
+    fn apply(&mut self, command: &Payload) -> Result<Payload, RpcError> {
+        KvStore::apply(self, command)
+    }
+
+    fn snapshot(&self) -> Option<Value> {
+        serde_json::to_value(self).ok()
+    }
+
+    fn restore(&mut self, snapshot: Value) -> anyhow::Result<()> {
+        *self = serde_json::from_value(snapshot)?;
+        Ok(())
+    }
+}
+
+/// Timing of elections and heartbeats, and when to take snapshots.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// A follower that hears nothing from a leader for a duration drawn
+    /// from here starts an election.
+    pub election_timeout: Range<Duration>,
+    /// How often the leader sends heartbeats. Should be well below the
+    /// election timeout.
+    pub heartbeat_interval: Duration,
+    /// How many applied entries the log holds before they are compacted
+    /// into a snapshot.
+    pub snapshot_threshold: u64,
+    /// The most bytes of snapshot one `install_snapshot` carries.
+    pub snapshot_chunk_size: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: Duration::from_millis(150)..Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            snapshot_threshold: 1000,
+            snapshot_chunk_size: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+enum Request {
+    Vote,
+    /// `append_entries` with `count` entries following `prev_index`.
+    Append {
+        prev_index: u64,
+        count: u64,
+    },
+    /// A chunk of the snapshot covering the log up to `index`.
+    Snapshot {
+        index: u64,
+    },
+    /// A client request passed on to the leader, whose reply goes back to
+    /// the client.
+    Forward(Message<()>),
+}
+
+/// What a [`RaftNode`]'s timers fall due with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timer {
+    /// Start an election, unless a leader is heard from first.
+    Election,
+    /// Send peers heartbeats, while leader.
+    Heartbeat,
+}
+
+/// A request to a peer that hasn't been answered yet.
+#[derive(Debug)]
+struct Outstanding {
+    peer: String,
+    /// Our term when the request went out; replies to older terms are
+    /// stale.
+    term: u64,
+    sent_at: Instant,
+    request: Request,
+    call: RpcCall,
+}
+
+/// A member of a Raft cluster replicating `S`.
+#[derive(Debug)]
+pub struct RaftNode<S = KvStore> {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    rpc: Rpc,
+    config: RaftConfig,
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    /// Who voted for us, while a candidate.
+    votes: HashSet<String>,
+    timers: TimerWheel<Timer>,
+    /// The pending election timeout, if it hasn't run out.
+    election: Option<TimerId>,
+    /// The next heartbeat, while leader.
+    next_heartbeat: Option<TimerId>,
+    outstanding: Vec<Outstanding>,
+    log: Log,
+    commit_index: u64,
+    last_applied: u64,
+    machine: S,
+    /// While leader: the next entry to send each peer, and the last one
+    /// known to be in its log.
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    /// While leader: the clients to answer once the entries they submitted
+    /// are applied, by index, with the term the entry was appended in.
+    waiting: HashMap<u64, (u64, Message<()>)>,
+    /// The latest snapshot, kept for followers that need it.
+    snapshot: Option<Snapshot>,
+    /// A snapshot from the leader, as far as it has arrived, and how it is
+    /// encoded if not as JSON.
+    incoming: Option<(Snapshot, Option<Encoding>)>,
+    /// Learns which protocol version peers speak; see
+    /// [`RaftNode::with_handshake`].
+    handshake: Option<Handshake>,
+}
+
+impl<S: StateMachine + Default> Default for RaftNode<S> {
+    fn default() -> Self {
+        Self::new(S::default(), RaftConfig::default())
+    }
+}
+
+impl<S: StateMachine + Default> RaftNode<S> {
+    /// A node set up as `config` says: whether it shakes hands with its
+    /// peers, and whether it sends them snapshots gzipped or as
+    /// MessagePack.
+    pub fn from_config(config: &Config) -> Self {
+        let node = Self::default();
+        match config.handshake || config.msgpack || config.gzip {
+            true => node.with_handshake(Handshake::new(config.capabilities())),
+            false => node,
+        }
+    }
+}
+
+impl<S: StateMachine> RaftNode<S> {
+    pub fn new(machine: S, config: RaftConfig) -> Self {
+        let msg_ids = MsgIdAllocator::new();
+        let mut node = Self {
+            membership: Membership::default(),
+            rpc: Rpc::new(msg_ids.clone()),
+            msg_ids,
+            config,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            timers: TimerWheel::default(),
+            election: None,
+            next_heartbeat: None,
+            outstanding: Vec::new(),
+            log: Log::new(),
+            commit_index: 0,
+            last_applied: 0,
+            machine,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            waiting: HashMap::new(),
+            snapshot: None,
+            incoming: None,
+            handshake: None,
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    /// Says hello to peers after `init` with `handshake`, so that they are
+    /// told this node's protocol version and it learns theirs. Without
+    /// one, a `hello` would be taken for a client command.
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Some(handshake);
+        self
+    }
+
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.handshake.as_ref()
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// The leader of the current term, if this node knows it.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn log(&self) -> &Log {
+        &self.log
+    }
+
+    /// The last entry known to be committed.
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.machine
+    }
+
+    fn reset_election_timer(&mut self) {
+        let timeout = &self.config.election_timeout;
+        let timeout = if timeout.is_empty() {
+            timeout.start
+        } else {
+            time::rng().gen_range(timeout.clone())
+        };
+        if let Some(election) = self.election.take() {
+            self.timers.cancel(election);
+        }
+        self.election = Some(self.timers.schedule(timeout, Timer::Election));
+    }
+
+    /// Drops outstanding requests that belong to our current role. Client
+    /// requests passed on to the leader are answered whatever we are.
+    fn forget_requests(&mut self) {
+        self.outstanding
+            .retain(|request| matches!(request.request, Request::Forward(_)));
+    }
+
+    /// Moves on to `term`, which is later than ours, as a follower.
+    fn step_down(&mut self, term: u64) {
+        if self.role != Role::Follower {
+            crate::info!("stepping down in term {term}");
+        }
+        self.term = term;
+        self.voted_for = None;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.votes.clear();
+        self.forget_requests();
+        // An election timeout that ran out while leader comes due now.
+        if self.election.is_none() {
+            self.election = Some(self.timers.schedule(Duration::ZERO, Timer::Election));
+        }
+    }
+
+    /// Steps down if `term` is later than ours.
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.step_down(term);
+        }
+    }
+
+    fn majority(&self) -> usize {
+        self.membership.node_ids.len() / 2 + 1
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.membership.peers().cloned().collect()
+    }
+
+    fn start_election(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.membership.node_id.clone());
+        self.votes = HashSet::from([self.membership.node_id.clone()]);
+        self.forget_requests();
+        self.reset_election_timer();
+        crate::debug!("starting election for term {}", self.term);
+        let request = Payload::RequestVote {
+            term: self.term,
+            candidate_id: self.membership.node_id.clone(),
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
+        for peer in self.peers() {
+            self.call(&peer, request.clone(), Request::Vote, out)?;
+        }
+        self.check_votes(out)
+    }
+
+    /// Becomes the leader once a majority has voted for us.
+    fn check_votes(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        if self.role != Role::Candidate || self.votes.len() < self.majority() {
+            return Ok(());
+        }
+        crate::info!("elected leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.membership.node_id.clone());
+        self.votes.clear();
+        self.forget_requests();
+        let next = self.log.last_index() + 1;
+        self.next_index = self.peers().into_iter().map(|p| (p, next)).collect();
+        self.match_index = self.peers().into_iter().map(|p| (p, 0)).collect();
+        self.heartbeat(out)
+    }
+
+    /// Sends every peer whatever it is missing, or an empty
+    /// `append_entries` if nothing. Peers in the middle of receiving a
+    /// snapshot hear from us often enough already.
+    fn heartbeat(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        if let Some(heartbeat) = self.next_heartbeat.take() {
+            self.timers.cancel(heartbeat);
+        }
+        let interval = self.config.heartbeat_interval;
+        self.next_heartbeat = Some(self.timers.schedule(interval, Timer::Heartbeat));
+        for peer in self.peers() {
+            if !self.sending_snapshot_to(&peer) {
+                self.replicate(&peer, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replicate(&mut self, peer: &str, out: &mut impl Write) -> anyhow::Result<()> {
+        let next = self.next_index.get(peer).copied().unwrap_or(1);
+        if next <= self.log.snapshot_index() {
+            return self.send_snapshot(peer, 0, out);
+        }
+        let prev_index = next - 1;
+        let entries = self.log.entries_from(next, MAX_ENTRIES);
+        let request = Request::Append {
+            prev_index,
+            count: entries.len() as u64,
+        };
+        let payload = Payload::AppendEntries {
+            term: self.term,
+            leader_id: self.membership.node_id.clone(),
+            prev_log_index: prev_index,
+            prev_log_term: self.log.term_at(prev_index).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.call(peer, payload, request, out)
+    }
+
+    /// Whether an `append_entries` or `install_snapshot` to `peer` is
+    /// still unanswered.
+    fn appending_to(&self, peer: &str) -> bool {
+        self.outstanding.iter().any(|o| {
+            o.peer == peer && matches!(o.request, Request::Append { .. } | Request::Snapshot { .. })
+        })
+    }
+
+    fn call(
+        &mut self,
+        peer: &str,
+        payload: Payload,
+        request: Request,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let call = self
+            .rpc
+            .call(&self.membership.node_id, peer, payload, out)?;
+        self.outstanding.push(Outstanding {
+            peer: peer.to_string(),
+            term: self.term,
+            sent_at: time::now(),
+            request,
+            call,
+        });
+        Ok(())
+    }
+
+    /// Handles the replies that have come in to outstanding requests, and
+    /// gives up on requests that have gone unanswered for too long.
+    fn collect_replies(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let give_up = self.config.election_timeout.end;
+        let mut replies = Vec::new();
+        let mut i = 0;
+        while i < self.outstanding.len() {
+            let outstanding = &self.outstanding[i];
+            let timeout = match outstanding.request {
+                Request::Forward(_) => FORWARD_TIMEOUT,
+                _ => give_up,
+            };
+            match outstanding.call.wait_timeout(Duration::ZERO) {
+                Some(reply) => replies.push((self.outstanding.swap_remove(i), reply)),
+                None if time::elapsed(outstanding.sent_at) >= timeout => {
+                    self.outstanding.swap_remove(i);
+                }
+                None => i += 1,
+            }
+        }
+        for (request, reply) in replies {
+            self.handle_reply(request, reply, out)?;
+        }
+        Ok(())
+    }
+
+    fn handle_reply(
+        &mut self,
+        request: Outstanding,
+        reply: Message,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Outstanding {
+            peer,
+            term,
+            request,
+            ..
+        } = request;
+        match (request, reply.body.payload) {
+            (Request::Forward(client), payload) => client
+                .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+                .send(out)?,
+            (
+                Request::Vote,
+                Payload::RequestVoteOk {
+                    term: their_term,
+                    vote_granted,
+                },
+            ) => {
+                self.observe_term(their_term);
+                if vote_granted && term == self.term && self.role == Role::Candidate {
+                    self.votes.insert(peer);
+                    self.check_votes(out)?;
+                }
+            }
+            (
+                Request::Append { prev_index, count },
+                Payload::AppendEntriesOk {
+                    term: their_term,
+                    success,
+                    match_index,
+                },
+            ) => {
+                self.observe_term(their_term);
+                if term != self.term || self.role != Role::Leader {
+                    return Ok(());
+                }
+                let next = self.next_index.entry(peer.clone()).or_insert(1);
+                if success {
+                    let matched = self.match_index.entry(peer.clone()).or_default();
+                    *matched = (*matched).max(prev_index + count);
+                    *next = (*next).max(*matched + 1);
+                    self.advance_commit(out)?;
+                } else {
+                    *next = (*next - 1).min(match_index + 1).max(1);
+                }
+                let behind = self.next_index[&peer] <= self.log.last_index();
+                if behind && !self.appending_to(&peer) {
+                    self.replicate(&peer, out)?;
+                }
+            }
+            (
+                Request::Snapshot { index },
+                Payload::InstallSnapshotOk {
+                    term: their_term,
+                    offset,
+                },
+            ) => {
+                self.observe_term(their_term);
+                if term == self.term && self.role == Role::Leader {
+                    self.snapshot_sent(&peer, index, offset, out)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Commits the last entry of our term that a majority stores.
+    fn advance_commit(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let mut matched: Vec<u64> = self.match_index.values().copied().collect();
+        matched.push(self.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(&index) = matched.get(self.majority() - 1) else {
+            return Ok(());
+        };
+        // Entries from earlier terms are only committed along with one from
+        // ours, as Raft's commitment rule requires.
+        if index > self.commit_index && self.log.term_at(index) == Some(self.term) {
+            self.commit_index = index;
+            self.apply_committed(out)?;
+        }
+        Ok(())
+    }
+
+    /// Applies committed entries, answering the clients that are waiting
+    /// for them.
+    fn apply_committed(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self
+                .log
+                .get(self.last_applied)
+                .expect("committed entries are in the log");
+            let result = self.machine.apply(&entry.command);
+            let Some((term, client)) = self.waiting.remove(&self.last_applied) else {
+                continue;
+            };
+            let msg_id = Some(self.msg_ids.next());
+            match result {
+                Ok(payload) if term == entry.term => client
+                    .into_reply(&self.membership.node_id, msg_id, payload)
+                    .send(out)?,
+                Err(err) if term == entry.term => client
+                    .into_error(&self.membership.node_id, msg_id, err)
+                    .send(out)?,
+                // Another leader's entry took the place of the client's,
+                // which was never committed.
+                _ => client
+                    .into_error(
+                        &self.membership.node_id,
+                        msg_id,
+                        not_leader("request was superseded"),
+                    )
+                    .send(out)?,
+            }
+        }
+        self.maybe_snapshot()
+    }
+
+    /// Appends a client's command to the log as leader, or passes it on to
+    /// the leader.
+    fn submit(
+        &mut self,
+        client: Message<()>,
+        command: Payload,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if self.role == Role::Leader {
+            let term = self.term;
+            let index = self.log.append(LogEntry { term, command });
+            self.waiting.insert(index, (term, client));
+            for peer in self.peers() {
+                if !self.appending_to(&peer) {
+                    self.replicate(&peer, out)?;
+                }
+            }
+            // A cluster of one commits straight away.
+            return self.advance_commit(out);
+        }
+        // Requests from peers were passed on once already; their view of
+        // the leader is as stale as ours.
+        match self.leader.clone() {
+            Some(leader) if !self.membership.is_peer(&client.src) => {
+                self.call(&leader, command, Request::Forward(client), out)
+            }
+            _ => Err(not_leader("no leader known").into()),
+        }
+    }
+
+    fn request_vote(
+        &mut self,
+        term: u64,
+        candidate_id: &str,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Payload {
+        self.observe_term(term);
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let vote_granted = term == self.term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted_for| voted_for == candidate_id);
+        if vote_granted {
+            self.voted_for = Some(candidate_id.to_string());
+            self.reset_election_timer();
+        }
+        Payload::RequestVoteOk {
+            term: self.term,
+            vote_granted,
+        }
+    }
+
+    /// Handles an `append_entries` whose `entries` follow the one at
+    /// `prev`, given as `(index, term)`.
+    fn append_entries(
+        &mut self,
+        term: u64,
+        leader_id: &str,
+        prev: (u64, u64),
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+        out: &mut impl Write,
+    ) -> anyhow::Result<Payload> {
+        let (mut prev_log_index, mut prev_log_term) = prev;
+        let mut entries = entries;
+        if term < self.term {
+            return Ok(Payload::AppendEntriesOk {
+                term: self.term,
+                success: false,
+                match_index: self.log.last_index(),
+            });
+        }
+        self.observe_term(term);
+        // A candidate that hears from the leader of its own term lost.
+        if self.role == Role::Candidate {
+            self.step_down(term);
+        }
+        self.leader = Some(leader_id.to_string());
+        self.reset_election_timer();
+        let last_new = prev_log_index + entries.len() as u64;
+        let compacted = self.log.snapshot_index();
+        if prev_log_index < compacted {
+            // Everything up to the snapshot is committed, so it matches the
+            // leader's log; only what follows needs checking.
+            let skip = (compacted - prev_log_index).min(entries.len() as u64);
+            entries.drain(..skip as usize);
+            prev_log_index += skip;
+            if prev_log_index < compacted {
+                return Ok(Payload::AppendEntriesOk {
+                    term: self.term,
+                    success: true,
+                    match_index: last_new,
+                });
+            }
+            prev_log_term = self.log.term_at(compacted).unwrap_or(0);
+        }
+        if !self.log.matches(prev_log_index, prev_log_term) {
+            return Ok(Payload::AppendEntriesOk {
+                term: self.term,
+                success: false,
+                match_index: self.log.last_index().min(prev_log_index.saturating_sub(1)),
+            });
+        }
+        self.log.merge(prev_log_index, entries);
+        if leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+            self.apply_committed(out)?;
+        }
+        Ok(Payload::AppendEntriesOk {
+            term: self.term,
+            success: true,
+            match_index: last_new,
+        })
+    }
+}
+
+fn not_leader(text: &str) -> RpcError {
+    RpcError::new(ErrorCode::TemporarilyUnavailable, text)
+}
+
+impl<S: StateMachine> Node for RaftNode<S> {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        self.collect_replies(output)?;
+        if let Some(handshake) = &mut self.handshake {
+            if handshake.handle(&input, output)? {
+                return Ok(());
+            }
+        }
+        let request = input.header();
+        let payload = match input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(&node_id, &node_ids);
+                self.reset_election_timer();
+                if let Some(handshake) = &mut self.handshake {
+                    handshake.init(&self.membership, output)?;
+                }
+                Payload::InitOk
+            }
+            Payload::RequestVote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => self.request_vote(term, &candidate_id, last_log_index, last_log_term),
+            Payload::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.append_entries(
+                term,
+                &leader_id,
+                (prev_log_index, prev_log_term),
+                entries,
+                leader_commit,
+                output,
+            )?,
+            Payload::InstallSnapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                offset,
+                data,
+                encoding,
+                done,
+            } => self.install_snapshot(
+                term,
+                &leader_id,
+                (last_included_index, last_included_term),
+                offset,
+                (data, encoding),
+                done,
+            )?,
+            // Replies to requests we already gave up on.
+            Payload::RequestVoteOk { .. }
+            | Payload::AppendEntriesOk { .. }
+            | Payload::InstallSnapshotOk { .. } => return Ok(()),
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            command => return self.submit(request, command, output),
+        };
+        request
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(TICK)
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.collect_replies(out)?;
+        if let Some(handshake) = &mut self.handshake {
+            handshake.tick(out)?;
+        }
+        for timer in self.timers.expire(time::now()) {
+            match timer {
+                Timer::Election => {
+                    self.election = None;
+                    if self.role != Role::Leader {
+                        self.start_election(out)?;
+                    }
+                }
+                Timer::Heartbeat => {
+                    self.next_heartbeat = None;
+                    if self.role == Role::Leader {
+                        self.heartbeat(out)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+
+    /// Where this node is in the protocol, and what its machine holds if
+    /// it can [snapshot](StateMachine::snapshot).
+    fn inspect(&self) -> Value {
+        json!({
+            "role": format!("{:?}", self.role).to_lowercase(),
+            "term": self.term,
+            "voted_for": self.voted_for,
+            "leader": self.leader,
+            "commit_index": self.commit_index,
+            "last_applied": self.last_applied,
+            "log": {
+                "snapshot_index": self.log.snapshot_index(),
+                "last_index": self.log.last_index(),
+                "last_term": self.log.last_term(),
+            },
+            "waiting": self.waiting.len(),
+            "machine": self.machine.snapshot(),
+        })
+    }
+
+    /// The cluster, and while leader, how far each peer's log is known to
+    /// match.
+    fn topology(&self) -> Value {
+        json!({
+            "node_id": self.membership.node_id,
+            "node_ids": self.membership.node_ids,
+            "leader": self.leader,
+            "match_index": self.match_index,
+        })
+    }
+}
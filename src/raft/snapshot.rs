@@ -0,0 +1,333 @@
+//! Log compaction, and catching up followers whose missing entries were
+//! compacted away.
+//!
+//! Once [`RaftConfig::snapshot_threshold`](super::RaftConfig) entries have
+//! been applied since the last snapshot, a node asks its state machine for
+//! a [snapshot](super::StateMachine::snapshot) and drops the entries it
+//! covers. When the leader finds that a follower needs one of those, it
+//! streams the snapshot over with `install_snapshot` instead, one chunk at
+//! a time: each reply says how much the follower has, which is where the
+//! next chunk starts, so a lost chunk is simply sent again.
+//!
+//! A node whose [handshake](crate::handshake) advertises
+//! [`Capability::Gzip`] also keeps its snapshots as base64
+//! [gzip], and one that advertises [`Capability::MsgPack`] as
+//! base64 [MessagePack](crate::msgpack), each if that is shorter. It
+//! streams followers the shortest form they read instead, saying so in
+//! each chunk's `encoding`.
+
+use super::{RaftNode, Request, Role, StateMachine};
+use crate::{
+    compress::{self, Encoding},
+    gzip,
+    handshake::Capability,
+    msgpack, ErrorCode, Payload, RpcError,
+};
+use serde_json::Value;
+use std::io::Write;
+
+/// A state machine's state after applying the log up to `index`, as JSON.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub index: u64,
+    /// The term of the entry at `index`.
+    pub term: u64,
+    pub data: String,
+    /// The same state as base64 MessagePack, for followers that read it.
+    packed: Option<String>,
+    /// The JSON gzipped, as base64, for followers that read it.
+    gzipped: Option<String>,
+}
+
+impl Snapshot {
+    /// The part of the data starting at `offset`, at most `size` bytes but
+    /// never splitting a character, and whether it is the last part.
+    pub fn chunk(&self, offset: usize, size: usize) -> (&str, bool) {
+        chunk(&self.data, offset, size)
+    }
+
+    /// The state as base64 MessagePack, if this node keeps it that way.
+    pub fn packed(&self) -> Option<&str> {
+        self.packed.as_deref()
+    }
+
+    /// The JSON gzipped, as base64, if this node keeps it that way.
+    pub fn gzipped(&self) -> Option<&str> {
+        self.gzipped.as_deref()
+    }
+
+    /// The data to send a follower that reads `encodings`: the shortest
+    /// form this snapshot is kept in that it reads, and how it is encoded
+    /// if not as JSON.
+    fn form(&self, encodings: &[Encoding]) -> (&str, Option<Encoding>) {
+        [
+            (&self.gzipped, Encoding::Gzip),
+            (&self.packed, Encoding::MsgPack),
+        ]
+        .into_iter()
+        .filter(|(_, encoding)| encodings.contains(encoding))
+        .filter_map(|(data, encoding)| Some((data.as_deref()?, Some(encoding))))
+        .chain([(self.data.as_str(), None)])
+        .min_by_key(|(data, _)| data.len())
+        .expect("the JSON is always there")
+    }
+}
+
+fn chunk(data: &str, offset: usize, size: usize) -> (&str, bool) {
+    let offset = offset.min(data.len());
+    let mut end = offset.saturating_add(size.max(4)).min(data.len());
+    while !data.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&data[offset..end], end == data.len())
+}
+
+/// The JSON of a snapshot sent as `data` in `encoding`.
+fn unpack(data: String, encoding: Option<Encoding>) -> Result<String, RpcError> {
+    match encoding {
+        None => Ok(data),
+        Some(Encoding::MsgPack) => {
+            let bytes = compress::from_base64(&data).ok_or_else(|| {
+                RpcError::new(ErrorCode::MalformedRequest, "snapshot is not base64")
+            })?;
+            Ok(msgpack::decode(&bytes)?.to_string())
+        }
+        Some(Encoding::Gzip) => {
+            let bytes = compress::from_base64(&data).ok_or_else(|| {
+                RpcError::new(ErrorCode::MalformedRequest, "snapshot is not base64")
+            })?;
+            String::from_utf8(gzip::decompress(&bytes)?)
+                .map_err(|_| RpcError::new(ErrorCode::MalformedRequest, "snapshot is not UTF-8"))
+        }
+        Some(encoding) => Err(RpcError::new(
+            ErrorCode::MalformedRequest,
+            format!("snapshots are never sent as {encoding:?}"),
+        )),
+    }
+}
+
+impl<S: StateMachine> RaftNode<S> {
+    /// The snapshot this node last took or installed, if any.
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// A snapshot of `state`, also gzipped and packed as MessagePack if
+    /// this node advertises those and they come out shorter.
+    fn snapshot_of(&self, index: u64, term: u64, state: &Value) -> Snapshot {
+        let data = state.to_string();
+        let advertises = |capability| {
+            self.handshake
+                .as_ref()
+                .is_some_and(|handshake| handshake.capabilities().contains(&capability))
+        };
+        let packed = advertises(Capability::MsgPack)
+            .then(|| compress::to_base64(&msgpack::encode(state)))
+            .filter(|packed| packed.len() < data.len());
+        let gzipped = advertises(Capability::Gzip)
+            .then(|| compress::to_base64(&gzip::compress(data.as_bytes())))
+            .filter(|gzipped| gzipped.len() < data.len());
+        Snapshot {
+            index,
+            term,
+            data,
+            packed,
+            gzipped,
+        }
+    }
+
+    /// The encodings `peer` is sent snapshots in, besides JSON.
+    fn encodings_for(&self, peer: &str) -> Vec<Encoding> {
+        let Some(handshake) = &self.handshake else {
+            return Vec::new();
+        };
+        [
+            (Capability::Gzip, Encoding::Gzip),
+            (Capability::MsgPack, Encoding::MsgPack),
+        ]
+        .into_iter()
+        .filter(|(capability, _)| handshake.supports(peer, *capability))
+        .map(|(_, encoding)| encoding)
+        .collect()
+    }
+
+    /// Compacts the log into a snapshot once enough entries have been
+    /// applied since the last one, if the state machine supports it.
+    pub(super) fn maybe_snapshot(&mut self) -> anyhow::Result<()> {
+        let index = self.last_applied;
+        if index - self.log.snapshot_index() < self.config.snapshot_threshold {
+            return Ok(());
+        }
+        let Some(state) = self.machine.snapshot() else {
+            return Ok(());
+        };
+        let term = self
+            .log
+            .term_at(index)
+            .expect("applied entries are in the log");
+        self.log.compact(index);
+        crate::debug!("compacted the log up to {index}");
+        self.snapshot = Some(self.snapshot_of(index, term, &state));
+        Ok(())
+    }
+
+    /// Whether an `install_snapshot` to `peer` is still unanswered.
+    pub(super) fn sending_snapshot_to(&self, peer: &str) -> bool {
+        self.outstanding
+            .iter()
+            .any(|o| o.peer == peer && matches!(o.request, Request::Snapshot { .. }))
+    }
+
+    /// Sends `peer` the chunk of our snapshot starting at `offset`.
+    pub(super) fn send_snapshot(
+        &mut self,
+        peer: &str,
+        offset: usize,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let encodings = self.encodings_for(peer);
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        let (data, encoding) = snapshot.form(&encodings);
+        let (data, done) = chunk(data, offset, self.config.snapshot_chunk_size);
+        let payload = Payload::InstallSnapshot {
+            term: self.term,
+            leader_id: self.membership.node_id.clone(),
+            last_included_index: snapshot.index,
+            last_included_term: snapshot.term,
+            offset,
+            data: data.to_string(),
+            encoding,
+            done,
+        };
+        let request = Request::Snapshot {
+            index: snapshot.index,
+        };
+        self.call(peer, payload, request, out)
+    }
+
+    /// Carries on sending our snapshot to `peer`, which has `offset` bytes
+    /// of the one covering `index`.
+    pub(super) fn snapshot_sent(
+        &mut self,
+        peer: &str,
+        index: u64,
+        offset: usize,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let encodings = self.encodings_for(peer);
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        if snapshot.index != index {
+            // We took a newer one since; start over with that.
+            return self.replicate(peer, out);
+        }
+        if offset < snapshot.form(&encodings).0.len() {
+            return self.send_snapshot(peer, offset, out);
+        }
+        let matched = self.match_index.entry(peer.to_string()).or_default();
+        *matched = (*matched).max(index);
+        let next = self.next_index.entry(peer.to_string()).or_insert(1);
+        *next = (*next).max(index + 1);
+        self.advance_commit(out)?;
+        if self.next_index[peer] <= self.log.last_index() {
+            self.replicate(peer, out)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a chunk of the leader's snapshot, which covers the log up to
+    /// `last_included`, given as `(index, term)`. The chunk comes as
+    /// `(data, encoding)`.
+    pub(super) fn install_snapshot(
+        &mut self,
+        term: u64,
+        leader_id: &str,
+        last_included: (u64, u64),
+        offset: usize,
+        chunk: (String, Option<Encoding>),
+        done: bool,
+    ) -> anyhow::Result<Payload> {
+        let (index, included_term) = last_included;
+        let (data, encoding) = chunk;
+        if term < self.term {
+            return Ok(Payload::InstallSnapshotOk {
+                term: self.term,
+                offset: 0,
+            });
+        }
+        self.observe_term(term);
+        if self.role == Role::Candidate {
+            self.step_down(term);
+        }
+        self.leader = Some(leader_id.to_string());
+        self.reset_election_timer();
+
+        if index <= self.last_applied {
+            // Nothing in it we don't have; let the leader think it's
+            // getting through so it moves on.
+            return Ok(Payload::InstallSnapshotOk {
+                term: self.term,
+                offset: offset + data.len(),
+            });
+        }
+        // A leader that learns mid-stream that this node reads MessagePack
+        // or gzip starts over in it.
+        let incoming = match &mut self.incoming {
+            Some((incoming, form))
+                if (incoming.index, incoming.term) == last_included && *form == encoding =>
+            {
+                incoming
+            }
+            _ if offset == 0 => {
+                let snapshot = Snapshot {
+                    index,
+                    term: included_term,
+                    data: String::new(),
+                    packed: None,
+                    gzipped: None,
+                };
+                &mut self.incoming.insert((snapshot, encoding)).0
+            }
+            _ => {
+                return Ok(Payload::InstallSnapshotOk {
+                    term: self.term,
+                    offset: 0,
+                })
+            }
+        };
+        let appended = offset == incoming.data.len();
+        if appended {
+            incoming.data.push_str(&data);
+        }
+        let received = incoming.data.len();
+        if appended && done {
+            let (snapshot, encoding) = self.incoming.take().expect("just assembled");
+            let data = unpack(snapshot.data, encoding)?;
+            self.restore(snapshot.index, snapshot.term, &serde_json::from_str(&data)?)?;
+        }
+        Ok(Payload::InstallSnapshotOk {
+            term: self.term,
+            offset: received,
+        })
+    }
+
+    /// Replaces the state machine's state, and the log it came from, with
+    /// `state` from a snapshot the leader sent, covering the log up to
+    /// `index` of `term`.
+    fn restore(&mut self, index: u64, term: u64, state: &Value) -> anyhow::Result<()> {
+        let snapshot = self.snapshot_of(index, term, state);
+        self.machine.restore(state.clone())?;
+        crate::info!("installed snapshot up to {}", snapshot.index);
+        self.log.reset(snapshot.index, snapshot.term);
+        self.last_applied = snapshot.index;
+        self.commit_index = self.commit_index.max(snapshot.index);
+        // Whatever these were, they are applied now, with no result to
+        // tell the client.
+        self.waiting.retain(|&index, _| index > snapshot.index);
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+}
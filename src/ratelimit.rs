@@ -0,0 +1,204 @@
+//! Token buckets on outgoing messages, per destination and overall, so
+//! retries and gossip can't flood the network however eagerly they fire.
+//!
+//! The writer thread passes what handlers wrote through a [`Limiter`],
+//! which lets a message to another node out only once a token is free in
+//! its destination's bucket and in the global one, and otherwise holds it
+//! until there is. Nothing is dropped, and messages to one destination stay
+//! in order. Replies to clients and calls to services aren't limited, since
+//! holding them back costs latency and saves nothing a node controls.
+
+use crate::output::to_node;
+use anyhow::{bail, Context};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// A sustained rate of messages with room for bursts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    /// Messages per second.
+    pub per_sec: f64,
+    /// How many may go at once after a quiet spell.
+    pub burst: f64,
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    /// Parses `rate` or `rate,burst`, such as `100,20`. The burst is a
+    /// second's worth by default.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("rate {s}"))?;
+        let (per_sec, burst) = match *numbers.as_slice() {
+            [per_sec] => (per_sec, per_sec),
+            [per_sec, burst] => (per_sec, burst),
+            _ => bail!("rate {s} is not rate or rate,burst"),
+        };
+        if !(per_sec > 0.0 && burst >= 1.0) {
+            bail!("rate {s} needs rate > 0 and burst >= 1");
+        }
+        Ok(Self { per_sec, burst })
+    }
+}
+
+/// The [`Rate`]s messages to other nodes are limited to, if any.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimits {
+    /// To each destination.
+    pub per_dest: Option<Rate>,
+    /// To all destinations together.
+    pub global: Option<Rate>,
+}
+
+impl RateLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.per_dest.is_some() || self.global.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.refilled = now;
+    }
+
+    /// When a token is free.
+    fn ready_at(&self) -> Instant {
+        match self.tokens >= 1.0 {
+            true => self.refilled,
+            false => {
+                self.refilled + Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_sec)
+            }
+        }
+    }
+}
+
+/// Holds back messages to other nodes beyond their [`RateLimits`].
+#[derive(Debug)]
+pub struct Limiter {
+    limits: RateLimits,
+    global: Option<TokenBucket>,
+    buckets: HashMap<String, TokenBucket>,
+    /// Messages waiting for a token, oldest first, with their destination.
+    held: VecDeque<(String, Vec<u8>)>,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            global: None,
+            buckets: HashMap::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Takes the messages in `chunk`, one per line, and returns the ones
+    /// that may go now, along with any held ones whose turn has come.
+    pub fn admit(&mut self, chunk: &[u8], now: Instant) -> Vec<u8> {
+        let mut out = Vec::with_capacity(chunk.len());
+        for line in chunk.split_inclusive(|&b| b == b'\n') {
+            match to_node(line) {
+                Some(dest) => self.held.push_back((dest, line.to_vec())),
+                None => out.extend_from_slice(line),
+            }
+        }
+        out.extend(self.release(now));
+        out
+    }
+
+    /// Returns the held messages that have a token now, in order.
+    pub fn release(&mut self, now: Instant) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut blocked = HashSet::new();
+        let mut held = VecDeque::with_capacity(self.held.len());
+        for (dest, line) in std::mem::take(&mut self.held) {
+            if blocked.contains(&dest) || !self.take(&dest, now) {
+                blocked.insert(dest.clone());
+                held.push_back((dest, line));
+                continue;
+            }
+            out.extend(line);
+        }
+        self.held = held;
+        if !self.held.is_empty() {
+            crate::debug!("holding {} messages over the rate limit", self.held.len());
+        }
+        out
+    }
+
+    /// When the next held message may go, if any are held.
+    pub fn next_release(&self, now: Instant) -> Option<Instant> {
+        let global = self.global.as_ref().map_or(now, TokenBucket::ready_at);
+        self.held
+            .iter()
+            .map(|(dest, _)| {
+                let own = self.buckets.get(dest).map_or(now, TokenBucket::ready_at);
+                own.max(global).max(now)
+            })
+            .min()
+    }
+
+    /// Every held message, whatever the limits, such as on shutdown.
+    pub fn drain(&mut self) -> Vec<u8> {
+        self.held.drain(..).flat_map(|(_, line)| line).collect()
+    }
+
+    /// How many messages are held.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Takes a token for `dest` from its bucket and the global one, if
+    /// both have one.
+    fn take(&mut self, dest: &str, now: Instant) -> bool {
+        let global = self.limits.global.map(|rate| {
+            self.global
+                .get_or_insert_with(|| TokenBucket::new(rate, now))
+        });
+        if let Some(global) = global {
+            global.refill(now);
+            if global.tokens < 1.0 {
+                return false;
+            }
+        }
+        if let Some(rate) = self.limits.per_dest {
+            let bucket = self
+                .buckets
+                .entry(dest.to_string())
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let Some(global) = &mut self.global {
+            global.tokens -= 1.0;
+        }
+        true
+    }
+}
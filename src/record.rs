@@ -0,0 +1,265 @@
+//! Recording a node's traffic, and replaying it against a fresh node.
+//!
+//! Once [`init`] has been called, every message the node reads and writes
+//! is appended to the recording as one JSON object per line:
+//!
+//! ```text
+//! {"dir":"in","t_us":1760000000123456,"msg":{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}}
+//! {"dir":"out","t_us":1760000000123510,"msg":{"src":"n1","dest":"c1","body":{"type":"echo_ok","in_reply_to":1,"echo":"hi"}}}
+//! ```
+//!
+//! `t_us` is wall-clock microseconds since the Unix epoch. Every node of a
+//! Maelstrom run may append to the same file; [`replay`] picks out one
+//! node's messages by their `dest` and `src`.
+//!
+//! [`replay`] feeds the messages a node received to a fresh node, spaced out
+//! as they originally arrived, so replies to its own RPC requests still
+//! come after the requests. [`diff`] then compares what it sends with what
+//! was recorded. Anything driven by ticks or by how threads interleave may
+//! legitimately differ between runs.
+
+use crate::{dedup::Deduped, run, transport, Config, Message, Node};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{mpsc, OnceLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+static FILE: OnceLock<File> = OnceLock::new();
+
+/// Appends every message read and written to `path` from now on. Only the
+/// first call has any effect.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening record file {}", path.display()))?;
+    let _ = FILE.set(file);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// One line of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub dir: Direction,
+    pub t_us: u64,
+    pub msg: Message<Value>,
+}
+
+/// Records a message as read, still as JSON.
+pub(crate) fn inbound(json: &str) {
+    if FILE.get().is_none() {
+        return;
+    }
+    // Input may be pretty-printed, but a recording has one entry per line.
+    if json.contains('\n') {
+        if let Ok(value) = serde_json::from_str::<Value>(json) {
+            append(Direction::In, &value.to_string());
+        }
+    } else {
+        append(Direction::In, json);
+    }
+}
+
+/// Records the messages in a chunk of output, one per line.
+pub(crate) fn outbound(chunk: &[u8]) {
+    if FILE.get().is_none() {
+        return;
+    }
+    for line in chunk.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        if let Ok(line) = std::str::from_utf8(line) {
+            append(Direction::Out, line);
+        }
+    }
+}
+
+fn append(dir: Direction, msg: &str) {
+    let Some(mut file) = FILE.get() else {
+        return;
+    };
+    let t_us = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let dir = match dir {
+        Direction::In => "in",
+        Direction::Out => "out",
+    };
+    // `msg` is already JSON, so there is no need to parse it just to
+    // serialize it again. One write per line keeps lines from concurrent
+    // nodes whole.
+    let line = format!("{{\"dir\":\"{dir}\",\"t_us\":{t_us},\"msg\":{msg}}}\n");
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        crate::warn!("writing record file: {e}");
+    }
+}
+
+/// Reads a recording back.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("opening recording {}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.context("reading recording")?;
+            serde_json::from_str(&line)
+                .with_context(|| format!("{}:{}: not a recorded message", path.display(), i + 1))
+        })
+        .collect()
+}
+
+/// The ids of the nodes with messages in `entries`.
+pub fn node_ids(entries: &[Entry]) -> BTreeSet<&str> {
+    entries
+        .iter()
+        .map(|entry| match entry.dir {
+            Direction::In => entry.msg.dest.as_str(),
+            Direction::Out => entry.msg.src.as_str(),
+        })
+        .collect()
+}
+
+/// The node `entries` were recorded from, if there is only one.
+pub fn only_node(entries: &[Entry]) -> anyhow::Result<String> {
+    let ids = node_ids(entries);
+    match ids.len() {
+        1 => Ok(ids.into_iter().next().unwrap().to_string()),
+        0 => bail!("the recording has no messages"),
+        _ => bail!("the recording has messages from several nodes: {ids:?}"),
+    }
+}
+
+/// Feeds what `node_id` received in `entries` to `node`, with the same
+/// spacing as when they were recorded, and returns what it sent.
+pub fn replay<N: Node>(
+    node: N,
+    config: &Config,
+    entries: &[Entry],
+    node_id: &str,
+) -> anyhow::Result<Vec<Message<Value>>> {
+    let inputs: Vec<(u64, String)> = entries
+        .iter()
+        .filter(|entry| entry.dir == Direction::In && entry.msg.dest == node_id)
+        .map(|entry| Ok((entry.t_us, serde_json::to_string(&entry.msg)?)))
+        .collect::<anyhow::Result<_>>()?;
+    let first = inputs.first().map_or(0, |(t_us, _)| *t_us);
+
+    let (tx, rx) = mpsc::channel();
+    let feeder = thread::spawn(move || {
+        let start = Instant::now();
+        for (t_us, json) in inputs {
+            let due = start + Duration::from_micros(t_us.saturating_sub(first));
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            if tx.send(json).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Don't append the replay to a recording.
+    let config = Config {
+        record_file: None,
+        ..config.clone()
+    };
+    let mut out = Vec::new();
+    let result = match config.dedup {
+        true => run(
+            Deduped::new(node).with_limits(config.dedup_limits),
+            &config,
+            rx,
+            &mut out,
+        ),
+        false => run(node, &config, rx, &mut out),
+    };
+    feeder.join().expect("replay feeder panicked");
+    result?;
+    transport::parse_lines(&out)
+}
+
+/// What `node_id` sent in `entries`.
+pub fn sent<'a>(entries: &'a [Entry], node_id: &str) -> Vec<&'a Message<Value>> {
+    entries
+        .iter()
+        .filter(|entry| entry.dir == Direction::Out && entry.msg.src == node_id)
+        .map(|entry| &entry.msg)
+        .collect()
+}
+
+/// The difference between recorded and replayed output, ignoring order.
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Recorded, but not sent on replay.
+    pub missing: Vec<Value>,
+    /// Sent on replay, but not recorded.
+    pub unexpected: Vec<Value>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for msg in &self.missing {
+            writeln!(f, "- {msg}")?;
+        }
+        for msg in &self.unexpected {
+            writeln!(f, "+ {msg}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares `recorded` with `replayed` as multisets of messages.
+pub fn diff<'a>(
+    recorded: impl IntoIterator<Item = &'a Message<Value>>,
+    replayed: impl IntoIterator<Item = &'a Message<Value>>,
+) -> anyhow::Result<Diff> {
+    // Keyed by the serialized message, whose object keys are sorted.
+    let mut counts: BTreeMap<String, (Value, isize)> = BTreeMap::new();
+    let mut count = |msg: &Message<Value>, n: isize| -> anyhow::Result<()> {
+        let value = serde_json::to_value(msg)?;
+        counts
+            .entry(value.to_string())
+            .or_insert_with(|| (value, 0))
+            .1 += n;
+        Ok(())
+    };
+    for msg in recorded {
+        count(msg, 1)?;
+    }
+    for msg in replayed {
+        count(msg, -1)?;
+    }
+    let mut diff = Diff::default();
+    for (value, count) in counts.into_values() {
+        let (side, n) = if count > 0 {
+            (&mut diff.missing, count)
+        } else {
+            (&mut diff.unexpected, -count)
+        };
+        side.extend(std::iter::repeat_n(value, n as usize));
+    }
+    Ok(diff)
+}
@@ -0,0 +1,226 @@
+//! Retransmission of messages until the destination acknowledges them.
+//!
+//! Every message sent through a [`RetryQueue`] is remembered under
+//! `(dest, msg_id)` and re-sent from [`RetryQueue::resend_due`] (typically
+//! called from [`Node::tick`](crate::Node::tick)) with exponential backoff
+//! and jitter, until a reply with a matching `in_reply_to` is passed to
+//! [`RetryQueue::ack`]. Resends are kept on a [`TimerWheel`], so a tick
+//! only looks at the messages that are due; [`RetryQueue::next_due`] says
+//! when the next one is, for [`Node::next_timer`](crate::Node::next_timer).
+//!
+//! A destination that never answers would have its messages retried
+//! forever, so a queue can be given [`CacheLimits`]: messages first sent
+//! longer ago than the TTL are given up on, and the oldest ones are once
+//! more than `max_entries` are waiting.
+
+use crate::{
+    metrics, time,
+    timer::{TimerId, TimerWheel},
+    CacheLimits, Message, Payload,
+};
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Delay before the first resend.
+    pub initial: Duration,
+    /// Upper bound for the delay between two sends.
+    pub max: Duration,
+    /// Each delay is scaled by a random factor in `1 ± jitter`.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(200),
+            max: Duration::from_secs(5),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay to wait after the `attempt`th send (starting at 0).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+        if self.jitter <= 0.0 {
+            return base;
+        }
+        let factor = time::rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        base.mul_f64(factor.max(0.0))
+    }
+}
+
+#[derive(Debug)]
+struct Entry<P> {
+    msg: Message<P>,
+    attempts: u32,
+    /// The next resend.
+    timer: TimerId,
+    /// When the message was first sent.
+    sent_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct RetryQueue<P = Payload> {
+    backoff: Backoff,
+    entries: HashMap<(String, usize), Entry<P>>,
+    timers: TimerWheel<(String, usize)>,
+    limits: CacheLimits,
+}
+
+impl<P> Default for RetryQueue<P> {
+    fn default() -> Self {
+        Self::new(Backoff::default())
+    }
+}
+
+impl<P> RetryQueue<P> {
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            entries: HashMap::new(),
+            timers: TimerWheel::default(),
+            limits: CacheLimits::default(),
+        }
+    }
+
+    /// Gives up on messages as `limits` says; by default none are.
+    pub fn with_limits(mut self, limits: CacheLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Drops the messages that have been retried for longer than the TTL,
+    /// and the oldest ones while too many are waiting.
+    fn evict(&mut self, now: Instant) {
+        let limits = self.limits;
+        let mut evicted: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| limits.expired(entry.sent_at, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let room = self.entries.len() - evicted.len();
+        if limits.over(room) {
+            let mut oldest: Vec<_> = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !limits.expired(entry.sent_at, now))
+                .map(|(key, entry)| (entry.sent_at, key.clone()))
+                .collect();
+            oldest.sort_unstable();
+            let excess = room - limits.max_entries.unwrap_or(room);
+            evicted.extend(oldest.into_iter().take(excess).map(|(_, key)| key));
+        }
+        for key in &evicted {
+            if let Some(entry) = self.entries.remove(key) {
+                self.timers.cancel(entry.timer);
+            }
+        }
+        if !evicted.is_empty() {
+            crate::debug!("gave up on {} unacked messages", evicted.len());
+        }
+        metrics::record_evictions("retry", evicted.len());
+    }
+
+    /// When the next message is due to be re-sent, if any are waiting.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+}
+
+impl<P: Serialize> RetryQueue<P> {
+    /// Sends `msg` and keeps re-sending it until it is acked. `msg` must
+    /// carry a `msg_id`, otherwise it could never be acknowledged.
+    pub fn send(&mut self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()> {
+        let Some(msg_id) = msg.body.id else {
+            anyhow::bail!("cannot retry a message without msg_id");
+        };
+        msg.send(out)?;
+        let key = (msg.dest.clone(), msg_id);
+        let timer = self.timers.schedule(self.backoff.delay(0), key.clone());
+        let entry = Entry {
+            msg,
+            attempts: 0,
+            timer,
+            sent_at: time::now(),
+        };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.timers.cancel(old.timer);
+        }
+        if self.limits.over(self.entries.len()) {
+            self.evict(time::now());
+        }
+        Ok(())
+    }
+
+    /// Stops retrying the message `reply` responds to. Returns whether such a
+    /// message was pending.
+    pub fn ack<Q>(&mut self, reply: &Message<Q>) -> bool {
+        let Some(in_reply_to) = reply.body.in_reply_to else {
+            return false;
+        };
+        match self.entries.remove(&(reply.src.clone(), in_reply_to)) {
+            Some(entry) => {
+                self.timers.cancel(entry.timer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Re-sends every message whose backoff has expired, after giving up
+    /// on those past the queue's limits. Returns how many were sent.
+    pub fn resend_due(&mut self, out: &mut impl Write) -> anyhow::Result<usize> {
+        self.resend_due_unless(out, |_| false)
+    }
+
+    /// Like [`RetryQueue::resend_due`], except that messages to peers
+    /// `is_down` says are down, e.g. by [`Heartbeats`](crate::heartbeat),
+    /// are held back, while their backoff grows as if they had been sent.
+    /// Once the peer is back they go out at the next, longer, delay.
+    pub fn resend_due_unless(
+        &mut self,
+        out: &mut impl Write,
+        is_down: impl Fn(&str) -> bool,
+    ) -> anyhow::Result<usize> {
+        let now = time::now();
+        self.evict(now);
+        let mut resent = 0;
+        for key in self.timers.expire(now) {
+            let Some(entry) = self.entries.get_mut(&key) else {
+                continue;
+            };
+            entry.attempts += 1;
+            let delay = self.backoff.delay(entry.attempts);
+            entry.timer = self.timers.schedule_at(now + delay, key);
+            if is_down(&entry.msg.dest) {
+                continue;
+            }
+            entry.msg.send(out)?;
+            resent += 1;
+        }
+        metrics::record_retries(resent);
+        Ok(resent)
+    }
+
+    /// Number of messages still waiting for an ack.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,125 @@
+//! Consistent hashing, for deciding which node owns a key.
+//!
+//! Every node is hashed onto a ring of `u64`s at several points, its
+//! virtual nodes, and a key belongs to the node at the first point at or
+//! after the key's own hash, wrapping around. Adding or removing a node
+//! only moves the keys next to its points, about `1 / n` of them, where
+//! `key % n` would move nearly all. More virtual nodes spread keys more
+//! evenly.
+//!
+//! Hashes are FNV-1a over the bytes [`Hash`] feeds in, so every node of
+//! the same build agrees on them, unlike with the randomly keyed
+//! `HashMap` hasher. FNV alone leaves similar inputs such as `n1` and `n2`
+//! close together, so the result is mixed once more at the end.
+
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
+
+/// How many points each node gets by default.
+pub const DEFAULT_VNODES: usize = 64;
+
+/// The nodes of a cluster on a consistent hashing ring.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashRing {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// A ring of `node_ids`, with [`DEFAULT_VNODES`] points each.
+    pub fn new(node_ids: &[String]) -> Self {
+        Self::with_vnodes(node_ids, DEFAULT_VNODES)
+    }
+
+    /// A ring of `node_ids`, with `vnodes` points each.
+    pub fn with_vnodes(node_ids: &[String], vnodes: usize) -> Self {
+        let mut ring = Self {
+            vnodes: vnodes.max(1),
+            points: BTreeMap::new(),
+        };
+        for node in node_ids {
+            ring.add(node);
+        }
+        ring
+    }
+
+    /// Puts `node` on the ring, taking over some keys from the others.
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.points.insert(hash(&(node, i)), node.to_string());
+        }
+    }
+
+    /// Takes `node` off the ring, handing its keys to the next nodes along.
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The node that owns `key`, or `None` if the ring is empty.
+    pub fn owner_of<K: Hash + ?Sized>(&self, key: &K) -> Option<&str> {
+        self.walk(hash(key)).next()
+    }
+
+    /// Up to `n` distinct nodes for `key`, its owner first and then the
+    /// next nodes along the ring, e.g. to place replicas on.
+    pub fn owners_of<K: Hash + ?Sized>(&self, key: &K, n: usize) -> Vec<&str> {
+        let mut owners: Vec<&str> = Vec::with_capacity(n);
+        for node in self.walk(hash(key)) {
+            if owners.len() == n {
+                break;
+            }
+            if !owners.contains(&node) {
+                owners.push(node);
+            }
+        }
+        owners
+    }
+
+    /// The owners of the points from `at` on, once around the ring.
+    fn walk(&self, at: u64) -> impl Iterator<Item = &str> {
+        self.points
+            .range(at..)
+            .chain(self.points.range(..at))
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+/// `key`'s hash, the same on every node of the same build.
+pub(crate) fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = Fnv::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a, finished with MurmurHash3's `fmix64`.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
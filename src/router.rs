@@ -0,0 +1,174 @@
+//! A [`Node`] assembled from per-type handler closures.
+//!
+//! ```no_run
+//! use serde_json::json;
+//! use whirlpool::{main_loop, router::Router};
+//!
+//! let mut router = Router::new(());
+//! router.on("echo", |msg, _ctx| Ok(json!({ "echo": msg.body.payload["echo"] })));
+//! main_loop(router).unwrap();
+//! ```
+
+use crate::{ErrorCode, Membership, Message, MsgIdAllocator, Node, RpcError};
+use anyhow::Context as _;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, io::Write};
+
+/// A message body as a plain JSON object, `type` field included.
+pub type RawPayload = Map<String, Value>;
+
+/// What a handler gets to work with besides the message itself.
+pub struct Context<'a, S> {
+    /// State shared by every handler of the router.
+    pub state: &'a mut S,
+    pub membership: &'a Membership,
+    pub msg_ids: &'a MsgIdAllocator,
+    pub out: &'a mut dyn Write,
+}
+
+impl<S> Context<'_, S> {
+    /// Sends a new message of type `kind` with `fields` in its body.
+    pub fn send(&mut self, dest: &str, kind: &str, fields: impl Serialize) -> anyhow::Result<()> {
+        let payload = typed_payload(kind, serde_json::to_value(fields)?)?;
+        Message::new(
+            &self.membership.node_id,
+            dest,
+            Some(self.msg_ids.next()),
+            payload,
+        )
+        .send(&mut self.out)
+    }
+}
+
+/// A handler returns the fields of its reply, which is sent with type
+/// `<type>_ok`. Returning `Value::Null` sends no reply at all.
+pub type Handler<S> =
+    Box<dyn FnMut(&Message<RawPayload>, &mut Context<'_, S>) -> anyhow::Result<Value>>;
+
+/// Dispatches messages to handlers by their `type`. `init` is answered
+/// automatically unless a handler for it is registered; messages without
+/// a handler go to the [`Router::on_unhandled`] handler if there is one,
+/// and otherwise get a `not-supported` error, or are dropped if they are
+/// replies.
+pub struct Router<S = ()> {
+    state: S,
+    membership: Membership,
+    msg_ids: MsgIdAllocator,
+    handlers: HashMap<String, Handler<S>>,
+    unhandled: Option<Handler<S>>,
+}
+
+impl<S> Router<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            membership: Membership::default(),
+            msg_ids: MsgIdAllocator::new(),
+            handlers: HashMap::new(),
+            unhandled: None,
+        }
+    }
+
+    /// Registers `handler` for messages of type `kind`, replacing any
+    /// previous handler for it.
+    pub fn on<F>(&mut self, kind: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: FnMut(&Message<RawPayload>, &mut Context<'_, S>) -> anyhow::Result<Value> + 'static,
+    {
+        self.handlers.insert(kind.into(), Box::new(handler));
+        self
+    }
+
+    /// Registers `handler` for messages of every type without a handler of
+    /// its own, replies included, to log them, answer them some other way
+    /// or pass them on with [`Context::send`]. What it returns is sent as
+    /// for any other handler, so it returns `Value::Null` for replies.
+    pub fn on_unhandled<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(&Message<RawPayload>, &mut Context<'_, S>) -> anyhow::Result<Value> + 'static,
+    {
+        self.unhandled = Some(Box::new(handler));
+        self
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+}
+
+impl<S> Node<RawPayload> for Router<S> {
+    fn handle(
+        &mut self,
+        input: Message<RawPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let kind = input
+            .body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| RpcError::new(ErrorCode::MalformedRequest, "message has no type"))?
+            .to_string();
+
+        if kind == "init" {
+            let node_id = input.body.payload.get("node_id").and_then(Value::as_str);
+            let node_ids: Vec<String> = input
+                .body
+                .payload
+                .get("node_ids")
+                .map(|ids| serde_json::from_value(ids.clone()))
+                .transpose()?
+                .unwrap_or_default();
+            self.membership
+                .init(node_id.context("init without node_id")?, &node_ids);
+        }
+
+        let handler = match self.handlers.get_mut(&kind) {
+            Some(handler) => Some(handler),
+            None if kind == "init" => None,
+            None => self.unhandled.as_mut(),
+        };
+        let fields = match handler {
+            Some(handler) => {
+                let mut ctx = Context {
+                    state: &mut self.state,
+                    membership: &self.membership,
+                    msg_ids: &self.msg_ids,
+                    out: output,
+                };
+                handler(&input, &mut ctx)?
+            }
+            None if kind == "init" => Value::Object(Map::new()),
+            // Unhandled replies are dropped: answering them with an error
+            // could start an endless exchange of errors between two nodes.
+            None if input.body.in_reply_to.is_some() => return Ok(()),
+            None => {
+                return Err(RpcError::not_supported(format!("no handler for {kind}")).into());
+            }
+        };
+        if fields.is_null() {
+            return Ok(());
+        }
+
+        let payload = typed_payload(&format!("{kind}_ok"), fields)?;
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
+
+/// Builds a body of type `kind` from `fields`, which must be an object.
+fn typed_payload(kind: &str, fields: Value) -> anyhow::Result<RawPayload> {
+    let mut payload = match fields {
+        Value::Object(fields) => fields,
+        Value::Null => Map::new(),
+        other => anyhow::bail!("message fields must be an object, got {other}"),
+    };
+    payload.insert("type".to_string(), Value::String(kind.to_string()));
+    Ok(payload)
+}
@@ -0,0 +1,418 @@
+//! Request/response correlation: send a message and wait for the reply whose
+//! `in_reply_to` matches its `msg_id`.
+//!
+//! Replies are matched on the stdin thread, before they reach the event
+//! loop, so a handler may block on (or `.await`) an [`RpcCall`] without
+//! starving the loop that would otherwise have to deliver the reply.
+//!
+//! A reply may never come, so calls can be given a deadline: with
+//! [`RpcCall::wait_timeout`] or [`RpcCall::timeout`], or, sending the request
+//! again a few times before giving up, with [`Rpc::call_timeout`] and
+//! [`Rpc::call_timeout_async`]. A call that runs out of time fails with an
+//! [`RpcError`] with code [`ErrorCode::Timeout`], which a handler can pass
+//! on to its client with `?`, and stops waiting for the reply.
+//!
+//! When the node shuts down, the main loop [cancels](Rpc::cancel_all) the
+//! calls still waiting, which then fail with a `crash` [`RpcError`], as the
+//! request may or may not have taken effect.
+
+use crate::{ErrorCode, Message, MsgIdAllocator, Payload, RpcError};
+use anyhow::Context as _;
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    future::Future,
+    io::Write,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+type Pending<P> = Arc<Mutex<HashMap<usize, Arc<Slot<P>>>>>;
+
+/// How long a call waits for its reply, and how many more times the
+/// request is sent, under the same `msg_id`, before the call gives up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    pub after: Duration,
+    pub retries: u32,
+}
+
+impl Timeout {
+    /// Waits `after` for the reply, without sending the request again.
+    pub fn after(after: Duration) -> Self {
+        Self { after, retries: 0 }
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+/// The set of outstanding requests of one node. Cloning is cheap and every
+/// clone refers to the same set.
+#[derive(Debug)]
+pub struct Rpc<P = Payload> {
+    msg_ids: MsgIdAllocator,
+    pending: Pending<P>,
+}
+
+impl<P> Clone for Rpc<P> {
+    fn clone(&self) -> Self {
+        Self {
+            msg_ids: self.msg_ids.clone(),
+            pending: Arc::clone(&self.pending),
+        }
+    }
+}
+
+impl<P> Default for Rpc<P> {
+    fn default() -> Self {
+        Self::new(MsgIdAllocator::new())
+    }
+}
+
+impl<P> Rpc<P> {
+    /// `msg_ids` should be the allocator the node uses for its other
+    /// messages, so that request ids never collide with reply ids.
+    pub fn new(msg_ids: MsgIdAllocator) -> Self {
+        Self {
+            msg_ids,
+            pending: Pending::default(),
+        }
+    }
+
+    /// The allocator request ids come from.
+    pub fn msg_ids(&self) -> MsgIdAllocator {
+        self.msg_ids.clone()
+    }
+
+    /// Sends `payload` to `dest` under a fresh `msg_id` and returns a handle
+    /// that resolves to the reply.
+    pub fn call(
+        &self,
+        src: &str,
+        dest: &str,
+        payload: P,
+        out: &mut impl Write,
+    ) -> anyhow::Result<RpcCall<P>>
+    where
+        P: Serialize,
+    {
+        let msg_id = self.msg_ids.next();
+        let slot = Arc::new(Slot::new(dest));
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(msg_id, Arc::clone(&slot));
+        let call = RpcCall {
+            msg_id,
+            slot,
+            pending: Arc::clone(&self.pending),
+        };
+        send_request(Message::new(src, dest, Some(msg_id), payload), out)?;
+        Ok(call)
+    }
+
+    /// Like [`Rpc::call`], blocking until the reply arrives. If none has
+    /// after `timeout.after`, the request is sent again, up to
+    /// `timeout.retries` times, and then the call fails with a timeout
+    /// [`RpcError`].
+    pub fn call_timeout(
+        &self,
+        src: &str,
+        dest: &str,
+        payload: P,
+        timeout: Timeout,
+        out: &mut impl Write,
+    ) -> anyhow::Result<Message<P>>
+    where
+        P: Serialize + Clone,
+    {
+        let call = self.call(src, dest, payload.clone(), out)?;
+        for attempt in 0..=timeout.retries {
+            if attempt > 0 {
+                crate::debug!("no reply to {} from {dest}, retrying", call.msg_id);
+                send_request(
+                    Message::new(src, dest, Some(call.msg_id), payload.clone()),
+                    out,
+                )?;
+            }
+            if let Some(reply) = call.settle_within(timeout.after) {
+                return Ok(reply?);
+            }
+        }
+        Err(timed_out(call.msg_id).into())
+    }
+
+    /// The async counterpart of [`Rpc::call_timeout`].
+    pub async fn call_timeout_async(
+        &self,
+        src: &str,
+        dest: &str,
+        payload: P,
+        timeout: Timeout,
+        out: &mut impl Write,
+    ) -> anyhow::Result<Message<P>>
+    where
+        P: Serialize + Clone,
+    {
+        let call = self.call(src, dest, payload.clone(), out)?;
+        for attempt in 0..=timeout.retries {
+            if attempt > 0 {
+                crate::debug!("no reply to {} from {dest}, retrying", call.msg_id);
+                send_request(
+                    Message::new(src, dest, Some(call.msg_id), payload.clone()),
+                    out,
+                )?;
+            }
+            match call.timeout(timeout.after).await {
+                Err(err) if err.code == ErrorCode::Timeout => {}
+                reply => return Ok(reply?),
+            }
+        }
+        Err(timed_out(call.msg_id).into())
+    }
+
+    /// Hands `msg` to the call waiting for it. Returns the message back if it
+    /// isn't a reply to any outstanding call.
+    pub fn resolve(&self, msg: Message<P>) -> Option<Message<P>> {
+        let Some(in_reply_to) = msg.body.in_reply_to else {
+            return Some(msg);
+        };
+        let Some(slot) = self.pending.lock().unwrap().remove(&in_reply_to) else {
+            return Some(msg);
+        };
+        slot.fill(msg);
+        None
+    }
+
+    /// Fails the call waiting on `msg_id`, if there is one, with `err`,
+    /// waking whoever waits on it.
+    pub fn cancel(&self, msg_id: usize, err: RpcError) {
+        if let Some(slot) = self.pending.lock().unwrap().remove(&msg_id) {
+            slot.cancel(err);
+        }
+    }
+
+    /// Fails every call still waiting for a reply with `err`, waking
+    /// whoever waits on them.
+    pub fn cancel_all(&self, err: RpcError) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for slot in pending.into_values() {
+            slot.cancel(err.clone());
+        }
+    }
+
+    /// How many calls are still waiting for a reply.
+    pub fn outstanding(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// The calls still waiting for a reply, oldest first.
+    pub fn pending(&self) -> Vec<PendingCall> {
+        let now = Instant::now();
+        let mut pending: Vec<_> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(msg_id, slot)| PendingCall {
+                msg_id: *msg_id,
+                dest: slot.dest.clone(),
+                waited: now.saturating_duration_since(slot.sent_at),
+            })
+            .collect();
+        pending.sort_by_key(|call| Reverse(call.waited));
+        pending
+    }
+}
+
+/// A call waiting for its reply, as [`Rpc::pending`] lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingCall {
+    pub msg_id: usize,
+    pub dest: String,
+    /// How long it has waited so far.
+    pub waited: Duration,
+}
+
+#[derive(Debug)]
+struct Slot<P> {
+    state: Mutex<SlotState<P>>,
+    filled: Condvar,
+    /// Where the request went, and when it first did.
+    dest: String,
+    sent_at: Instant,
+}
+
+#[derive(Debug)]
+struct SlotState<P> {
+    reply: Option<Result<Message<P>, RpcError>>,
+    waker: Option<Waker>,
+}
+
+impl<P> Slot<P> {
+    fn new(dest: &str) -> Self {
+        Self {
+            state: Mutex::new(SlotState {
+                reply: None,
+                waker: None,
+            }),
+            filled: Condvar::new(),
+            dest: dest.to_string(),
+            sent_at: Instant::now(),
+        }
+    }
+
+    fn fill(&self, reply: Message<P>) {
+        self.settle(Ok(reply));
+    }
+
+    fn cancel(&self, err: RpcError) {
+        self.settle(Err(err));
+    }
+
+    fn settle(&self, reply: Result<Message<P>, RpcError>) {
+        let mut state = self.state.lock().unwrap();
+        state.reply = Some(reply);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.filled.notify_all();
+    }
+}
+
+/// An outstanding request. Either `.await` it or block with
+/// [`RpcCall::wait`], for the reply or the error the call was cancelled
+/// with; dropping it stops listening for the reply.
+#[derive(Debug)]
+pub struct RpcCall<P = Payload> {
+    msg_id: usize,
+    slot: Arc<Slot<P>>,
+    pending: Pending<P>,
+}
+
+impl<P> RpcCall<P> {
+    pub fn msg_id(&self) -> usize {
+        self.msg_id
+    }
+
+    /// Blocks until the reply arrives, or the call is cancelled.
+    pub fn wait(self) -> Result<Message<P>, RpcError> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(reply) = state.reply.take() {
+                return reply;
+            }
+            state = self.slot.filled.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks until the reply arrives or `timeout` elapses. `None` if it
+    /// didn't, or if the call was cancelled.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Message<P>> {
+        match self.settle_within(timeout)? {
+            Ok(reply) => Some(reply),
+            Err(err) => {
+                // Cancelled for good, so it stays that way.
+                self.slot.state.lock().unwrap().reply = Some(Err(err));
+                None
+            }
+        }
+    }
+
+    /// Blocks until the reply arrives, the call is cancelled, or `timeout`
+    /// elapses.
+    fn settle_within(&self, timeout: Duration) -> Option<Result<Message<P>, RpcError>> {
+        let state = self.slot.state.lock().unwrap();
+        let (mut state, _) = self
+            .slot
+            .filled
+            .wait_timeout_while(state, timeout, |state| state.reply.is_none())
+            .unwrap();
+        state.reply.take()
+    }
+
+    /// Resolves to the reply, or to a timeout [`RpcError`] if it hasn't
+    /// arrived `after` from now. The async counterpart of
+    /// [`RpcCall::wait_timeout`]; the call can be awaited again afterwards.
+    pub fn timeout(&self, after: Duration) -> Deadline<'_, P> {
+        Deadline {
+            call: self,
+            at: Instant::now() + after,
+            alarm: false,
+        }
+    }
+}
+
+/// A reply awaited until a deadline; see [`RpcCall::timeout`].
+#[derive(Debug)]
+pub struct Deadline<'a, P> {
+    call: &'a RpcCall<P>,
+    at: Instant,
+    /// Whether a thread is set to wake the task at `at`.
+    alarm: bool,
+}
+
+impl<P> Future for Deadline<'_, P> {
+    type Output = Result<Message<P>, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let call = self.call;
+        let mut state = call.slot.state.lock().unwrap();
+        if let Some(reply) = state.reply.take() {
+            return Poll::Ready(reply);
+        }
+        let left = self.at.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Poll::Ready(Err(timed_out(call.msg_id)));
+        }
+        state.waker = Some(cx.waker().clone());
+        drop(state);
+        if !std::mem::replace(&mut self.alarm, true) {
+            let waker = cx.waker().clone();
+            thread::spawn(move || {
+                thread::sleep(left);
+                waker.wake();
+            });
+        }
+        Poll::Pending
+    }
+}
+
+fn send_request<P: Serialize>(request: Message<P>, out: &mut impl Write) -> anyhow::Result<()> {
+    request.send(out)?;
+    // The caller is about to wait for the reply, so the request can't sit in
+    // a buffer.
+    out.flush().context("flushing request")
+}
+
+fn timed_out(msg_id: usize) -> RpcError {
+    RpcError::new(ErrorCode::Timeout, format!("no reply to {msg_id} in time"))
+}
+
+impl<P> Future for RpcCall<P> {
+    type Output = Result<Message<P>, RpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        match state.reply.take() {
+            Some(reply) => Poll::Ready(reply),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<P> Drop for RpcCall<P> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.msg_id);
+    }
+}
@@ -0,0 +1,395 @@
+//! An async flavour of [`main_loop`](crate::main_loop), enabled with the
+//! `async` feature.
+//!
+//! Handlers are `async fn`s, so they can `.await` things like RPC replies
+//! that arrive on the stdin thread while the handler is suspended. Each
+//! message, tick and shutdown is handled by a task of its own, and while
+//! one waits the main loop moves on to the next event, so a node can have
+//! calls out for many requests at once. Tasks run on the calling thread,
+//! one at a time: a new one runs until it first waits before the next
+//! event is taken, so handlers still start in the order their messages
+//! arrived, and one that is woken gets its turn between two events.
+//!
+//! Since several handlers can be part way through at once, they take
+//! `&self`, as with [`SharedNode`](crate::pool::SharedNode); a node keeps
+//! its state in `Cell`s and `RefCell`s, and shouldn't hold a borrow of
+//! one across an `.await`. Ticks don't overlap: one that comes while the
+//! last is still running is skipped. On shutdown the node's
+//! [`shutdown`](AsyncNode::shutdown) runs, then the calls still pending are
+//! cancelled and the main loop waits for the handlers left to finish.
+//!
+//! The executor is the crate's own rather than tokio's: the crate is built
+//! against an offline registry that doesn't carry tokio, and handlers only
+//! ever wait on replies read by the input thread, which needs no reactor,
+//! just a waker that puts an [`Event::Wake`] on the main loop's queue.
+
+use crate::{
+    cancel_pending, handle_unknown,
+    input::{self, InputSource},
+    next_event,
+    output::{self, Outbox},
+    reply_on_rpc_error, spawn_event_sources, tcp,
+    trace::{Current, Span},
+    udp, ws, Config, Event, Message, MsgIdAllocator, Payload, Replier, Rpc,
+};
+use anyhow::Context as _;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::{self, Write},
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// The async counterpart of [`Node`](crate::Node).
+// The runtime polls everything on one thread, so the returned futures don't
+// need to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncNode<P = Payload> {
+    async fn handle(&self, msg: Message<P>, out: &mut impl Write) -> anyhow::Result<()>;
+
+    /// How often [`AsyncNode::tick`] should run. `None` disables ticks.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    async fn tick(&self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// See [`Node::next_timer`](crate::Node::next_timer).
+    fn next_timer(&self) -> Option<Instant> {
+        None
+    }
+
+    /// See [`Node::rpc`](crate::Node::rpc).
+    fn rpc(&self) -> Option<Rpc<P>> {
+        None
+    }
+
+    /// See [`Node::msg_ids`](crate::Node::msg_ids).
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.rpc().map(|rpc| rpc.msg_ids())
+    }
+
+    /// See [`Node::shutdown`](crate::Node::shutdown).
+    async fn shutdown(&self, _out: &mut impl Write) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// See [`Node::inspect`](crate::Node::inspect).
+    fn inspect(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// See [`Node::topology`](crate::Node::topology).
+    fn topology(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
+
+/// Feeds `node` with messages from stdin, plus ticks if it asks for them,
+/// until stdin is closed. Runtime behaviour is taken from
+/// [`Config::from_env`], including whether to talk over
+/// [TCP](crate::tcp), [UDP](crate::udp), [WebSocket](crate::ws) or a
+/// [unix socket](crate::unix) instead.
+pub fn async_main_loop<P, N>(node: N) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: AsyncNode<P>,
+{
+    let config = Config::from_env()?;
+    // Before `run` applies the rest, for listening to be logged as asked.
+    crate::log::set_level(config.log_level);
+    if let Some(tcp) = &config.tcp {
+        let (input, out) = tcp::listen(tcp)?;
+        return async_run(node, &config, input, out);
+    }
+    if let Some(udp) = &config.udp {
+        let (input, out) = udp::listen(udp, udp::UdpOptions::from_config(&config))?;
+        return async_run(node, &config, input, out);
+    }
+    if let Some(ws) = &config.ws {
+        let (input, out) = ws::listen(ws)?;
+        return async_run(node, &config, input, out);
+    }
+    if let Some(socket) = &config.unix {
+        #[cfg(unix)]
+        {
+            let (input, out) = crate::unix::listen(socket)?;
+            return async_run(node, &config, input, out);
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("can't listen on {}: no unix sockets here", socket.listen);
+    }
+    async_run(node, &config, input::stdin(), std::io::stdout())
+}
+
+/// The async counterpart of [`run`](crate::run).
+pub fn async_run<P, N, W>(
+    node: N,
+    config: &Config,
+    input: impl InputSource,
+    out: W,
+) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    N: AsyncNode<P>,
+    W: Write + Send,
+{
+    config.apply()?;
+
+    let node = &node;
+    let reader = thread::scope(|scope| {
+        let (outbox, writer) = output::spawn_writer(scope, out, config);
+        let replier = Replier::new(node.msg_ids().unwrap_or_default());
+        let ((wake, events), reader) = spawn_event_sources(
+            input,
+            node.tick_interval(),
+            node.rpc(),
+            &replier,
+            config,
+            outbox.clone(),
+        )?;
+        let mut out = SharedOut(Rc::new(RefCell::new(outbox.clone())));
+        let mut tasks = Tasks {
+            running: Vec::new(),
+            wake,
+            out: out.clone(),
+        };
+        let mut handled = 0;
+        let mut cancelled = None;
+        let mut handle_events = || -> anyhow::Result<()> {
+            let mut stopping = false;
+            loop {
+                tasks.run_woken()?;
+                if stopping && !tasks.running(Kind::Shutdown) {
+                    // No replies are coming for the calls still waiting;
+                    // failing them lets their handlers finish.
+                    cancelled.get_or_insert_with(|| cancel_pending(node.rpc()));
+                    if tasks.running.is_empty() {
+                        break;
+                    }
+                }
+                let event = match next_event(&events, node.next_timer()) {
+                    Some(Event::Wake) => continue,
+                    Some(event) => event,
+                    None => break,
+                };
+                handled += 1;
+                match event {
+                    Event::Message(input) if !stopping => {
+                        let span = Span::message(&input);
+                        let current = span.current();
+                        let request = input.header();
+                        let replier = replier.clone();
+                        let mut out = out.clone();
+                        tasks.spawn(Kind::Message, current, async move {
+                            let result = node.handle(input, &mut out).await;
+                            reply_on_rpc_error(result, &request, &replier, &mut out)?;
+                            span.finish();
+                            Ok(())
+                        });
+                    }
+                    Event::Unknown(input) if !stopping => {
+                        handle_unknown(&input, config.unknown_messages, &replier, &mut out)?
+                    }
+                    // A tick still running covers this one.
+                    Event::Tick if !stopping && !tasks.running(Kind::Tick) => {
+                        let span = Span::tick();
+                        let current = span.current();
+                        let mut out = out.clone();
+                        tasks.spawn(Kind::Tick, current, async move {
+                            node.tick(&mut out)
+                                .await
+                                .context("Node tick function failed")?;
+                            span.finish();
+                            Ok(())
+                        });
+                    }
+                    Event::Shutdown if !stopping => {
+                        stopping = true;
+                        let mut out = out.clone();
+                        tasks.spawn(Kind::Shutdown, Current::default(), async move {
+                            node.shutdown(&mut out)
+                                .await
+                                .context("Node shutdown function failed")
+                        });
+                    }
+                    #[cfg(feature = "admin")]
+                    Event::Inspect(inspect) => {
+                        inspect.answer(|| node.inspect(), || node.topology())
+                    }
+                    _ => {}
+                }
+                out.flush().context("handing output to the writer")?;
+            }
+            Ok(())
+        };
+        let result = handle_events();
+        let cancelled = cancelled.unwrap_or_else(|| cancel_pending(node.rpc()));
+        reader.report_stop(handled, cancelled, || node.inspect());
+        drop(tasks);
+        let closed = outbox.close();
+        // If the writer failed, its error explains any the handler hit.
+        writer.join().expect("writer thread panicked")?;
+        result?;
+        closed.context("closing output")?;
+        Ok::<_, anyhow::Error>(reader)
+    })?;
+
+    // A node stopped by a signal leaves its counters behind, enabled or not.
+    if crate::metrics::enabled() || reader.signalled() {
+        crate::metrics::report();
+    }
+    crate::prometheus::dump();
+    crate::trace::flush();
+
+    reader.join().context("reading input")
+}
+
+/// What a task is handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Message,
+    Tick,
+    Shutdown,
+}
+
+/// The handlers in flight, which take turns on the main loop's thread.
+struct Tasks<'a, P> {
+    running: Vec<Task<'a, P>>,
+    /// Where their wakers nudge the main loop.
+    wake: mpsc::SyncSender<Event<P>>,
+    out: SharedOut,
+}
+
+struct Task<'a, P> {
+    kind: Kind,
+    future: Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>,
+    waker: Arc<TaskWaker<P>>,
+    span: Current,
+}
+
+impl<'a, P: Send + 'static> Tasks<'a, P> {
+    /// Starts a task running `future`, within `span`. It first runs on
+    /// the main loop's next turn, before any later event is taken.
+    fn spawn(
+        &mut self,
+        kind: Kind,
+        span: Current,
+        future: impl Future<Output = anyhow::Result<()>> + 'a,
+    ) {
+        // The span was made current when it started; tasks only have it so
+        // while they run.
+        Current::default().enter();
+        let waker = Arc::new(TaskWaker {
+            woken: AtomicBool::new(true),
+            wake: self.wake.clone(),
+        });
+        self.running.push(Task {
+            kind,
+            future: Box::pin(future),
+            waker,
+            span,
+        });
+    }
+
+    /// Runs each task woken since its last turn until it next waits,
+    /// handing what it wrote to the writer, and drops those that finish.
+    /// Fails with the first error a task does.
+    fn run_woken(&mut self) -> anyhow::Result<()> {
+        let mut i = 0;
+        while i < self.running.len() {
+            let task = &mut self.running[i];
+            if !task.waker.woken.swap(false, Ordering::AcqRel) {
+                i += 1;
+                continue;
+            }
+            let waker = Waker::from(Arc::clone(&task.waker));
+            task.span.enter();
+            let poll = task.future.as_mut().poll(&mut Context::from_waker(&waker));
+            Current::default().enter();
+            self.out.flush().context("handing output to the writer")?;
+            match poll {
+                Poll::Ready(result) => {
+                    self.running.remove(i);
+                    result?;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn running(&self, kind: Kind) -> bool {
+        self.running.iter().any(|task| task.kind == kind)
+    }
+}
+
+/// Marks its task as due a turn, and has the main loop come and give it
+/// one.
+struct TaskWaker<P> {
+    woken: AtomicBool,
+    wake: mpsc::SyncSender<Event<P>>,
+}
+
+impl<P: Send + 'static> Wake for TaskWaker<P> {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            // A full queue means the main loop has events to take first,
+            // and it runs woken tasks after each anyway.
+            let _ = self.wake.try_send(Event::Wake);
+        }
+    }
+}
+
+/// The output every task writes to. Only one runs at a time, and what it
+/// wrote goes to the writer before the next does, so output from
+/// different handlers never interleaves.
+#[derive(Clone)]
+struct SharedOut(Rc<RefCell<Outbox>>);
+
+impl Write for SharedOut {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking it whenever
+/// the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! A typed client for Maelstrom's last-write-wins `lww-kv` service.
+
+use super::{KvClient, KvError, LWW_KV};
+use crate::{clock::HybridClock, Rpc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// A value tagged with a version so that concurrent writers can agree on
+/// which write is the latest, regardless of the order `lww-kv` applies them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<V> {
+    pub version: u64,
+    pub value: V,
+}
+
+impl<V> Versioned<V> {
+    /// `value`, versioned with a timestamp from `clock`. Hybrid logical
+    /// time keeps writes from nodes whose clocks are skewed in causal
+    /// order, which raw wall-clock versions would not.
+    pub fn stamped(clock: &HybridClock, value: V) -> Self {
+        Self {
+            version: clock.now().to_u64(),
+            value,
+        }
+    }
+}
+
+/// `lww-kv` is highly available but may return any recent write, so reads
+/// can go backwards. Every value read or written is remembered in a local
+/// cache that [`LwwKv::read_cached`] can serve without a round trip; clones
+/// share the cache.
+#[derive(Debug, Clone)]
+pub struct LwwKv {
+    client: KvClient,
+    cache: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl LwwKv {
+    pub fn new(node_id: impl Into<String>, rpc: Rpc) -> Self {
+        Self {
+            client: KvClient::new(LWW_KV, node_id, rpc),
+            cache: Arc::default(),
+        }
+    }
+
+    /// The untyped client underneath.
+    pub fn client(&self) -> &KvClient {
+        &self.client
+    }
+
+    /// Reads `key` from the service, or `None` if it has never been written.
+    pub fn read<V: DeserializeOwned + Serialize>(
+        &self,
+        key: impl Serialize,
+        out: &mut impl Write,
+    ) -> Result<Option<V>, KvError> {
+        let key = serde_json::to_value(key)?;
+        let value: Option<V> = self.client.read_typed(&key, out)?;
+        if let Some(value) = &value {
+            self.remember(&key, serde_json::to_value(value)?);
+        }
+        Ok(value)
+    }
+
+    /// The value this node last read or wrote for `key`, without asking the
+    /// service.
+    pub fn read_cached<V: DeserializeOwned>(
+        &self,
+        key: impl Serialize,
+    ) -> Result<Option<V>, KvError> {
+        let key = serde_json::to_value(key)?.to_string();
+        match self.cache.lock().unwrap().get(&key) {
+            Some(value) => Ok(Some(serde_json::from_value(value.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn write<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        value: &V,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        let key = serde_json::to_value(key)?;
+        self.client.write_typed(&key, value, out)?;
+        self.remember(&key, serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    /// Writes `value` unless the service already holds a version at least as
+    /// new. Returns whether the write happened.
+    ///
+    /// The write is a CAS from the value just read, retried from a fresh
+    /// read whenever another writer got there in between, so an older
+    /// version never replaces a newer one.
+    pub fn write_if_newer<V: DeserializeOwned + Serialize>(
+        &self,
+        key: impl Serialize,
+        value: &Versioned<V>,
+        out: &mut impl Write,
+    ) -> Result<bool, KvError> {
+        let key = serde_json::to_value(key)?;
+        let to = serde_json::to_value(value)?;
+        loop {
+            let current: Option<Value> = self.client.read_typed(&key, out)?;
+            if let Some(current) = &current {
+                let version: Versioned<V> = serde_json::from_value(current.clone())?;
+                if version.version >= value.version {
+                    self.remember(&key, current.clone());
+                    return Ok(false);
+                }
+            }
+            // Versioned values are objects, so `null` never matches one
+            // that is there: the CAS can only create the key.
+            let from = current.unwrap_or(Value::Null);
+            match self.client.cas(key.clone(), from, to.clone(), true, out) {
+                Ok(()) => break,
+                Err(e) => match KvError::from(e) {
+                    KvError::PreconditionFailed => continue,
+                    e => return Err(e),
+                },
+            }
+        }
+        self.remember(&key, to);
+        Ok(true)
+    }
+
+    /// Forgets everything in the local cache.
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn remember(&self, key: &Value, value: Value) {
+        self.cache.lock().unwrap().insert(key.to_string(), value);
+    }
+}
@@ -0,0 +1,233 @@
+//! Clients for the services Maelstrom runs alongside the nodes.
+
+use crate::{payload::ReadValue, rpc::Timeout, ErrorCode, Payload, Rpc, RpcError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{fmt, io::Write, time::Duration};
+
+pub mod lww_kv;
+pub mod seq_kv;
+
+pub use lww_kv::{LwwKv, Versioned};
+pub use seq_kv::SeqKv;
+
+/// Maelstrom's sequentially consistent key-value store.
+pub const SEQ_KV: &str = "seq-kv";
+/// Maelstrom's linearizable key-value store.
+pub const LIN_KV: &str = "lin-kv";
+/// Maelstrom's last-write-wins key-value store.
+pub const LWW_KV: &str = "lww-kv";
+
+/// How long to wait for a service reply before giving up.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client for one of Maelstrom's key-value services.
+///
+/// Errors reported by the service come back as [`RpcError`]s inside the
+/// `anyhow::Error`, so callers can `downcast_ref` to react to e.g.
+/// [`ErrorCode::PreconditionFailed`].
+#[derive(Debug, Clone)]
+pub struct KvClient {
+    service: String,
+    node_id: String,
+    rpc: Rpc,
+}
+
+impl KvClient {
+    pub fn new(service: impl Into<String>, node_id: impl Into<String>, rpc: Rpc) -> Self {
+        Self {
+            service: service.into(),
+            node_id: node_id.into(),
+            rpc,
+        }
+    }
+
+    pub fn read(&self, key: Value, out: &mut impl Write) -> anyhow::Result<Value> {
+        match self.call(
+            Payload::Read {
+                key: Some(key),
+                consistency: None,
+            },
+            out,
+        )? {
+            Payload::ReadOk {
+                value: ReadValue::Value { value },
+            } => Ok(value),
+            other => anyhow::bail!("unexpected reply to read: {other:?}"),
+        }
+    }
+
+    pub fn write(&self, key: Value, value: Value, out: &mut impl Write) -> anyhow::Result<()> {
+        match self.call(
+            Payload::Write {
+                key,
+                value,
+                consistency: None,
+            },
+            out,
+        )? {
+            Payload::WriteOk => Ok(()),
+            other => anyhow::bail!("unexpected reply to write: {other:?}"),
+        }
+    }
+
+    pub fn cas(
+        &self,
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let payload = Payload::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        };
+        match self.call(payload, out)? {
+            Payload::CasOk => Ok(()),
+            other => anyhow::bail!("unexpected reply to cas: {other:?}"),
+        }
+    }
+
+    /// Replaces the value under `key` with `f(current)` using a read +
+    /// compare-and-set loop, treating a missing key as `default`. Returns
+    /// the value the successful CAS replaced.
+    pub fn update(
+        &self,
+        key: Value,
+        default: Value,
+        f: impl Fn(&Value) -> anyhow::Result<Value>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<Value> {
+        loop {
+            let current = match self.read(key.clone(), out) {
+                Ok(value) => value,
+                Err(e) if is_error_code(&e, ErrorCode::KeyDoesNotExist) => default.clone(),
+                Err(e) => return Err(e),
+            };
+            let next = f(&current)?;
+            match self.cas(key.clone(), current.clone(), next, true, out) {
+                Ok(()) => return Ok(current),
+                Err(e) if is_error_code(&e, ErrorCode::PreconditionFailed) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// [`KvClient::read`] for any deserializable value; a missing key reads
+    /// as `None`.
+    pub fn read_typed<V: DeserializeOwned>(
+        &self,
+        key: impl Serialize,
+        out: &mut impl Write,
+    ) -> Result<Option<V>, KvError> {
+        match self
+            .read(serde_json::to_value(key)?, out)
+            .map_err(KvError::from)
+        {
+            Ok(value) => Ok(Some(serde_json::from_value(value)?)),
+            Err(KvError::KeyDoesNotExist) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// [`KvClient::write`] for any serializable value.
+    pub fn write_typed<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        value: &V,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        self.write(
+            serde_json::to_value(key)?,
+            serde_json::to_value(value)?,
+            out,
+        )?;
+        Ok(())
+    }
+
+    /// [`KvClient::cas`] for any serializable value.
+    pub fn cas_typed<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        from: &V,
+        to: &V,
+        create_if_not_exists: bool,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        self.cas(
+            serde_json::to_value(key)?,
+            serde_json::to_value(from)?,
+            serde_json::to_value(to)?,
+            create_if_not_exists,
+            out,
+        )?;
+        Ok(())
+    }
+
+    fn call(&self, payload: Payload, out: &mut impl Write) -> anyhow::Result<Payload> {
+        let timeout = Timeout::after(TIMEOUT);
+        let reply = self
+            .rpc
+            .call_timeout(&self.node_id, &self.service, payload, timeout, out)?;
+        match reply.body.payload {
+            Payload::Error { code, text } => Err(RpcError::new(code, text).into()),
+            payload => Ok(payload),
+        }
+    }
+}
+
+/// Whether `err` is an error reply from a service with the given code.
+pub fn is_error_code(err: &anyhow::Error, code: ErrorCode) -> bool {
+    err.downcast_ref::<RpcError>()
+        .is_some_and(|err| err.code == code)
+}
+
+/// A KV service error, translated from the `error` reply or from a failure
+/// to talk to the service at all.
+#[derive(Debug)]
+pub enum KvError {
+    KeyDoesNotExist,
+    PreconditionFailed,
+    Timeout,
+    /// Any other error code the service replied with.
+    Service(RpcError),
+    /// The request could not be sent or the reply could not be decoded.
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for KvError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<RpcError>() {
+            Ok(err) => match err.code {
+                ErrorCode::KeyDoesNotExist => KvError::KeyDoesNotExist,
+                ErrorCode::PreconditionFailed => KvError::PreconditionFailed,
+                ErrorCode::Timeout => KvError::Timeout,
+                _ => KvError::Service(err),
+            },
+            Err(err) => KvError::Other(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for KvError {
+    fn from(err: serde_json::Error) -> Self {
+        KvError::Other(err.into())
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => f.write_str("key does not exist"),
+            KvError::PreconditionFailed => f.write_str("precondition failed"),
+            KvError::Timeout => f.write_str("service timed out"),
+            KvError::Service(err) => err.fmt(f),
+            KvError::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
@@ -0,0 +1,66 @@
+//! A typed client for Maelstrom's sequentially consistent `seq-kv` service.
+
+use super::{KvClient, KvError, SEQ_KV};
+use crate::Rpc;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+
+/// Reads may be stale, but every node observes writes in the same order and
+/// a successful `cas` is always based on the latest value.
+#[derive(Debug, Clone)]
+pub struct SeqKv {
+    client: KvClient,
+}
+
+impl SeqKv {
+    pub fn new(node_id: impl Into<String>, rpc: Rpc) -> Self {
+        Self {
+            client: KvClient::new(SEQ_KV, node_id, rpc),
+        }
+    }
+
+    /// The untyped client underneath.
+    pub fn client(&self) -> &KvClient {
+        &self.client
+    }
+
+    /// Reads `key`, or `None` if it has never been written.
+    pub fn read<V: DeserializeOwned>(
+        &self,
+        key: impl Serialize,
+        out: &mut impl Write,
+    ) -> Result<Option<V>, KvError> {
+        self.client.read_typed(key, out)
+    }
+
+    pub fn write<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        value: &V,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        self.client.write_typed(key, value, out)
+    }
+
+    /// Sets `key` to `to` if it currently holds `from`.
+    pub fn cas<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        from: &V,
+        to: &V,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        self.client.cas_typed(key, from, to, false, out)
+    }
+
+    /// Like [`SeqKv::cas`], but a missing key is created with `to`.
+    pub fn cas_or_create<V: Serialize>(
+        &self,
+        key: impl Serialize,
+        from: &V,
+        to: &V,
+        out: &mut impl Write,
+    ) -> Result<(), KvError> {
+        self.client.cas_typed(key, from, to, true, out)
+    }
+}
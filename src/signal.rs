@@ -0,0 +1,161 @@
+//! Stopping on SIGTERM and SIGINT the way the main loop stops when its
+//! input is closed, so a node killed by a nemesis or by hand still runs
+//! [`Node::shutdown`](crate::Node::shutdown), sends what it has buffered
+//! and leaves a summary of how it stopped in its log.
+//!
+//! Signal handlers may do next to nothing safely, so the one installed here
+//! only writes the signal number to a pipe. A thread reading the other end
+//! passes each signal on to whoever is [watching](on_next) for one. Each
+//! watcher sees a single signal, and stops watching when its [`Watch`] is
+//! dropped. A signal nobody is left to take, such as a second one while the
+//! node is already stopping, does what it would have done without a
+//! handler: it ends the process.
+
+use std::{
+    fmt, io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Term,
+    Int,
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Signal::Term => "SIGTERM",
+            Signal::Int => "SIGINT",
+        })
+    }
+}
+
+/// Calls `watcher` with the next SIGTERM or SIGINT the process gets, unless
+/// the returned [`Watch`] is dropped first, installing the handlers the
+/// first time. The watcher returns whether it took the signal; if none
+/// does, the process gets it as if nobody had been watching. Does nothing
+/// on platforms without signals.
+pub fn on_next(watcher: impl FnOnce(Signal) -> bool + Send + 'static) -> io::Result<Watch> {
+    let id = NEXT_WATCH.fetch_add(1, Ordering::Relaxed);
+    imp::on_next(id, Box::new(watcher))?;
+    Ok(Watch { id })
+}
+
+static NEXT_WATCH: AtomicU64 = AtomicU64::new(0);
+
+/// A watcher waiting for a signal, which stops waiting once this is
+/// dropped.
+#[derive(Debug)]
+pub struct Watch {
+    id: u64,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        imp::forget(self.id);
+    }
+}
+
+type Watcher = Box<dyn FnOnce(Signal) -> bool + Send>;
+
+#[cfg(unix)]
+mod imp {
+    use super::{Signal, Watcher};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Mutex, OnceLock,
+        },
+        thread,
+    };
+
+    /// The end of the pipe the handler writes to.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    static WATCHERS: Mutex<Vec<(u64, Watcher)>> = Mutex::new(Vec::new());
+    static INSTALLED: OnceLock<Result<(), String>> = OnceLock::new();
+
+    pub(super) fn on_next(id: u64, watcher: Watcher) -> io::Result<()> {
+        INSTALLED
+            .get_or_init(|| install().map_err(|err| err.to_string()))
+            .clone()
+            .map_err(io::Error::other)?;
+        WATCHERS.lock().unwrap().push((id, watcher));
+        Ok(())
+    }
+
+    pub(super) fn forget(id: u64) {
+        WATCHERS.lock().unwrap().retain(|(watch, _)| *watch != id);
+    }
+
+    extern "C" fn handle(signal: libc::c_int) {
+        let byte = signal as u8;
+        // Nothing can be done about a full pipe here, and a signal
+        // already waiting in it will stop the node anyway.
+        unsafe { libc::write(PIPE.load(Ordering::Relaxed), (&byte as *const u8).cast(), 1) };
+    }
+
+    fn install() -> io::Result<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        PIPE.store(fds[1], Ordering::Relaxed);
+        thread::Builder::new()
+            .name("signals".into())
+            .spawn(move || dispatch(fds[0]))?;
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Reads of the input go on as if nothing happened.
+            action.sa_flags = libc::SA_RESTART;
+            if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(pipe: libc::c_int) {
+        loop {
+            let mut byte = 0u8;
+            match unsafe { libc::read(pipe, (&mut byte as *mut u8).cast(), 1) } {
+                1 => {}
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => return,
+            }
+            let number = libc::c_int::from(byte);
+            let signal = match number {
+                libc::SIGTERM => Signal::Term,
+                _ => Signal::Int,
+            };
+            let watchers = std::mem::take(&mut *WATCHERS.lock().unwrap());
+            // Every watcher gets a look, whether or not an earlier one took it.
+            let taken = watchers
+                .into_iter()
+                .fold(false, |taken, (_, watcher)| watcher(signal) | taken);
+            if !taken {
+                unsafe {
+                    libc::signal(number, libc::SIG_DFL);
+                    libc::raise(number);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::Watcher;
+    use std::io;
+
+    pub(super) fn on_next(_id: u64, _watcher: Watcher) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn forget(_id: u64) {}
+}
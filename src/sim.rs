@@ -0,0 +1,485 @@
+//! Runs several nodes in one process, connected by a simulated network, so
+//! convergence can be checked with `cargo test` instead of Maelstrom.
+//!
+//! The network delivers each message after a random delay drawn from
+//! [`Network::delay`], loses a [`Network::drop_rate`] fraction of them, and
+//! cuts links between the groups of any partition scheduled with
+//! [`Sim::partition`]. Requests to `seq-kv`, `lin-kv` and `lww-kv` are
+//! answered straight away by in-memory [`KvStore`]s, which lets nodes that
+//! block on [`Rpc`] calls run unchanged.
+//!
+//! Time is virtual: [`Sim::run_for`] moves a clock of the simulation's own
+//! from one due message, tick or [`Node::next_timer`] to the next, without
+//! waiting for them. While the simulation lives, nodes on its thread read
+//! that clock through [`time::now`], and take their random choices from
+//! [`time::rng`], seeded like the network, so a run with a given seed plays
+//! out the same way every time.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use whirlpool::{payload::Payload, sim::Sim, BroadcastNode};
+//!
+//! let mut sim = Sim::new(5, |_| BroadcastNode::default()).unwrap();
+//! sim.network().drop_rate = 0.2;
+//! sim.client_send("n0", Payload::Broadcast { message: 1 });
+//! sim.run_for(Duration::from_secs(2)).unwrap();
+//! assert!(sim.nodes().all(|node| node.seen.contains(&1)));
+//! ```
+
+use crate::{
+    kv::KvStore,
+    reply_on_rpc_error,
+    services::{LIN_KV, LWW_KV, SEQ_KV},
+    time::{self, Clock},
+    transport, Message, MsgIdAllocator, Node, Payload, Replier, Rpc,
+};
+use anyhow::Context;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    io::{self, Write},
+    ops::Range,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// The client id [`Sim::client_send`] sends from.
+pub const CLIENT: &str = "c1";
+
+/// How the simulated network treats messages between nodes.
+#[derive(Debug, Clone)]
+pub struct Network {
+    /// Each message is delivered after a delay drawn uniformly from here.
+    pub delay: Range<Duration>,
+    /// The fraction of messages lost, from 0 to 1.
+    pub drop_rate: f64,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(1)..Duration::from_millis(5),
+            drop_rate: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Partition {
+    groups: Vec<Vec<String>>,
+    from: Instant,
+    until: Instant,
+}
+
+impl Partition {
+    fn group_of(&self, node: &str) -> Option<usize> {
+        self.groups
+            .iter()
+            .position(|group| group.iter().any(|id| id == node))
+    }
+
+    /// Nodes in none of the groups are together in one more group.
+    fn separates(&self, a: &str, b: &str) -> bool {
+        self.group_of(a) != self.group_of(b)
+    }
+}
+
+enum Due<P> {
+    Deliver(Message<P>),
+    Tick(String),
+    /// A node's [`Node::next_timer`].
+    Timer(String),
+}
+
+struct Scheduled<P> {
+    at: Instant,
+    seq: u64,
+    due: Due<P>,
+}
+
+impl<P> PartialEq for Scheduled<P> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<P> Eq for Scheduled<P> {}
+
+impl<P> PartialOrd for Scheduled<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P> Ord for Scheduled<P> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// A cluster of `N`s on a simulated network.
+pub struct Sim<N, P = Payload> {
+    nodes: BTreeMap<String, N>,
+    network: Network,
+    partitions: Vec<Partition>,
+    services: HashMap<&'static str, KvStore>,
+    queue: BinaryHeap<Reverse<Scheduled<P>>>,
+    /// The earliest [`Due::Timer`] queued for each node.
+    timers: HashMap<String, Instant>,
+    seq: u64,
+    rng: StdRng,
+    clock: Rc<Clock>,
+    client_msg_ids: MsgIdAllocator,
+    inbox: Vec<Message<P>>,
+}
+
+impl<N, P> Sim<N, P>
+where
+    N: Node<P>,
+    P: Serialize + DeserializeOwned,
+{
+    /// Starts nodes `n0` to `n{count - 1}`, built by `make` from their id,
+    /// and initializes them.
+    pub fn new(count: usize, make: impl FnMut(&str) -> N) -> anyhow::Result<Self> {
+        Self::with_seed(count, make, rand::random())
+    }
+
+    /// Like [`Sim::new`], with the random delays and drops, and the nodes'
+    /// own random choices, drawn from generators seeded with `seed`.
+    pub fn with_seed(
+        count: usize,
+        mut make: impl FnMut(&str) -> N,
+        seed: u64,
+    ) -> anyhow::Result<Self> {
+        let node_ids: Vec<String> = (0..count).map(|i| format!("n{i}")).collect();
+        // Nodes draw from a generator of their own, so what they do with it
+        // doesn't change what the network does.
+        let clock = Rc::new(Clock::new(seed.rotate_left(32) ^ 0x5eed));
+        time::set(&clock);
+        let mut sim = Self {
+            nodes: node_ids.iter().map(|id| (id.clone(), make(id))).collect(),
+            network: Network::default(),
+            partitions: Vec::new(),
+            services: [SEQ_KV, LIN_KV, LWW_KV]
+                .into_iter()
+                .map(|name| (name, KvStore::default()))
+                .collect(),
+            queue: BinaryHeap::new(),
+            timers: HashMap::new(),
+            seq: 0,
+            rng: StdRng::seed_from_u64(seed),
+            clock,
+            client_msg_ids: MsgIdAllocator::new(),
+            inbox: Vec::new(),
+        };
+        for id in &node_ids {
+            sim.init(id, node_ids.clone())?;
+        }
+        // Nobody is interested in the init_oks.
+        sim.inbox.clear();
+        Ok(sim)
+    }
+
+    /// Starts `node` as `id` while the simulation runs, with an `init`
+    /// naming `node_ids` as the cluster; the node has to join it itself.
+    pub fn add_node(&mut self, id: &str, node: N, node_ids: Vec<String>) -> anyhow::Result<()> {
+        time::set(&self.clock);
+        self.nodes.insert(id.to_string(), node);
+        let inbox = std::mem::take(&mut self.inbox);
+        self.init(id, node_ids)?;
+        self.inbox = inbox;
+        Ok(())
+    }
+
+    fn init(&mut self, id: &str, node_ids: Vec<String>) -> anyhow::Result<()> {
+        let init = Payload::Init {
+            node_id: id.to_string(),
+            node_ids,
+        };
+        let init = convert(&Message::new("c0", id, Some(0), init))?;
+        self.deliver(init)?;
+        if let Some(interval) = self.nodes[id].tick_interval() {
+            self.schedule(self.clock.now() + interval, Due::Tick(id.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn network(&mut self) -> &mut Network {
+        &mut self.network
+    }
+
+    /// Cuts the links between `groups` from `from` until `until`, both
+    /// measured from the start of the simulation.
+    pub fn partition(&mut self, groups: Vec<Vec<String>>, from: Duration, until: Duration) {
+        self.partitions.push(Partition {
+            groups,
+            from: self.clock.start() + from,
+            until: self.clock.start() + until,
+        });
+    }
+
+    pub fn node(&self, id: &str) -> Option<&N> {
+        self.nodes.get(id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &N> {
+        self.nodes.values()
+    }
+
+    /// Sends `payload` from [`CLIENT`] to `dest`, delivered on the next
+    /// [`Sim::run_for`]. Client links are never delayed, dropped or cut.
+    /// Returns the request's `msg_id`.
+    pub fn client_send(&mut self, dest: &str, payload: P) -> usize {
+        let msg_id = self.client_msg_ids.next();
+        let msg = Message::new(CLIENT, dest, Some(msg_id), payload);
+        self.schedule(self.clock.now(), Due::Deliver(msg));
+        msg_id
+    }
+
+    /// Delivers `msg` exactly as given on the next [`Sim::run_for`], for
+    /// messages [`Sim::client_send`] can't build, such as replies or ones
+    /// claiming to come from another node.
+    pub fn inject(&mut self, msg: Message<P>) {
+        self.schedule(self.clock.now(), Due::Deliver(msg));
+    }
+
+    /// Everything nodes sent to clients since the last call.
+    pub fn take_replies(&mut self) -> Vec<Message<P>> {
+        std::mem::take(&mut self.inbox)
+    }
+
+    /// Delivers messages and fires ticks as they fall due, for `duration`
+    /// of simulated time.
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        time::set(&self.clock);
+        let end = self.clock.now() + duration;
+        while self.step_until(end)? {}
+        self.clock.advance_to(end);
+        Ok(())
+    }
+
+    /// Runs until `done` holds, checking after each message delivered and
+    /// tick fired, for at most `limit` of simulated time. Returns whether
+    /// `done` came to hold.
+    pub fn run_until(
+        &mut self,
+        limit: Duration,
+        mut done: impl FnMut(&Self) -> bool,
+    ) -> anyhow::Result<bool> {
+        time::set(&self.clock);
+        let end = self.clock.now() + limit;
+        while !done(self) {
+            if !self.step_until(end)? {
+                self.clock.advance_to(end);
+                return Ok(done(self));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Handles the next thing due, if it is due by `end`.
+    fn step_until(&mut self, end: Instant) -> anyhow::Result<bool> {
+        match self.queue.peek() {
+            Some(Reverse(next)) if next.at <= end => self.clock.advance_to(next.at),
+            _ => return Ok(false),
+        }
+        let Some(Reverse(due)) = self.queue.pop() else {
+            return Ok(false);
+        };
+        match due.due {
+            Due::Deliver(msg) => self.deliver(msg)?,
+            Due::Tick(id) => self.tick(&id)?,
+            Due::Timer(id) => self.fire(&id, due.at)?,
+        }
+        Ok(true)
+    }
+
+    fn schedule(&mut self, at: Instant, due: Due<P>) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            at,
+            seq: self.seq,
+            due,
+        }));
+    }
+
+    fn deliver(&mut self, msg: Message<P>) -> anyhow::Result<()> {
+        let Some(node) = self.nodes.get_mut(&msg.dest) else {
+            self.inbox.push(msg);
+            return Ok(());
+        };
+        let rpc = node.rpc();
+        let msg = match &rpc {
+            Some(rpc) => match rpc.resolve(msg) {
+                Some(msg) => msg,
+                None => return Ok(()),
+            },
+            None => msg,
+        };
+        let id = msg.dest.clone();
+        let replier = Replier::named(&id, node.msg_ids().unwrap_or_default());
+        let mut out = SimOut::new(&mut self.services, rpc);
+        let request = msg.header();
+        let result = node.handle(msg, &mut out);
+        reply_on_rpc_error(result, &request, &replier, &mut out)?;
+        let sent = out.finish()?;
+        self.arm(&id);
+        self.route(sent);
+        Ok(())
+    }
+
+    fn tick(&mut self, id: &str) -> anyhow::Result<()> {
+        let node = self
+            .nodes
+            .get_mut(id)
+            .expect("ticks are only scheduled for nodes");
+        let mut out = SimOut::new(&mut self.services, node.rpc());
+        node.tick(&mut out).context("Node tick function failed")?;
+        let sent = out.finish()?;
+        if let Some(interval) = node.tick_interval() {
+            self.schedule(self.clock.now() + interval, Due::Tick(id.to_string()));
+        }
+        self.arm(id);
+        self.route(sent);
+        Ok(())
+    }
+
+    /// Ticks `id` for the timer queued for `at`, if its timer has passed.
+    fn fire(&mut self, id: &str, at: Instant) -> anyhow::Result<()> {
+        if self.timers.get(id) == Some(&at) {
+            self.timers.remove(id);
+        }
+        let Some(node) = self.nodes.get_mut(id) else {
+            return Ok(());
+        };
+        if node
+            .next_timer()
+            .is_none_or(|timer| timer > self.clock.now())
+        {
+            self.arm(id);
+            return Ok(());
+        }
+        let mut out = SimOut::new(&mut self.services, node.rpc());
+        node.tick(&mut out).context("Node tick function failed")?;
+        let sent = out.finish()?;
+        self.arm(id);
+        self.route(sent);
+        Ok(())
+    }
+
+    /// Queues a [`Due::Timer`] for `id`'s next timer, unless one at or
+    /// before it is queued already.
+    fn arm(&mut self, id: &str) {
+        let Some(at) = self.nodes.get(id).and_then(Node::next_timer) else {
+            return;
+        };
+        if self.timers.get(id).is_some_and(|armed| *armed <= at) {
+            return;
+        }
+        self.timers.insert(id.to_string(), at);
+        self.schedule(at, Due::Timer(id.to_string()));
+    }
+
+    fn route(&mut self, sent: Vec<Message<P>>) {
+        let now = self.clock.now();
+        for msg in sent {
+            if !self.nodes.contains_key(&msg.dest) {
+                self.inbox.push(msg);
+                continue;
+            }
+            let cut = self
+                .partitions
+                .iter()
+                .any(|p| p.from <= now && now < p.until && p.separates(&msg.src, &msg.dest));
+            if cut || self.rng.gen_bool(self.network.drop_rate.clamp(0.0, 1.0)) {
+                continue;
+            }
+            let delay = if self.network.delay.is_empty() {
+                self.network.delay.start
+            } else {
+                self.rng.gen_range(self.network.delay.clone())
+            };
+            self.schedule(now + delay, Due::Deliver(msg));
+        }
+    }
+}
+
+impl<N, P> Drop for Sim<N, P> {
+    fn drop(&mut self) {
+        time::unset(&self.clock);
+    }
+}
+
+/// Converts between payload types that share a JSON representation.
+fn convert<P: Serialize, Q: DeserializeOwned>(msg: &Message<P>) -> anyhow::Result<Message<Q>> {
+    serde_json::to_value(msg)
+        .and_then(serde_json::from_value)
+        .context("converting between payload types")
+}
+
+/// What a node writes to while the simulation runs it. Requests to the KV
+/// services are answered on flush, before the handler gets to wait for the
+/// reply; everything else is collected for routing.
+struct SimOut<'a, P> {
+    buf: Vec<u8>,
+    services: &'a mut HashMap<&'static str, KvStore>,
+    rpc: Option<Rpc<P>>,
+    sent: Vec<Message<P>>,
+}
+
+impl<'a, P: Serialize + DeserializeOwned> SimOut<'a, P> {
+    fn new(services: &'a mut HashMap<&'static str, KvStore>, rpc: Option<Rpc<P>>) -> Self {
+        Self {
+            buf: Vec::new(),
+            services,
+            rpc,
+            sent: Vec::new(),
+        }
+    }
+
+    fn process(&mut self) -> anyhow::Result<()> {
+        let Some(end) = self.buf.iter().rposition(|b| *b == b'\n') else {
+            return Ok(());
+        };
+        let lines: Vec<u8> = self.buf.drain(..=end).collect();
+        for msg in transport::parse_lines::<P>(&lines)? {
+            let Some(store) = self.services.get_mut(msg.dest.as_str()) else {
+                self.sent.push(msg);
+                continue;
+            };
+            let request: Message = convert(&msg)?;
+            let reply = match store.apply(&request.body.payload) {
+                Ok(payload) => request.into_reply(&request.dest, None, payload),
+                Err(err) => request.into_error(&request.dest, None, err),
+            };
+            let reply = convert(&reply)?;
+            let unclaimed = match &self.rpc {
+                Some(rpc) => rpc.resolve(reply),
+                None => Some(reply),
+            };
+            // A reply nobody is waiting for goes back through the network
+            // like any other message.
+            self.sent.extend(unclaimed);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<Vec<Message<P>>> {
+        self.process()?;
+        Ok(self.sent)
+    }
+}
+
+impl<P: Serialize + DeserializeOwned> Write for SimOut<'_, P> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.process()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
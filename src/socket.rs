@@ -0,0 +1,266 @@
+//! What the [TCP](crate::tcp) and [unix socket](crate::unix) transports
+//! share: newline-delimited JSON over connections of some [`Stream`], read
+//! into one input and routed back out by `dest`. [UDP](crate::udp) shares
+//! the configuration and the `init`.
+//!
+//! The messages arriving on every connection to a node make up its input.
+//! Each message it sends goes to the connection that `dest` last sent
+//! something on, which is how clients get their replies, or failing that,
+//! for a node in [`SocketConfig::peers`], to a connection dialed to the
+//! address listed for it. Messages to anyone else are dropped, as are
+//! messages a connection fails to take, like a network would.
+//!
+//! Nobody sends `init` unless asked to: with [`SocketConfig::node_id`],
+//! the node sends itself one naming it and every peer. Maelstrom's
+//! services, such as `lin-kv`, aren't there.
+
+use crate::input::{InputSource, JsonStream};
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufReader, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+/// What the `init` a node sends itself comes from. Its reply is dropped.
+pub const INIT_SRC: &str = "init";
+
+/// Where a node listens, and where its peers do.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// The address to listen on, such as `127.0.0.1:7000`, or a path for a
+    /// unix socket.
+    pub listen: String,
+    /// The address each node of the cluster listens on, by id.
+    pub peers: BTreeMap<String, String>,
+    /// This node's id, for it to be sent an `init` naming it and every node
+    /// in `peers` rather than wait for one.
+    pub node_id: Option<String>,
+}
+
+/// Parses `n1=127.0.0.1:7000,n2=127.0.0.1:7001` into addresses by node.
+pub fn parse_peers(s: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    s.split(',')
+        .filter(|peer| !peer.trim().is_empty())
+        .map(|peer| match peer.split_once('=') {
+            Some((node, addr)) => Ok((node.trim().to_string(), addr.trim().to_string())),
+            None => bail!("expected node=address, got {peer}"),
+        })
+        .collect()
+}
+
+/// A connection messages travel over.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    /// Another handle to the same connection, to read from while this one
+    /// is written to.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+/// Passes on what arrives on every connection `accept` returns, on a
+/// thread named `name`, and has the output send messages over them or
+/// dial `config.peers` with `dial`.
+pub(crate) fn serve<S: Stream>(
+    name: &str,
+    mut accept: impl FnMut() -> io::Result<S> + Send + 'static,
+    dial: fn(&str) -> io::Result<S>,
+    config: &SocketConfig,
+) -> anyhow::Result<(SocketInput, SocketOutput<S>)> {
+    let (tx, rx) = mpsc::channel();
+    let shared = Arc::new(Shared {
+        routes: Mutex::new(HashMap::new()),
+        tx,
+        next_conn: AtomicUsize::new(0),
+    });
+    let accepting = shared.clone();
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || loop {
+            match accept() {
+                Ok(stream) => {
+                    accepting.read_from(stream);
+                }
+                Err(e) => crate::warn!("accepting a connection: {e}"),
+            }
+        })?;
+    let input = SocketInput::new(config, rx);
+    let output = SocketOutput {
+        buf: Vec::new(),
+        peers: config.peers.clone(),
+        dial,
+        shared,
+    };
+    Ok((input, output))
+}
+
+/// The fields a message is routed by.
+#[derive(Deserialize)]
+pub(crate) struct Envelope {
+    pub src: String,
+    pub dest: String,
+}
+
+/// An open connection, and which one it is.
+struct Route<S> {
+    conn: usize,
+    stream: S,
+}
+
+struct Shared<S> {
+    /// The connection to send each node or client's messages on.
+    routes: Mutex<HashMap<String, Route<S>>>,
+    tx: mpsc::Sender<String>,
+    next_conn: AtomicUsize,
+}
+
+impl<S: Stream> Shared<S> {
+    /// Passes on the messages arriving on `stream`, on a thread of its own,
+    /// and routes messages to their senders back over it.
+    fn read_from(self: &Arc<Self>, stream: S) -> usize {
+        let conn = self.next_conn.fetch_add(1, Ordering::Relaxed);
+        let shared = self.clone();
+        let spawned = thread::Builder::new()
+            .name("socket-read".into())
+            .spawn(move || {
+                if let Err(e) = shared.read(conn, &stream) {
+                    crate::warn!("closing connection {conn}: {e:#}");
+                }
+                shared.routes.lock().unwrap().retain(|_, r| r.conn != conn);
+            });
+        if let Err(e) = spawned {
+            crate::warn!("reading a connection: {e}");
+        }
+        conn
+    }
+
+    fn read(&self, conn: usize, stream: &S) -> anyhow::Result<()> {
+        let mut messages = JsonStream::new(BufReader::new(stream.try_clone()?));
+        while let Some(json) = messages.next_message() {
+            let json = json?;
+            let Envelope { src, .. } = serde_json::from_str(&json).context("message has no src")?;
+            let mut routes = self.routes.lock().unwrap();
+            if routes.get(&src).is_none_or(|route| route.conn != conn) {
+                let stream = stream.try_clone()?;
+                routes.insert(src, Route { conn, stream });
+            }
+            drop(routes);
+            if self.tx.send(json).is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The messages arriving on every connection. Never exhausted, so the main
+/// loop runs until the process is stopped.
+pub struct SocketInput {
+    /// The `init` to start with, if the node isn't to wait for one.
+    init: Option<String>,
+    rx: mpsc::Receiver<String>,
+}
+
+impl SocketInput {
+    /// The messages coming out of `rx`, after an `init` if `config` names
+    /// this node.
+    pub(crate) fn new(config: &SocketConfig, rx: mpsc::Receiver<String>) -> Self {
+        let init = config.node_id.as_ref().map(|node_id| {
+            let init = json!({
+                "src": INIT_SRC,
+                "dest": node_id,
+                "body": {
+                    "type": "init",
+                    "msg_id": 0,
+                    "node_id": node_id,
+                    "node_ids": config.peers.keys().collect::<Vec<_>>(),
+                },
+            });
+            init.to_string()
+        });
+        Self { init, rx }
+    }
+}
+
+impl InputSource for SocketInput {
+    fn next_message(&mut self) -> Option<anyhow::Result<String>> {
+        if let Some(init) = self.init.take() {
+            return Some(Ok(init));
+        }
+        self.rx.recv().ok().map(Ok)
+    }
+}
+
+/// Sends each complete line written to it over the connection for its
+/// `dest`.
+pub struct SocketOutput<S> {
+    buf: Vec<u8>,
+    peers: BTreeMap<String, String>,
+    dial: fn(&str) -> io::Result<S>,
+    shared: Arc<Shared<S>>,
+}
+
+impl<S: Stream> SocketOutput<S> {
+    fn send(&mut self, line: &[u8]) {
+        let dest = match serde_json::from_slice::<Envelope>(line) {
+            Ok(envelope) => envelope.dest,
+            Err(e) => {
+                crate::warn!("not sending unroutable message: {e}");
+                return;
+            }
+        };
+        let mut routes = self.shared.routes.lock().unwrap();
+        if let Some(route) = routes.get_mut(&dest) {
+            match route.stream.write_all(line) {
+                Ok(()) => return,
+                Err(e) => crate::debug!("sending to {dest}: {e}"),
+            }
+            routes.remove(&dest);
+        }
+        // Not while holding the lock, which would hold up every reader.
+        drop(routes);
+        let Some(addr) = self.peers.get(&dest) else {
+            if dest != INIT_SRC {
+                crate::debug!("no connection to {dest}, dropping message");
+            }
+            return;
+        };
+        let mut stream = match (self.dial)(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                crate::debug!("dialing {dest} at {addr}: {e}");
+                return;
+            }
+        };
+        if let Err(e) = stream.write_all(line) {
+            crate::debug!("sending to {dest}: {e}");
+            return;
+        }
+        // The peer may answer over the same connection.
+        let conn = match stream.try_clone() {
+            Ok(reading) => self.shared.read_from(reading),
+            Err(_) => usize::MAX,
+        };
+        let mut routes = self.shared.routes.lock().unwrap();
+        routes.insert(dest, Route { conn, stream });
+    }
+}
+
+impl<S: Stream> Write for SocketOutput<S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            self.send(&line);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,379 @@
+//! Keeping node state across crashes, for Maelstrom's kill/restart nemesis.
+//!
+//! A node that implements [`Persistent`] can be wrapped in [`Persisted`],
+//! which snapshots its state to `<dir>/<node_id>.json` every so often and,
+//! when the node is initialized again after a restart, restores the last
+//! snapshot on top of whatever `init` set up. On its own, anything that
+//! happened after the last snapshot is lost, which suits state that peers
+//! can fill back in, like the values a broadcast node has seen.
+//!
+//! With [`Persisted::with_wal`], every message that changes the node's
+//! state is also appended to a [write-ahead log](crate::wal) before the
+//! node handles it, and so before it is acknowledged. Recovery replays the
+//! entries the last snapshot doesn't cover, and each snapshot truncates the
+//! log again. Nothing the node sends while replaying goes out, and calls it
+//! makes fail straight away as cancelled, rather than wait out their
+//! timeouts for replies that won't come.
+//!
+//! Nodes that keep their state in Maelstrom's KV services, like
+//! [`CounterNode`](crate::CounterNode), don't need this: the services
+//! outlive node processes.
+
+use crate::{
+    broadcast::BroadcastNode,
+    kafka::Logs,
+    time,
+    txn::{Store, TxnNode},
+    wal::Wal,
+    Config, KafkaNode, Message, MsgIdAllocator, Node, Payload, Rpc, RpcError,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// A node whose state can be saved and restored.
+pub trait Persistent {
+    type State: Serialize + DeserializeOwned;
+
+    fn state(&self) -> Self::State;
+
+    /// Merges `state`, as saved before a restart, into the node's.
+    fn restore(&mut self, state: Self::State);
+}
+
+impl Persistent for BroadcastNode {
+    type State = HashSet<usize>;
+
+    fn state(&self) -> Self::State {
+        self.seen.clone()
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        self.seen.extend(state);
+    }
+}
+
+/// Only the node's local logs; replicated logs live in `lin-kv`.
+impl Persistent for KafkaNode {
+    type State = Logs;
+
+    fn state(&self) -> Self::State {
+        self.logs.clone()
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        self.logs = state;
+    }
+}
+
+impl Persistent for TxnNode {
+    type State = Store;
+
+    fn state(&self) -> Self::State {
+        self.store.clone()
+    }
+
+    fn restore(&mut self, state: Self::State) {
+        self.store = state;
+    }
+}
+
+/// A snapshot file, replaced atomically on every save.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    path: PathBuf,
+}
+
+impl Storage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The last saved snapshot, or `None` if there is none yet.
+    pub fn load<S: DeserializeOwned>(&self) -> anyhow::Result<Option<S>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", self.path.display()));
+            }
+        };
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("parsing snapshot {}", self.path.display()))
+    }
+
+    pub fn save<S: Serialize>(&self, state: &S) -> anyhow::Result<()> {
+        self.save_bytes(&serde_json::to_vec(state).context("serializing snapshot")?)
+    }
+
+    /// Writes to a temporary file first, so a crash mid-write leaves the
+    /// previous snapshot intact.
+    fn save_bytes(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        file.write_all(bytes)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| format!("replacing {}", self.path.display()))
+    }
+}
+
+/// What a snapshot file holds: the state, and the last write-ahead log
+/// entry already applied to it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint<S> {
+    seq: u64,
+    state: S,
+}
+
+/// Whether handling `payload` changes a node's state, and so has to be in
+/// the write-ahead log before it is acknowledged.
+pub fn is_mutation(payload: &Payload) -> bool {
+    matches!(
+        payload,
+        Payload::Add { .. }
+            | Payload::Broadcast { .. }
+            | Payload::Gossip { .. }
+            | Payload::CatchUpOk { .. }
+            | Payload::SyncResponse { .. }
+            | Payload::Send { .. }
+            | Payload::CommitOffsets { .. }
+            | Payload::Txn { .. }
+            | Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::Remove { .. }
+            | Payload::Merge { .. }
+    )
+}
+
+/// `N`, snapshotted to `dir` every `interval`, and optionally with every
+/// mutation logged in between.
+#[derive(Debug)]
+pub struct Persisted<N> {
+    node: N,
+    dir: PathBuf,
+    interval: Duration,
+    /// Set up once `init` tells us the node's id.
+    storage: Option<Storage>,
+    use_wal: bool,
+    wal: Option<Wal>,
+    last_saved: Vec<u8>,
+    saved_at: Instant,
+    ticked_at: Instant,
+}
+
+impl<N: Node + Persistent> Persisted<N> {
+    pub fn new(node: N, dir: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            node,
+            dir: dir.into(),
+            interval,
+            storage: None,
+            use_wal: false,
+            wal: None,
+            last_saved: Vec::new(),
+            saved_at: time::now(),
+            ticked_at: time::now(),
+        }
+    }
+
+    /// A node snapshotted to `dir` as `config` says, with a write-ahead
+    /// log if `config.wal` is set.
+    pub fn configured(node: N, dir: impl Into<PathBuf>, config: &Config) -> Self {
+        let persisted = Self::new(node, dir, config.snapshot_interval);
+        if config.wal {
+            persisted.with_wal()
+        } else {
+            persisted
+        }
+    }
+
+    /// Also appends every message that [mutates](is_mutation) the node to
+    /// `<dir>/<node_id>.wal` before handling it, so nothing acknowledged is
+    /// lost between snapshots. Each snapshot truncates the log.
+    pub fn with_wal(mut self) -> Self {
+        self.use_wal = true;
+        self
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.node
+    }
+
+    /// Saves a snapshot now, unless nothing changed since the last one, and
+    /// truncates the write-ahead log it makes redundant.
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        self.saved_at = time::now();
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let checkpoint = Checkpoint {
+            seq: self.wal.as_ref().map_or(0, Wal::seq),
+            state: self.node.state(),
+        };
+        let bytes = serde_json::to_vec(&checkpoint).context("serializing snapshot")?;
+        if bytes != self.last_saved {
+            storage.save_bytes(&bytes)?;
+            self.last_saved = bytes;
+            if let Some(wal) = &mut self.wal {
+                wal.truncate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the last snapshot and replays the log entries it doesn't
+    /// cover. Returns the last sequence number seen.
+    fn recover(&mut self, storage: &Storage, wal: &Path) -> anyhow::Result<u64> {
+        let mut covered = 0;
+        if let Some(checkpoint) = storage.load::<Checkpoint<N::State>>()? {
+            crate::info!("restored state from {}", storage.path().display());
+            self.node.restore(checkpoint.state);
+            covered = checkpoint.seq;
+        }
+        let mut seq = covered;
+        let mut replayed = 0;
+        let mut out = Replay {
+            rpc: self.node.rpc(),
+            buf: Vec::new(),
+        };
+        for (n, msg) in Wal::read::<Message>(wal)? {
+            seq = seq.max(n);
+            if n <= covered {
+                continue;
+            }
+            // The replies went out before the crash; only the effects are
+            // wanted now. Requests that failed then fail again.
+            if let Err(e) = self.node.handle(msg, &mut out) {
+                if e.downcast_ref::<RpcError>().is_none() {
+                    return Err(e).context("replaying write-ahead log");
+                }
+            }
+            replayed += 1;
+        }
+        if replayed > 0 {
+            crate::info!("replayed {replayed} entries from {}", wal.display());
+        }
+        Ok(seq)
+    }
+}
+
+/// Where what a node sends while replaying the write-ahead log goes:
+/// nowhere. Each request it makes through its [`Rpc`] is cancelled as soon
+/// as it is written.
+struct Replay {
+    rpc: Option<Rpc>,
+    buf: Vec<u8>,
+}
+
+impl Write for Replay {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(rpc) = &self.rpc else {
+            return Ok(data.len());
+        };
+        self.buf.extend_from_slice(data);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            let Ok(msg) = serde_json::from_slice::<Message<serde_json::Value>>(&line) else {
+                continue;
+            };
+            if let (Some(msg_id), None) = (msg.body.id, msg.body.in_reply_to) {
+                rpc.cancel(msg_id, RpcError::crash("not sent while replaying"));
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<N: Node + Persistent> Node for Persisted<N> {
+    fn handle(&mut self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let Payload::Init { node_id, .. } = &msg.body.payload else {
+            if let Some(wal) = &mut self.wal {
+                if is_mutation(&msg.body.payload) {
+                    wal.append(&msg)?;
+                }
+            }
+            return self.node.handle(msg, out);
+        };
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating state directory {}", self.dir.display()))?;
+        let storage = Storage::new(self.dir.join(format!("{node_id}.json")));
+        let wal = self.dir.join(format!("{node_id}.wal"));
+        self.node.handle(msg, out)?;
+        let seq = self.recover(&storage, &wal)?;
+        if self.use_wal {
+            self.wal = Some(Wal::open(wal, seq)?);
+        }
+        self.storage = Some(storage);
+        Ok(())
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(match self.node.tick_interval() {
+            Some(interval) => interval.min(self.interval),
+            None => self.interval,
+        })
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        // Ticks may come more often than the node asked for. Half a tick
+        // of slack keeps it from missing every other one when they don't.
+        let slack = self.tick_interval().unwrap_or_default() / 2;
+        let now = time::now();
+        let interval_due = self
+            .node
+            .tick_interval()
+            .is_some_and(|interval| time::elapsed(self.ticked_at) + slack >= interval);
+        if interval_due {
+            self.ticked_at = now;
+        }
+        if interval_due || self.node.next_timer().is_some_and(|at| at <= now) {
+            self.node.tick(out)?;
+        }
+        if time::elapsed(self.saved_at) + slack >= self.interval {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.node.next_timer()
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        self.node.rpc()
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        self.node.msg_ids()
+    }
+
+    /// Takes a last snapshot, so a clean shutdown loses nothing.
+    fn shutdown(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        self.node.shutdown(out)?;
+        self.save()
+    }
+
+    fn inspect(&self) -> serde_json::Value {
+        self.node.inspect()
+    }
+
+    fn topology(&self) -> serde_json::Value {
+        self.node.topology()
+    }
+}
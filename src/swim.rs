@@ -0,0 +1,428 @@
+//! SWIM-style failure detection: which peers are alive, suspected or dead.
+//!
+//! Every [`SwimConfig::probe_interval`], a [`FailureDetector`] pings the
+//! next peer in a shuffled round. If no `ping_ok` comes back within
+//! [`SwimConfig::probe_timeout`], it asks [`SwimConfig::indirect_probes`]
+//! other peers to try with a `ping_req`, in case only the link between
+//! the two is down. If nobody got an answer by the end of the interval,
+//! the peer is suspected, and it is declared dead once it has been
+//! suspected for [`SwimConfig::suspect_timeout`].
+//!
+//! Changes of state spread by piggybacking on the pings and their replies.
+//! Each comes with the member's incarnation number, which only the member
+//! itself raises: a node that hears it is suspected or dead refutes it by
+//! announcing itself alive under a new incarnation, which overrides the
+//! older rumour everywhere. Pings carry the pinger's view of the target,
+//! so a node that was declared dead during a partition finds out, and
+//! comes back, once it is reachable again; dead members keep being probed
+//! for that reason.
+//!
+//! A node embeds a detector, passes it its messages first with
+//! [`FailureDetector::handle`], ticks it, and asks it about its peers, as
+//! [`BroadcastNode`](crate::BroadcastNode) does to route around dead
+//! neighbors.
+
+use crate::{time, Membership, Message, MsgIdAllocator, Payload};
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    /// Missed a probe; dead unless it refutes this in time.
+    Suspect,
+    Dead,
+}
+
+/// What one node believes about `node`, as of `incarnation`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub node: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SwimConfig {
+    /// How often a peer is probed, and how long it has to answer directly
+    /// or indirectly.
+    pub probe_interval: Duration,
+    /// How long to wait for a direct answer before asking others to probe.
+    pub probe_timeout: Duration,
+    /// How many peers are asked to probe indirectly.
+    pub indirect_probes: usize,
+    /// How long a suspected peer has to refute it before it is dead.
+    pub suspect_timeout: Duration,
+    /// How many messages each update is piggybacked on.
+    pub retransmits: u32,
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_millis(300),
+            probe_timeout: Duration::from_millis(100),
+            indirect_probes: 3,
+            suspect_timeout: Duration::from_secs(1),
+            retransmits: 6,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    state: MemberState,
+    incarnation: u64,
+    /// When it entered `state`.
+    since: Instant,
+}
+
+/// The peer being probed this interval.
+#[derive(Debug)]
+struct Probe {
+    target: String,
+    sent_at: Instant,
+    /// Whether others were asked to probe it too.
+    indirect: bool,
+}
+
+/// A `ping_req` this node is carrying out, waiting for the target's
+/// answer to `ping` to pass back to `requester`.
+#[derive(Debug)]
+struct Relay {
+    requester: Message<()>,
+    target: String,
+    sent_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct FailureDetector {
+    config: SwimConfig,
+    membership: Membership,
+    msg_ids: MsgIdAllocator,
+    incarnation: u64,
+    members: HashMap<String, Member>,
+    /// Peers left to probe this round.
+    round: Vec<String>,
+    probe: Option<Probe>,
+    next_probe: Option<Instant>,
+    /// Indirect probes for others, by the `msg_id` of our `ping`.
+    relays: HashMap<usize, Relay>,
+    /// Updates to piggyback, with how many more times to send each.
+    updates: Vec<(MemberUpdate, u32)>,
+}
+
+impl FailureDetector {
+    pub fn new(config: SwimConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Starts tracking the cluster from `init`, every peer alive, with
+    /// `msg_ids` shared with the node the detector is part of.
+    pub fn init(&mut self, membership: &Membership, msg_ids: MsgIdAllocator) {
+        self.membership = membership.clone();
+        self.msg_ids = msg_ids;
+        let now = time::now();
+        self.members = membership
+            .peers()
+            .map(|peer| {
+                let member = Member {
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                    since: now,
+                };
+                (peer.clone(), member)
+            })
+            .collect();
+    }
+
+    /// Follows nodes joining or leaving after `init`. Peers that joined
+    /// start out alive; those that left are no longer probed.
+    pub fn set_membership(&mut self, membership: &Membership) {
+        self.membership = membership.clone();
+        let now = time::now();
+        self.members.retain(|peer, _| membership.is_peer(peer));
+        self.round.retain(|peer| membership.is_peer(peer));
+        for peer in membership.peers() {
+            self.members.entry(peer.clone()).or_insert(Member {
+                state: MemberState::Alive,
+                incarnation: 0,
+                since: now,
+            });
+        }
+    }
+
+    /// What this node believes about `node`, if it is a peer.
+    pub fn state(&self, node: &str) -> Option<MemberState> {
+        self.members.get(node).map(|member| member.state)
+    }
+
+    /// Whether `node` is a peer that was declared dead.
+    pub fn is_dead(&self, node: &str) -> bool {
+        self.state(node) == Some(MemberState::Dead)
+    }
+
+    /// Every peer and its state.
+    pub fn members(&self) -> impl Iterator<Item = (&String, MemberState)> {
+        self.members
+            .iter()
+            .map(|(node, member)| (node, member.state))
+    }
+
+    /// The peers that aren't dead.
+    pub fn live_peers(&self) -> impl Iterator<Item = &String> {
+        self.members()
+            .filter(|(_, state)| *state != MemberState::Dead)
+            .map(|(node, _)| node)
+    }
+
+    /// Handles `msg` if it is part of the protocol, and returns whether it
+    /// was.
+    pub fn handle(&mut self, msg: &Message, out: &mut impl Write) -> anyhow::Result<bool> {
+        let me = self.membership.node_id.clone();
+        match &msg.body.payload {
+            Payload::Ping { updates } => {
+                self.apply(updates);
+                let reply = Payload::PingOk {
+                    updates: self.piggyback(),
+                };
+                msg.into_reply(&self.membership.node_id, Some(self.msg_ids.next()), reply)
+                    .send(out)?;
+            }
+            Payload::PingOk { updates } => {
+                self.apply(updates);
+                self.acked(&msg.src);
+                let relay = msg.body.in_reply_to.and_then(|id| self.relays.remove(&id));
+                if let Some(relay) = relay {
+                    let reply = Payload::PingReqOk {
+                        target: relay.target,
+                    };
+                    relay
+                        .requester
+                        .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), reply)
+                        .send(out)?;
+                }
+            }
+            Payload::PingReq { target, updates } => {
+                self.apply(updates);
+                let msg_id = self.msg_ids.next();
+                let relay = Relay {
+                    requester: msg.header(),
+                    target: target.clone(),
+                    sent_at: time::now(),
+                };
+                self.relays.insert(msg_id, relay);
+                let ping = self.ping(target);
+                Message::new(&me, target, Some(msg_id), ping).send(out)?;
+            }
+            Payload::PingReqOk { target } => self.acked(target),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Runs the protocol: probes, indirect probes and timeouts.
+    pub fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let now = time::now();
+        let config = self.config.clone();
+        self.relays
+            .retain(|_, relay| now - relay.sent_at < config.probe_interval);
+
+        if let Some(probe) = &mut self.probe {
+            let waited = now - probe.sent_at;
+            if waited >= config.probe_interval {
+                let target = probe.target.clone();
+                self.probe = None;
+                self.suspect(&target);
+            } else if waited >= config.probe_timeout && !probe.indirect {
+                probe.indirect = true;
+                let target = probe.target.clone();
+                self.probe_indirectly(&target, out)?;
+            }
+        }
+
+        let expired: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(_, member)| {
+                member.state == MemberState::Suspect && now - member.since >= config.suspect_timeout
+            })
+            .map(|(node, member)| (node.clone(), member.incarnation))
+            .collect();
+        for (node, incarnation) in expired {
+            crate::warn!("{node} is dead");
+            self.set(&node, MemberState::Dead, incarnation);
+        }
+
+        if self.probe.is_none() && self.next_probe.is_none_or(|at| now >= at) {
+            self.next_probe = Some(now + config.probe_interval);
+            if let Some(target) = self.next_target() {
+                let ping = self.ping(&target);
+                let msg_id = Some(self.msg_ids.next());
+                Message::new(&self.membership.node_id, &target, msg_id, ping).send(out)?;
+                self.probe = Some(Probe {
+                    target,
+                    sent_at: now,
+                    indirect: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The next peer to probe, reshuffling once every peer had its turn.
+    fn next_target(&mut self) -> Option<String> {
+        if self.round.is_empty() {
+            self.round = self.members.keys().cloned().collect();
+            self.round.shuffle(&mut time::rng());
+        }
+        self.round.pop()
+    }
+
+    fn probe_indirectly(&mut self, target: &str, out: &mut impl Write) -> anyhow::Result<()> {
+        let helpers = self
+            .live_peers()
+            .filter(|peer| *peer != target)
+            .cloned()
+            .choose_multiple(&mut time::rng(), self.config.indirect_probes);
+        for helper in helpers {
+            let req = Payload::PingReq {
+                target: target.to_string(),
+                updates: self.piggyback(),
+            };
+            let msg_id = Some(self.msg_ids.next());
+            Message::new(&self.membership.node_id, &helper, msg_id, req).send(out)?;
+        }
+        Ok(())
+    }
+
+    /// A `ping` for `target`, telling it what we think of it on top of the
+    /// usual updates.
+    fn ping(&mut self, target: &str) -> Payload {
+        let mut updates = self.piggyback();
+        if let Some(member) = self.members.get(target) {
+            updates.retain(|update| update.node != target);
+            updates.push(MemberUpdate {
+                node: target.to_string(),
+                state: member.state,
+                incarnation: member.incarnation,
+            });
+        }
+        Payload::Ping { updates }
+    }
+
+    /// The updates to send with the next message, counting it towards
+    /// each one's retransmissions.
+    fn piggyback(&mut self) -> Vec<MemberUpdate> {
+        let updates = self
+            .updates
+            .iter_mut()
+            .map(|(update, left)| {
+                *left -= 1;
+                update.clone()
+            })
+            .collect();
+        self.updates.retain(|(_, left)| *left > 0);
+        updates
+    }
+
+    /// Notes that `node` answered a probe.
+    fn acked(&mut self, node: &str) {
+        if self
+            .probe
+            .as_ref()
+            .is_some_and(|probe| probe.target == node)
+        {
+            self.probe = None;
+        }
+    }
+
+    fn suspect(&mut self, node: &str) {
+        let Some(member) = self.members.get(node) else {
+            return;
+        };
+        if member.state == MemberState::Alive {
+            crate::info!("suspecting {node}");
+            let incarnation = member.incarnation;
+            self.set(node, MemberState::Suspect, incarnation);
+        }
+    }
+
+    /// Merges what others believe into our view.
+    fn apply(&mut self, updates: &[MemberUpdate]) {
+        for update in updates {
+            if update.node == self.membership.node_id {
+                self.refute(update);
+                continue;
+            }
+            let Some(member) = self.members.get(&update.node) else {
+                continue;
+            };
+            let newer = update.incarnation > member.incarnation;
+            let same = update.incarnation == member.incarnation;
+            let takes = match update.state {
+                MemberState::Alive => newer,
+                MemberState::Suspect => newer || (same && member.state == MemberState::Alive),
+                MemberState::Dead => (newer || same) && member.state != MemberState::Dead,
+            };
+            if takes {
+                self.set(&update.node, update.state, update.incarnation);
+            }
+        }
+    }
+
+    /// Answers a rumour that we are suspected or dead by announcing we are
+    /// alive under a later incarnation. One about an earlier incarnation
+    /// means the sender missed our last refutation, so that is repeated.
+    fn refute(&mut self, update: &MemberUpdate) {
+        if update.state == MemberState::Alive {
+            return;
+        }
+        if update.incarnation >= self.incarnation {
+            self.incarnation = update.incarnation + 1;
+            crate::info!(
+                "refuting {:?} with incarnation {}",
+                update.state,
+                self.incarnation
+            );
+        }
+        let alive = MemberUpdate {
+            node: self.membership.node_id.clone(),
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+        };
+        self.spread(alive);
+    }
+
+    fn set(&mut self, node: &str, state: MemberState, incarnation: u64) {
+        let member = Member {
+            state,
+            incarnation,
+            since: time::now(),
+        };
+        self.members.insert(node.to_string(), member);
+        let update = MemberUpdate {
+            node: node.to_string(),
+            state,
+            incarnation,
+        };
+        self.spread(update);
+    }
+
+    /// Queues `update` for piggybacking, replacing older news of the same
+    /// node.
+    fn spread(&mut self, update: MemberUpdate) {
+        self.updates
+            .retain(|(queued, _)| queued.node != update.node);
+        self.updates.push((update, self.config.retransmits));
+    }
+}
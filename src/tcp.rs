@@ -0,0 +1,69 @@
+//! Running nodes over TCP instead of under Maelstrom, so a cluster can be
+//! started and poked by hand, or by a client of one's own. Messages are
+//! routed as [`crate::socket`] describes.
+//!
+//! Clients connect, write messages with a `src` of their choosing, and
+//! read replies from the same connection, e.g. with `nc`:
+//!
+//! ```text
+//! $ nc localhost 7000
+//! {"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}
+//! {"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":2,"in_reply_to":1,"echo":"hi"}}
+//! ```
+
+use crate::socket::{self, SocketConfig, SocketInput, SocketOutput, Stream};
+use anyhow::Context;
+use std::{
+    io,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// How long dialing a peer may take before the message to it is dropped.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub type TcpInput = SocketInput;
+pub type TcpOutput = SocketOutput<TcpStream>;
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+/// Listens on `config.listen`; see [`serve`].
+pub fn listen(config: &SocketConfig) -> anyhow::Result<(TcpInput, TcpOutput)> {
+    let listener = TcpListener::bind(&config.listen)
+        .with_context(|| format!("listening on {}", config.listen))?;
+    serve(listener, config)
+}
+
+/// Accepts connections on `listener` from now on, for their messages to
+/// come out of the input, and has the output send messages over them or
+/// to `config.peers`. `config.listen` is left alone.
+pub fn serve(
+    listener: TcpListener,
+    config: &SocketConfig,
+) -> anyhow::Result<(TcpInput, TcpOutput)> {
+    crate::info!("listening on {}", listener.local_addr()?);
+    let accept = move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    };
+    socket::serve("tcp-accept", accept, dial, config)
+}
+
+pub(crate) fn dial(addr: &str) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "address resolves to nothing");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
@@ -0,0 +1,630 @@
+//! Random messages for property tests.
+//!
+//! [`Arbitrary`] builds a value from an [`Rng`], so a test can seed a
+//! `StdRng`, generate a few thousand messages and check that each one
+//! survives a trip through serde_json unchanged. That catches a renamed
+//! field or a `flatten` that no longer lines up, which hand-written JSON
+//! fixtures only do for the variants someone remembered to write one for.
+//!
+//! Generated values stay within what the wire format can represent
+//! exactly: floats are small binary fractions, and `read` never gets a
+//! `null` key, which would come back as no key at all.
+
+use crate::{
+    bloom::BloomFilter,
+    clock::VectorClock,
+    compress::{Encoding, Packed},
+    handshake::Capability,
+    kafka::{Offsets, Records},
+    kv::quorum::Consistency,
+    paxos::{Ballot, Proposal},
+    payload::{AddValue, ReadValue},
+    raft::LogEntry,
+    services::Versioned,
+    swim::{MemberState, MemberUpdate},
+    txn::{Op, OpKind},
+    Body, ErrorCode, Message, Payload,
+};
+use rand::Rng;
+use serde_json::Value;
+
+/// Types that can be generated at random.
+pub trait Arbitrary: Sized {
+    fn arbitrary(rng: &mut impl Rng) -> Self;
+}
+
+/// How many variants [`Payload`] has; see [`variant`].
+pub const VARIANTS: usize = 78;
+
+/// The index of `payload`'s variant, in declaration order.
+///
+/// There is deliberately no catch-all arm: adding a variant to [`Payload`]
+/// fails to compile here until the generator below learns about it.
+pub fn variant(payload: &Payload) -> usize {
+    match payload {
+        Payload::Add { .. } => 0,
+        Payload::AddOk => 1,
+        Payload::Echo { .. } => 2,
+        Payload::EchoOk { .. } => 3,
+        Payload::Init { .. } => 4,
+        Payload::InitOk => 5,
+        Payload::Generate => 6,
+        Payload::GenerateOk { .. } => 7,
+        Payload::Broadcast { .. } => 8,
+        Payload::BroadcastOk => 9,
+        Payload::Gossip { .. } => 10,
+        Payload::GossipOk => 11,
+        Payload::CatchUp { .. } => 12,
+        Payload::CatchUpOk { .. } => 13,
+        Payload::Read { .. } => 14,
+        Payload::ReadOk { .. } => 15,
+        Payload::Write { .. } => 16,
+        Payload::WriteOk => 17,
+        Payload::Cas { .. } => 18,
+        Payload::CasOk => 19,
+        Payload::TopologyOk => 20,
+        Payload::Topology { .. } => 21,
+        Payload::Send { .. } => 22,
+        Payload::SendOk { .. } => 23,
+        Payload::Poll { .. } => 24,
+        Payload::PollOk { .. } => 25,
+        Payload::CommitOffsets { .. } => 26,
+        Payload::CommitOffsetsOk => 27,
+        Payload::ListCommittedOffsets { .. } => 28,
+        Payload::ListCommittedOffsetsOk { .. } => 29,
+        Payload::Txn { .. } => 30,
+        Payload::TxnOk { .. } => 31,
+        Payload::RequestVote { .. } => 32,
+        Payload::RequestVoteOk { .. } => 33,
+        Payload::AppendEntries { .. } => 34,
+        Payload::AppendEntriesOk { .. } => 35,
+        Payload::InstallSnapshot { .. } => 36,
+        Payload::InstallSnapshotOk { .. } => 37,
+        Payload::Propose { .. } => 38,
+        Payload::ProposeOk { .. } => 39,
+        Payload::Prepare { .. } => 40,
+        Payload::Promise { .. } => 41,
+        Payload::Accept { .. } => 42,
+        Payload::Accepted { .. } => 43,
+        Payload::TxnPrepare { .. } => 44,
+        Payload::TxnVote { .. } => 45,
+        Payload::TxnCommit { .. } => 46,
+        Payload::TxnAbort { .. } => 47,
+        Payload::TxnDone { .. } => 48,
+        Payload::Ping { .. } => 49,
+        Payload::PingOk { .. } => 50,
+        Payload::PingReq { .. } => 51,
+        Payload::PingReqOk { .. } => 52,
+        Payload::Heartbeat => 53,
+        Payload::Join { .. } => 54,
+        Payload::JoinOk { .. } => 55,
+        Payload::Leave { .. } => 56,
+        Payload::LeaveOk => 57,
+        Payload::ReplicaRead { .. } => 58,
+        Payload::ReplicaReadOk { .. } => 59,
+        Payload::ReplicaWrite { .. } => 60,
+        Payload::ReplicaWriteOk => 61,
+        Payload::MerkleDigest { .. } => 62,
+        Payload::MerkleKeys { .. } => 63,
+        Payload::Handoff { .. } => 64,
+        Payload::HandoffOk => 65,
+        Payload::Remove { .. } => 66,
+        Payload::RemoveOk => 67,
+        Payload::Merge { .. } => 68,
+        Payload::MergeOk { .. } => 69,
+        Payload::CausalBroadcast { .. } => 70,
+        Payload::CausalBroadcastOk => 71,
+        Payload::Digest { .. } => 72,
+        Payload::SyncRequest { .. } => 73,
+        Payload::SyncResponse { .. } => 74,
+        Payload::Hello { .. } => 75,
+        Payload::HelloOk { .. } => 76,
+        Payload::Error { .. } => 77,
+    }
+}
+
+impl Arbitrary for Payload {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        match rng.gen_range(0..VARIANTS) {
+            0 => Payload::Add {
+                value: AddValue::arbitrary(rng),
+                key: rng.gen_bool(0.5).then(|| key(rng)),
+            },
+            1 => Payload::AddOk,
+            2 => Payload::Echo { echo: string(rng) },
+            3 => Payload::EchoOk { echo: string(rng) },
+            4 => Payload::Init {
+                node_id: node_id(rng),
+                node_ids: vec_of(rng, node_id),
+            },
+            5 => Payload::InitOk,
+            6 => Payload::Generate,
+            7 => Payload::GenerateOk { id: key(rng) },
+            8 => Payload::Broadcast { message: rng.gen() },
+            9 => Payload::BroadcastOk,
+            10 => Payload::Gossip {
+                messages: vec_of(rng, |rng| rng.gen()),
+                clock: rng.gen_bool(0.5).then(|| VectorClock::arbitrary(rng)),
+                packed: rng.gen_bool(0.5).then(|| Packed::arbitrary(rng)),
+                origins: vec_of(rng, |rng| {
+                    (node_id(rng), vec_of(rng, |rng| (rng.gen(), rng.gen())))
+                })
+                .into_iter()
+                .collect(),
+                accepts: vec_of(rng, |_| Encoding::DeltaVarint),
+            },
+            11 => Payload::GossipOk,
+            12 => Payload::CatchUp {
+                clock: VectorClock::arbitrary(rng),
+            },
+            13 => Payload::CatchUpOk {
+                clock: VectorClock::arbitrary(rng),
+                values: vec_of(rng, |rng| (node_id(rng), vec_of(rng, |rng| rng.gen())))
+                    .into_iter()
+                    .collect(),
+            },
+            14 => Payload::Read {
+                key: rng.gen_bool(0.5).then(|| key(rng)),
+                consistency: rng.gen_bool(0.5).then(|| Consistency::arbitrary(rng)),
+            },
+            15 => Payload::ReadOk {
+                value: ReadValue::arbitrary(rng),
+            },
+            16 => Payload::Write {
+                key: key(rng),
+                value: value(rng, 2),
+                consistency: rng.gen_bool(0.5).then(|| Consistency::arbitrary(rng)),
+            },
+            17 => Payload::WriteOk,
+            18 => Payload::Cas {
+                key: key(rng),
+                from: value(rng, 2),
+                to: value(rng, 2),
+                create_if_not_exists: rng.gen(),
+            },
+            19 => Payload::CasOk,
+            20 => Payload::TopologyOk,
+            21 => Payload::Topology {
+                topology: vec_of(rng, |rng| (node_id(rng), vec_of(rng, node_id)))
+                    .into_iter()
+                    .collect(),
+            },
+            22 => Payload::Send {
+                key: string(rng),
+                msg: rng.gen(),
+            },
+            23 => Payload::SendOk { offset: rng.gen() },
+            24 => Payload::Poll {
+                offsets: offsets(rng),
+            },
+            25 => Payload::PollOk { msgs: records(rng) },
+            26 => Payload::CommitOffsets {
+                offsets: offsets(rng),
+            },
+            27 => Payload::CommitOffsetsOk,
+            28 => Payload::ListCommittedOffsets {
+                keys: vec_of(rng, string),
+            },
+            29 => Payload::ListCommittedOffsetsOk {
+                offsets: offsets(rng),
+            },
+            30 => Payload::Txn {
+                txn: vec_of(rng, Op::arbitrary),
+            },
+            31 => Payload::TxnOk {
+                txn: vec_of(rng, Op::arbitrary),
+            },
+            32 => Payload::RequestVote {
+                term: rng.gen(),
+                candidate_id: node_id(rng),
+                last_log_index: rng.gen(),
+                last_log_term: rng.gen(),
+            },
+            33 => Payload::RequestVoteOk {
+                term: rng.gen(),
+                vote_granted: rng.gen(),
+            },
+            34 => Payload::AppendEntries {
+                term: rng.gen(),
+                leader_id: node_id(rng),
+                prev_log_index: rng.gen(),
+                prev_log_term: rng.gen(),
+                entries: vec_of(rng, LogEntry::arbitrary),
+                leader_commit: rng.gen(),
+            },
+            35 => Payload::AppendEntriesOk {
+                term: rng.gen(),
+                success: rng.gen(),
+                match_index: rng.gen(),
+            },
+            36 => Payload::InstallSnapshot {
+                term: rng.gen(),
+                leader_id: node_id(rng),
+                last_included_index: rng.gen(),
+                last_included_term: rng.gen(),
+                offset: rng.gen(),
+                data: string(rng),
+                encoding: match rng.gen_range(0..3) {
+                    0 => None,
+                    1 => Some(Encoding::MsgPack),
+                    _ => Some(Encoding::Gzip),
+                },
+                done: rng.gen(),
+            },
+            37 => Payload::InstallSnapshotOk {
+                term: rng.gen(),
+                offset: rng.gen(),
+            },
+            38 => Payload::Propose {
+                value: value(rng, 2),
+            },
+            39 => Payload::ProposeOk {
+                value: value(rng, 2),
+            },
+            40 => Payload::Prepare {
+                ballot: Ballot::arbitrary(rng),
+            },
+            41 => Payload::Promise {
+                ballot: Ballot::arbitrary(rng),
+                promised: rng.gen(),
+                accepted: rng.gen_bool(0.5).then(|| Proposal::arbitrary(rng)),
+            },
+            42 => Payload::Accept {
+                ballot: Ballot::arbitrary(rng),
+                value: value(rng, 2),
+            },
+            43 => Payload::Accepted {
+                ballot: Ballot::arbitrary(rng),
+                value: value(rng, 2),
+            },
+            44 => Payload::TxnPrepare {
+                txn_id: string(rng),
+                txn: vec_of(rng, Op::arbitrary),
+            },
+            45 => Payload::TxnVote {
+                txn_id: string(rng),
+                commit: rng.gen(),
+                txn: vec_of(rng, Op::arbitrary),
+            },
+            46 => Payload::TxnCommit {
+                txn_id: string(rng),
+            },
+            47 => Payload::TxnAbort {
+                txn_id: string(rng),
+            },
+            48 => Payload::TxnDone {
+                txn_id: string(rng),
+            },
+            49 => Payload::Ping {
+                updates: vec_of(rng, MemberUpdate::arbitrary),
+            },
+            50 => Payload::PingOk {
+                updates: vec_of(rng, MemberUpdate::arbitrary),
+            },
+            51 => Payload::PingReq {
+                target: node_id(rng),
+                updates: vec_of(rng, MemberUpdate::arbitrary),
+            },
+            52 => Payload::PingReqOk {
+                target: node_id(rng),
+            },
+            53 => Payload::Heartbeat,
+            54 => Payload::Join { node: node_id(rng) },
+            55 => Payload::JoinOk {
+                node_ids: vec_of(rng, node_id),
+                messages: vec_of(rng, |rng| rng.gen()),
+            },
+            56 => Payload::Leave { node: node_id(rng) },
+            57 => Payload::LeaveOk,
+            58 => Payload::ReplicaRead { key: key(rng) },
+            59 => Payload::ReplicaReadOk {
+                value: rng.gen_bool(0.5).then(|| Versioned {
+                    version: rng.gen(),
+                    value: value(rng, 2),
+                }),
+            },
+            60 => Payload::ReplicaWrite {
+                key: key(rng),
+                value: value(rng, 2),
+                version: rng.gen(),
+            },
+            61 => Payload::ReplicaWriteOk,
+            62 => Payload::MerkleDigest {
+                nodes: vec_of(rng, |rng| (rng.gen_range(0..511), rng.gen())),
+            },
+            63 => Payload::MerkleKeys {
+                nodes: vec_of(rng, |rng| rng.gen_range(255..511)),
+                entries: vec_of(rng, entry),
+            },
+            64 => Payload::Handoff {
+                entries: vec_of(rng, entry),
+            },
+            65 => Payload::HandoffOk,
+            66 => Payload::Remove {
+                element: value(rng, 2),
+                key: rng.gen_bool(0.5).then(|| key(rng)),
+            },
+            67 => Payload::RemoveOk,
+            68 => Payload::Merge {
+                state: value(rng, 2),
+                seq: rng.gen(),
+            },
+            69 => Payload::MergeOk { seq: rng.gen() },
+            70 => Payload::CausalBroadcast {
+                message: rng.gen(),
+                clock: VectorClock::arbitrary(rng),
+            },
+            71 => Payload::CausalBroadcastOk,
+            72 => Payload::Digest {
+                filter: BloomFilter::arbitrary(rng),
+            },
+            73 => Payload::SyncRequest {
+                clock: rng.gen_bool(0.5).then(|| VectorClock::arbitrary(rng)),
+                after: rng.gen_bool(0.5).then(|| rng.gen()),
+            },
+            74 => Payload::SyncResponse {
+                values: vec_of(rng, |rng| rng.gen()),
+                done: rng.gen(),
+            },
+            75 => Payload::Hello {
+                version: rng.gen(),
+                capabilities: vec_of(rng, |_| Capability::PackedValues),
+            },
+            76 => Payload::HelloOk {
+                version: rng.gen(),
+                capabilities: vec_of(rng, |_| Capability::PackedValues),
+            },
+            _ => Payload::Error {
+                code: ErrorCode::arbitrary(rng),
+                text: string(rng),
+            },
+        }
+    }
+}
+
+impl Arbitrary for Consistency {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        match rng.gen_range(0..3) {
+            0 => Consistency::One,
+            1 => Consistency::Quorum,
+            _ => Consistency::All,
+        }
+    }
+}
+
+impl Arbitrary for AddValue {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        if rng.gen() {
+            AddValue::Delta { delta: rng.gen() }
+        } else {
+            AddValue::Element {
+                element: value(rng, 2),
+            }
+        }
+    }
+}
+
+impl Arbitrary for ReadValue {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        if rng.gen() {
+            ReadValue::Messages {
+                messages: vec_of(rng, |rng| rng.gen()),
+            }
+        } else {
+            ReadValue::Value {
+                value: value(rng, 2),
+            }
+        }
+    }
+}
+
+impl Arbitrary for BloomFilter {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let values: Vec<usize> = vec_of(rng, |rng| rng.gen());
+        let mut filter = BloomFilter::new(values.len(), 0.01, rng.gen());
+        for value in &values {
+            filter.insert(value);
+        }
+        filter
+    }
+}
+
+impl Arbitrary for Packed {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let values = vec_of(rng, |rng| rng.gen());
+        let encoding = match rng.gen_range(0..3) {
+            0 => Encoding::DeltaVarint,
+            1 => Encoding::MsgPack,
+            _ => Encoding::Gzip,
+        };
+        Packed::encode(encoding, &values)
+    }
+}
+
+impl Arbitrary for VectorClock {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let mut clock = VectorClock::new();
+        for (node, counter) in vec_of(rng, |rng| (node_id(rng), rng.gen())) {
+            clock.observe(&node, counter);
+        }
+        clock
+    }
+}
+
+/// Only key-value commands, so that entries don't nest without bound.
+impl Arbitrary for LogEntry {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let command = match rng.gen_range(0..3) {
+            0 => Payload::Read {
+                key: Some(key(rng)),
+                consistency: None,
+            },
+            1 => Payload::Write {
+                key: key(rng),
+                value: value(rng, 2),
+                consistency: None,
+            },
+            _ => Payload::Cas {
+                key: key(rng),
+                from: value(rng, 2),
+                to: value(rng, 2),
+                create_if_not_exists: rng.gen(),
+            },
+        };
+        LogEntry {
+            term: rng.gen(),
+            command,
+        }
+    }
+}
+
+impl Arbitrary for Ballot {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        Ballot {
+            round: rng.gen(),
+            node: node_id(rng),
+        }
+    }
+}
+
+impl Arbitrary for Proposal {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        Proposal {
+            ballot: Ballot::arbitrary(rng),
+            value: value(rng, 2),
+        }
+    }
+}
+
+impl Arbitrary for MemberUpdate {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let state = match rng.gen_range(0..3) {
+            0 => MemberState::Alive,
+            1 => MemberState::Suspect,
+            _ => MemberState::Dead,
+        };
+        MemberUpdate {
+            node: node_id(rng),
+            state,
+            incarnation: rng.gen(),
+        }
+    }
+}
+
+impl Arbitrary for ErrorCode {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        // Mostly the standard codes, which have variants of their own.
+        let code = if rng.gen_bool(0.8) {
+            rng.gen_range(0..=30)
+        } else {
+            rng.gen()
+        };
+        ErrorCode::from(code)
+    }
+}
+
+impl Arbitrary for Op {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        let kind = if rng.gen() {
+            OpKind::Read
+        } else {
+            OpKind::Write
+        };
+        Op(kind, rng.gen(), rng.gen_bool(0.5).then(|| rng.gen()))
+    }
+}
+
+impl<P: Arbitrary> Arbitrary for Body<P> {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        Body {
+            id: rng.gen_bool(0.7).then(|| rng.gen()),
+            in_reply_to: rng.gen_bool(0.3).then(|| rng.gen()),
+            traceparent: None,
+            payload: P::arbitrary(rng),
+        }
+    }
+}
+
+impl<P: Arbitrary> Arbitrary for Message<P> {
+    fn arbitrary(rng: &mut impl Rng) -> Self {
+        Message {
+            src: node_id(rng),
+            dest: node_id(rng),
+            body: Body::arbitrary(rng),
+        }
+    }
+}
+
+/// Up to eight elements made by `element`.
+fn vec_of<T, R: Rng>(rng: &mut R, mut element: impl FnMut(&mut R) -> T) -> Vec<T> {
+    let len = rng.gen_range(0..8);
+    (0..len).map(|_| element(rng)).collect()
+}
+
+fn node_id(rng: &mut impl Rng) -> String {
+    let prefix = ["n", "c", "seq-kv", "lin-kv"][rng.gen_range(0..4)];
+    format!("{prefix}{}", rng.gen_range(0..16))
+}
+
+/// A short string, heavy on characters that need escaping in JSON.
+fn string(rng: &mut impl Rng) -> String {
+    const CHARS: &[char] = &[
+        'a', 'z', '0', ' ', '"', '\\', '/', '\n', '\t', '\u{0}', '\u{1f}', 'é', '€', '💥',
+    ];
+    let len = rng.gen_range(0..12);
+    (0..len)
+        .map(|_| CHARS[rng.gen_range(0..CHARS.len())])
+        .collect()
+}
+
+/// A KV key: anything but `null`.
+/// A versioned key-value entry, as replicas exchange them.
+fn entry(rng: &mut impl Rng) -> (Value, Versioned<Value>) {
+    let versioned = Versioned {
+        version: rng.gen(),
+        value: value(rng, 2),
+    };
+    (key(rng), versioned)
+}
+
+fn key(rng: &mut impl Rng) -> Value {
+    loop {
+        let key = value(rng, 1);
+        if !key.is_null() {
+            return key;
+        }
+    }
+}
+
+/// A JSON value nested at most `depth` levels deep.
+fn value(rng: &mut impl Rng, depth: usize) -> Value {
+    let kinds = if depth == 0 { 5 } else { 7 };
+    match rng.gen_range(0..kinds) {
+        0 => Value::Null,
+        1 => Value::Bool(rng.gen()),
+        2 => Value::from(rng.gen::<i64>()),
+        // Eighths are exact in binary, so they parse back to the same f64.
+        3 => Value::from(f64::from(rng.gen_range(-8000..8000)) / 8.0),
+        4 => Value::String(string(rng)),
+        5 => Value::Array(vec_of(rng, |rng| value(rng, depth - 1))),
+        _ => Value::Object(
+            vec_of(rng, |rng| (string(rng), value(rng, depth - 1)))
+                .into_iter()
+                .collect(),
+        ),
+    }
+}
+
+fn offsets(rng: &mut impl Rng) -> Offsets {
+    vec_of(rng, |rng| (string(rng), rng.gen()))
+        .into_iter()
+        .collect()
+}
+
+fn records(rng: &mut impl Rng) -> Records {
+    vec_of(rng, |rng| {
+        (string(rng), vec_of(rng, |rng| (rng.gen(), rng.gen())))
+    })
+    .into_iter()
+    .collect()
+}
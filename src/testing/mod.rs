@@ -0,0 +1,157 @@
+//! A stand-in for Maelstrom's client side, for end-to-end tests of a single
+//! node.
+//!
+//! [`Client::start`] runs the node with [`run`] on a thread of
+//! its own, fed through an input channel and writing to a
+//! [`ChannelWriter`](crate::transport::ChannelWriter), and gets it through
+//! `init`. From there, [`Client::request`] sends a workload op and waits for
+//! the matching reply, checking its addressing along the way.
+//!
+//! ```no_run
+//! use whirlpool::{payload::Payload, testing::Client, EchoNode};
+//!
+//! let mut client = Client::start(EchoNode::default(), "n1", &["n1"]).unwrap();
+//! let reply = client.request(Payload::Echo { echo: "hi".into() }).unwrap();
+//! assert!(matches!(reply.body.payload, Payload::EchoOk { .. }));
+//! client.shutdown().unwrap();
+//! ```
+
+pub mod arbitrary;
+
+use crate::{run, transport, Config, Message, MsgIdAllocator, Node, Payload};
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The id requests are sent from.
+pub const CLIENT: &str = "c1";
+
+/// How long [`Client::request`] and [`Client::recv`] wait by default.
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Drives one node through its input and output, the way Maelstrom does.
+pub struct Client<P = Payload> {
+    node_id: String,
+    input: mpsc::Sender<String>,
+    output: mpsc::Receiver<Message<P>>,
+    /// Messages received while waiting for something else.
+    unclaimed: VecDeque<Message<P>>,
+    msg_ids: MsgIdAllocator,
+    node: JoinHandle<anyhow::Result<()>>,
+    timeout: Duration,
+}
+
+impl<P> Client<P>
+where
+    P: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Starts `node` and initializes it as `node_id` in a cluster of
+    /// `node_ids`, failing unless it answers with `init_ok`.
+    pub fn start<N>(node: N, node_id: &str, node_ids: &[&str]) -> anyhow::Result<Self>
+    where
+        N: Node<P> + Send + 'static,
+    {
+        let (input, messages) = mpsc::channel();
+        let (out, output) = transport::channel();
+        let node = thread::spawn(move || run(node, &Config::default(), messages, out));
+        let mut client = Self {
+            node_id: node_id.to_string(),
+            input,
+            output,
+            unclaimed: VecDeque::new(),
+            msg_ids: MsgIdAllocator::new(),
+            node,
+            timeout: TIMEOUT,
+        };
+        let init = Payload::Init {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+        };
+        let msg_id = client.msg_ids.next();
+        client.send_raw(&Message::new(CLIENT, node_id, Some(msg_id), init))?;
+        let reply: Message = client.reply_to(CLIENT, msg_id).and_then(|reply| {
+            serde_json::to_value(reply)
+                .and_then(serde_json::from_value)
+                .context("init reply")
+        })?;
+        if !matches!(reply.body.payload, Payload::InitOk) {
+            bail!("expected init_ok, got {:?}", reply.body.payload);
+        }
+        Ok(client)
+    }
+
+    /// How long to wait for replies from now on.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn send_raw<Q: Serialize>(&mut self, msg: &Message<Q>) -> anyhow::Result<()> {
+        let json = serde_json::to_string(msg).context("serialize message")?;
+        self.input.send(json).context("node has stopped reading")
+    }
+
+    /// Sends `payload` to the node from [`CLIENT`] and returns its `msg_id`.
+    pub fn send(&mut self, payload: P) -> anyhow::Result<usize> {
+        self.send_from(CLIENT, payload)
+    }
+
+    /// Sends `payload` to the node as if it came from `src`, e.g. a peer.
+    pub fn send_from(&mut self, src: &str, payload: P) -> anyhow::Result<usize> {
+        let msg_id = self.msg_ids.next();
+        self.send_raw(&Message::new(src, &self.node_id, Some(msg_id), payload))?;
+        Ok(msg_id)
+    }
+
+    /// Sends `payload` and waits for the reply to it, which must come from
+    /// the node, be addressed to [`CLIENT`] and carry `in_reply_to`.
+    pub fn request(&mut self, payload: P) -> anyhow::Result<Message<P>> {
+        let msg_id = self.send(payload)?;
+        self.reply_to(CLIENT, msg_id)
+    }
+
+    /// Waits for the node's reply to the message `src` sent as `msg_id`.
+    /// Anything else that arrives meanwhile is kept for [`Client::recv`].
+    pub fn reply_to(&mut self, src: &str, msg_id: usize) -> anyhow::Result<Message<P>> {
+        let is_reply = |msg: &Message<P>| msg.dest == src && msg.body.in_reply_to == Some(msg_id);
+        if let Some(i) = self.unclaimed.iter().position(is_reply) {
+            return Ok(self.unclaimed.remove(i).expect("position is in range"));
+        }
+        loop {
+            let msg = self
+                .output
+                .recv_timeout(self.timeout)
+                .with_context(|| format!("no reply to {src}'s msg_id {msg_id}"))?;
+            if is_reply(&msg) {
+                if msg.src != self.node_id {
+                    bail!("reply came from {}, not {}", msg.src, self.node_id);
+                }
+                return Ok(msg);
+            }
+            self.unclaimed.push_back(msg);
+        }
+    }
+
+    /// The next message the node sent, whoever it was for.
+    pub fn recv(&mut self) -> anyhow::Result<Message<P>> {
+        if let Some(msg) = self.unclaimed.pop_front() {
+            return Ok(msg);
+        }
+        self.output
+            .recv_timeout(self.timeout)
+            .context("node sent nothing")
+    }
+
+    /// Closes the node's input and waits for it to stop, returning any error
+    /// its main loop hit.
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        let Self { input, node, .. } = self;
+        drop(input);
+        node.join().expect("node thread panicked")
+    }
+}
@@ -0,0 +1,133 @@
+//! The time and randomness nodes go by: the real ones, except on a thread
+//! with a [`Sim`](crate::sim::Sim), where they are the simulation's own
+//! virtual clock and seeded generator, so a run with a given seed plays out
+//! the same way every time, and a test looking at its nodes between runs
+//! sees the time they do.
+//!
+//! Nodes call [`now`] where they would call [`Instant::now`], [`system_now`]
+//! where they would call [`SystemTime::now`], and take random choices from
+//! [`rng`] rather than [`rand::thread_rng`].
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+
+thread_local! {
+    static SIMULATED: RefCell<Option<Rc<Clock>>> = const { RefCell::new(None) };
+}
+
+/// The current time: the simulation's, if there is one on this thread.
+pub fn now() -> Instant {
+    SIMULATED.with_borrow(|clock| match clock {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    })
+}
+
+/// The current wall-clock time: the simulation's, if there is one on this
+/// thread, which started out at the real time the simulation did.
+pub fn system_now() -> SystemTime {
+    SIMULATED.with_borrow(|clock| match clock {
+        Some(clock) => clock.system_start + clock.elapsed.get(),
+        None => SystemTime::now(),
+    })
+}
+
+/// How long it has been since `at`, by [`now`].
+pub fn elapsed(at: Instant) -> Duration {
+    now().saturating_duration_since(at)
+}
+
+/// A random number generator: the simulation's, if there is one on this
+/// thread, or else the thread's own.
+pub fn rng() -> NodeRng {
+    NodeRng
+}
+
+/// What [`rng`] returns. Each number it gives comes from whichever
+/// generator is current at the time.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeRng;
+
+impl NodeRng {
+    fn with<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        SIMULATED.with_borrow(|clock| match clock {
+            Some(clock) => f(&mut *clock.rng.borrow_mut()),
+            None => f(&mut rand::thread_rng()),
+        })
+    }
+}
+
+impl RngCore for NodeRng {
+    fn next_u32(&mut self) -> u32 {
+        Self::with(|rng| rng.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        Self::with(|rng| rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Self::with(|rng| rng.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        Self::with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+
+/// A simulation's virtual clock, which only moves when it is advanced, and
+/// the generator its nodes draw from.
+#[derive(Debug)]
+pub(crate) struct Clock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Cell<Duration>,
+    rng: RefCell<StdRng>,
+}
+
+impl Clock {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            elapsed: Cell::new(Duration::ZERO),
+            rng: RefCell::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub(crate) fn start(&self) -> Instant {
+        self.start
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+
+    /// Moves the clock on to `at`, unless it is there already.
+    pub(crate) fn advance_to(&self, at: Instant) {
+        let elapsed = at.saturating_duration_since(self.start);
+        self.elapsed.set(self.elapsed.get().max(elapsed));
+    }
+}
+
+/// Makes `clock` the one [`now`] and [`rng`] go by on this thread, until
+/// another is set or it is [`unset`].
+pub(crate) fn set(clock: &Rc<Clock>) {
+    SIMULATED.set(Some(clock.clone()));
+}
+
+/// Goes back to real time on this thread, if `clock` is still the one set.
+pub(crate) fn unset(clock: &Rc<Clock>) {
+    SIMULATED.with_borrow_mut(|current| {
+        if current
+            .as_ref()
+            .is_some_and(|current| Rc::ptr_eq(current, clock))
+        {
+            *current = None;
+        }
+    });
+}
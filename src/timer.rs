@@ -0,0 +1,182 @@
+//! A hashed timer wheel, for the deadlines a node keeps: retries, election
+//! timeouts, gossip rounds.
+//!
+//! Time is cut into slots of [`TimerWheel::resolution`], and a timer goes
+//! in the slot its deadline falls in, modulo the number of slots, so
+//! scheduling and cancelling take constant time however many timers are
+//! pending, and [`TimerWheel::expire`] only looks at the slots that have
+//! passed since it last ran. Timers more than one turn of the wheel away
+//! share a slot with nearer ones and are skipped until their turn comes.
+//!
+//! A node reports the wheel's [`TimerWheel::next_deadline`] from
+//! [`Node::next_timer`](crate::Node::next_timer), and the main loop calls
+//! [`Node::tick`](crate::Node::tick) once it has passed, where the node
+//! takes the events that fell due:
+//!
+//! ```
+//! use std::time::{Duration, Instant};
+//! use whirlpool::timer::TimerWheel;
+//!
+//! let mut timers = TimerWheel::default();
+//! let election = timers.schedule(Duration::from_millis(300), "election");
+//! timers.schedule(Duration::from_millis(100), "heartbeat");
+//! timers.cancel(election);
+//! let later = Instant::now() + Duration::from_secs(1);
+//! assert_eq!(timers.expire(later), ["heartbeat"]);
+//! ```
+
+use crate::time;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The slot width [`TimerWheel::default`] uses.
+pub const RESOLUTION: Duration = Duration::from_millis(10);
+
+/// How many slots [`TimerWheel::default`] has, about five seconds' worth.
+pub const SLOTS: usize = 512;
+
+/// Names a scheduled timer, to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+#[derive(Debug)]
+struct Timer<E> {
+    id: TimerId,
+    deadline: Instant,
+    /// How many slot widths after the wheel's start the deadline falls.
+    tick: u64,
+    event: E,
+}
+
+/// Events scheduled to fall due at a deadline.
+#[derive(Debug)]
+pub struct TimerWheel<E> {
+    resolution: Duration,
+    start: Instant,
+    slots: Vec<Vec<Timer<E>>>,
+    /// The first tick [`TimerWheel::expire`] hasn't been through yet.
+    current: u64,
+    /// The slot each pending timer is in.
+    pending: HashMap<TimerId, usize>,
+    next_id: u64,
+}
+
+impl<E> Default for TimerWheel<E> {
+    fn default() -> Self {
+        Self::new(RESOLUTION, SLOTS)
+    }
+}
+
+impl<E> TimerWheel<E> {
+    /// An empty wheel of `slots` slots, each `resolution` wide. Timers
+    /// fall due up to `resolution` late at worst, never early.
+    pub fn new(resolution: Duration, slots: usize) -> Self {
+        Self {
+            resolution: resolution.max(Duration::from_micros(1)),
+            start: time::now(),
+            slots: (0..slots.max(1)).map(|_| Vec::new()).collect(),
+            current: 0,
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    /// Has `event` fall due `after` from now.
+    pub fn schedule(&mut self, after: Duration, event: E) -> TimerId {
+        self.schedule_at(time::now() + after, event)
+    }
+
+    /// Has `event` fall due at `deadline`.
+    pub fn schedule_at(&mut self, deadline: Instant, event: E) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let tick = self.tick_of(deadline).max(self.current);
+        let slot = self.slot_of(tick);
+        self.slots[slot].push(Timer {
+            id,
+            deadline,
+            tick,
+            event,
+        });
+        self.pending.insert(id, slot);
+        id
+    }
+
+    /// Unschedules `id`, returning its event if it hadn't fallen due yet.
+    pub fn cancel(&mut self, id: TimerId) -> Option<E> {
+        let slot = self.pending.remove(&id)?;
+        let timers = &mut self.slots[slot];
+        let index = timers.iter().position(|timer| timer.id == id)?;
+        Some(timers.swap_remove(index).event)
+    }
+
+    /// Takes the events whose deadline is at or before `now`, earliest
+    /// first.
+    pub fn expire(&mut self, now: Instant) -> Vec<E> {
+        // Timers scheduled in the past sit in the current slot.
+        let until = self.tick_of(now).max(self.current);
+        let mut due = Vec::new();
+        // One turn of the wheel visits every slot.
+        let last = until.min(self.current + self.slots.len() as u64 - 1);
+        for tick in self.current..=last {
+            let slot = self.slot_of(tick);
+            let timers = std::mem::take(&mut self.slots[slot]);
+            let (ready, waiting) = timers
+                .into_iter()
+                .partition(|timer| timer.tick <= until && timer.deadline <= now);
+            self.slots[slot] = waiting;
+            due.extend(ready);
+        }
+        // The slot `now` falls in may still hold timers due later in it.
+        self.current = until;
+        for timer in &due {
+            self.pending.remove(&timer.id);
+        }
+        due.sort_by_key(|timer: &Timer<E>| (timer.deadline, timer.id));
+        due.into_iter().map(|timer| timer.event).collect()
+    }
+
+    /// The earliest deadline of any pending timer.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        // The nearest non-empty slot holds it, unless all its timers are
+        // a turn or more away.
+        for tick in self.current..self.current + self.slots.len() as u64 {
+            let nearest = self.slots[self.slot_of(tick)]
+                .iter()
+                .filter(|timer| timer.tick <= tick)
+                .map(|timer| timer.deadline)
+                .min();
+            if nearest.is_some() {
+                return nearest;
+            }
+        }
+        self.slots
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+    }
+
+    /// How many timers are pending.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn tick_of(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.start).as_nanos() / self.resolution.as_nanos()) as u64
+    }
+
+    fn slot_of(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
@@ -0,0 +1,77 @@
+//! Strategies for choosing which nodes a node talks to directly.
+
+use anyhow::{bail, Context};
+use std::{collections::HashMap, str::FromStr};
+
+/// How a node picks its neighbors. Every strategy other than
+/// [`TopologyStrategy::Provided`] ignores the `topology` message and derives
+/// the same graph on every node from the `node_ids` sent in `init`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TopologyStrategy {
+    /// Use whatever Maelstrom sends in `topology`.
+    #[default]
+    Provided,
+    /// Every node is a neighbor of every other node.
+    FullMesh,
+    /// A balanced tree where each node has up to `fanout` children.
+    Tree { fanout: usize },
+    /// Each node talks to its predecessor and successor.
+    Ring,
+}
+
+impl TopologyStrategy {
+    /// The neighbors of `node_id`. `provided` is the topology Maelstrom sent.
+    pub fn neighbors(
+        &self,
+        node_id: &str,
+        node_ids: &[String],
+        provided: &HashMap<String, Vec<String>>,
+    ) -> Vec<String> {
+        if let TopologyStrategy::Provided = self {
+            return provided.get(node_id).cloned().unwrap_or_default();
+        }
+
+        let mut nodes = node_ids.to_vec();
+        nodes.sort();
+        let Some(me) = nodes.iter().position(|n| n == node_id) else {
+            return Vec::new();
+        };
+        let n = nodes.len();
+
+        let mut neighbors: Vec<usize> = match *self {
+            TopologyStrategy::Provided => unreachable!(),
+            TopologyStrategy::FullMesh => (0..n).filter(|&i| i != me).collect(),
+            TopologyStrategy::Tree { fanout } => {
+                let fanout = fanout.max(1);
+                let parent = (me > 0).then(|| (me - 1) / fanout);
+                let children = (me * fanout + 1..=me * fanout + fanout).filter(|&i| i < n);
+                parent.into_iter().chain(children).collect()
+            }
+            TopologyStrategy::Ring if n < 2 => Vec::new(),
+            TopologyStrategy::Ring => vec![(me + n - 1) % n, (me + 1) % n],
+        };
+        neighbors.sort();
+        neighbors.dedup();
+        neighbors.into_iter().map(|i| nodes[i].clone()).collect()
+    }
+}
+
+impl FromStr for TopologyStrategy {
+    type Err = anyhow::Error;
+
+    /// Parses `provided`, `full-mesh`, `ring`, `tree` or `tree:<fanout>`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "provided" => TopologyStrategy::Provided,
+            "full-mesh" => TopologyStrategy::FullMesh,
+            "ring" => TopologyStrategy::Ring,
+            "tree" => TopologyStrategy::Tree { fanout: 4 },
+            _ => match s.strip_prefix("tree:") {
+                Some(fanout) => TopologyStrategy::Tree {
+                    fanout: fanout.parse().context("tree fanout")?,
+                },
+                None => bail!("unknown topology strategy {s}"),
+            },
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! Per-event spans: how long the node spent handling each message and tick.
+//!
+//! Finished spans are logged at debug level and, if a trace file is set up
+//! with [`init`], appended to it as one JSON object per line:
+//!
+//! ```text
+//! {"duration_us":48,"msg_id":3,"pid":4242,"src":"c1","start_us":1760000000123456,"type":"broadcast"}
+//! ```
+//!
+//! `start_us` is wall-clock microseconds since the Unix epoch, so spans from
+//! different nodes line up. Every node process appends to the same file, so
+//! `pid` tells them apart.
+//!
+//! With the `otel` feature, spans can be exported over OTLP too, and
+//! stitched together across nodes; see `crate::otel`.
+
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::{
+    log::{self, Level},
+    metrics,
+};
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::OnceLock,
+    time::{Instant, SystemTime},
+};
+
+static FILE: OnceLock<File> = OnceLock::new();
+
+/// Appends finished spans to `path` from now on. Only the first call has
+/// any effect.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening trace file {}", path.display()))?;
+    let _ = FILE.set(file);
+    Ok(())
+}
+
+fn enabled() -> bool {
+    #[cfg(feature = "otel")]
+    if otel::enabled() {
+        return true;
+    }
+    FILE.get().is_some() || log::enabled(Level::Debug) || metrics::enabled()
+}
+
+/// The `traceparent` for messages sent while handling the current span on
+/// this thread to carry, if spans are exported.
+pub(crate) fn traceparent() -> Option<String> {
+    #[cfg(feature = "otel")]
+    {
+        otel::current().map(|context| context.traceparent())
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        None
+    }
+}
+
+/// Sends what is left of the spans to export, if they are.
+pub(crate) fn flush() {
+    #[cfg(feature = "otel")]
+    otel::flush();
+}
+
+/// The `type` of a serialized payload, or `?` if it has none.
+pub(crate) fn payload_kind<P: Serialize>(payload: &P) -> String {
+    match serde_json::to_value(payload) {
+        Ok(value) => value
+            .get("type")
+            .and_then(|kind| kind.as_str())
+            .unwrap_or("?")
+            .to_string(),
+        Err(_) => "?".to_string(),
+    }
+}
+
+/// A message or tick being handled. Spans are inert unless a trace file,
+/// debug logging, [`metrics`] or exporting spans is enabled.
+pub struct Span(Option<SpanData>);
+
+struct SpanData {
+    src: String,
+    kind: String,
+    msg_id: Option<usize>,
+    started_at: SystemTime,
+    start: Instant,
+    #[cfg(feature = "otel")]
+    exported: Option<otel::Active>,
+}
+
+impl Span {
+    pub fn message<P: Serialize>(msg: &crate::Message<P>) -> Span {
+        Span(enabled().then(|| SpanData {
+            src: msg.src.clone(),
+            kind: payload_kind(&msg.body.payload),
+            msg_id: msg.body.id,
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            #[cfg(feature = "otel")]
+            exported: otel::start(msg.body.traceparent.as_deref(), Some(&msg.dest)),
+        }))
+    }
+
+    pub fn tick() -> Span {
+        Span(enabled().then(|| SpanData {
+            src: String::new(),
+            kind: "tick".to_string(),
+            msg_id: None,
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            #[cfg(feature = "otel")]
+            exported: otel::start(None, None),
+        }))
+    }
+
+    /// This span, to make current again whenever its handler runs.
+    #[cfg(feature = "async")]
+    pub(crate) fn current(&self) -> Current {
+        Current {
+            #[cfg(feature = "otel")]
+            context: self
+                .0
+                .as_ref()
+                .and_then(|span| span.exported.as_ref())
+                .map(otel::Active::context),
+        }
+    }
+
+    /// Records the span as ending now.
+    pub fn finish(self) {
+        let Some(span) = self.0 else {
+            return;
+        };
+        let duration = span.start.elapsed();
+        crate::debug!(
+            "handled type={} msg_id={:?} in {duration:?}",
+            span.kind,
+            span.msg_id
+        );
+        if metrics::enabled() {
+            metrics::record_handled(&span.kind, duration);
+        }
+        #[cfg(feature = "otel")]
+        if let Some(exported) = span.exported {
+            exported.finish(
+                &span.kind,
+                &span.src,
+                span.msg_id,
+                span.started_at,
+                duration,
+            );
+        }
+        let Some(mut file) = FILE.get() else {
+            return;
+        };
+        let start_us = span
+            .started_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let line = json!({
+            "pid": std::process::id(),
+            "src": span.src,
+            "type": span.kind,
+            "msg_id": span.msg_id,
+            "start_us": start_us,
+            "duration_us": duration.as_micros() as u64,
+        });
+        // One write per line keeps lines from concurrent nodes whole.
+        let mut buf = line.to_string();
+        buf.push('\n');
+        if let Err(e) = file.write_all(buf.as_bytes()) {
+            crate::warn!("writing trace file: {e}");
+        }
+    }
+}
+
+/// Which span is current on a thread, for the [async
+/// runtime](crate::runtime) to switch between the spans of the handlers
+/// taking turns on it, so the calls each makes carry its own trace. Only
+/// exported spans are ever current.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Current {
+    #[cfg(feature = "otel")]
+    context: Option<otel::SpanContext>,
+}
+
+#[cfg(feature = "async")]
+impl Current {
+    /// Makes this the current span on this thread.
+    pub(crate) fn enter(self) {
+        #[cfg(feature = "otel")]
+        otel::set_current(self.context);
+    }
+}
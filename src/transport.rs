@@ -0,0 +1,62 @@
+//! Where outgoing messages go.
+//!
+//! Handlers and the main loop only ever see an `impl Write`: stdout in
+//! production, via [`main_loop`](crate::main_loop), or anything else via
+//! [`run`](crate::run). A `Vec<u8>` is enough to capture output and pick it
+//! apart with [`parse_lines`]; a [`ChannelWriter`] hands each message to
+//! another thread as soon as it is complete.
+
+use crate::{Message, Payload};
+use serde::de::DeserializeOwned;
+use std::{
+    io::{self, Write},
+    marker::PhantomData,
+    sync::mpsc,
+};
+
+/// Parses newline-delimited JSON output back into messages.
+pub fn parse_lines<P: DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<Vec<Message<P>>> {
+    serde_json::Deserializer::from_slice(bytes)
+        .into_iter()
+        .map(|msg| Ok(msg?))
+        .collect()
+}
+
+/// Writes messages to a channel instead of a byte stream. Each complete
+/// line is parsed as a `Message<P>` and sent on; writing a line that isn't
+/// one fails with [`io::ErrorKind::InvalidData`].
+pub struct ChannelWriter<P = Payload> {
+    buf: Vec<u8>,
+    tx: mpsc::Sender<Message<P>>,
+    _payload: PhantomData<fn() -> P>,
+}
+
+/// A [`ChannelWriter`] and the receiving end of its messages.
+pub fn channel<P>() -> (ChannelWriter<P>, mpsc::Receiver<Message<P>>) {
+    let (tx, rx) = mpsc::channel();
+    let writer = ChannelWriter {
+        buf: Vec::new(),
+        tx,
+        _payload: PhantomData,
+    };
+    (writer, rx)
+}
+
+impl<P: DeserializeOwned> Write for ChannelWriter<P> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            let msg = serde_json::from_slice(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.tx
+                .send(msg)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! The `txn-rw-register` workload (Gossip Glomers challenge 6).
+//!
+//! [`TxnNode`] keeps every register on every node. With
+//! [`two_phase`], registers are spread over the cluster instead, and
+//! transactions that touch several nodes commit with two-phase commit.
+
+pub mod two_phase;
+
+pub use two_phase::TwoPhaseTxnNode;
+
+use crate::{
+    clock::{HlcTimestamp, HybridClock},
+    ErrorCode, Membership, Message, MsgIdAllocator, Node, Payload, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// One micro-operation, `["r", key, null]` or `["w", key, value]` on the
+/// wire. Reads come back with the value filled in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Op(pub OpKind, pub u64, pub Option<i64>);
+
+/// A register's value and when the transaction that wrote it committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Register {
+    pub value: i64,
+    pub written_at: HlcTimestamp,
+}
+
+/// Registers keyed by integer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Store {
+    registers: HashMap<u64, Register>,
+}
+
+impl Store {
+    /// Starts a transaction that reads from the store as it is now.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            snapshot: &self.registers,
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Installs the writes of a transaction that committed `at` all at
+    /// once.
+    pub fn commit(&mut self, writes: HashMap<u64, i64>, at: HlcTimestamp) {
+        self.registers
+            .extend(writes.into_iter().map(|(key, value)| {
+                let register = Register {
+                    value,
+                    written_at: at,
+                };
+                (key, register)
+            }));
+    }
+
+    /// When `key` was last written, if ever.
+    pub fn written_at(&self, key: u64) -> Option<HlcTimestamp> {
+        self.registers.get(&key).map(|register| register.written_at)
+    }
+
+    /// Takes every register `other` wrote later than this store did: last
+    /// writer wins, by hybrid logical time. Stores that have merged each
+    /// other's registers agree, whatever order they merged in.
+    pub fn merge(&mut self, other: &Store) {
+        for (&key, &theirs) in &other.registers {
+            let ours = self.registers.entry(key).or_insert(theirs);
+            // Equal timestamps from different nodes fall back to the
+            // value, so that both sides pick the same one.
+            if (theirs.written_at, theirs.value) > (ours.written_at, ours.value) {
+                *ours = theirs;
+            }
+        }
+    }
+
+    /// Runs `txn` in a transaction and commits it at `at` if every op
+    /// succeeded. Returns the ops with every read's value filled in.
+    pub fn apply(&mut self, txn: &[Op], at: HlcTimestamp) -> Result<Vec<Op>, RpcError> {
+        let mut tx = self.begin();
+        let ops = tx.run(txn)?;
+        let writes = tx.into_writes();
+        self.commit(writes, at);
+        Ok(ops)
+    }
+}
+
+/// A transaction in progress. Writes are buffered until the transaction is
+/// committed, so nobody else ever observes a transaction's intermediate
+/// state, while the transaction itself reads its own writes.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    snapshot: &'a HashMap<u64, Register>,
+    writes: HashMap<u64, i64>,
+}
+
+impl Transaction<'_> {
+    pub fn read(&self, key: u64) -> Option<i64> {
+        self.writes
+            .get(&key)
+            .copied()
+            .or_else(|| self.snapshot.get(&key).map(|register| register.value))
+    }
+
+    pub fn write(&mut self, key: u64, value: i64) {
+        self.writes.insert(key, value);
+    }
+
+    /// Runs every op in turn. Returns the ops with every read's value
+    /// filled in.
+    pub fn run(&mut self, txn: &[Op]) -> Result<Vec<Op>, RpcError> {
+        txn.iter()
+            .map(|&Op(kind, key, value)| match kind {
+                OpKind::Read => Ok(Op(kind, key, self.read(key))),
+                OpKind::Write => {
+                    let value = value.ok_or_else(|| {
+                        RpcError::new(ErrorCode::MalformedRequest, "write without a value")
+                    })?;
+                    self.write(key, value);
+                    Ok(Op(kind, key, Some(value)))
+                }
+            })
+            .collect()
+    }
+
+    /// The final value of every key this transaction wrote.
+    pub fn into_writes(self) -> HashMap<u64, i64> {
+        self.writes
+    }
+}
+
+/// Serves the `txn-rw-register` workload against this node's own store.
+#[derive(Debug, Default)]
+pub struct TxnNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub store: Store,
+    /// Stamps each commit, so that stores can be merged last-writer-wins.
+    pub clock: HybridClock,
+}
+
+impl Node for TxnNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                Payload::InitOk
+            }
+            // A txn that cannot be applied is answered with the error the
+            // store reports, e.g. txn-conflict, instead of txn_ok.
+            Payload::Txn { txn } => Payload::TxnOk {
+                txn: self.store.apply(txn, self.clock.now())?,
+            },
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => return Err(RpcError::not_supported("txn node cannot handle this message").into()),
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,559 @@
+//! Transactions over registers spread across the cluster, committed with
+//! two-phase commit.
+//!
+//! Each register lives on one node, its [`owner`]. The node a client sends
+//! a `txn` to coordinates it: every owner involved gets the ops on its
+//! registers in a `txn_prepare`. A participant that can lock all of those
+//! registers runs the ops against its store, holds the writes back and
+//! votes to commit, with the values it read; one that can't votes to
+//! abort. Once every participant has voted to commit, the coordinator
+//! decides to commit, and otherwise, or if a vote doesn't come in time, to
+//! abort. The decision goes into its [`DecisionLog`] before anybody hears
+//! of it, then out to the participants as `txn_commit` or `txn_abort`,
+//! which they acknowledge with `txn_done`. The client gets `txn_ok`, or a
+//! `txn-conflict` error.
+//!
+//! Messages get lost and coordinators restart, so both sides repeat
+//! themselves until they are heard: the coordinator resends decisions that
+//! weren't acknowledged yet, including those recovered from its log, and a
+//! participant that stays prepared for a while votes again. A coordinator
+//! with no record of the transaction a vote is for never decided it, or
+//! has been told it was done with, and answers `txn_abort` (presumed
+//! abort). That is only safe if decisions survive restarts, so give the
+//! node a [decision log on disk](TwoPhaseTxnNode::with_decision_log)
+//! wherever nodes get killed. Participants keep prepared transactions in
+//! memory only.
+
+use super::{Op, Store};
+use crate::{
+    clock::{HlcTimestamp, HybridClock},
+    time,
+    wal::Wal,
+    Membership, Message, MsgIdAllocator, Node, Payload, RpcError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// How long a coordinator waits for votes before deciding to abort.
+const VOTE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a participant waits to hear the decision before voting again.
+const REVOTE_AFTER: Duration = Duration::from_millis(300);
+
+/// The node that owns `key`, out of `node_ids`, which must not be empty.
+pub fn owner(key: u64, node_ids: &[String]) -> &str {
+    &node_ids[(key % node_ids.len() as u64) as usize]
+}
+
+/// What a coordinator decided to do with a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+impl Decision {
+    fn payload(self, txn_id: &str) -> Payload {
+        let txn_id = txn_id.to_string();
+        match self {
+            Decision::Commit => Payload::TxnCommit { txn_id },
+            Decision::Abort => Payload::TxnAbort { txn_id },
+        }
+    }
+}
+
+/// One line of a decision log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Decided {
+        txn_id: String,
+        decision: Decision,
+        participants: Vec<String>,
+    },
+    /// Every participant has acknowledged the decision.
+    Done { txn_id: String },
+}
+
+/// A coordinator's decisions that not every participant has acknowledged
+/// yet, in a [write-ahead log](crate::wal) if it was opened from a file.
+#[derive(Debug, Default)]
+pub struct DecisionLog {
+    wal: Option<Wal>,
+    /// Each decision, with the participants yet to acknowledge it.
+    pending: HashMap<String, (Decision, HashSet<String>)>,
+}
+
+impl DecisionLog {
+    /// A log kept in memory only, and lost if the node restarts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the log at `path`, creating it if needed, and recovers the
+    /// decisions in it that weren't acknowledged yet.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut pending = HashMap::new();
+        let mut seq = 0;
+        for (n, record) in Wal::read::<Record>(&path)? {
+            seq = n;
+            match record {
+                Record::Decided {
+                    txn_id,
+                    decision,
+                    participants,
+                } => {
+                    pending.insert(txn_id, (decision, participants.into_iter().collect()));
+                }
+                Record::Done { txn_id } => {
+                    pending.remove(&txn_id);
+                }
+            }
+        }
+        if !pending.is_empty() {
+            crate::info!(
+                "recovered {} unacknowledged decisions from {}",
+                pending.len(),
+                path.display()
+            );
+        }
+        Ok(Self {
+            wal: Some(Wal::open(path, seq)?),
+            pending,
+        })
+    }
+
+    /// The decision on `txn_id`, if it is still pending.
+    pub fn get(&self, txn_id: &str) -> Option<Decision> {
+        self.pending.get(txn_id).map(|(decision, _)| *decision)
+    }
+
+    /// Every pending decision, with the participants yet to acknowledge it.
+    pub fn pending(&self) -> impl Iterator<Item = (&String, Decision, &HashSet<String>)> {
+        self.pending
+            .iter()
+            .map(|(txn_id, (decision, waiting))| (txn_id, *decision, waiting))
+    }
+
+    /// Records `decision`, on disk if the log is kept there. Participants
+    /// must not hear of it before this returns.
+    pub fn decide(
+        &mut self,
+        txn_id: &str,
+        decision: Decision,
+        participants: HashSet<String>,
+    ) -> anyhow::Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(&Record::Decided {
+                txn_id: txn_id.to_string(),
+                decision,
+                participants: participants.iter().cloned().collect(),
+            })?;
+        }
+        self.pending
+            .insert(txn_id.to_string(), (decision, participants));
+        Ok(())
+    }
+
+    /// Notes that `participant` acted on the decision on `txn_id`. Once
+    /// all of them have, the decision is forgotten, and once none are
+    /// pending, the file is emptied.
+    pub fn acknowledged(&mut self, txn_id: &str, participant: &str) -> anyhow::Result<()> {
+        let Some((_, waiting)) = self.pending.get_mut(txn_id) else {
+            return Ok(());
+        };
+        waiting.remove(participant);
+        if !waiting.is_empty() {
+            return Ok(());
+        }
+        self.pending.remove(txn_id);
+        match &mut self.wal {
+            Some(wal) if self.pending.is_empty() => wal.truncate()?,
+            Some(wal) => {
+                wal.append(&Record::Done {
+                    txn_id: txn_id.to_string(),
+                })?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// A transaction a participant voted to commit.
+#[derive(Debug)]
+struct Prepared {
+    coordinator: String,
+    /// The ops as run, with the values read.
+    ops: Vec<Op>,
+    writes: HashMap<u64, i64>,
+    voted_at: Instant,
+}
+
+/// A node's share of the registers, and the transactions prepared on them.
+#[derive(Debug, Default)]
+pub struct Participant {
+    pub store: Store,
+    prepared: HashMap<String, Prepared>,
+    /// Which prepared transaction holds each locked register.
+    locks: HashMap<u64, String>,
+}
+
+impl Participant {
+    /// Runs `ops` for `txn_id` and locks their registers, holding the
+    /// writes back until `coordinator` decides. Returns the ops with every
+    /// read's value filled in, or a `txn-conflict` error if another
+    /// prepared transaction holds one of the locks.
+    pub fn prepare(
+        &mut self,
+        txn_id: &str,
+        coordinator: &str,
+        ops: &[Op],
+    ) -> Result<Vec<Op>, RpcError> {
+        if let Some(prepared) = self.prepared.get(txn_id) {
+            return Ok(prepared.ops.clone());
+        }
+        if let Some(Op(_, key, _)) = ops
+            .iter()
+            .find(|Op(_, key, _)| self.locks.get(key).is_some_and(|holder| holder != txn_id))
+        {
+            return Err(RpcError::txn_conflict(format!("register {key} is locked")));
+        }
+        let mut tx = self.store.begin();
+        let ops = tx.run(ops)?;
+        let writes = tx.into_writes();
+        for Op(_, key, _) in &ops {
+            self.locks.insert(*key, txn_id.to_string());
+        }
+        let prepared = Prepared {
+            coordinator: coordinator.to_string(),
+            ops: ops.clone(),
+            writes,
+            voted_at: time::now(),
+        };
+        self.prepared.insert(txn_id.to_string(), prepared);
+        Ok(ops)
+    }
+
+    /// Installs the writes of `txn_id` at `at` if the decision was to
+    /// commit, and releases its locks either way. Transactions that aren't
+    /// prepared here were finished already, or never got this far.
+    pub fn finish(&mut self, txn_id: &str, decision: Decision, at: HlcTimestamp) {
+        let Some(prepared) = self.prepared.remove(txn_id) else {
+            return;
+        };
+        self.locks.retain(|_, holder| holder != txn_id);
+        if decision == Decision::Commit {
+            self.store.commit(prepared.writes, at);
+        }
+    }
+
+    /// Prepared transactions that haven't heard the decision for a while,
+    /// as `(coordinator, txn_id, ops)`, to vote on again.
+    fn revotes(&mut self) -> Vec<(String, String, Vec<Op>)> {
+        let now = time::now();
+        self.prepared
+            .iter_mut()
+            .filter(|(_, prepared)| now - prepared.voted_at >= REVOTE_AFTER)
+            .map(|(txn_id, prepared)| {
+                prepared.voted_at = now;
+                let ops = prepared.ops.clone();
+                (prepared.coordinator.clone(), txn_id.clone(), ops)
+            })
+            .collect()
+    }
+}
+
+/// A transaction this node is coordinating, waiting for votes.
+#[derive(Debug)]
+struct InFlight {
+    client: Message<()>,
+    ops: Vec<Op>,
+    /// The indices of the ops each participant runs.
+    shards: HashMap<String, Vec<usize>>,
+    /// The ops as each participant that voted to commit ran them.
+    votes: HashMap<String, Vec<Op>>,
+    deadline: Instant,
+}
+
+/// Serves the `txn-rw-register` workload with registers spread over the
+/// cluster, coordinating the transactions clients send it.
+#[derive(Debug, Default)]
+pub struct TwoPhaseTxnNode {
+    pub membership: Membership,
+    pub msg_ids: MsgIdAllocator,
+    pub participant: Participant,
+    pub decisions: DecisionLog,
+    /// Stamps each commit on this node's registers.
+    pub clock: HybridClock,
+    /// Where to keep the decision log, opened once `init` names the node.
+    log_dir: Option<PathBuf>,
+    in_flight: HashMap<String, InFlight>,
+}
+
+impl TwoPhaseTxnNode {
+    /// A node that keeps its decision log in `<dir>/<node_id>.decisions`,
+    /// and picks up the decisions in it left pending by a crash.
+    pub fn with_decision_log(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            log_dir: Some(dir.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Sends the ops on each owner's registers to the owner to prepare.
+    fn begin(
+        &mut self,
+        client: Message<()>,
+        ops: Vec<Op>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        // With no participants there is nothing to decide, and nobody to
+        // acknowledge a logged decision.
+        if ops.is_empty() {
+            let msg_id = Some(self.msg_ids.next());
+            return client
+                .into_reply(
+                    &self.membership.node_id,
+                    msg_id,
+                    Payload::TxnOk { txn: ops },
+                )
+                .send(out);
+        }
+        let txn_id = Uuid::new_v4().to_string();
+        let mut shards: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, Op(_, key, _)) in ops.iter().enumerate() {
+            let owner = owner(*key, &self.membership.node_ids);
+            shards.entry(owner.to_string()).or_default().push(i);
+        }
+        let requests: Vec<_> = shards
+            .iter()
+            .map(|(participant, indices)| {
+                let txn: Vec<_> = indices.iter().map(|&i| ops[i].clone()).collect();
+                (participant.clone(), txn)
+            })
+            .collect();
+        self.in_flight.insert(
+            txn_id.clone(),
+            InFlight {
+                client,
+                ops,
+                shards,
+                votes: HashMap::new(),
+                deadline: time::now() + VOTE_TIMEOUT,
+            },
+        );
+
+        let me = self.membership.node_id.clone();
+        for (participant, txn) in requests {
+            if participant == me {
+                let vote = self.prepare(&txn_id, &me, &txn);
+                self.voted(&me, &txn_id, vote.is_some(), vote.unwrap_or_default(), out)?;
+            } else {
+                let txn_id = txn_id.clone();
+                Message::new(&me, &participant, None, Payload::TxnPrepare { txn_id, txn })
+                    .send(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares `txn` as a participant, for `coordinator`. Returns the
+    /// ops as run if the vote is to commit.
+    fn prepare(&mut self, txn_id: &str, coordinator: &str, txn: &[Op]) -> Option<Vec<Op>> {
+        self.participant
+            .prepare(txn_id, coordinator, txn)
+            .map_err(|err| crate::debug!("voting to abort {txn_id}: {}", err.text))
+            .ok()
+    }
+
+    /// Counts `participant`'s vote on `txn_id`, deciding once it's clear
+    /// what to do.
+    fn voted(
+        &mut self,
+        participant: &str,
+        txn_id: &str,
+        commit: bool,
+        txn: Vec<Op>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(in_flight) = self.in_flight.get_mut(txn_id) else {
+            let decision = self.decisions.get(txn_id).unwrap_or(Decision::Abort);
+            return self.tell(participant, txn_id, decision, out);
+        };
+        if !commit {
+            return self.decide(txn_id, Decision::Abort, out);
+        }
+        in_flight.votes.insert(participant.to_string(), txn);
+        if in_flight.votes.len() == in_flight.shards.len() {
+            self.decide(txn_id, Decision::Commit, out)?;
+        }
+        Ok(())
+    }
+
+    /// Logs `decision` on `txn_id`, then tells the participants and the
+    /// client.
+    fn decide(
+        &mut self,
+        txn_id: &str,
+        decision: Decision,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(mut txn) = self.in_flight.remove(txn_id) else {
+            return Ok(());
+        };
+        crate::debug!("decided to {decision:?} {txn_id}");
+        let participants: HashSet<_> = txn.shards.keys().cloned().collect();
+        self.decisions
+            .decide(txn_id, decision, participants.clone())?;
+        for participant in &participants {
+            self.tell(participant, txn_id, decision, out)?;
+        }
+
+        let msg_id = Some(self.msg_ids.next());
+        if decision == Decision::Abort {
+            let err = RpcError::txn_conflict("transaction aborted");
+            return txn
+                .client
+                .into_error(&self.membership.node_id, msg_id, err)
+                .send(out);
+        }
+        for (participant, indices) in &txn.shards {
+            for (&i, op) in indices.iter().zip(&txn.votes[participant]) {
+                txn.ops[i] = op.clone();
+            }
+        }
+        txn.client
+            .into_reply(
+                &self.membership.node_id,
+                msg_id,
+                Payload::TxnOk { txn: txn.ops },
+            )
+            .send(out)
+    }
+
+    /// Tells `participant` the decision on `txn_id`.
+    fn tell(
+        &mut self,
+        participant: &str,
+        txn_id: &str,
+        decision: Decision,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let me = &self.membership.node_id;
+        if participant == me {
+            self.participant.finish(txn_id, decision, self.clock.now());
+            let me = me.clone();
+            return self.decisions.acknowledged(txn_id, &me);
+        }
+        Message::new(me, participant, None, decision.payload(txn_id)).send(out)
+    }
+}
+
+impl Node for TwoPhaseTxnNode {
+    fn handle(&mut self, input: Message, output: &mut impl Write) -> anyhow::Result<()> {
+        let me = self.membership.node_id.clone();
+        let payload = match &input.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.membership.init(node_id, node_ids);
+                if let Some(dir) = &self.log_dir {
+                    self.decisions = DecisionLog::open(dir.join(format!("{node_id}.decisions")))?;
+                }
+                Payload::InitOk
+            }
+            Payload::Txn { txn } => return self.begin(input.header(), txn.clone(), output),
+            Payload::TxnPrepare { txn_id, txn } => {
+                let ops = self.prepare(txn_id, &input.src, txn);
+                let vote = Payload::TxnVote {
+                    txn_id: txn_id.clone(),
+                    commit: ops.is_some(),
+                    txn: ops.unwrap_or_default(),
+                };
+                return Message::new(&me, &input.src, None, vote).send(output);
+            }
+            Payload::TxnVote {
+                txn_id,
+                commit,
+                txn,
+            } => return self.voted(&input.src, txn_id, *commit, txn.clone(), output),
+            Payload::TxnCommit { txn_id } | Payload::TxnAbort { txn_id } => {
+                let decision = match input.body.payload {
+                    Payload::TxnCommit { .. } => Decision::Commit,
+                    _ => Decision::Abort,
+                };
+                self.participant.finish(txn_id, decision, self.clock.now());
+                let done = Payload::TxnDone {
+                    txn_id: txn_id.clone(),
+                };
+                return Message::new(&me, &input.src, None, done).send(output);
+            }
+            Payload::TxnDone { txn_id } => {
+                return self.decisions.acknowledged(txn_id, &input.src);
+            }
+            Payload::InitOk | Payload::Error { .. } => return Ok(()),
+            _ => return Err(RpcError::not_supported("txn node cannot handle this message").into()),
+        };
+        input
+            .into_reply(&self.membership.node_id, Some(self.msg_ids.next()), payload)
+            .send(output)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(100))
+    }
+
+    /// Aborts transactions whose votes are overdue, and repeats decisions
+    /// and votes that may have been lost.
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let now = time::now();
+        let overdue: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, txn)| txn.deadline <= now)
+            .map(|(txn_id, _)| txn_id.clone())
+            .collect();
+        for txn_id in overdue {
+            self.decide(&txn_id, Decision::Abort, out)?;
+        }
+
+        let pending: Vec<_> = self
+            .decisions
+            .pending()
+            .flat_map(|(txn_id, decision, waiting)| {
+                waiting
+                    .iter()
+                    .map(move |participant| (participant.clone(), txn_id.clone(), decision))
+            })
+            .collect();
+        for (participant, txn_id, decision) in pending {
+            self.tell(&participant, &txn_id, decision, out)?;
+        }
+
+        let me = self.membership.node_id.clone();
+        for (coordinator, txn_id, txn) in self.participant.revotes() {
+            if coordinator == me {
+                self.voted(&me, &txn_id, true, txn, out)?;
+            } else {
+                let vote = Payload::TxnVote {
+                    txn_id,
+                    commit: true,
+                    txn,
+                };
+                Message::new(&me, &coordinator, None, vote).send(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
@@ -0,0 +1,422 @@
+//! Running nodes over UDP, for experiments with lossy networks outside
+//! Maelstrom. The [configuration](SocketConfig) and `init` are those of the
+//! other [socket](crate::socket) transports; messages are routed the same
+//! way, to where `dest` last sent from or else to the address listed for
+//! it, but travel one per datagram.
+//!
+//! Between nodes, a thin reliability layer makes up for datagrams that are
+//! lost. Each message goes in a `udp_data` frame numbered per destination,
+//! is acknowledged with a `udp_ack` once it arrives, and until then is
+//! resent by a [`RetryQueue`] with backoff, like any other unacked
+//! message. The receiver passes each message on once, however often it
+//! arrives, and in the order it first does, which isn't always the order
+//! it was sent in. Frames carry a session, the time the sender started,
+//! so a restarted sender's numbering starts afresh, and frames still on
+//! their way from before the restart are acked but not passed on.
+//!
+//! Clients may frame their messages too, or send them bare, e.g. with
+//! `nc -u`, and are then answered bare, with no acks or resends.
+//! [`UdpOptions::loss`] drops a share of datagrams on purpose, to see how
+//! a node copes with a lossier network than the one it is on.
+
+use crate::{
+    retry::{Backoff, RetryQueue},
+    socket::{Envelope, SocketConfig, SocketInput, INIT_SRC},
+    CacheLimits, Config, Message,
+};
+use anyhow::Context;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The most a datagram can carry; longer messages can't be sent.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// The longest the resending thread sleeps, to pick up messages sent since.
+const RESEND_POLL: Duration = Duration::from_millis(10);
+
+/// How many messages from a sender that arrived ahead of a missing one are
+/// remembered, before the sender is taken to have given up on it.
+const MAX_AHEAD: usize = 1024;
+
+pub type UdpInput = SocketInput;
+
+/// How datagrams are lost on purpose, and how lost ones are resent.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpOptions {
+    /// The chance of each datagram sent being dropped, from 0 to 1.
+    pub loss: f64,
+    /// How long to wait for an ack before resending.
+    pub backoff: Backoff,
+    /// When to give up on a message that is never acked.
+    pub limits: CacheLimits,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            backoff: Backoff {
+                initial: Duration::from_millis(50),
+                max: Duration::from_secs(1),
+                jitter: 0.2,
+            },
+            limits: CacheLimits::default(),
+        }
+    }
+}
+
+impl UdpOptions {
+    /// Losing [`Config::udp_loss`] of datagrams, and giving up on messages
+    /// within [`Config::retry_limits`].
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            loss: config.udp_loss,
+            limits: config.retry_limits,
+            ..Self::default()
+        }
+    }
+}
+
+/// What nodes put in a datagram.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    /// A message, numbered by `msg_id`, for the receiver to ack.
+    UdpData { session: u64, message: Value },
+    /// The receipt of the `udp_data` numbered by `in_reply_to`.
+    UdpAck,
+}
+
+/// Binds `config.listen`; see [`serve`].
+pub fn listen(config: &SocketConfig, options: UdpOptions) -> anyhow::Result<(UdpInput, UdpOutput)> {
+    let socket = UdpSocket::bind(&config.listen)
+        .with_context(|| format!("listening on {}", config.listen))?;
+    serve(socket, config, options)
+}
+
+/// Takes datagrams arriving on `socket` from now on, for their messages to
+/// come out of the input, and has the output send messages from it to
+/// their senders or to `config.peers`. `config.listen` is left alone.
+pub fn serve(
+    socket: UdpSocket,
+    config: &SocketConfig,
+    options: UdpOptions,
+) -> anyhow::Result<(UdpInput, UdpOutput)> {
+    crate::info!("listening on udp {}", socket.local_addr()?);
+    let mut peers = HashMap::new();
+    for (node, addr) in &config.peers {
+        let resolved = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("resolving {node} at {addr}"))?;
+        peers.insert(node.clone(), resolved);
+    }
+    let shared = Arc::new(Shared {
+        socket,
+        peers,
+        options,
+        session: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64,
+        state: Mutex::new(State {
+            retry: RetryQueue::new(options.backoff).with_limits(options.limits),
+            next_seq: HashMap::new(),
+            received: HashMap::new(),
+            clients: HashMap::new(),
+        }),
+    });
+    let (tx, rx) = mpsc::channel();
+    let receiving = shared.clone();
+    thread::Builder::new()
+        .name("udp-receive".into())
+        .spawn(move || receiving.receive(tx))?;
+    let resending = shared.clone();
+    thread::Builder::new()
+        .name("udp-resend".into())
+        .spawn(move || resending.resend())?;
+    let output = UdpOutput {
+        buf: Vec::new(),
+        shared,
+    };
+    Ok((SocketInput::new(config, rx), output))
+}
+
+/// Where a sender that isn't a peer sent from.
+#[derive(Debug, Clone, Copy)]
+struct Client {
+    addr: SocketAddr,
+    /// Whether it sends `udp_data` frames, rather than bare messages.
+    framed: bool,
+}
+
+/// Which of a sender's messages have been passed on, so each is passed on
+/// once however often it is resent.
+#[derive(Debug, Default)]
+struct Window {
+    session: u64,
+    /// Every message numbered below this one has been.
+    below: usize,
+    /// Those numbered above it that have been.
+    ahead: BTreeSet<usize>,
+}
+
+impl Window {
+    /// Whether the message numbered `seq` is new, marking it seen. None
+    /// from a session older than the last one seen are.
+    fn first_time(&mut self, session: u64, seq: usize) -> bool {
+        if session < self.session {
+            return false;
+        }
+        if session > self.session {
+            *self = Self {
+                session,
+                ..Self::default()
+            };
+        }
+        if seq < self.below || !self.ahead.insert(seq) {
+            return false;
+        }
+        if self.ahead.len() > MAX_AHEAD {
+            self.below = self.ahead.first().copied().unwrap_or(self.below);
+        }
+        while self.ahead.remove(&self.below) {
+            self.below += 1;
+        }
+        true
+    }
+}
+
+struct State {
+    retry: RetryQueue<Frame>,
+    /// The number of the next message to each destination.
+    next_seq: HashMap<String, usize>,
+    /// What has been passed on from each sender.
+    received: HashMap<String, Window>,
+    clients: HashMap<String, Client>,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    peers: HashMap<String, SocketAddr>,
+    options: UdpOptions,
+    session: u64,
+    state: Mutex<State>,
+}
+
+impl Shared {
+    /// Sends `datagram` to `addr`, unless it is to be lost.
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) {
+        if self.options.loss > 0.0 && rand::thread_rng().gen_bool(self.options.loss.min(1.0)) {
+            return;
+        }
+        if let Err(e) = self.socket.send_to(datagram, addr) {
+            crate::debug!("sending to {addr}: {e}");
+        }
+    }
+
+    /// Passes on the messages in arriving datagrams, acking frames.
+    fn receive(&self, tx: mpsc::Sender<String>) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    crate::debug!("receiving: {e}");
+                    continue;
+                }
+            };
+            let datagram = &buf[..len];
+            let message = match serde_json::from_slice::<Message<Frame>>(datagram) {
+                Ok(frame) => self.take_frame(frame, from),
+                Err(_) => self.take_bare(datagram, from),
+            };
+            if let Some(message) = message {
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn take_frame(&self, frame: Message<Frame>, from: SocketAddr) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let Frame::UdpData { session, message } = frame.body.payload else {
+            state.retry.ack(&frame);
+            return None;
+        };
+        let seq = frame.body.id?;
+        if !self.peers.contains_key(&frame.src) {
+            let client = Client {
+                addr: from,
+                framed: true,
+            };
+            state.clients.insert(frame.src.clone(), client);
+        }
+        let mut ack = Message::new(&frame.dest, &frame.src, None, Frame::UdpAck);
+        ack.body.in_reply_to = Some(seq);
+        if let Ok(ack) = serde_json::to_vec(&ack) {
+            self.send_to(&ack, from);
+        }
+        let window = state.received.entry(frame.src).or_default();
+        window.first_time(session, seq).then(|| message.to_string())
+    }
+
+    fn take_bare(&self, datagram: &[u8], from: SocketAddr) -> Option<String> {
+        let Ok(envelope) = serde_json::from_slice::<Envelope>(datagram) else {
+            crate::warn!("dropping datagram from {from} that isn't a message");
+            return None;
+        };
+        if !self.peers.contains_key(&envelope.src) {
+            let client = Client {
+                addr: from,
+                framed: false,
+            };
+            let mut state = self.state.lock().unwrap();
+            state.clients.insert(envelope.src, client);
+        }
+        let message = std::str::from_utf8(datagram).ok()?;
+        Some(message.trim().to_string())
+    }
+
+    /// Resends unacked frames as they come due, for as long as the process
+    /// runs.
+    fn resend(&self) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let State { retry, clients, .. } = &mut *state;
+            let mut datagrams = Datagrams::new(self, clients);
+            if let Err(e) = retry.resend_due(&mut datagrams) {
+                crate::debug!("resending: {e:#}");
+            }
+            let wait = retry
+                .next_due()
+                .map_or(RESEND_POLL, |due| {
+                    due.saturating_duration_since(Instant::now())
+                })
+                .min(RESEND_POLL);
+            drop(state);
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Sends each line written to it as a datagram to its `dest`.
+struct Datagrams<'a> {
+    shared: &'a Shared,
+    clients: &'a HashMap<String, Client>,
+    buf: Vec<u8>,
+}
+
+impl<'a> Datagrams<'a> {
+    fn new(shared: &'a Shared, clients: &'a HashMap<String, Client>) -> Self {
+        Self {
+            shared,
+            clients,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for Datagrams<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            let Ok(envelope) = serde_json::from_slice::<Envelope>(&line) else {
+                continue;
+            };
+            let addr = match self.clients.get(&envelope.dest) {
+                Some(client) => Some(client.addr),
+                None => self.shared.peers.get(&envelope.dest).copied(),
+            };
+            if let Some(addr) = addr {
+                self.shared.send_to(&line, addr);
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends each complete line written to it to its `dest`, framed and
+/// resent until acked unless `dest` is a client that sends bare messages.
+pub struct UdpOutput {
+    buf: Vec<u8>,
+    shared: Arc<Shared>,
+}
+
+impl UdpOutput {
+    fn send(&mut self, line: &[u8]) {
+        let message = match serde_json::from_slice::<Value>(line) {
+            Ok(message) => message,
+            Err(e) => {
+                crate::warn!("not sending unroutable message: {e}");
+                return;
+            }
+        };
+        let Ok(Envelope { src, dest }) = Envelope::deserialize(&message) else {
+            crate::warn!("not sending message without src and dest");
+            return;
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        match state.clients.get(&dest) {
+            Some(Client {
+                addr,
+                framed: false,
+            }) => return self.shared.send_to(line, *addr),
+            Some(_) => {}
+            None if self.shared.peers.contains_key(&dest) => {}
+            None => {
+                if dest != INIT_SRC {
+                    crate::debug!("no address for {dest}, dropping message");
+                }
+                return;
+            }
+        }
+        let State {
+            retry,
+            next_seq,
+            clients,
+            ..
+        } = &mut *state;
+        let seq = next_seq.entry(dest.clone()).or_default();
+        let frame = Frame::UdpData {
+            session: self.shared.session,
+            message,
+        };
+        let frame = Message::new(src, dest, Some(*seq), frame);
+        *seq += 1;
+        if let Err(e) = retry.send(frame, &mut Datagrams::new(&self.shared, clients)) {
+            crate::debug!("sending: {e:#}");
+        }
+    }
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=end).collect();
+            self.send(&line);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
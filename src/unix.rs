@@ -0,0 +1,160 @@
+//! Running nodes over unix domain sockets, for experiments on one machine
+//! without Maelstrom or its JVM in the way, e.g. to profile a node. The
+//! wire format is the same newline-delimited JSON as on stdin, routed as
+//! [`crate::socket`] describes, with addresses being socket paths.
+//!
+//! A [`Supervisor`] launches a cluster of nodes as child processes, each
+//! listening on a socket in one directory and told where the others are.
+//! `whirlpool cluster 3 --workload broadcast` does so from the command
+//! line, after which clients can connect, e.g. with `nc -U /tmp/n0.sock`.
+
+use crate::socket::{self, SocketConfig, SocketInput, SocketOutput, Stream};
+use anyhow::{bail, Context};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+pub type UnixInput = SocketInput;
+pub type UnixOutput = SocketOutput<UnixStream>;
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+/// Listens on a socket at `config.listen`, replacing any left behind by an
+/// earlier run; see [`serve`].
+pub fn listen(config: &SocketConfig) -> anyhow::Result<(UnixInput, UnixOutput)> {
+    let path = Path::new(&config.listen);
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path).with_context(|| format!("removing old {}", config.listen))?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("listening on {}", config.listen))?;
+    serve(listener, config)
+}
+
+/// Accepts connections on `listener` from now on, for their messages to
+/// come out of the input, and has the output send messages over them or
+/// to `config.peers`. `config.listen` is left alone.
+pub fn serve(
+    listener: UnixListener,
+    config: &SocketConfig,
+) -> anyhow::Result<(UnixInput, UnixOutput)> {
+    crate::info!("listening on {}", config.listen);
+    let accept = move || Ok(listener.accept()?.0);
+    socket::serve("unix-accept", accept, dial, config)
+}
+
+fn dial(path: &str) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+/// A cluster of nodes running as child processes, talking over unix
+/// sockets in one directory. Dropping it kills them and removes the
+/// sockets.
+pub struct Supervisor {
+    sockets: BTreeMap<String, String>,
+    children: Vec<(String, Child)>,
+}
+
+impl Supervisor {
+    /// Launches `count` nodes, `n0` onwards, each from the command `make`
+    /// returns for its id, with `WHIRLPOOL_UNIX_*` set so that it listens
+    /// on `<dir>/<id>.sock`, knows where the others listen and sends
+    /// itself an `init`.
+    pub fn launch(
+        dir: &Path,
+        count: usize,
+        mut make: impl FnMut(&str) -> Command,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let mut sockets = BTreeMap::new();
+        for i in 0..count {
+            let path = dir.join(format!("n{i}.sock"));
+            let path = path.to_str().context("socket directory isn't UTF-8")?;
+            if path.contains([',', '=']) {
+                bail!("socket path {path} can't be listed in WHIRLPOOL_UNIX_PEERS");
+            }
+            sockets.insert(format!("n{i}"), path.to_string());
+        }
+        let peers = sockets
+            .iter()
+            .map(|(node, path)| format!("{node}={path}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut supervisor = Self {
+            sockets,
+            children: Vec::new(),
+        };
+        for (node, path) in &supervisor.sockets {
+            let child = make(node)
+                .env("WHIRLPOOL_UNIX_LISTEN", path)
+                .env("WHIRLPOOL_UNIX_PEERS", &peers)
+                .env("WHIRLPOOL_UNIX_NODE_ID", node)
+                .spawn()
+                .with_context(|| format!("launching {node}"))?;
+            supervisor.children.push((node.clone(), child));
+        }
+        Ok(supervisor)
+    }
+
+    /// The socket each node listens on, by id.
+    pub fn sockets(&self) -> &BTreeMap<String, String> {
+        &self.sockets
+    }
+
+    /// Where `node` listens, to connect a client to.
+    pub fn socket(&self, node: &str) -> Option<&Path> {
+        self.sockets.get(node).map(Path::new)
+    }
+
+    /// Waits for `node`'s socket to be there, for up to `timeout`.
+    pub fn wait_for(&self, node: &str, timeout: Duration) -> anyhow::Result<PathBuf> {
+        let path = self.socket(node).context("no such node")?;
+        for _ in 0..timeout.as_millis() / 10 {
+            if UnixStream::connect(path).is_ok() {
+                return Ok(path.to_path_buf());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        bail!("{node} isn't listening on {}", path.display())
+    }
+
+    /// Blocks until some node exits, failing unless it exited successfully.
+    pub fn wait(&mut self) -> anyhow::Result<()> {
+        loop {
+            for (node, child) in &mut self.children {
+                if let Some(status) = child.try_wait()? {
+                    if !status.success() {
+                        bail!("{node} exited with {status}");
+                    }
+                    return Ok(());
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        for (_, child) in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        for path in self.sockets.values() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Turning away malformed messages before they reach the node, for
+//! catching protocol bugs in development.
+//!
+//! With [`Config::validate`](crate::Config::validate), the reader thread
+//! checks every message once `init` has said which node this is and which
+//! others are in the cluster:
+//!
+//! - its `dest` has to be this node;
+//! - a request from outside the cluster, a client's, has to carry a
+//!   `msg_id`, since the client is waiting for a reply to it; nodes may
+//!   send each other one-way messages;
+//! - if the node makes its calls through an [`Rpc`](crate::Rpc), a reply
+//!   has to answer one of them, which the reader would have already
+//!   handed to the call, so any other reply is to a call that has given up
+//!   or was never made.
+//!
+//! With [`Config::strict`](crate::Config::strict), a message with fields
+//! its type doesn't have, which serde would otherwise skip over, is turned
+//! away too; see [`unknown_fields`].
+//!
+//! A request that can be answered gets a `malformed-request` error. Other
+//! messages, replies included, are dropped with a warning: answering a
+//! reply with an error could start two nodes trading errors forever.
+
+use crate::{ErrorCode, Init, Message, Replier, RpcError};
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+
+/// Checks messages as they are read.
+#[derive(Debug, Default)]
+pub struct Validator {
+    node_id: Option<String>,
+    node_ids: Vec<String>,
+    /// Whether every reply the node wants is to an [`Rpc`](crate::Rpc)
+    /// call.
+    tracks_replies: bool,
+    replier: Replier,
+}
+
+impl Validator {
+    pub fn new(tracks_replies: bool) -> Self {
+        Self {
+            tracks_replies,
+            ..Self::default()
+        }
+    }
+
+    /// Sends the errors it answers with through `replier`, which should be
+    /// the main loop's.
+    pub fn with_replier(mut self, replier: Replier) -> Self {
+        self.replier = replier;
+        self
+    }
+
+    /// Checks `msg`, read as `json`, answering or dropping it if it isn't
+    /// valid. Returns whether the node should have it.
+    pub fn admit<P>(
+        &mut self,
+        json: &str,
+        msg: &Message<P>,
+        out: &mut impl Write,
+    ) -> anyhow::Result<bool> {
+        if self.node_id.is_none() {
+            if let Some(init) = Init::parse(json) {
+                self.replier.learn(json);
+                self.node_id = init.node_id;
+                self.node_ids = init.node_ids;
+            }
+        }
+        match self.check(msg) {
+            Ok(()) => Ok(true),
+            Err(err) => turn_away(msg, err, &self.replier, out).map(|()| false),
+        }
+    }
+
+    fn check<P>(&self, msg: &Message<P>) -> Result<(), RpcError> {
+        let Some(node_id) = &self.node_id else {
+            return Ok(());
+        };
+        if msg.dest != *node_id {
+            return Err(malformed(format!("sent to {}, not {node_id}", msg.dest)));
+        }
+        match (msg.body.id, msg.body.in_reply_to) {
+            (None, None) if !self.node_ids.contains(&msg.src) => {
+                Err(malformed("request without a msg_id".to_string()))
+            }
+            (_, Some(in_reply_to)) if self.tracks_replies => Err(malformed(format!(
+                "reply to {in_reply_to}, which no call is waiting for"
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The fields of `json` that parsing it as `msg` skipped over, as paths
+/// such as `body.colour`. Found by serializing `msg` again and looking for
+/// what is missing, so fields that serialize to nothing unless set count as
+/// known if `json` has them `null`, `false` or empty.
+pub fn unknown_fields<P: Serialize>(json: &str, msg: &Message<P>) -> Vec<String> {
+    let (Ok(read), Ok(parsed)) = (
+        serde_json::from_str::<Value>(json),
+        serde_json::to_value(msg),
+    ) else {
+        return Vec::new();
+    };
+    let mut unknown = Vec::new();
+    missing(&read, &parsed, "", &mut unknown);
+    unknown
+}
+
+fn missing(read: &Value, parsed: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (read, parsed) {
+        (Value::Object(read), Value::Object(parsed)) => {
+            for (key, value) in read {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{path}.{key}"),
+                };
+                match parsed.get(key) {
+                    Some(parsed) => missing(value, parsed, &path, unknown),
+                    None if is_unset(value) => {}
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (Value::Array(read), Value::Array(parsed)) => {
+            for (i, (value, parsed)) in read.iter().zip(parsed).enumerate() {
+                missing(value, parsed, &format!("{path}[{i}]"), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether `value` is what a field left out when unset reads as.
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => true,
+        Value::Array(values) => values.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+/// Turns `msg`, read as `json`, away if it has [`unknown_fields`],
+/// answering through `replier`. Returns whether the node should have it.
+pub fn admit_strictly<P: Serialize>(
+    json: &str,
+    msg: &Message<P>,
+    replier: &Replier,
+    out: &mut impl Write,
+) -> anyhow::Result<bool> {
+    let unknown = unknown_fields(json, msg);
+    if unknown.is_empty() {
+        return Ok(true);
+    }
+    let err = malformed(format!("unknown fields {}", unknown.join(", ")));
+    turn_away(msg, err, replier, out).map(|()| false)
+}
+
+/// Answers `msg` with `err` if it is a request, or drops it with a warning.
+fn turn_away<P>(
+    msg: &Message<P>,
+    err: RpcError,
+    replier: &Replier,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    match (msg.body.id, msg.body.in_reply_to) {
+        (Some(_), None) => {
+            replier.error(msg, err).send(out)?;
+            out.flush().context("handing output to the writer")
+        }
+        _ => {
+            crate::warn!("dropping message from {}: {}", msg.src, err.text);
+            Ok(())
+        }
+    }
+}
+
+fn malformed(text: String) -> RpcError {
+    RpcError::new(ErrorCode::MalformedRequest, text)
+}
@@ -0,0 +1,99 @@
+//! A write-ahead log: entries appended and synced to disk one at a time,
+//! read back in order after a crash.
+//!
+//! Each entry is a JSON line tagged with a sequence number, so a snapshot
+//! can record the last entry it includes and recovery can skip those even
+//! if the log wasn't truncated after the snapshot was taken.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Line<T> {
+    seq: u64,
+    entry: T,
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    /// The sequence number of the last entry appended.
+    seq: u64,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed. Appends continue
+    /// after `seq`, which should be at least the last entry's number, see
+    /// [`Wal::read`].
+    pub fn open(path: impl Into<PathBuf>, seq: u64) -> anyhow::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening write-ahead log {}", path.display()))?;
+        Ok(Self { path, file, seq })
+    }
+
+    /// Every entry in the log at `path` with its sequence number, oldest
+    /// first. A last line cut short by a crash is skipped.
+    pub fn read<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<(u64, T)>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let mut entries = Vec::new();
+        let mut lines = text.lines().enumerate().peekable();
+        while let Some((i, line)) = lines.next() {
+            match serde_json::from_str::<Line<T>>(line) {
+                Ok(line) => entries.push((line.seq, line.entry)),
+                Err(_) if lines.peek().is_none() && !text.ends_with('\n') => {
+                    crate::warn!("ignoring torn last entry of {}", path.display());
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("{}:{}", path.display(), i + 1));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The sequence number of the last entry appended.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Appends `entry` and waits for it to reach the disk. Returns its
+    /// sequence number.
+    pub fn append<T: Serialize>(&mut self, entry: &T) -> anyhow::Result<u64> {
+        let seq = self.seq + 1;
+        let mut line = serde_json::to_vec(&Line { seq, entry }).context("serializing entry")?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("appending to {}", self.path.display()))?;
+        self.seq = seq;
+        Ok(seq)
+    }
+
+    /// Drops every entry, once they are all covered by a snapshot.
+    /// Sequence numbers carry on where they were.
+    pub fn truncate(&mut self) -> anyhow::Result<()> {
+        self.file
+            .set_len(0)
+            .and_then(|()| self.file.sync_all())
+            .with_context(|| format!("truncating {}", self.path.display()))
+    }
+}
@@ -0,0 +1,320 @@
+//! Running nodes over WebSocket, so a web page can talk to a node: send it
+//! `echo` or `broadcast` requests and watch the replies come in. Messages
+//! are routed as [`crate::socket`] describes, one JSON message per text
+//! frame.
+//!
+//! Plain HTTP requests for `/` on the same port get a demo page that does
+//! just that: open `http://localhost:7000/` while a node listens there.
+//! Nodes reach their peers over WebSocket too, so a cluster of them can
+//! be watched from several tabs.
+
+mod sha1;
+
+use crate::{
+    compress::to_base64,
+    socket::{self, SocketConfig, SocketInput, SocketOutput, Stream},
+    tcp,
+};
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The page served for `/`.
+const DEMO: &str = include_str!("demo.html");
+
+/// What the key of a handshake is hashed with, as RFC 6455 has it.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long the other end may take over its half of the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request or response head read during a handshake.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// The longest message taken, to bound what a bad length can make us
+/// allocate.
+const MAX_MESSAGE: u64 = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+pub type WsInput = SocketInput;
+pub type WsOutput = SocketOutput<WsStream>;
+
+/// Listens on `config.listen`; see [`serve`].
+pub fn listen(config: &SocketConfig) -> anyhow::Result<(WsInput, WsOutput)> {
+    let listener = TcpListener::bind(&config.listen)
+        .with_context(|| format!("listening on {}", config.listen))?;
+    serve(listener, config)
+}
+
+/// Accepts WebSocket connections on `listener` from now on, for their
+/// messages to come out of the input, and has the output send messages
+/// over them or to `config.peers`. Other HTTP requests get the demo page.
+/// `config.listen` is left alone.
+pub fn serve(listener: TcpListener, config: &SocketConfig) -> anyhow::Result<(WsInput, WsOutput)> {
+    crate::info!("listening on http://{}/", listener.local_addr()?);
+    let page = DEMO.replace("{{node_id}}", config.node_id.as_deref().unwrap_or("n0"));
+    let accept = move || loop {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        match upgrade(stream, &page) {
+            Ok(Some(stream)) => return Ok(stream),
+            Ok(None) => {}
+            Err(e) => crate::debug!("handshake failed: {e}"),
+        }
+    };
+    socket::serve("ws-accept", accept, connect, config)
+}
+
+/// Opens a WebSocket connection to `addr`, as a client.
+pub fn connect(addr: &str) -> io::Result<WsStream> {
+    let mut stream = tcp::dial(addr)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let key = to_base64(&rand::random::<[u8; 16]>());
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )?;
+    let (status, headers) = read_head(&mut stream)?;
+    if !status.starts_with("HTTP/1.1 101") {
+        return Err(invalid(format!("upgrade refused: {status}")));
+    }
+    if headers.get("sec-websocket-accept") != Some(&accept_key(&key)) {
+        return Err(invalid("upgrade accepted with the wrong key".into()));
+    }
+    stream.set_read_timeout(None)?;
+    WsStream::new(stream, true)
+}
+
+/// The `Sec-WebSocket-Accept` answering a handshake's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    to_base64(&sha1::sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Answers the HTTP request on `stream`: upgrades it if it asks to be,
+/// otherwise serves `page` for `/` and closes it.
+fn upgrade(mut stream: TcpStream, page: &str) -> io::Result<Option<WsStream>> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let (request, headers) = read_head(&mut stream)?;
+    let Some(key) = headers.get("sec-websocket-key") else {
+        let response = match request.split(' ').nth(1) {
+            Some("/") | Some("/index.html") => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{page}",
+                page.len()
+            ),
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        stream.write_all(response.as_bytes())?;
+        return Ok(None);
+    };
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    stream.set_read_timeout(None)?;
+    WsStream::new(stream, false).map(Some)
+}
+
+/// Reads the head of an HTTP request or response: its first line and its
+/// headers, by lowercase name. Reads a byte at a time, to leave whatever
+/// follows unread.
+fn read_head(stream: &mut TcpStream) -> io::Result<(String, HashMap<String, String>)> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HEAD {
+            return Err(invalid("HTTP head too long".into()));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).map_err(|_| invalid("HTTP head isn't UTF-8".into()))?;
+    let mut lines = head.lines();
+    let first = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok((first, headers))
+}
+
+fn invalid(text: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, text)
+}
+
+/// A WebSocket connection, read and written as newline-delimited JSON:
+/// each text message read comes out followed by a newline, and each line
+/// written goes out as a text message.
+pub struct WsStream {
+    reader: BufReader<TcpStream>,
+    /// Shared between clones, so frames written by each don't interleave.
+    writer: Arc<Mutex<TcpStream>>,
+    /// Whether frames sent are masked, as a client's must be.
+    masked: bool,
+    /// Text read and not yet returned.
+    pending: Vec<u8>,
+    /// The start of what hasn't been returned of `pending`.
+    pos: usize,
+    /// The fragments of a message being read.
+    message: Vec<u8>,
+    closed: bool,
+    /// A line being written.
+    line: Vec<u8>,
+}
+
+impl WsStream {
+    fn new(stream: TcpStream, masked: bool) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: Arc::new(Mutex::new(stream)),
+            masked,
+            pending: Vec::new(),
+            pos: 0,
+            message: Vec::new(),
+            closed: false,
+            line: Vec::new(),
+        })
+    }
+
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.masked {
+            let mask: [u8; 4] = rand::random();
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.writer.lock().unwrap().write_all(&frame)
+    }
+
+    /// Reads a frame, adding a message it completes to `pending` and
+    /// answering pings and closes.
+    fn read_frame(&mut self) -> io::Result<()> {
+        let mut header = [0; 2];
+        self.reader.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        if len.saturating_add(self.message.len() as u64) > MAX_MESSAGE {
+            return Err(invalid(format!("message longer than {MAX_MESSAGE} bytes")));
+        }
+        let mut mask = [0; 4];
+        if header[1] & 0x80 != 0 {
+            self.reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (b, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *b ^= m;
+        }
+        match opcode {
+            CONTINUATION | TEXT | BINARY => {
+                self.message.extend_from_slice(&payload);
+                if fin {
+                    self.pending.append(&mut self.message);
+                    self.pending.push(b'\n');
+                }
+            }
+            CLOSE => {
+                // Echoing the status, as the closing handshake goes.
+                let _ = self.send_frame(CLOSE, &payload[..payload.len().min(2)]);
+                self.closed = true;
+            }
+            PING => self.send_frame(PONG, &payload)?,
+            PONG => {}
+            _ => return Err(invalid(format!("unknown opcode {opcode:#x}"))),
+        }
+        Ok(())
+    }
+}
+
+impl Stream for WsStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(self.reader.get_ref().try_clone()?),
+            writer: self.writer.clone(),
+            masked: self.masked,
+            pending: Vec::new(),
+            pos: 0,
+            message: Vec::new(),
+            closed: false,
+            line: Vec::new(),
+        })
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            self.pending.clear();
+            self.pos = 0;
+            if self.closed {
+                return Ok(0);
+            }
+            match self.read_frame() {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(data);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.send_frame(TEXT, &line[..end])?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
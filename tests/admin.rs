@@ -0,0 +1,147 @@
+//! The admin server answers with what a running node holds, knows of and
+//! waits for, without getting in the way of its messages.
+#![cfg(feature = "admin")]
+
+use serde_json::{json, Value};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use whirlpool::{run, BroadcastNode, Config, CounterNode, Node, TopologyStrategy};
+
+fn msg(src: &str, body: Value) -> String {
+    json!({"src": src, "dest": "n1", "body": body}).to_string()
+}
+
+fn init(node_ids: &[&str]) -> String {
+    msg(
+        "c0",
+        json!({"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": node_ids}),
+    )
+}
+
+/// Runs `node` on what is sent down the returned channel, with the admin
+/// server on a port of its own, whose address is returned too.
+fn start<N: Node + Send + 'static>(node: N) -> (mpsc::Sender<String>, String) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let config = Config {
+        admin: Some(addr.clone()),
+        ..Config::default()
+    };
+    let (tx, input) = mpsc::channel();
+    thread::spawn(move || run(node, &config, input, io::sink()));
+    (tx, addr)
+}
+
+/// GETs `path`, returning the status code and the JSON body.
+fn get(addr: &str, path: &str) -> (u16, Value) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(e) => panic!("admin server never came up: {e}"),
+        }
+    };
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+/// GETs `path` until `done` says the answer is the one expected.
+fn get_until(addr: &str, path: &str, done: impl Fn(&Value) -> bool) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (status, body) = get(addr, path);
+        if status == 200 && done(&body) {
+            return body;
+        }
+        assert!(Instant::now() < deadline, "{path} never got there: {body}");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn state_and_topology_come_from_the_node() {
+    let node = BroadcastNode::default().with_topology(TopologyStrategy::FullMesh);
+    let (tx, addr) = start(node);
+    tx.send(init(&["n1", "n2"])).unwrap();
+    for (msg_id, message) in [(2, 7), (3, 3)] {
+        let body = json!({"type": "broadcast", "msg_id": msg_id, "message": message});
+        tx.send(msg("c1", body)).unwrap();
+    }
+
+    let state = get_until(&addr, "/state", |state| state["seen"] == json!([3, 7]));
+    assert!(state["unacked"].is_u64());
+    let topology = get_until(&addr, "/topology", |topology| topology["node_id"] == "n1");
+    assert_eq!(topology["node_ids"], json!(["n1", "n2"]));
+    assert_eq!(topology["neighbors"], json!(["n2"]));
+
+    let metrics = get_until(&addr, "/metrics", |metrics| metrics["received"] != 0);
+    assert!(metrics["latency"].is_object());
+}
+
+#[test]
+fn pending_rpcs_are_listed_until_answered() {
+    let (tx, addr) = start(CounterNode::default());
+    tx.send(init(&["n1"])).unwrap();
+    // Nothing answers for seq-kv here, so the node's read of it waits.
+    tx.send(msg("c1", json!({"type": "add", "msg_id": 2, "delta": 1})))
+        .unwrap();
+
+    let pending = get_until(&addr, "/pending-rpcs", |calls| calls != &json!([]));
+    assert_eq!(pending[0]["dest"], "seq-kv");
+    assert!(pending[0]["waited_ms"].is_u64());
+
+    let msg_id = pending[0]["msg_id"].clone();
+    let reply = json!({"type": "read_ok", "in_reply_to": msg_id, "value": 0});
+    tx.send(json!({"src": "seq-kv", "dest": "n1", "body": reply}).to_string())
+        .unwrap();
+    get_until(&addr, "/pending-rpcs", |calls| {
+        calls
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|call| call["msg_id"] != msg_id)
+    });
+}
+
+#[test]
+fn metrics_are_served_for_prometheus() {
+    let (tx, addr) = start(CounterNode::default());
+    tx.send(init(&["n1"])).unwrap();
+    get_until(&addr, "/metrics", |metrics| {
+        metrics["received"]["init"] == 1
+    });
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    write!(stream, "GET /prometheus HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(body.contains("# TYPE whirlpool_messages_received_total counter"));
+    assert!(body.contains("whirlpool_messages_received_total{type=\"init\"}"));
+}
+
+#[test]
+fn nodes_without_views_answer_null_and_unknown_paths_404() {
+    let (tx, addr) = start(CounterNode::default());
+    tx.send(init(&["n1"])).unwrap();
+
+    assert_eq!(get(&addr, "/state"), (200, Value::Null));
+    let (status, body) = get(&addr, "/nope");
+    assert_eq!(status, 404);
+    assert!(body["paths"].as_array().unwrap().contains(&json!("/state")));
+}
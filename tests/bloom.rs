@@ -0,0 +1,38 @@
+//! Bloom filters hold what was inserted and little else.
+
+use whirlpool::bloom::BloomFilter;
+
+#[test]
+fn inserted_items_are_always_found() {
+    let mut filter = BloomFilter::new(1000, 0.01, 1);
+    for item in 0..1000 {
+        filter.insert(&item);
+    }
+    assert!((0..1000).all(|item| filter.contains(&item)));
+}
+
+#[test]
+fn false_positives_stay_near_the_rate_asked_for() {
+    let mut filter = BloomFilter::new(1000, 0.01, 2);
+    for item in 0..1000 {
+        filter.insert(&item);
+    }
+    let false_positives = (1000..11_000).filter(|item| filter.contains(item)).count();
+    assert!(false_positives < 300, "{false_positives} of 10000");
+}
+
+#[test]
+fn seeds_pick_different_false_positives() {
+    let filled = |seed| {
+        let mut filter = BloomFilter::new(100, 0.05, seed);
+        for item in 0..100 {
+            filter.insert(&item);
+        }
+        filter
+    };
+    let (a, b) = (filled(3), filled(4));
+    let both = (100..10_100)
+        .filter(|item| a.contains(item) && b.contains(item))
+        .count();
+    assert!(both < 50, "{both} false positives in both");
+}
@@ -0,0 +1,66 @@
+//! Causal broadcast holds values back until what they depend on has been
+//! delivered.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+use whirlpool::{clock::VectorClock, payload::Payload, sim::Sim, CausalBroadcastNode, Message};
+
+fn stamp(counters: &[(&str, u64)]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for &(node, counter) in counters {
+        clock.observe(node, counter);
+    }
+    clock
+}
+
+#[test]
+fn values_wait_for_the_ones_they_depend_on() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let make = |_: &str| {
+        let log = log.clone();
+        CausalBroadcastNode::default()
+            .on_deliver(move |origin, message| log.borrow_mut().push((origin.to_string(), message)))
+    };
+    let mut sim = Sim::with_seed(3, make, 1).unwrap();
+    let causal = |message, clock| Payload::CausalBroadcast { message, clock };
+
+    // n1 broadcast 2 after delivering n0's 1, which n2 hasn't received.
+    let second = causal(2, stamp(&[("n0", 1), ("n1", 1)]));
+    sim.inject(Message::new("n1", "n2", Some(1), second));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    let n2 = sim.node("n2").unwrap();
+    assert!(n2.delivered().is_empty());
+    assert_eq!(n2.held(), 1);
+
+    sim.inject(Message::new(
+        "n0",
+        "n2",
+        Some(1),
+        causal(1, stamp(&[("n0", 1)])),
+    ));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    let n2 = sim.node("n2").unwrap();
+    assert_eq!(n2.delivered(), [1, 2]);
+    assert_eq!(n2.held(), 0);
+    assert_eq!(
+        *log.borrow(),
+        [("n0".to_string(), 1), ("n1".to_string(), 2)]
+    );
+}
+
+#[test]
+fn every_node_delivers_in_causal_order_despite_drops() {
+    let mut sim = Sim::with_seed(5, |_| CausalBroadcastNode::default(), 2).unwrap();
+    sim.network().drop_rate = 0.25;
+    for message in 1..=5 {
+        sim.client_send("n0", Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    assert_eq!(sim.node("n1").unwrap().delivered(), [1, 2, 3, 4, 5]);
+
+    // n1 has delivered all of n0's values, so 10 comes after them.
+    sim.client_send("n1", Payload::Broadcast { message: 10 });
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.delivered(), [1, 2, 3, 4, 5, 10]);
+    }
+}
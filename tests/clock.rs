@@ -0,0 +1,100 @@
+//! Logical clocks.
+
+use std::cmp::Ordering;
+use whirlpool::{
+    clock::{HlcTimestamp, HybridClock, LamportClock, Stamped, VectorClock},
+    payload::Payload,
+    transport,
+    txn::{Op, OpKind, Store},
+    Message,
+};
+
+#[test]
+fn vector_clocks_order_causally() {
+    let mut a = VectorClock::new();
+    a.increment("n1");
+    let mut b = a.clone();
+    b.increment("n2");
+    assert_eq!(a.compare(&b), Some(Ordering::Less));
+    assert!(b.includes(&a));
+
+    a.increment("n1");
+    assert!(a.concurrent(&b));
+    a.merge(&b);
+    assert_eq!(a.get("n1"), 2);
+    assert_eq!(a.get("n2"), 1);
+    assert_eq!(a.compare(&b), Some(Ordering::Greater));
+}
+
+#[test]
+fn lamport_receipt_is_later_than_the_send() {
+    let sender = LamportClock::new();
+    let receiver = LamportClock::new();
+    for _ in 0..5 {
+        sender.tick();
+    }
+
+    let mut out = Vec::new();
+    let msg = Message::new("n1", "n2", Some(1), Payload::Echo { echo: "hi".into() });
+    sender.send(msg, &mut out).unwrap();
+    let sent = transport::parse_lines::<Stamped<Payload>>(&out).unwrap();
+    assert_eq!(sent[0].body.payload.lamport, Some(6));
+
+    let received = receiver.receive(sent.into_iter().next().unwrap());
+    assert!(matches!(received.body.payload, Payload::Echo { .. }));
+    assert_eq!(receiver.now(), 7);
+    assert!(receiver.timestamp("n2") > sender.timestamp("n1"));
+}
+
+#[test]
+fn unstamped_messages_still_parse() {
+    let json = br#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+    let msg = &transport::parse_lines::<Stamped<Payload>>(json).unwrap()[0];
+    assert_eq!(msg.body.payload.lamport, None);
+    assert!(matches!(msg.body.payload.payload, Payload::Echo { .. }));
+}
+
+#[test]
+fn hybrid_clocks_stay_causal_despite_skew() {
+    // n1's clock runs a second ahead of n2's, which never moves.
+    let ahead = HybridClock::with_wall_clock(|| 2_000);
+    let behind = HybridClock::with_wall_clock(|| 1_000);
+
+    let sent = ahead.now();
+    assert_eq!(
+        sent,
+        HlcTimestamp {
+            wall: 2_000,
+            logical: 0
+        }
+    );
+    let received = behind.observe(sent);
+    assert!(received > sent);
+    assert!(behind.now() > received);
+    assert_eq!(behind.last().wall, 2_000);
+
+    let packed = received.to_u64();
+    assert_eq!(HlcTimestamp::from_u64(packed), received);
+    assert!(packed > sent.to_u64());
+}
+
+#[test]
+fn txn_stores_merge_last_writer_wins() {
+    let clock = HybridClock::with_wall_clock(|| 1_000);
+    let write = |value| [Op(OpKind::Write, 1, Some(value))];
+    let mut a = Store::default();
+    let mut b = Store::default();
+    a.apply(&write(10), clock.now()).unwrap();
+    b.apply(&write(20), clock.now()).unwrap();
+
+    let mut merged = a.clone();
+    merged.merge(&b);
+    b.merge(&a);
+    for store in [&merged, &b] {
+        let read = store
+            .clone()
+            .apply(&[Op(OpKind::Read, 1, None)], clock.now());
+        assert_eq!(read.unwrap(), [Op(OpKind::Read, 1, Some(20))]);
+    }
+    assert_eq!(merged.written_at(1), b.written_at(1));
+}
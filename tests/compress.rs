@@ -0,0 +1,71 @@
+//! Packed gossip values decode to what was packed, and take less room.
+
+use whirlpool::{
+    compress::{self, Encoding, Packed},
+    ErrorCode,
+};
+
+#[test]
+fn packed_values_decode_sorted_and_deduplicated() {
+    let values = [300, 5, 1 << 40, 7, 5, 0];
+    let packed = Packed::encode(Encoding::DeltaVarint, &values);
+    assert_eq!(packed.decode().unwrap(), [0, 5, 7, 300, 1 << 40]);
+    assert_eq!(
+        Packed::encode(Encoding::DeltaVarint, &[]).decode().unwrap(),
+        Vec::<usize>::new()
+    );
+}
+
+#[test]
+fn runs_of_values_pack_smaller_than_json() {
+    let values: Vec<usize> = (1000..2000).collect();
+    let packed = Packed::encode(Encoding::DeltaVarint, &values);
+    let json = serde_json::to_string(&values).unwrap();
+    assert_eq!(compress::json_len(&values), json.len());
+    assert!(packed.data.len() * 3 < json.len(), "{}", packed.data.len());
+}
+
+#[test]
+fn malformed_data_is_rejected() {
+    for data in ["not base64!", "gA==", "abc"] {
+        let packed = Packed {
+            encoding: Encoding::DeltaVarint,
+            data: data.to_string(),
+        };
+        let err = packed.decode().unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedRequest, "{data}");
+    }
+}
+
+#[test]
+fn msgpack_keeps_order_and_duplicates() {
+    let values = [300, 5, 1 << 40, 7, 5, 0];
+    let packed = Packed::encode(Encoding::MsgPack, &values);
+    assert_eq!(packed.decode().unwrap(), values);
+
+    let negative = Packed {
+        encoding: Encoding::MsgPack,
+        data: "kf8=".to_string(),
+    };
+    let err = negative.decode().unwrap_err();
+    assert_eq!(err.code, ErrorCode::MalformedRequest);
+}
+
+#[test]
+fn gzip_keeps_order_and_duplicates() {
+    let values = [300, 5, 1 << 40, 7, 5, 0];
+    let packed = Packed::encode(Encoding::Gzip, &values);
+    assert_eq!(packed.decode().unwrap(), values);
+
+    let runs: Vec<usize> = (1000..2000).collect();
+    let packed = Packed::encode(Encoding::Gzip, &runs);
+    assert!(packed.data.len() * 2 < compress::json_len(&runs));
+    assert_eq!(packed.decode().unwrap(), runs);
+
+    let not_gzip = Packed {
+        encoding: Encoding::Gzip,
+        data: "kf8=".to_string(),
+    };
+    let err = not_gzip.decode().unwrap_err();
+    assert_eq!(err.code, ErrorCode::MalformedRequest);
+}
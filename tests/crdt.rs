@@ -0,0 +1,270 @@
+//! CRDTs merge to the same state in any order, and nodes serving them
+//! converge on the simulated network.
+
+use serde_json::json;
+use std::time::Duration;
+use whirlpool::{
+    clock::HybridClock,
+    crdt::{map::Nested, Crdt, CrdtMap, GSet, LwwMap, OrSet, Replicated},
+    payload::{AddValue, Payload, ReadValue},
+    sim::Sim,
+    CrdtNode, ErrorCode, GSetNode,
+};
+
+fn gset(elements: &[i64]) -> GSet<i64> {
+    let mut set = GSet::default();
+    for &element in elements {
+        set.add(element);
+    }
+    set
+}
+
+#[test]
+fn g_set_merges_are_commutative_and_idempotent() {
+    let (a, b) = (gset(&[1, 2]), gset(&[2, 3]));
+    let mut ab = a.clone();
+    ab.merge(&b);
+    let mut ba = b.clone();
+    ba.merge(&a);
+    assert_eq!(ab, ba);
+    assert_eq!(ab, gset(&[1, 2, 3]));
+    let once = ab.clone();
+    ab.merge(&b);
+    ab.merge(&ab.clone());
+    assert_eq!(ab, once);
+}
+
+#[test]
+fn g_set_nodes_converge_despite_drops() {
+    let mut sim = Sim::with_seed(5, |_| GSetNode::default(), 8).unwrap();
+    sim.network().drop_rate = 0.3;
+    for element in 0..20 {
+        let value = AddValue::Element {
+            element: json!(element),
+        };
+        sim.client_send(
+            &format!("n{}", element % 5),
+            Payload::Add { value, key: None },
+        );
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.state, gset(&(0..20).collect::<Vec<_>>()));
+    }
+
+    sim.take_replies();
+    sim.network().drop_rate = 0.0;
+    sim.client_send(
+        "n3",
+        Payload::Read {
+            key: None,
+            consistency: None,
+        },
+    );
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(
+        replies[0].body.payload,
+        Payload::ReadOk {
+            value: ReadValue::Value {
+                value: json!((0..20).collect::<Vec<_>>())
+            }
+        }
+    );
+}
+
+#[test]
+fn nodes_forget_deltas_once_every_peer_has_them() {
+    let mut sim = Sim::with_seed(3, |_| CrdtNode::<GSet<i64>>::default(), 11).unwrap();
+    for element in 0..6 {
+        let value = AddValue::Element {
+            element: json!(element),
+        };
+        sim.client_send(
+            &format!("n{}", element % 3),
+            Payload::Add { value, key: None },
+        );
+    }
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert!(sim.nodes().all(|node| node.pending_deltas() == 2));
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.pending_deltas(), 0);
+        assert_eq!(node.state, gset(&[0, 1, 2, 3, 4, 5]));
+    }
+}
+
+#[test]
+fn or_set_adds_win_over_concurrent_removes() {
+    let mut set = OrSet::default();
+    set.add("n0", 1);
+    let mut removed = set.clone();
+    assert!(removed.remove(&1));
+    let mut readded = set.clone();
+    readded.add("n1", 1);
+
+    let mut stale = removed.clone();
+    stale.merge(&set);
+    assert!(!stale.contains(&1), "merging an old state undid a remove");
+    removed.merge(&readded);
+    readded.merge(&stale);
+    assert_eq!(removed, readded);
+    assert!(removed.contains(&1));
+}
+
+#[test]
+fn or_set_nodes_converge_on_removals_despite_drops() {
+    let mut sim = Sim::with_seed(5, |_| CrdtNode::<OrSet<i64>>::default(), 9).unwrap();
+    for element in 0..10 {
+        let value = AddValue::Element {
+            element: json!(element),
+        };
+        sim.client_send(
+            &format!("n{}", element % 5),
+            Payload::Add { value, key: None },
+        );
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    sim.network().drop_rate = 0.3;
+    for element in (0..10).step_by(2) {
+        let remove = Payload::Remove {
+            element: json!(element),
+            key: None,
+        };
+        sim.client_send(&format!("n{}", (element + 1) % 5), remove);
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        let elements: Vec<i64> = node.state.iter().copied().collect();
+        assert_eq!(elements, [1, 3, 5, 7, 9], "{}", node.membership.node_id);
+    }
+}
+
+/// A map whose clock always reads `wall` milliseconds.
+fn lww_map(wall: u64) -> LwwMap {
+    LwwMap::with_clock(HybridClock::with_wall_clock(move || wall))
+}
+
+#[test]
+fn lww_map_breaks_ties_by_node() {
+    let (mut a, mut b) = (lww_map(1000), lww_map(1000));
+    let key = json!("k");
+    a.set("n1", &key, json!(1));
+    b.set("n2", &key, json!(2));
+    let a_before = a.clone();
+    a.merge(&b);
+    b.merge(&a_before);
+    assert_eq!(a, b);
+    assert_eq!(a.get(&key).unwrap().value, json!(2));
+}
+
+#[test]
+fn lww_map_writes_made_after_a_merge_win_despite_skew() {
+    let (mut ahead, mut behind) = (lww_map(5000), lww_map(1000));
+    let key = json!("k");
+    ahead.set("n0", &key, json!(1));
+    behind.merge(&ahead);
+    behind.set("n1", &key, json!(2));
+    ahead.merge(&behind);
+    assert_eq!(ahead.get(&key).unwrap().value, json!(2));
+}
+
+#[test]
+fn lww_map_nodes_converge_on_the_last_write() {
+    let mut sim = Sim::with_seed(3, |_| CrdtNode::<LwwMap>::default(), 10).unwrap();
+    for (i, node) in ["n0", "n1", "n2", "n0"].into_iter().enumerate() {
+        let write = Payload::Write {
+            key: json!("k"),
+            value: json!(i),
+            consistency: None,
+        };
+        sim.client_send(node, write);
+        sim.run_for(Duration::from_millis(5)).unwrap();
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.state.get(&json!("k")).unwrap().value, json!(3));
+    }
+}
+
+fn add(key: &str, value: AddValue) -> Payload {
+    Payload::Add {
+        value,
+        key: Some(json!(key)),
+    }
+}
+
+fn read(key: &str) -> Payload {
+    Payload::Read {
+        key: Some(json!(key)),
+        consistency: None,
+    }
+}
+
+#[test]
+fn crdt_map_keys_hold_whatever_their_first_request_needs() {
+    let mut sim = Sim::with_seed(3, |_| CrdtNode::<CrdtMap>::default(), 12).unwrap();
+    let requests = [
+        ("n0", add("hits", AddValue::Delta { delta: 5 })),
+        ("n1", add("hits", AddValue::Delta { delta: -2 })),
+        ("n1", add("tags", AddValue::Element { element: json!(7) })),
+        ("n2", add("tags", AddValue::Element { element: json!(3) })),
+        (
+            "n2",
+            Payload::Write {
+                key: json!("owner"),
+                value: json!("n2"),
+                consistency: None,
+            },
+        ),
+    ];
+    for (node, request) in requests {
+        sim.client_send(node, request);
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let first = &sim.node("n0").unwrap().state;
+    assert!(matches!(first.get("hits"), Some(Nested::Counter(_))));
+    assert!(sim.nodes().all(|node| node.state == *first));
+
+    sim.take_replies();
+    for request in [read("hits"), read("tags"), read("owner"), read("nothing")] {
+        sim.client_send("n1", request);
+        sim.run_for(Duration::from_millis(5)).unwrap();
+    }
+    sim.client_send("n1", add("owner", AddValue::Delta { delta: 1 }));
+    sim.run_for(Duration::from_millis(5)).unwrap();
+    let replies: Vec<Payload> = sim
+        .take_replies()
+        .into_iter()
+        .map(|reply| reply.body.payload)
+        .collect();
+    let read_ok = |value| Payload::ReadOk {
+        value: ReadValue::Value { value },
+    };
+    assert_eq!(replies[0], read_ok(json!(3)));
+    assert_eq!(replies[1], read_ok(json!([3, 7])));
+    assert_eq!(replies[2], read_ok(json!("n2")));
+    for (reply, expected) in replies[3..]
+        .iter()
+        .zip([ErrorCode::KeyDoesNotExist, ErrorCode::PreconditionFailed])
+    {
+        let Payload::Error { code, .. } = reply else {
+            panic!("expected an error, got {reply:?}");
+        };
+        assert_eq!(*code, expected);
+    }
+}
+
+#[test]
+fn crdt_map_keeps_one_type_for_a_key_started_as_two() {
+    let (mut a, mut b) = (CrdtMap::default(), CrdtMap::default());
+    a.apply("n0", &add("k", AddValue::Delta { delta: 1 }))
+        .unwrap();
+    b.apply("n1", &add("k", AddValue::Element { element: json!(1) }))
+        .unwrap();
+    let a_before = a.clone();
+    a.merge(&b);
+    b.merge(&a_before);
+    assert_eq!(a, b);
+    assert!(matches!(a.get("k"), Some(Nested::Set(_))));
+}
@@ -0,0 +1,99 @@
+//! Retried requests are answered from a cache instead of handled again.
+
+use serde_json::json;
+use std::time::Duration;
+use whirlpool::{
+    dedup::Deduped,
+    kv::quorum::Consistency,
+    payload::{AddValue, Payload, ReadValue},
+    sim::{Sim, CLIENT},
+    CacheLimits, CounterNode, Message, QuorumKvNode,
+};
+
+fn add(delta: i64) -> Payload {
+    Payload::Add {
+        value: AddValue::Delta { delta },
+        key: None,
+    }
+}
+
+fn read() -> Payload {
+    Payload::Read {
+        key: None,
+        consistency: None,
+    }
+}
+
+#[test]
+fn a_retried_add_is_applied_once() {
+    let mut sim = Sim::with_seed(1, |_| Deduped::new(CounterNode::default()), 1).unwrap();
+    for _ in 0..3 {
+        sim.inject(Message::new(CLIENT, "n0", Some(100), add(5)));
+        sim.run_for(Duration::from_millis(10)).unwrap();
+    }
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 3);
+    assert!(replies.iter().all(|reply| reply == &replies[0]));
+
+    sim.client_send("n0", read());
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert_eq!(
+        sim.take_replies()[0].body.payload,
+        Payload::ReadOk {
+            value: ReadValue::Value { value: json!(5) }
+        }
+    );
+}
+
+#[test]
+fn duplicates_of_a_request_still_in_flight_are_dropped() {
+    let mut sim = Sim::with_seed(5, |_| Deduped::new(QuorumKvNode::default()), 2).unwrap();
+    let write = Payload::Write {
+        key: json!("k"),
+        value: json!(1),
+        consistency: Some(Consistency::All),
+    };
+    sim.inject(Message::new(CLIENT, "n0", Some(100), write.clone()));
+    sim.inject(Message::new(CLIENT, "n0", Some(100), write.clone()));
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].body.payload, Payload::WriteOk);
+
+    sim.inject(Message::new(CLIENT, "n0", Some(100), write));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert_eq!(sim.take_replies(), replies);
+}
+
+#[test]
+fn requests_are_forgotten_past_the_limits() {
+    let limits = CacheLimits {
+        ttl: Some(Duration::from_millis(100)),
+        max_entries: Some(2),
+    };
+    let make = |_: &str| Deduped::new(CounterNode::default()).with_limits(limits);
+    let mut sim = Sim::with_seed(1, make, 1).unwrap();
+    sim.inject(Message::new(CLIENT, "n0", Some(100), add(5)));
+    sim.run_for(Duration::from_millis(150)).unwrap();
+    // Too old to be remembered, so applied again.
+    sim.inject(Message::new(CLIENT, "n0", Some(100), add(5)));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert_eq!(sim.node("n0").unwrap().len(), 1);
+
+    for msg_id in 101..=102 {
+        sim.inject(Message::new(CLIENT, "n0", Some(msg_id), add(1)));
+    }
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert!(sim.node("n0").unwrap().len() <= 2);
+    // The first of the three was evicted to make room.
+    sim.inject(Message::new(CLIENT, "n0", Some(100), add(5)));
+    sim.take_replies();
+    sim.client_send("n0", read());
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    assert_eq!(
+        sim.take_replies().last().unwrap().body.payload,
+        Payload::ReadOk {
+            value: ReadValue::Value { value: json!(17) }
+        }
+    );
+}
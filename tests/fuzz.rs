@@ -0,0 +1,65 @@
+//! Random input through the fuzzing entry point, as a quick stand-in for a
+//! `cargo fuzz` run. A panic fails the test, naming the seed and the input
+//! it came on.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::panic::{self, AssertUnwindSafe};
+use whirlpool::{
+    fuzz, payload::Payload, testing::arbitrary::Arbitrary, BroadcastNode, CounterNode, EchoNode,
+    KafkaNode, KvNode, Message, TxnNode,
+};
+
+const CASES: usize = 200;
+
+/// The seed the cases come from, unless `WHIRLPOOL_FUZZ_SEED` gives
+/// another.
+const SEED: u64 = 0x5eed;
+
+/// A few arbitrary messages, one per line, with the odd corrupted byte.
+fn input(rng: &mut StdRng) -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..rng.gen_range(1..8) {
+        let mut line = serde_json::to_vec(&Message::<Payload>::arbitrary(rng)).unwrap();
+        if rng.gen_bool(0.1) {
+            let i = rng.gen_range(0..line.len());
+            line[i] = rng.gen();
+        }
+        data.extend(line);
+        data.push(b'\n');
+    }
+    data
+}
+
+#[test]
+fn nodes_survive_arbitrary_messages() {
+    let seed = match std::env::var("WHIRLPOOL_FUZZ_SEED") {
+        Ok(seed) => seed.parse().expect("WHIRLPOOL_FUZZ_SEED is a u64"),
+        Err(_) => SEED,
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..CASES {
+        let data = input(&mut rng);
+        let survived = panic::catch_unwind(AssertUnwindSafe(|| {
+            fuzz::step(|_| EchoNode::default(), &data);
+            fuzz::step(|_| BroadcastNode::default(), &data);
+            fuzz::step(|_| CounterNode::default(), &data);
+            fuzz::step(|_| KafkaNode::default(), &data);
+            fuzz::step(|_| TxnNode::default(), &data);
+            fuzz::step(|_| KvNode::default(), &data);
+        }));
+        if survived.is_err() {
+            panic!("seed {seed} fails on:\n{}", String::from_utf8_lossy(&data));
+        }
+    }
+}
+
+#[test]
+fn counter_survives_overflow() {
+    let data =
+        br#"{"src":"c1","dest":"n0","body":{"type":"add","msg_id":1,"delta":9223372036854775807}}
+{"src":"c1","dest":"n0","body":{"type":"add","msg_id":2,"delta":9223372036854775807}}
+{"src":"c1","dest":"n0","body":{"type":"add","msg_id":3,"delta":-9223372036854775808}}
+{"src":"c1","dest":"n0","body":{"type":"read","msg_id":4}}
+"#;
+    fuzz::step(|_| CounterNode::default(), data);
+}
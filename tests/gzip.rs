@@ -0,0 +1,71 @@
+//! gzip reads back what it wrote and what other gzip writers write, takes
+//! repeats down in size, and rejects what it can't read.
+
+use whirlpool::{gzip, ErrorCode};
+
+#[test]
+fn data_round_trips() {
+    let json = serde_json::to_vec(&(0..5000).collect::<Vec<u64>>()).unwrap();
+    let noise: Vec<u8> = (0..70_000u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let cases = [Vec::new(), b"a".to_vec(), vec![7; 100_000], json, noise];
+    for data in cases {
+        let compressed = gzip::compress(&data);
+        assert_eq!(&compressed[..2], [0x1f, 0x8b]);
+        assert_eq!(gzip::decompress(&compressed).unwrap(), data);
+    }
+}
+
+#[test]
+fn repeats_compress_well_and_noise_barely_grows() {
+    let json = serde_json::to_vec(&(1000..2000).collect::<Vec<u64>>()).unwrap();
+    let compressed = gzip::compress(&json);
+    assert!(
+        compressed.len() * 2 < json.len(),
+        "{} of {}",
+        compressed.len(),
+        json.len()
+    );
+
+    let noise: Vec<u8> = (0..10_000u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    assert!(gzip::compress(&noise).len() < noise.len() + 40);
+}
+
+#[test]
+fn reads_dynamic_blocks_from_other_writers() {
+    // `gzip.compress(data, 9, mtime=0)` in Python, a dynamic Huffman block.
+    let sample = "1f8b0800000000000203ed8ec90d80300c045bd90a68806a389c602031b938523d163df0\
+                  40e2b99a5969f244088587057d94c3c3c889b9b82d41768ac88ad7ae5e18c5b6cf7a47de\
+                  3af5dc855ea583f304c33b29aae4b1722812f56b53f362c2dffbc5de1b691a27b8c0020000";
+    let bytes: Vec<u8> = (0..sample.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&sample[i..i + 2], 16).unwrap())
+        .collect();
+    let line = b"the quick brown fox jumps over the lazy dog; ".repeat(3);
+    let expected = [line, b"pack my box with five dozen liquor jugs. ".to_vec()]
+        .concat()
+        .repeat(4);
+    assert_eq!(gzip::decompress(&bytes).unwrap(), expected);
+}
+
+#[test]
+fn malformed_data_is_rejected() {
+    let good = gzip::compress(b"hello, hello, hello");
+    let mut bad_crc = good.clone();
+    let at = bad_crc.len() - 8;
+    bad_crc[at] ^= 1;
+    let cases = [
+        Vec::new(),
+        b"not gzip at all".to_vec(),
+        good[..good.len() - 3].to_vec(),
+        good[..12].to_vec(),
+        bad_crc,
+    ];
+    for bytes in cases {
+        let err = gzip::decompress(&bytes).unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedRequest, "{bytes:?}");
+    }
+}
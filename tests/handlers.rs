@@ -0,0 +1,219 @@
+use serde_json::json;
+use std::io::Write;
+use whirlpool::{
+    ids::IdScheme,
+    payload::{Payload, ReadValue},
+    testing::Client,
+    transport,
+    txn::{Op, OpKind},
+    BroadcastNode, EchoNode, ErrorCode, KvNode, Message, MsgIdAllocator, Node, RpcError, TxnNode,
+};
+
+#[test]
+fn echo_replies_with_the_same_text() {
+    let mut client = Client::start(EchoNode::default(), "n1", &["n1"]).unwrap();
+    let reply = client
+        .request(Payload::Echo {
+            echo: "hello".into(),
+        })
+        .unwrap();
+    assert!(matches!(reply.body.payload, Payload::EchoOk { echo } if echo == "hello"));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn replies_come_from_the_node_id_from_init() {
+    let mut node = EchoNode::default();
+    let init = Payload::Init {
+        node_id: "n1".into(),
+        node_ids: vec!["n1".into()],
+    };
+    node.handle(Message::new("c0", "n1", Some(0), init), &mut Vec::new())
+        .unwrap();
+    // Whatever the message was addressed to, the node answers as itself.
+    let echo = Payload::Echo { echo: "hi".into() };
+    let mut out = Vec::new();
+    node.handle(Message::new("c1", "n9", Some(1), echo), &mut out)
+        .unwrap();
+    let replies: Vec<Message> = transport::parse_lines(&out).unwrap();
+    assert_eq!(replies[0].src, "n1");
+    assert_eq!(replies[0].dest, "c1");
+}
+
+#[test]
+fn generate_returns_distinct_ids() {
+    let mut client = Client::start(EchoNode::default(), "n1", &["n1"]).unwrap();
+    let mut ids = std::collections::HashSet::new();
+    for _ in 0..10 {
+        match client.request(Payload::Generate).unwrap().body.payload {
+            Payload::GenerateOk { id } => assert!(ids.insert(id.to_string())),
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn snowflake_ids_are_increasing_numbers() {
+    let node = EchoNode::new(IdScheme::Snowflake);
+    let mut client = Client::start(node, "n2", &["n1", "n2"]).unwrap();
+    let mut last = 0;
+    for _ in 0..10 {
+        match client.request(Payload::Generate).unwrap().body.payload {
+            Payload::GenerateOk { id } => {
+                let id = id.as_u64().expect("snowflake ids are numbers");
+                assert!(id > last);
+                // The node index sits above the 12 sequence bits.
+                assert_eq!(id >> 12 & 0x3ff, 1);
+                last = id;
+            }
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn every_id_scheme_is_unique_per_node() {
+    let schemes = [
+        IdScheme::Uuid,
+        IdScheme::UuidV7,
+        IdScheme::Ulid,
+        IdScheme::Counter,
+        IdScheme::Snowflake,
+    ];
+    for scheme in schemes {
+        let mut ids = std::collections::HashSet::new();
+        for node in ["n1", "n2"] {
+            let mut client = Client::start(EchoNode::new(scheme), node, &["n1", "n2"]).unwrap();
+            for _ in 0..100 {
+                match client.request(Payload::Generate).unwrap().body.payload {
+                    Payload::GenerateOk { id } => {
+                        assert!(ids.insert(id.to_string()), "{scheme:?} repeated {id}")
+                    }
+                    other => panic!("unexpected reply {other:?}"),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn broadcast_read_returns_broadcast_values() {
+    let mut client = Client::start(BroadcastNode::default(), "n1", &["n1"]).unwrap();
+    for message in [3, 1, 2] {
+        let reply = client.request(Payload::Broadcast { message }).unwrap();
+        assert!(matches!(reply.body.payload, Payload::BroadcastOk));
+    }
+    match client
+        .request(Payload::Read {
+            key: None,
+            consistency: None,
+        })
+        .unwrap()
+        .body
+        .payload
+    {
+        Payload::ReadOk {
+            value: ReadValue::Messages { mut messages },
+        } => {
+            messages.sort();
+            assert_eq!(messages, vec![1, 2, 3]);
+        }
+        other => panic!("unexpected reply {other:?}"),
+    }
+}
+
+#[test]
+fn kv_cas_fails_on_a_stale_value() {
+    let mut client = Client::start(KvNode::default(), "n1", &["n1"]).unwrap();
+    client
+        .request(Payload::Write {
+            key: json!(1),
+            value: json!(10),
+            consistency: None,
+        })
+        .unwrap();
+    let reply = client
+        .request(Payload::Cas {
+            key: json!(1),
+            from: json!(11),
+            to: json!(12),
+            create_if_not_exists: false,
+        })
+        .unwrap();
+    assert!(matches!(
+        reply.body.payload,
+        Payload::Error {
+            code: ErrorCode::PreconditionFailed,
+            ..
+        }
+    ));
+}
+
+/// Turns down everything but `init`.
+#[derive(Default)]
+struct Unavailable {
+    msg_ids: MsgIdAllocator,
+}
+
+impl Node for Unavailable {
+    fn handle(&mut self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        if let Payload::Init { node_id, .. } = &msg.body.payload {
+            let msg_id = self.msg_ids.next();
+            return msg
+                .into_reply(node_id, Some(msg_id), Payload::InitOk)
+                .send(out);
+        }
+        Err(RpcError::new(ErrorCode::TemporarilyUnavailable, "not today").into())
+    }
+
+    fn msg_ids(&self) -> Option<MsgIdAllocator> {
+        Some(self.msg_ids.clone())
+    }
+}
+
+#[test]
+fn error_replies_are_sent_like_any_other_reply() {
+    let mut client = Client::start(Unavailable::default(), "n1", &["n1"]).unwrap();
+    for msg_id in 1..3 {
+        let reply = client.request(Payload::Generate).unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Error {
+                code: ErrorCode::TemporarilyUnavailable,
+                ..
+            }
+        ));
+        assert_eq!(reply.src, "n1");
+        // After the one init_ok took, from the same allocator.
+        assert_eq!(reply.body.id, Some(msg_id));
+    }
+}
+
+#[test]
+fn txn_reads_its_own_writes() {
+    let mut client = Client::start(TxnNode::default(), "n1", &["n1"]).unwrap();
+    let txn = vec![Op(OpKind::Write, 1, Some(5)), Op(OpKind::Read, 1, None)];
+    match client.request(Payload::Txn { txn }).unwrap().body.payload {
+        Payload::TxnOk { txn } => assert_eq!(txn[1], Op(OpKind::Read, 1, Some(5))),
+        other => panic!("unexpected reply {other:?}"),
+    }
+}
+
+#[test]
+fn unknown_messages_get_not_supported() {
+    let mut client = Client::start(EchoNode::default(), "n1", &["n1"]).unwrap();
+    let reply = client.request(Payload::Generate).unwrap();
+    assert!(matches!(reply.body.payload, Payload::GenerateOk { .. }));
+    let reply = client
+        .request(Payload::Topology {
+            topology: Default::default(),
+        })
+        .unwrap();
+    assert!(matches!(
+        reply.body.payload,
+        Payload::Error {
+            code: ErrorCode::NotSupported,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,195 @@
+//! Protocol versions and capabilities exchanged after `init`, and the
+//! baseline spoken with peers that never say theirs.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use whirlpool::{
+    compress::Encoding,
+    handshake::{Capability, Handshake, PROTOCOL_VERSION},
+    middleware::{Layered, Next},
+    payload::Payload,
+    sim::Sim,
+    transport, BroadcastNode, Membership, Message, TopologyStrategy,
+};
+
+fn handshake<'a>(sim: &'a Sim<BroadcastNode>, node: &str) -> &'a Handshake {
+    sim.node(node).unwrap().handshake().unwrap()
+}
+
+#[test]
+fn peers_learn_what_each_other_can_do() {
+    let make = |id: &str| {
+        let node = BroadcastNode::default()
+            .with_topology(TopologyStrategy::FullMesh)
+            .with_handshake(Handshake::default());
+        match id {
+            "n2" => node,
+            _ => node.with_compression(16),
+        }
+    };
+    let mut sim = Sim::with_seed(5, make, 3).unwrap();
+    sim.run_for(Duration::from_millis(300)).unwrap();
+
+    let n0 = handshake(&sim, "n0");
+    assert!(n0.is_settled());
+    assert_eq!(n0.version("n1"), Some(PROTOCOL_VERSION));
+    assert_eq!(n0.version("n2"), Some(PROTOCOL_VERSION));
+    assert!(n0.supports("n1", Capability::PackedValues));
+    assert!(!n0.supports("n2", Capability::PackedValues));
+    assert!(!handshake(&sim, "n2").supports("n0", Capability::PackedValues));
+    assert_eq!(n0.common(), Vec::new());
+}
+
+#[test]
+fn peers_without_handshakes_get_the_baseline() {
+    let make = |id: &str| {
+        let node = BroadcastNode::default().with_topology(TopologyStrategy::FullMesh);
+        match id {
+            "n2" => node,
+            _ => node
+                .with_compression(16)
+                .with_handshake(Handshake::default()),
+        }
+    };
+    let mut sim = Sim::with_seed(5, make, 3).unwrap();
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert!(!handshake(&sim, "n0").is_settled());
+
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let n0 = handshake(&sim, "n0");
+    assert!(n0.is_settled());
+    assert_eq!(n0.version("n2"), Some(0));
+    assert!(n0.supports("n1", Capability::PackedValues));
+    assert!(!n0.supports("n2", Capability::PackedValues));
+
+    sim.client_send("n0", Payload::Broadcast { message: 7 });
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    assert!(sim.node("n2").unwrap().seen.contains(&7));
+}
+
+#[test]
+fn capabilities_from_later_versions_are_never_used() {
+    let json = r#"{"src":"n2","dest":"n1","body":{"type":"hello","version":9,"capabilities":["packed-values","time-travel"]}}"#;
+    let hello: Message = serde_json::from_str(json).unwrap();
+    assert_eq!(
+        hello.body.payload,
+        Payload::Hello {
+            version: 9,
+            capabilities: vec![Capability::PackedValues, Capability::Unknown],
+        }
+    );
+
+    let mut membership = Membership::default();
+    membership.init("n1", &["n1".into(), "n2".into()]);
+    let mut handshake = Handshake::new([Capability::PackedValues, Capability::Unknown]);
+    let mut out = Vec::new();
+    handshake.init(&membership, &mut out).unwrap();
+    assert!(handshake.handle(&hello, &mut out).unwrap());
+
+    let peer = handshake.peer("n2").unwrap();
+    assert_eq!(peer.version, 9);
+    assert_eq!(peer.capabilities, vec![Capability::PackedValues]);
+    assert!(!handshake.supports("n2", Capability::Unknown));
+    assert_eq!(handshake.common(), vec![Capability::PackedValues]);
+
+    let sent = transport::parse_lines::<Payload>(&out).unwrap();
+    assert!(matches!(&sent[0].body.payload, Payload::Hello { .. }));
+    assert!(
+        matches!(&sent[1].body.payload, Payload::HelloOk { version, .. } if *version == PROTOCOL_VERSION)
+    );
+}
+
+#[test]
+fn gossip_goes_as_msgpack_between_peers_that_read_it() {
+    let received = Arc::new(Mutex::new(HashMap::<_, Vec<Encoding>>::new()));
+    let make = |id: &str| {
+        let capabilities = (id != "n2").then_some(Capability::MsgPack);
+        let node = BroadcastNode::default()
+            .with_topology(TopologyStrategy::FullMesh)
+            .with_compression(16)
+            .with_handshake(Handshake::new(capabilities));
+        let received = received.clone();
+        Layered::new(
+            node,
+            move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+                if let Payload::Gossip {
+                    packed: Some(packed),
+                    ..
+                } = &msg.body.payload
+                {
+                    let mut received = received.lock().unwrap();
+                    received
+                        .entry((msg.src.clone(), msg.dest.clone()))
+                        .or_default()
+                        .push(packed.encoding);
+                }
+                next.run(msg, out)
+            },
+        )
+    };
+    let mut sim = Sim::with_seed(5, make, 3).unwrap();
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    for message in 0..30 {
+        sim.client_send("n0", Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+
+    for node in sim.nodes() {
+        assert_eq!(node.inner().seen.len(), 30);
+    }
+    let received = received.lock().unwrap();
+    let from_n0 = |dest: &str| &received[&("n0".to_string(), dest.to_string())];
+    assert!(from_n0("n1").iter().all(|e| *e == Encoding::MsgPack));
+    assert!(from_n0("n2").iter().all(|e| *e == Encoding::DeltaVarint));
+}
+
+#[test]
+fn gossip_goes_gzipped_to_peers_that_read_it() {
+    let received = Arc::new(Mutex::new(HashMap::<_, Vec<Encoding>>::new()));
+    let make = |id: &str| {
+        let capabilities = match id {
+            "n2" => vec![Capability::MsgPack],
+            _ => vec![Capability::MsgPack, Capability::Gzip],
+        };
+        let node = BroadcastNode::default()
+            .with_topology(TopologyStrategy::FullMesh)
+            .with_compression(16)
+            .with_handshake(Handshake::new(capabilities));
+        let received = received.clone();
+        Layered::new(
+            node,
+            move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+                if let Payload::Gossip {
+                    packed: Some(packed),
+                    ..
+                } = &msg.body.payload
+                {
+                    let mut received = received.lock().unwrap();
+                    received
+                        .entry((msg.src.clone(), msg.dest.clone()))
+                        .or_default()
+                        .push(packed.encoding);
+                }
+                next.run(msg, out)
+            },
+        )
+    };
+    let mut sim = Sim::with_seed(5, make, 3).unwrap();
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    for message in 0..30 {
+        sim.client_send("n0", Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+
+    for node in sim.nodes() {
+        assert_eq!(node.inner().seen.len(), 30);
+    }
+    let received = received.lock().unwrap();
+    let from_n0 = |dest: &str| &received[&("n0".to_string(), dest.to_string())];
+    assert!(from_n0("n1").iter().all(|e| *e == Encoding::Gzip));
+    assert!(from_n0("n2").iter().all(|e| *e == Encoding::MsgPack));
+}
@@ -0,0 +1,69 @@
+//! Heartbeats, and retries that hold off for peers that went quiet.
+
+use std::time::Duration;
+use whirlpool::{
+    heartbeat::Heartbeats, payload::Payload, sim::Sim, time, Backoff, BroadcastNode, Message,
+    RetryQueue, TopologyStrategy,
+};
+
+fn heartbeats<'a>(sim: &'a Sim<BroadcastNode>, node: &str) -> &'a Heartbeats {
+    sim.node(node).unwrap().heartbeats().unwrap()
+}
+
+#[test]
+fn quiet_peers_are_reported_down_until_heard_from() {
+    let make = |_: &str| {
+        BroadcastNode::default()
+            .with_topology(TopologyStrategy::FullMesh)
+            .with_heartbeats(Heartbeats::new(Duration::from_millis(100)))
+    };
+    let mut sim = Sim::with_seed(3, make, 4).unwrap();
+    sim.partition(
+        vec![vec!["n2".into()]],
+        Duration::from_millis(300),
+        Duration::from_millis(1500),
+    );
+    sim.run_for(Duration::from_millis(1200)).unwrap();
+    assert!(heartbeats(&sim, "n0").is_alive("n1"));
+    assert!(!heartbeats(&sim, "n0").is_alive("n2"));
+    assert!(!heartbeats(&sim, "n2").is_alive("n1"));
+    let last_seen = heartbeats(&sim, "n0").last_seen("n2").unwrap();
+    let silence = time::now().duration_since(last_seen);
+    assert!(silence >= Duration::from_millis(500), "{silence:?}");
+    assert_eq!(heartbeats(&sim, "n0").last_seen("c1"), None);
+
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    for node in ["n0", "n1", "n2"] {
+        for peer in ["n0", "n1", "n2"] {
+            assert_eq!(heartbeats(&sim, node).is_alive(peer), node != peer);
+        }
+    }
+}
+
+#[test]
+fn retries_hold_off_for_peers_that_are_down() {
+    let backoff = Backoff {
+        initial: Duration::ZERO,
+        max: Duration::ZERO,
+        jitter: 0.0,
+    };
+    let mut retries = RetryQueue::new(backoff);
+    let mut out = Vec::new();
+    let msg = Message::new("n1", "n2", Some(1), Payload::Broadcast { message: 1 });
+    retries.send(msg, &mut out).unwrap();
+
+    assert_eq!(
+        retries
+            .resend_due_unless(&mut out, |peer| peer == "n2")
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        retries
+            .resend_due_unless(&mut out, |peer| peer == "n3")
+            .unwrap(),
+        1
+    );
+    assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+    assert_eq!(retries.len(), 1);
+}
@@ -0,0 +1,112 @@
+//! Logs kept in `lin-kv` hand out offsets without gaps, even when an append
+//! fails half-way.
+#![cfg(feature = "kafka")]
+
+use std::io::{self, Write};
+use whirlpool::{
+    kafka::{LinKvLogs, Offsets},
+    kv::KvStore,
+    payload::Payload,
+    services::KvClient,
+    transport, Rpc, RpcError,
+};
+
+/// `lin-kv`, answering each request as it is written, and crashing on the
+/// first `failures` writes of records.
+struct FakeLinKv {
+    rpc: Rpc,
+    store: KvStore,
+    failures: usize,
+    buf: Vec<u8>,
+}
+
+impl FakeLinKv {
+    fn answer(&mut self, payload: &Payload) -> Result<Payload, RpcError> {
+        if let Payload::Cas { key, .. } = payload {
+            if key.as_str().unwrap().starts_with("entry/") && self.failures > 0 {
+                self.failures -= 1;
+                return Err(RpcError::crash("lost the write"));
+            }
+        }
+        self.store.apply(payload)
+    }
+}
+
+impl Write for FakeLinKv {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') else {
+            return Ok(buf.len());
+        };
+        let lines: Vec<u8> = self.buf.drain(..=end).collect();
+        for request in transport::parse_lines::<Payload>(&lines).unwrap() {
+            let reply = match self.answer(&request.body.payload) {
+                Ok(payload) => request.into_reply("lin-kv", None, payload),
+                Err(err) => request.into_error("lin-kv", None, err),
+            };
+            self.rpc.resolve(reply);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Logs on a fresh node, with nothing cached, and `lin-kv` for them.
+fn logs(rpc: &Rpc) -> LinKvLogs {
+    LinKvLogs::new(KvClient::new("lin-kv", "n1", rpc.clone()))
+}
+
+fn lin_kv(rpc: &Rpc, failures: usize) -> FakeLinKv {
+    FakeLinKv {
+        rpc: rpc.clone(),
+        store: KvStore::default(),
+        failures,
+        buf: Vec::new(),
+    }
+}
+
+#[test]
+fn a_failed_append_leaves_no_gap() {
+    let rpc = Rpc::default();
+    let mut lin_kv = lin_kv(&rpc, 1);
+    let mut appender = logs(&rpc);
+
+    assert!(appender.append("k", 7, &mut lin_kv).is_err());
+    assert_eq!(appender.append("k", 8, &mut lin_kv).unwrap(), 0);
+    assert_eq!(appender.append("k", 9, &mut lin_kv).unwrap(), 1);
+
+    // Another node sees both records, from the start.
+    let offsets = Offsets::from([("k".to_string(), 0)]);
+    let records = logs(&rpc).poll(&offsets, &mut lin_kv).unwrap();
+    assert_eq!(records["k"], vec![(0, 8), (1, 9)]);
+}
+
+#[test]
+fn appends_from_a_stale_hint_take_the_next_free_offset() {
+    let rpc = Rpc::default();
+    let mut lin_kv = lin_kv(&rpc, 0);
+    // Another node appended twice without getting to raise the hint.
+    for (offset, msg) in [(0, 1), (1, 2)] {
+        let entry = format!("entry/k/{offset}");
+        lin_kv.store.write(&entry.into(), msg.into());
+    }
+
+    assert_eq!(logs(&rpc).append("k", 3, &mut lin_kv).unwrap(), 2);
+    let offsets = Offsets::from([("k".to_string(), 1)]);
+    let records = logs(&rpc).poll(&offsets, &mut lin_kv).unwrap();
+    assert_eq!(records["k"], vec![(1, 2), (2, 3)]);
+}
+
+#[test]
+fn polling_from_the_last_offset_finds_nothing() {
+    let rpc = Rpc::default();
+    let mut lin_kv = lin_kv(&rpc, 0);
+    logs(&rpc).append("k", 1, &mut lin_kv).unwrap();
+
+    let offsets = Offsets::from([("k".to_string(), usize::MAX)]);
+    let records = logs(&rpc).poll(&offsets, &mut lin_kv).unwrap();
+    assert!(records.is_empty());
+}
@@ -0,0 +1,96 @@
+//! Versioned writes to `lww-kv` never replace a newer version, even when
+//! another writer gets in between the read and the write.
+
+use std::io::{self, Write};
+use whirlpool::{
+    kv::KvStore,
+    payload::Payload,
+    services::{LwwKv, Versioned},
+    transport, Rpc,
+};
+
+/// `lww-kv`, answering each request as it is written, and letting `racer`
+/// write just before the first CAS it sees.
+struct FakeLwwKv {
+    rpc: Rpc,
+    store: KvStore,
+    racer: Option<Versioned<String>>,
+    buf: Vec<u8>,
+}
+
+impl Write for FakeLwwKv {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') else {
+            return Ok(buf.len());
+        };
+        let lines: Vec<u8> = self.buf.drain(..=end).collect();
+        for request in transport::parse_lines::<Payload>(&lines).unwrap() {
+            if let (Payload::Cas { key, .. }, Some(racer)) = (&request.body.payload, &self.racer) {
+                let racer = serde_json::to_value(racer).unwrap();
+                self.store.write(key, racer);
+                self.racer = None;
+            }
+            let reply = match self.store.apply(&request.body.payload) {
+                Ok(payload) => request.into_reply("lww-kv", None, payload),
+                Err(err) => request.into_error("lww-kv", None, err),
+            };
+            self.rpc.resolve(reply);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lww_kv(rpc: &Rpc, racer: Option<Versioned<String>>) -> FakeLwwKv {
+    FakeLwwKv {
+        rpc: rpc.clone(),
+        store: KvStore::default(),
+        racer,
+        buf: Vec::new(),
+    }
+}
+
+fn versioned(version: u64, value: &str) -> Versioned<String> {
+    Versioned {
+        version,
+        value: value.to_string(),
+    }
+}
+
+#[test]
+fn only_newer_versions_are_written() {
+    let rpc = Rpc::default();
+    let mut service = lww_kv(&rpc, None);
+    let kv = LwwKv::new("n1", rpc.clone());
+
+    assert!(kv
+        .write_if_newer("k", &versioned(2, "b"), &mut service)
+        .unwrap());
+    assert!(!kv
+        .write_if_newer("k", &versioned(1, "a"), &mut service)
+        .unwrap());
+    assert!(kv
+        .write_if_newer("k", &versioned(3, "c"), &mut service)
+        .unwrap());
+    let stored: Option<Versioned<String>> = kv.read("k", &mut service).unwrap();
+    assert_eq!(stored.unwrap().value, "c");
+}
+
+#[test]
+fn a_newer_version_written_in_between_is_kept() {
+    let rpc = Rpc::default();
+    let mut service = lww_kv(&rpc, Some(versioned(5, "theirs")));
+    let kv = LwwKv::new("n1", rpc.clone());
+
+    assert!(!kv
+        .write_if_newer("k", &versioned(4, "ours"), &mut service)
+        .unwrap());
+    let stored: Option<Versioned<String>> = kv.read("k", &mut service).unwrap();
+    assert_eq!(stored.unwrap().value, "theirs");
+    let cached: Option<Versioned<String>> = kv.read_cached("k").unwrap();
+    assert_eq!(cached.unwrap().version, 5);
+}
@@ -0,0 +1,83 @@
+//! Broadcast nodes joining and leaving after `init`.
+
+use std::{collections::HashMap, time::Duration};
+use whirlpool::{payload::Payload, sim::Sim, BroadcastMode, BroadcastNode, TopologyStrategy};
+
+fn node_ids(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+}
+
+#[test]
+fn joining_node_gets_the_values_seen_so_far_and_new_ones() {
+    let make = || BroadcastNode::new(BroadcastMode::Gossip).with_topology(TopologyStrategy::Ring);
+    let mut sim = Sim::with_seed(3, |_| make(), 11).unwrap();
+    sim.network().drop_rate = 0.1;
+    for message in 0..5 {
+        sim.client_send(&format!("n{}", message % 3), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+
+    sim.add_node("n3", make(), node_ids(&["n0", "n1", "n2"]))
+        .unwrap();
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+    let n3 = sim.node("n3").unwrap();
+    assert_eq!(n3.seen.len(), 5, "n3 saw {:?}", n3.seen);
+    for node in sim.nodes() {
+        assert_eq!(node.membership.node_ids.len(), 4);
+    }
+    // The ring is n0 - n1 - n2 - n3 now.
+    assert_eq!(sim.node("n0").unwrap().neighbors(), ["n1", "n3"]);
+
+    sim.client_send("n3", Payload::Broadcast { message: 5 });
+    sim.client_send("n1", Payload::Broadcast { message: 6 });
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            7,
+            "{} saw {:?}",
+            node.membership.node_id,
+            node.seen
+        );
+    }
+}
+
+#[test]
+fn leaving_node_is_bridged_over_in_the_provided_topology() {
+    let mut sim = Sim::with_seed(4, |_| BroadcastNode::new(BroadcastMode::Gossip), 12).unwrap();
+    // A line: n0 - n1 - n2 - n3.
+    let topology: HashMap<_, _> = [
+        ("n0", vec!["n1"]),
+        ("n1", vec!["n0", "n2"]),
+        ("n2", vec!["n1", "n3"]),
+        ("n3", vec!["n2"]),
+    ]
+    .into_iter()
+    .map(|(node, neighbors)| (node.to_string(), node_ids(&neighbors)))
+    .collect();
+    for node in ["n0", "n1", "n2", "n3"] {
+        sim.client_send(
+            node,
+            Payload::Topology {
+                topology: topology.clone(),
+            },
+        );
+    }
+    sim.client_send("n1", Payload::Leave { node: "n1".into() });
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert!(sim
+        .take_replies()
+        .iter()
+        .any(|reply| reply.body.payload == Payload::LeaveOk));
+    for node in ["n0", "n2", "n3"] {
+        let node = sim.node(node).unwrap();
+        assert!(!node.membership.is_peer("n1"));
+        assert!(!node.neighbors().contains(&"n1".to_string()));
+    }
+    assert_eq!(sim.node("n0").unwrap().neighbors(), ["n2"]);
+
+    sim.client_send("n0", Payload::Broadcast { message: 1 });
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    assert!(sim.node("n3").unwrap().seen.contains(&1));
+    assert!(sim.node("n1").unwrap().seen.is_empty());
+}
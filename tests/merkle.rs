@@ -0,0 +1,45 @@
+//! Merkle trees agree on the same entries and lead to the leaves that
+//! differ.
+
+use whirlpool::MerkleTree;
+
+fn tree(entries: &[(&str, u64)]) -> MerkleTree {
+    let mut tree = MerkleTree::new(4);
+    for (key, value) in entries {
+        tree.insert(*key, value);
+    }
+    tree
+}
+
+#[test]
+fn trees_of_the_same_entries_agree_whatever_the_order() {
+    let entries = [("a", 1), ("b", 2), ("c", 3)];
+    let mut reversed = entries;
+    reversed.reverse();
+    assert_eq!(tree(&entries).root(), tree(&reversed).root());
+    assert_ne!(tree(&entries).root(), tree(&entries[..2]).root());
+    assert_ne!(
+        tree(&entries).root(),
+        tree(&[("a", 1), ("b", 2), ("c", 4)]).root()
+    );
+    assert_ne!(tree(&[]).root(), tree(&entries).root());
+}
+
+#[test]
+fn walking_down_differing_nodes_ends_at_the_changed_key() {
+    let ours = tree(&[("a", 1), ("b", 2), ("c", 3)]);
+    let theirs = tree(&[("a", 1), ("b", 5), ("c", 3)]);
+    let mut pending = vec![0];
+    let mut leaves = Vec::new();
+    while let Some(node) = pending.pop() {
+        if ours.diff(&[(node, theirs.get(node).unwrap())]).is_empty() {
+            continue;
+        }
+        match ours.children(node) {
+            Some(children) => pending.extend(children),
+            None => leaves.push(node),
+        }
+    }
+    assert_eq!(leaves, vec![ours.leaf_of("b")]);
+    assert!(ours.is_leaf(leaves[0]));
+}
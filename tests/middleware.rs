@@ -0,0 +1,63 @@
+//! Middleware runs around a node's handler, outermost layer first, and can
+//! answer a message without passing it on.
+
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+use whirlpool::{
+    middleware::{Layered, Next},
+    payload::Payload,
+    testing::Client,
+    EchoNode, ErrorCode, Message, RpcError,
+};
+
+#[test]
+fn layers_run_outermost_first_and_may_stop_a_message() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&seen);
+    let logged = move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+        log.lock().unwrap().push(format!("before {}", msg.src));
+        let result = next.run(msg, out);
+        log.lock()
+            .unwrap()
+            .push(format!("after {}", result.is_ok()));
+        result
+    };
+    let guard = Arc::clone(&seen);
+    let guarded = move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+        if let Payload::Echo { echo } = &msg.body.payload {
+            guard.lock().unwrap().push(format!("checking {echo}"));
+            if echo == "forbidden" {
+                return Err(RpcError::new(ErrorCode::Abort, "not allowed").into());
+            }
+        }
+        next.run(msg, out)
+    };
+    let node = Layered::new(Layered::new(EchoNode::default(), guarded), logged);
+
+    let mut client = Client::start(node, "n1", &["n1"]).unwrap();
+    seen.lock().unwrap().clear();
+    let echo = |text: &str| Payload::Echo { echo: text.into() };
+    let reply = client.request(echo("hello")).unwrap();
+    assert!(matches!(reply.body.payload, Payload::EchoOk { echo } if echo == "hello"));
+    let reply = client.request(echo("forbidden")).unwrap();
+    assert!(matches!(
+        reply.body.payload,
+        Payload::Error {
+            code: ErrorCode::Abort,
+            ..
+        }
+    ));
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            "before c1",
+            "checking hello",
+            "after true",
+            "before c1",
+            "checking forbidden",
+            "after false",
+        ]
+    );
+}
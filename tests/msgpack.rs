@@ -0,0 +1,61 @@
+//! MessagePack reads back what was written, in the bytes the format lays
+//! down, and rejects what it can't read.
+
+use serde_json::{json, Value};
+use whirlpool::{msgpack, ErrorCode};
+
+#[test]
+fn values_round_trip() {
+    let long = "x".repeat(300);
+    let many: Vec<u64> = (0..70_000).collect();
+    let value = json!({
+        "null": null,
+        "bools": [true, false],
+        "ints": [0, 127, 128, 255, 256, 65_535, 65_536, u32::MAX, u64::MAX],
+        "negative": [-1, -32, -33, -128, -129, -32_768, -32_769, i64::MIN],
+        "floats": [0.5, -1e300],
+        "strings": ["", "héllo", long],
+        "many": many,
+        "nested": {"a": [{"b": {}}, []]},
+    });
+    assert_eq!(msgpack::decode(&msgpack::encode(&value)).unwrap(), value);
+}
+
+#[test]
+fn encodes_in_the_smallest_form() {
+    let cases = [
+        (json!(5), vec![0x05]),
+        (json!(-3), vec![0xfd]),
+        (json!(200), vec![0xcc, 200]),
+        (json!(-100), vec![0xd0, 0x9c]),
+        (json!(1000), vec![0xcd, 0x03, 0xe8]),
+        (json!("ab"), vec![0xa2, b'a', b'b']),
+        (json!([1, null]), vec![0x92, 0x01, 0xc0]),
+        (json!({"a": true}), vec![0x81, 0xa1, b'a', 0xc3]),
+    ];
+    for (value, bytes) in cases {
+        assert_eq!(msgpack::encode(&value), bytes, "{value}");
+    }
+    let long = Value::String("x".repeat(40));
+    assert_eq!(msgpack::encode(&long)[..2], [0xd9, 40]);
+}
+
+#[test]
+fn malformed_data_is_rejected() {
+    let cases: [&[u8]; 6] = [
+        &[],
+        &[0x92, 0x01],
+        &[0x01, 0x02],
+        &[0x81, 0x01, 0x01],
+        &[0xa1, 0xff],
+        &[0xc4, 0x00],
+    ];
+    for bytes in cases {
+        let err = msgpack::decode(bytes).unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedRequest, "{bytes:?}");
+    }
+    let deep = [vec![0x91; 200], vec![0xc0]].concat();
+    assert!(msgpack::decode(&deep).is_err());
+    // A length far beyond the data is an error, not an allocation.
+    assert!(msgpack::decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+}
@@ -0,0 +1,118 @@
+//! Handler spans are exported over OTLP, and messages carry them on to the
+//! nodes that handle them next, for traces to span the cluster.
+#![cfg(feature = "otel")]
+
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+};
+use whirlpool::{otel::SpanContext, run, transport::parse_lines, Config, EchoNode, Message};
+
+const PARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+/// Takes OTLP/HTTP requests on a port of its own, passing on their bodies.
+fn collector() -> (String, mpsc::Receiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, requests) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let (mut line, mut length) = (String::new(), 0);
+            reader.read_line(&mut line).unwrap();
+            assert!(line.starts_with("POST /v1/traces "), "{line}");
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = tx.send(serde_json::from_slice(&body).unwrap());
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .unwrap();
+        }
+    });
+    (endpoint, requests)
+}
+
+#[test]
+fn spans_are_exported_as_children_of_the_senders() {
+    let (endpoint, requests) = collector();
+    let config = Config {
+        otel_endpoint: Some(endpoint),
+        ..Config::default()
+    };
+    let msg = |body: Value| json!({"src": "c1", "dest": "n1", "body": body}).to_string();
+    let (tx, input) = mpsc::channel();
+    for body in [
+        json!({"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}),
+        json!({"type": "echo", "msg_id": 2, "echo": "hi", "traceparent": PARENT}),
+        json!({"type": "echo", "msg_id": 3, "echo": "hi"}),
+    ] {
+        tx.send(msg(body)).unwrap();
+    }
+    drop(tx);
+    let mut out = Vec::new();
+    run(EchoNode::default(), &config, input, &mut out).unwrap();
+
+    // Replies carry the span they were sent from.
+    let replies: Vec<Message> = parse_lines(&out).unwrap();
+    let sent_from = |msg_id| {
+        let reply = replies
+            .iter()
+            .find(|reply| reply.body.in_reply_to == Some(msg_id))
+            .unwrap();
+        SpanContext::parse(reply.body.traceparent.as_deref().unwrap()).unwrap()
+    };
+    let parent = SpanContext::parse(PARENT).unwrap();
+    let continued = sent_from(2);
+    assert_eq!(continued.trace_id, parent.trace_id);
+    assert_ne!(continued.span_id, parent.span_id);
+    assert_ne!(sent_from(3).trace_id, parent.trace_id);
+
+    // The exporter is flushed before `run` returns.
+    let resources: Vec<Value> = requests
+        .try_iter()
+        .flat_map(|request| request["resourceSpans"].as_array().unwrap().clone())
+        .collect();
+    assert!(!resources.is_empty());
+    let service = &resources[0]["resource"]["attributes"][0];
+    assert_eq!(service["key"], "service.name");
+    assert_eq!(service["value"]["stringValue"], "n1");
+    let spans: Vec<&Value> = resources
+        .iter()
+        .flat_map(|resource| resource["scopeSpans"][0]["spans"].as_array().unwrap())
+        .collect();
+    assert_eq!(spans.len(), 3);
+    let child = spans
+        .iter()
+        .find(|span| span["parentSpanId"] == "b7ad6b7169203331")
+        .unwrap();
+    assert_eq!(child["traceId"], "0af7651916cd43dd8448eb211c80319c");
+    assert_eq!(child["spanId"], format!("{:016x}", continued.span_id));
+    assert_eq!(child["name"], "echo");
+}
+
+#[test]
+fn traceparents_parse_and_print() {
+    let context = SpanContext::parse(PARENT).unwrap();
+    assert_eq!(context.traceparent(), PARENT);
+    for bad in [
+        "",
+        "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+        "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333x-01",
+    ] {
+        assert_eq!(SpanContext::parse(bad), None, "{bad}");
+    }
+}
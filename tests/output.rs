@@ -0,0 +1,67 @@
+//! Replies to clients overtake gossip on the way out.
+
+use std::io::Write;
+use whirlpool::{input::Messages, payload::Payload, run, transport, Config, Message, Node};
+
+/// Gossips every echo to a peer before answering it.
+struct Chatty;
+
+impl Node for Chatty {
+    fn handle(&mut self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let Payload::Echo { echo } = &msg.body.payload else {
+            return Ok(());
+        };
+        let gossip = Payload::Gossip {
+            messages: vec![echo.len()],
+            clock: None,
+            packed: None,
+            origins: Default::default(),
+            accepts: Vec::new(),
+        };
+        Message::new("n0", "n1", None, gossip).send(out)?;
+        let echo = echo.clone();
+        msg.into_reply("n0", None, Payload::EchoOk { echo })
+            .send(out)
+    }
+}
+
+fn sent(config: &Config) -> Vec<String> {
+    let input = (1..=20).map(|i| {
+        let echo = Payload::Echo {
+            echo: "x".repeat(i),
+        };
+        Message::new("c1", "n0", Some(i), echo)
+    });
+    let mut out = Vec::new();
+    run(Chatty, config, Messages::new(input).unwrap(), &mut out).unwrap();
+    transport::parse_lines::<Payload>(&out)
+        .unwrap()
+        .into_iter()
+        .map(|msg| msg.dest)
+        .collect()
+}
+
+#[test]
+fn replies_go_out_before_gossip_sent_with_them() {
+    let dests = sent(&Config::default());
+    assert_eq!(dests.len(), 40);
+    // Every gossip is preceded by at least as many replies.
+    let mut replies = 0;
+    for (i, dest) in dests.iter().enumerate() {
+        match dest.as_str() {
+            "c1" => replies += 1,
+            _ => assert!(replies > i - replies, "gossip {i} went out first"),
+        }
+    }
+
+    let config = Config {
+        reply_priority: false,
+        ..Config::default()
+    };
+    let dests = sent(&config);
+    let alternating: Vec<_> = ["n1", "c1"].iter().cycle().take(40).collect();
+    assert!(dests
+        .iter()
+        .zip(alternating)
+        .all(|(dest, want)| dest == want));
+}
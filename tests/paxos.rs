@@ -0,0 +1,84 @@
+//! Single-decree Paxos on the simulated network.
+
+use serde_json::json;
+use std::time::Duration;
+use whirlpool::{payload::Payload, sim::Sim, PaxosNode};
+
+fn propose(value: u64) -> Payload {
+    Payload::Propose {
+        value: json!(value),
+    }
+}
+
+#[test]
+fn one_proposal_is_chosen_everywhere() {
+    let mut sim = Sim::with_seed(5, |_| PaxosNode::default(), 3).unwrap();
+    sim.client_send("n2", propose(7));
+    sim.run_for(Duration::from_millis(300)).unwrap();
+
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(
+        replies[0].body.payload,
+        Payload::ProposeOk { value: json!(7) }
+    );
+    assert!(sim.nodes().all(|node| node.chosen() == Some(&json!(7))));
+
+    // Later proposals learn the decision instead of overturning it.
+    sim.client_send("n4", propose(8));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(
+        replies[0].body.payload,
+        Payload::ProposeOk { value: json!(7) }
+    );
+}
+
+#[test]
+fn competing_proposers_agree_despite_loss() {
+    for seed in 0..3 {
+        let mut sim = Sim::with_seed(5, |_| PaxosNode::default(), seed).unwrap();
+        sim.network().drop_rate = 0.2;
+        for n in 0..5 {
+            sim.client_send(&format!("n{n}"), propose(n));
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+
+        let replies = sim.take_replies();
+        assert_eq!(replies.len(), 5, "seed {seed}");
+        let Payload::ProposeOk { value } = &replies[0].body.payload else {
+            panic!("unexpected reply {replies:?}");
+        };
+        assert!(replies.iter().all(|reply| reply.body.payload
+            == Payload::ProposeOk {
+                value: value.clone()
+            }));
+        assert!(sim
+            .nodes()
+            .all(|node| node.chosen().is_none_or(|chosen| chosen == value)));
+    }
+}
+
+#[test]
+fn minority_side_of_a_partition_cannot_choose() {
+    let mut sim = Sim::with_seed(5, |_| PaxosNode::default(), 9).unwrap();
+    sim.partition(
+        vec![vec!["n0".into(), "n1".into()]],
+        Duration::ZERO,
+        Duration::from_millis(800),
+    );
+    sim.client_send("n0", propose(1));
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    assert!(sim.take_replies().is_empty());
+    assert!(sim.nodes().all(|node| node.chosen().is_none()));
+
+    sim.client_send("n3", propose(2));
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 2);
+    let chosen = sim.node("n3").unwrap().chosen().cloned().unwrap();
+    assert!(replies.iter().all(|reply| reply.body.payload
+        == Payload::ProposeOk {
+            value: chosen.clone()
+        }));
+}
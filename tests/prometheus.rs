@@ -0,0 +1,62 @@
+//! Metrics come out in the Prometheus text format, written to a file on
+//! shutdown for a textfile collector to pick up.
+
+use serde_json::json;
+use std::{sync::mpsc, time::Duration};
+use whirlpool::{run, Config, EchoNode};
+
+fn msg(msg_id: usize, mut body: serde_json::Value) -> String {
+    body["msg_id"] = json!(msg_id);
+    json!({"src": "c1", "dest": "n1", "body": body}).to_string()
+}
+
+#[test]
+fn metrics_are_written_for_prometheus() {
+    let path = std::env::temp_dir().join(format!("whirlpool-{}.prom", std::process::id()));
+    let config = Config {
+        prometheus_file: Some(path.clone()),
+        prometheus_interval: Duration::from_secs(3600),
+        ..Config::default()
+    };
+    let (tx, input) = mpsc::channel();
+    tx.send(msg(
+        1,
+        json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]}),
+    ))
+    .unwrap();
+    for msg_id in 2..5 {
+        tx.send(msg(msg_id, json!({"type": "echo", "echo": "hi"})))
+            .unwrap();
+    }
+    drop(tx);
+    run(EchoNode::default(), &config, input, std::io::sink()).unwrap();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    for line in [
+        "# TYPE whirlpool_messages_received_total counter",
+        "whirlpool_messages_received_total{type=\"echo\"} 3",
+        "whirlpool_messages_sent_total{type=\"echo_ok\"} 3",
+        "# TYPE whirlpool_handler_seconds histogram",
+        "whirlpool_handler_seconds_bucket{type=\"echo\",le=\"+Inf\"} 3",
+        "whirlpool_handler_seconds_count{type=\"echo\"} 3",
+        "whirlpool_queue_depth{queue=\"events\"} 0",
+    ] {
+        assert!(text.lines().any(|l| l == line), "no {line:?} in\n{text}");
+    }
+
+    // Buckets are cumulative, as Prometheus has them.
+    let buckets: Vec<u64> = text
+        .lines()
+        .filter(|line| line.starts_with("whirlpool_handler_seconds_bucket{type=\"echo\""))
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert!(buckets.len() > 1);
+    assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    let max_events = text
+        .lines()
+        .find_map(|line| line.strip_prefix("whirlpool_queue_depth_max{queue=\"events\"} "))
+        .unwrap();
+    assert!(max_events.parse::<u64>().unwrap() >= 1);
+}
@@ -0,0 +1,80 @@
+//! Messages a node doesn't understand go to its upstream, and the
+//! upstream's replies come back to whoever sent them.
+
+use serde_json::{json, Value};
+use std::sync::mpsc;
+use whirlpool::{proxy::FIRST_MSG_ID, run, Config, EchoNode};
+
+fn msg(src: &str, body: Value) -> String {
+    json!({"src": src, "dest": "n1", "body": body}).to_string()
+}
+
+#[test]
+fn unknown_requests_are_answered_by_the_upstream() {
+    let config = Config {
+        proxy_upstream: Some("n9".into()),
+        ..Config::default()
+    };
+    let (tx, input) = mpsc::channel();
+    for line in [
+        msg("c1", json!({"type": "frobnicate", "msg_id": 7, "knob": 3})),
+        msg("c2", json!({"type": "frobnicate", "msg_id": 8})),
+        msg(
+            "n9",
+            json!({"type": "frobnicate_ok", "in_reply_to": FIRST_MSG_ID, "knob": 4}),
+        ),
+        // Even an error, which the node could parse, is relayed.
+        msg(
+            "n9",
+            json!({"type": "error", "in_reply_to": FIRST_MSG_ID + 1, "code": 11, "text": "busy"}),
+        ),
+        // The upstream's own unknown requests aren't sent back to it.
+        msg("n9", json!({"type": "frobnicate", "msg_id": 1})),
+        msg("c1", json!({"type": "echo", "msg_id": 9, "echo": "hi"})),
+    ] {
+        tx.send(line).unwrap();
+    }
+    drop(tx);
+    let mut out = Vec::new();
+    run(EchoNode::default(), &config, input, &mut out).unwrap();
+
+    let sent: Vec<Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // Replies to clients may overtake messages to nodes.
+    let mut summary: Vec<_> = sent
+        .iter()
+        .map(|msg| {
+            (
+                msg["dest"].as_str().unwrap(),
+                msg["body"]["type"].as_str().unwrap(),
+                msg["body"]["in_reply_to"].as_u64(),
+            )
+        })
+        .collect();
+    summary.sort();
+    assert_eq!(
+        summary,
+        [
+            ("c1", "echo_ok", Some(9)),
+            ("c1", "frobnicate_ok", Some(7)),
+            ("c2", "error", Some(8)),
+            ("n9", "error", Some(1)),
+            ("n9", "frobnicate", None),
+            ("n9", "frobnicate", None),
+        ]
+    );
+    let to = |dest: &str, kind: &str| {
+        sent.iter()
+            .find(|msg| msg["dest"] == dest && msg["body"]["type"] == kind)
+            .unwrap()
+    };
+    let forwarded = to("n9", "frobnicate");
+    assert_eq!(forwarded["src"], "n1");
+    assert_eq!(forwarded["body"]["knob"], 3);
+    assert_eq!(forwarded["body"]["msg_id"], FIRST_MSG_ID);
+    assert_eq!(to("c1", "frobnicate_ok")["body"]["knob"], 4);
+    assert_eq!(to("c2", "error")["body"]["code"], 11);
+}
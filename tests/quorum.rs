@@ -0,0 +1,224 @@
+//! Quorum reads and writes on the simulated network.
+
+use serde_json::json;
+use std::time::Duration;
+use whirlpool::{
+    kv::quorum::{Consistency, QuorumConfig},
+    payload::{Payload, ReadValue},
+    sim::Sim,
+    ErrorCode, QuorumKvNode,
+};
+
+fn write(value: i64, consistency: Option<Consistency>) -> Payload {
+    Payload::Write {
+        key: json!("k"),
+        value: json!(value),
+        consistency,
+    }
+}
+
+fn read(consistency: Option<Consistency>) -> Payload {
+    Payload::Read {
+        key: Some(json!("k")),
+        consistency,
+    }
+}
+
+/// A cluster of five, and the three replicas of `k` in it.
+fn cluster(seed: u64) -> (Sim<QuorumKvNode>, Vec<String>) {
+    let sim = Sim::with_seed(5, |_| QuorumKvNode::default(), seed).unwrap();
+    let replicas = sim
+        .node("n0")
+        .unwrap()
+        .replicas_of(&json!("k"))
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    (sim, replicas)
+}
+
+/// Sends `payload` to `node` and returns the answer.
+fn request(sim: &mut Sim<QuorumKvNode>, node: &str, payload: Payload, wait: u64) -> Payload {
+    sim.client_send(node, payload);
+    sim.run_for(Duration::from_millis(wait)).unwrap();
+    let mut replies = sim.take_replies();
+    assert_eq!(replies.len(), 1, "{replies:?}");
+    replies.remove(0).body.payload
+}
+
+#[test]
+fn writes_and_reads_need_only_a_quorum() {
+    let (mut sim, replicas) = cluster(1);
+    assert_eq!(replicas.len(), 3);
+    sim.partition(
+        vec![vec![replicas[2].clone()]],
+        Duration::ZERO,
+        Duration::from_secs(60),
+    );
+    let coordinator = &replicas[0];
+    assert_eq!(
+        request(&mut sim, coordinator, write(1, None), 200),
+        Payload::WriteOk
+    );
+    let other = (0..5)
+        .map(|i| format!("n{i}"))
+        .find(|node| !replicas.contains(node))
+        .unwrap();
+    assert_eq!(
+        request(&mut sim, &other, read(None), 200),
+        Payload::ReadOk {
+            value: ReadValue::Value { value: json!(1) }
+        }
+    );
+    let stored = |node: &str| sim.node(node).unwrap().get(&json!("k")).is_some();
+    assert!(stored(&replicas[0]) && stored(&replicas[1]));
+    assert!(!stored(&replicas[2]) && !stored(&other));
+}
+
+#[test]
+fn consistency_can_be_raised_or_lowered_per_request() {
+    let (mut sim, replicas) = cluster(2);
+    let coordinator = &replicas[0];
+    request(&mut sim, coordinator, write(1, None), 200);
+    sim.partition(
+        vec![vec![replicas[1].clone()]],
+        Duration::ZERO,
+        Duration::from_secs(60),
+    );
+    let Payload::Error { code, .. } = request(
+        &mut sim,
+        coordinator,
+        write(2, Some(Consistency::All)),
+        1200,
+    ) else {
+        panic!("a write to all replicas can't succeed with one cut off");
+    };
+    assert_eq!(code, ErrorCode::Timeout);
+
+    // The coordinator is a replica itself, so one answer is its own.
+    let payload = request(&mut sim, coordinator, read(Some(Consistency::One)), 50);
+    assert_eq!(
+        payload,
+        Payload::ReadOk {
+            value: ReadValue::Value { value: json!(2) }
+        }
+    );
+}
+
+#[test]
+fn cas_compares_against_the_newest_version() {
+    let (mut sim, replicas) = cluster(3);
+    let cas = |from: i64, to: i64| Payload::Cas {
+        key: json!("k"),
+        from: json!(from),
+        to: json!(to),
+        create_if_not_exists: true,
+    };
+    assert_eq!(request(&mut sim, "n0", cas(0, 1), 200), Payload::CasOk);
+    assert_eq!(request(&mut sim, "n1", cas(1, 2), 200), Payload::CasOk);
+    let Payload::Error { code, .. } = request(&mut sim, "n2", cas(1, 3), 200) else {
+        panic!("cas from a stale value succeeded");
+    };
+    assert_eq!(code, ErrorCode::PreconditionFailed);
+    for replica in &replicas {
+        let stored = sim.node(replica).unwrap().get(&json!("k")).unwrap();
+        assert_eq!(stored.value, json!(2));
+    }
+}
+
+/// A cluster where the last replica of `k` missed the write of 1.
+fn with_stale_replica(seed: u64) -> (Sim<QuorumKvNode>, Vec<String>) {
+    let (mut sim, replicas) = cluster(seed);
+    sim.partition(
+        vec![vec![replicas[2].clone()]],
+        Duration::ZERO,
+        Duration::from_millis(300),
+    );
+    request(&mut sim, &replicas[0], write(1, None), 400);
+    assert!(sim.node(&replicas[2]).unwrap().get(&json!("k")).is_none());
+    (sim, replicas)
+}
+
+#[test]
+fn reads_repair_replicas_that_missed_a_write() {
+    let (mut sim, replicas) = with_stale_replica(4);
+    request(&mut sim, &replicas[0], read(Some(Consistency::All)), 200);
+    let repaired = sim.node(&replicas[2]).unwrap().get(&json!("k")).unwrap();
+    assert_eq!(repaired.value, json!(1));
+}
+
+#[test]
+fn replicas_that_answer_after_the_client_are_repaired_too() {
+    let (mut sim, replicas) = with_stale_replica(5);
+    // The coordinator's own copy is enough to answer with.
+    sim.client_send(&replicas[0], read(Some(Consistency::One)));
+    sim.run_for(Duration::ZERO).unwrap();
+    assert_eq!(sim.take_replies().len(), 1);
+    assert!(sim.node(&replicas[2]).unwrap().get(&json!("k")).is_none());
+
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    let repaired = sim.node(&replicas[2]).unwrap().get(&json!("k")).unwrap();
+    assert_eq!(repaired.value, json!(1));
+}
+
+#[test]
+fn anti_entropy_catches_up_replicas_nobody_reads_from() {
+    let node = || QuorumKvNode::default().with_anti_entropy(Duration::from_millis(20));
+    let mut sim = Sim::with_seed(5, |_| node(), 6).unwrap();
+    let replicas: Vec<String> = sim
+        .node("n0")
+        .unwrap()
+        .replicas_of(&json!("k"))
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    sim.partition(
+        vec![vec![replicas[2].clone()]],
+        Duration::ZERO,
+        Duration::from_millis(300),
+    );
+    request(&mut sim, &replicas[0], write(1, None), 200);
+    assert!(sim.node(&replicas[2]).unwrap().get(&json!("k")).is_none());
+
+    sim.run_for(Duration::from_millis(800)).unwrap();
+    let synced = sim.node(&replicas[2]).unwrap().get(&json!("k")).unwrap();
+    assert_eq!(synced.value, json!(1));
+}
+
+#[test]
+fn hints_stand_in_for_cut_off_replicas_until_they_are_back() {
+    let node = || QuorumKvNode::default().with_hinted_handoff();
+    let mut sim = Sim::with_seed(5, |_| node(), 7).unwrap();
+    let replicas: Vec<String> = sim
+        .node("n0")
+        .unwrap()
+        .replicas_of(&json!("k"))
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    sim.partition(
+        vec![vec![replicas[2].clone()]],
+        Duration::ZERO,
+        Duration::from_millis(600),
+    );
+    let coordinator = &replicas[0];
+    assert_eq!(
+        request(&mut sim, coordinator, write(1, Some(Consistency::All)), 300),
+        Payload::WriteOk
+    );
+    assert_eq!(sim.node(coordinator).unwrap().hinted(), 1);
+    assert!(sim.node(&replicas[2]).unwrap().get(&json!("k")).is_none());
+
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+    let handed_off = sim.node(&replicas[2]).unwrap().get(&json!("k")).unwrap();
+    assert_eq!(handed_off.value, json!(1));
+    assert_eq!(sim.node(coordinator).unwrap().hinted(), 0);
+}
+
+#[test]
+fn quorum_config_parses_n_w_r() {
+    let config: QuorumConfig = "5,3,2".parse().unwrap();
+    assert_eq!((config.replicas, config.write, config.read), (5, 3, 2));
+    assert!("3,4,1".parse::<QuorumConfig>().is_err());
+    assert!("3,2".parse::<QuorumConfig>().is_err());
+}
@@ -0,0 +1,327 @@
+//! Raft clusters on the simulated network.
+
+use serde_json::json;
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use whirlpool::{
+    compress::Encoding,
+    handshake::{Capability, Handshake, PROTOCOL_VERSION},
+    kv::KvStore,
+    middleware::{Layered, Next},
+    payload::Payload,
+    raft::{LogEntry, RaftConfig, RaftNode, Role, Snapshot},
+    sim::Sim,
+    ErrorCode, Message, Node,
+};
+
+fn leaders(sim: &Sim<RaftNode>) -> Vec<(String, u64)> {
+    sim.nodes()
+        .filter(|node| node.role() == Role::Leader)
+        .map(|node| (node.membership.node_id.clone(), node.term()))
+        .collect()
+}
+
+#[test]
+fn cluster_elects_a_single_leader() {
+    let mut sim = Sim::with_seed(5, |_| RaftNode::default(), 3).unwrap();
+    sim.network().drop_rate = 0.1;
+    sim.run_for(Duration::from_secs(1)).unwrap();
+
+    let leaders = leaders(&sim);
+    assert_eq!(leaders.len(), 1, "leaders: {leaders:?}");
+    let (leader, term) = &leaders[0];
+    for node in sim.nodes() {
+        assert_eq!(node.term(), *term);
+        assert_eq!(node.leader(), Some(leader.as_str()));
+    }
+}
+
+#[test]
+fn timers_say_when_to_tick() {
+    let mut node: RaftNode = RaftNode::default();
+    let init = Payload::Init {
+        node_id: "n0".to_string(),
+        node_ids: vec!["n0".to_string(), "n1".to_string()],
+    };
+    let mut out = Vec::new();
+    let start = Instant::now();
+    node.handle(Message::new("c1", "n0", Some(1), init), &mut out)
+        .unwrap();
+    let election = node.next_timer().unwrap();
+    assert!(election > start + Duration::from_millis(100));
+    assert!(election <= Instant::now() + Duration::from_millis(300));
+
+    std::thread::sleep(election.saturating_duration_since(Instant::now()));
+    node.tick(&mut out).unwrap();
+    assert_eq!(node.role(), Role::Candidate);
+    assert!(node.next_timer().unwrap() > election);
+}
+
+#[test]
+fn stale_appends_never_lower_the_commit_index() {
+    let mut node: RaftNode = RaftNode::default();
+    let init = Payload::Init {
+        node_id: "n1".to_string(),
+        node_ids: vec!["n0".to_string(), "n1".to_string(), "n2".to_string()],
+    };
+    let mut out = Vec::new();
+    node.handle(Message::new("c1", "n1", Some(1), init), &mut out)
+        .unwrap();
+    let append = |prev_log_index, entries: Vec<LogEntry>, leader_commit| {
+        let append = Payload::AppendEntries {
+            term: 1,
+            leader_id: "n0".to_string(),
+            prev_log_index,
+            prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+            entries,
+            leader_commit,
+        };
+        Message::new("n0", "n1", Some(2), append)
+    };
+    let entries = (0..3)
+        .map(|key| LogEntry {
+            term: 1,
+            command: write(key, key),
+        })
+        .collect();
+    node.handle(append(0, entries, 3), &mut out).unwrap();
+    assert_eq!(node.commit_index(), 3);
+
+    // An older append, delivered late: it only vouches for the first entry,
+    // but names a commit index past what this follower has.
+    node.handle(append(1, Vec::new(), 4), &mut out).unwrap();
+    assert_eq!(node.commit_index(), 3);
+}
+
+#[test]
+fn hellos_are_not_taken_for_commands() {
+    let make = |_: &str| RaftNode::default().with_handshake(Handshake::default());
+    let mut sim = Sim::with_seed(5, make, 3).unwrap();
+    sim.run_for(Duration::from_secs(1)).unwrap();
+
+    assert_eq!(leaders(&sim).len(), 1);
+    for node in sim.nodes() {
+        let handshake = node.handshake().unwrap();
+        assert!(handshake.is_settled());
+        for peer in node.membership.peers() {
+            assert_eq!(handshake.version(peer), Some(PROTOCOL_VERSION));
+        }
+        assert_eq!(node.log().last_index(), 0);
+    }
+}
+
+#[test]
+fn partitioned_leader_is_replaced_and_steps_down() {
+    let mut sim = Sim::with_seed(5, |_| RaftNode::default(), 5).unwrap();
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    let (old, old_term) = leaders(&sim).pop().expect("a leader was elected");
+
+    sim.partition(
+        vec![vec![old.clone()]],
+        Duration::from_secs(1),
+        Duration::from_secs(2),
+    );
+    sim.run_for(Duration::from_millis(900)).unwrap();
+    let majority = sim
+        .nodes()
+        .find(|node| node.is_leader() && node.membership.node_id != old)
+        .expect("the majority elected a new leader");
+    assert!(majority.term() > old_term);
+
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    let leaders = leaders(&sim);
+    assert_eq!(leaders.len(), 1, "leaders: {leaders:?}");
+    // The old leader may well win again, but not in its old term.
+    assert!(leaders[0].1 > old_term);
+    assert_eq!(sim.node(&old).unwrap().term(), leaders[0].1);
+}
+
+fn write(key: u64, value: u64) -> Payload {
+    Payload::Write {
+        key: json!(key),
+        value: json!(value),
+        consistency: None,
+    }
+}
+
+/// Whether every node has applied `key = value`.
+fn replicated(sim: &Sim<RaftNode>, key: u64, value: u64) -> bool {
+    sim.nodes()
+        .all(|node| node.state_machine().read(&json!(key)).ok() == Some(json!(value)))
+}
+
+#[test]
+fn writes_through_any_node_are_replicated() {
+    let mut sim = Sim::with_seed(5, |_| RaftNode::default(), 11).unwrap();
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    for key in 0..10 {
+        sim.client_send(&format!("n{}", key % 5), write(key, key * 10));
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 10);
+    assert!(replies
+        .iter()
+        .all(|reply| reply.body.payload == Payload::WriteOk));
+    assert!((0..10).all(|key| replicated(&sim, key, key * 10)));
+
+    let cas = |from: u64, to: u64| Payload::Cas {
+        key: json!(3),
+        from: json!(from),
+        to: json!(to),
+        create_if_not_exists: false,
+    };
+    sim.client_send("n1", cas(30, 31));
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    sim.client_send("n2", cas(30, 32));
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    let replies = sim.take_replies();
+    assert_eq!(replies[0].body.payload, Payload::CasOk);
+    assert!(matches!(
+        replies[1].body.payload,
+        Payload::Error {
+            code: ErrorCode::PreconditionFailed,
+            ..
+        }
+    ));
+    assert!(replicated(&sim, 3, 31));
+}
+
+#[test]
+fn committed_writes_survive_a_leader_change() {
+    let mut sim = Sim::with_seed(5, |_| RaftNode::default(), 13).unwrap();
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    let (old, _) = leaders(&sim).pop().expect("a leader was elected");
+    sim.client_send(&old, write(1, 1));
+    sim.run_for(Duration::from_millis(200)).unwrap();
+
+    sim.partition(
+        vec![vec![old.clone()]],
+        Duration::from_millis(1200),
+        Duration::from_millis(2500),
+    );
+    sim.run_for(Duration::from_millis(800)).unwrap();
+    let other = sim
+        .nodes()
+        .map(|node| node.membership.node_id.clone())
+        .find(|id| *id != old)
+        .unwrap();
+    sim.client_send(&other, write(2, 2));
+    // The old leader can't commit this one without a majority.
+    sim.client_send(&old, write(3, 3));
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+
+    assert!(replicated(&sim, 1, 1));
+    assert!(replicated(&sim, 2, 2));
+    assert!(sim
+        .nodes()
+        .all(|node| node.state_machine().read(&json!(3)).is_err()));
+    let replies = sim.take_replies();
+    assert!(replies
+        .iter()
+        .any(|reply| reply.dest == "c1" && reply.body.payload == Payload::WriteOk));
+}
+
+#[test]
+fn lagging_follower_catches_up_from_a_snapshot() {
+    let config = RaftConfig {
+        snapshot_threshold: 10,
+        snapshot_chunk_size: 64,
+        ..RaftConfig::default()
+    };
+    let make = |_: &str| RaftNode::new(KvStore::default(), config.clone());
+    let mut sim = Sim::with_seed(5, make, 17).unwrap();
+    sim.partition(
+        vec![vec!["n4".into()]],
+        Duration::ZERO,
+        Duration::from_millis(2500),
+    );
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    for key in 0..40 {
+        sim.client_send(&format!("n{}", key % 4), write(key, key));
+        sim.run_for(Duration::from_millis(20)).unwrap();
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let leader = sim.nodes().find(|node| node.is_leader()).unwrap();
+    assert!(leader.log().snapshot_index() >= 30);
+    assert!(leader.log().len() < 20);
+
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    let lagging = sim.node("n4").unwrap();
+    assert!(lagging.snapshot().is_some_and(|s| s.index >= 30));
+    assert!((0..40).all(|key| replicated(&sim, key, key)));
+}
+
+/// Runs five nodes that advertise `capability`, with n4 cut off while 40
+/// writes go in, until n4 has caught up from a snapshot. Returns the
+/// leader's snapshot and the encoding of every `install_snapshot` sent.
+fn catch_up_from_snapshot(capability: Capability) -> (Snapshot, Vec<Option<Encoding>>) {
+    let config = RaftConfig {
+        snapshot_threshold: 10,
+        snapshot_chunk_size: 64,
+        ..RaftConfig::default()
+    };
+    let encodings = Arc::new(Mutex::new(Vec::new()));
+    let make = |_: &str| {
+        let node = RaftNode::new(KvStore::default(), config.clone())
+            .with_handshake(Handshake::new([capability]));
+        let encodings = encodings.clone();
+        Layered::new(
+            node,
+            move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+                if let Payload::InstallSnapshot { encoding, .. } = &msg.body.payload {
+                    encodings.lock().unwrap().push(*encoding);
+                }
+                next.run(msg, out)
+            },
+        )
+    };
+    let mut sim = Sim::with_seed(5, make, 17).unwrap();
+    sim.partition(
+        vec![vec!["n4".into()]],
+        Duration::from_secs(1),
+        Duration::from_millis(2500),
+    );
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    for key in 0..40 {
+        sim.client_send(&format!("n{}", key % 4), write(key, key));
+        sim.run_for(Duration::from_millis(20)).unwrap();
+    }
+    let caught_up = sim
+        .run_until(Duration::from_secs(10), |sim| {
+            let lagging = sim.node("n4").unwrap().inner();
+            lagging.snapshot().is_some_and(|s| s.index >= 30)
+                && sim.nodes().all(|node| {
+                    node.inner().state_machine().read(&json!(39)).ok() == Some(json!(39))
+                })
+        })
+        .unwrap();
+    assert!(caught_up);
+
+    let leader = sim.nodes().find(|node| node.inner().is_leader()).unwrap();
+    let snapshot = leader.inner().snapshot().unwrap().clone();
+    let encodings = encodings.lock().unwrap().clone();
+    assert!(!encodings.is_empty());
+    (snapshot, encodings)
+}
+
+#[test]
+fn snapshots_go_as_msgpack_to_followers_that_read_it() {
+    let (snapshot, encodings) = catch_up_from_snapshot(Capability::MsgPack);
+    assert!(snapshot.packed().is_some());
+    assert!(encodings
+        .iter()
+        .all(|encoding| *encoding == Some(Encoding::MsgPack)));
+}
+
+#[test]
+fn snapshots_go_gzipped_to_followers_that_read_it() {
+    let (snapshot, encodings) = catch_up_from_snapshot(Capability::Gzip);
+    assert!(snapshot.gzipped().is_some());
+    assert!(encodings
+        .iter()
+        .all(|encoding| *encoding == Some(Encoding::Gzip)));
+}
@@ -0,0 +1,71 @@
+//! Messages to other nodes are held back beyond their rate limits.
+
+use std::time::{Duration, Instant};
+use whirlpool::ratelimit::{Limiter, Rate, RateLimits};
+
+fn to(dest: &str, msg_id: usize) -> String {
+    format!("{{\"src\":\"n0\",\"dest\":\"{dest}\",\"body\":{{\"type\":\"gossip\",\"msg_id\":{msg_id}}}}}\n")
+}
+
+fn lines(out: &[u8]) -> Vec<String> {
+    String::from_utf8(out.to_vec())
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn each_destination_gets_its_own_bucket() {
+    let mut limiter = Limiter::new(RateLimits {
+        per_dest: Some("10,2".parse().unwrap()),
+        global: None,
+    });
+    let start = Instant::now();
+    let chunk = [
+        to("n1", 1),
+        to("n1", 2),
+        to("n1", 3),
+        to("n2", 4),
+        to("c1", 5),
+    ]
+    .concat();
+    let out = lines(&limiter.admit(chunk.as_bytes(), start));
+    // Clients aren't limited, and n1's third message waits for a token.
+    assert_eq!(out.len(), 4);
+    assert!(out.iter().all(|line| !line.contains("\"msg_id\":3")));
+    assert_eq!(limiter.held(), 1);
+
+    let next = limiter.next_release(start).unwrap();
+    assert_eq!(next - start, Duration::from_millis(100));
+    assert!(limiter.release(next - Duration::from_millis(1)).is_empty());
+    assert_eq!(lines(&limiter.release(next)), [to("n1", 3).trim_end()]);
+    assert_eq!(limiter.next_release(next), None);
+}
+
+#[test]
+fn the_global_bucket_is_shared_and_order_is_kept() {
+    let mut limiter = Limiter::new(RateLimits {
+        per_dest: None,
+        global: Some(Rate {
+            per_sec: 100.0,
+            burst: 1.0,
+        }),
+    });
+    let start = Instant::now();
+    let chunk = [to("n1", 1), to("n2", 2), to("n1", 3)].concat();
+    assert_eq!(lines(&limiter.admit(chunk.as_bytes(), start)).len(), 1);
+    let later = start + Duration::from_millis(10);
+    assert_eq!(lines(&limiter.release(later)), [to("n2", 2).trim_end()]);
+    assert_eq!(limiter.drain(), to("n1", 3).as_bytes());
+}
+
+#[test]
+fn rates_parse_with_an_optional_burst() {
+    let rate: Rate = "50".parse().unwrap();
+    assert_eq!((rate.per_sec, rate.burst), (50.0, 50.0));
+    let rate: Rate = "50,5".parse().unwrap();
+    assert_eq!((rate.per_sec, rate.burst), (50.0, 5.0));
+    assert!("0".parse::<Rate>().is_err());
+    assert!("1,2,3".parse::<Rate>().is_err());
+}
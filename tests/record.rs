@@ -0,0 +1,61 @@
+//! Replaying recorded traffic.
+
+use serde_json::json;
+use whirlpool::{
+    record::{self, Direction, Entry},
+    Config, EchoNode,
+};
+
+fn entry(dir: Direction, t_us: u64, msg: serde_json::Value) -> Entry {
+    Entry {
+        dir,
+        t_us,
+        msg: serde_json::from_value(msg).unwrap(),
+    }
+}
+
+fn echo_recording(reply: &str) -> Vec<Entry> {
+    vec![
+        entry(
+            Direction::In,
+            1_000,
+            json!({"src": "c0", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+        ),
+        entry(
+            Direction::Out,
+            1_100,
+            json!({"src": "n1", "dest": "c0", "body": {"type": "init_ok", "msg_id": 0, "in_reply_to": 1}}),
+        ),
+        entry(
+            Direction::In,
+            2_000,
+            json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 2, "echo": "hi"}}),
+        ),
+        entry(
+            Direction::Out,
+            2_100,
+            json!({"src": "n1", "dest": "c1", "body": {"type": "echo_ok", "msg_id": 1, "in_reply_to": 2, "echo": reply}}),
+        ),
+    ]
+}
+
+#[test]
+fn replay_matches_recording() {
+    let entries = echo_recording("hi");
+    let node_id = record::only_node(&entries).unwrap();
+    assert_eq!(node_id, "n1");
+    let replayed =
+        record::replay(EchoNode::default(), &Config::default(), &entries, &node_id).unwrap();
+    let diff = record::diff(record::sent(&entries, &node_id), &replayed).unwrap();
+    assert!(diff.is_empty(), "{diff}");
+}
+
+#[test]
+fn replay_reports_differences() {
+    let entries = echo_recording("bye");
+    let replayed = record::replay(EchoNode::default(), &Config::default(), &entries, "n1").unwrap();
+    let diff = record::diff(record::sent(&entries, "n1"), &replayed).unwrap();
+    assert_eq!(diff.missing.len(), 1);
+    assert_eq!(diff.unexpected.len(), 1);
+    assert_eq!(diff.unexpected[0]["body"]["echo"], "hi");
+}
@@ -0,0 +1,58 @@
+//! Retry queues give up on messages past their limits.
+
+use std::time::Duration;
+use whirlpool::{metrics, payload::Payload, Backoff, CacheLimits, Message, RetryQueue};
+
+fn broadcast(msg_id: usize) -> Message {
+    Message::new(
+        "n1",
+        "n2",
+        Some(msg_id),
+        Payload::Broadcast { message: msg_id },
+    )
+}
+
+fn immediate() -> Backoff {
+    Backoff {
+        initial: Duration::ZERO,
+        max: Duration::ZERO,
+        jitter: 0.0,
+    }
+}
+
+#[test]
+fn the_oldest_messages_are_dropped_once_too_many_wait() {
+    metrics::enable();
+    let mut retries = RetryQueue::new(immediate()).with_limits(CacheLimits {
+        ttl: None,
+        max_entries: Some(2),
+    });
+    let mut out = Vec::new();
+    for msg_id in 1..=3 {
+        retries.send(broadcast(msg_id), &mut out).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(retries.len(), 2);
+
+    // Only 2 and 3 are still retried.
+    let mut resent = Vec::new();
+    assert_eq!(retries.resend_due(&mut resent).unwrap(), 2);
+    assert!(!String::from_utf8(resent).unwrap().contains("\"msg_id\":1"));
+    assert!(retries.ack(&broadcast(2).into_reply("n2", None, Payload::BroadcastOk)));
+    assert!(metrics::snapshot().evictions["retry"] >= 1);
+}
+
+#[test]
+fn messages_are_given_up_on_after_the_ttl() {
+    let mut retries = RetryQueue::new(immediate()).with_limits(CacheLimits {
+        ttl: Some(Duration::from_millis(50)),
+        max_entries: None,
+    });
+    let mut out = Vec::new();
+    retries.send(broadcast(1), &mut out).unwrap();
+    assert_eq!(retries.resend_due(&mut out).unwrap(), 1);
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(retries.resend_due(&mut out).unwrap(), 0);
+    assert!(retries.is_empty());
+}
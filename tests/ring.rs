@@ -0,0 +1,55 @@
+//! Key ownership on a consistent hashing ring.
+
+use std::collections::HashMap;
+use whirlpool::HashRing;
+
+fn node_ids(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("n{i}")).collect()
+}
+
+fn owners(ring: &HashRing, keys: u64) -> Vec<String> {
+    (0..keys)
+        .map(|key| ring.owner_of(&key).unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn keys_are_spread_over_every_node() {
+    let ring = HashRing::new(&node_ids(5));
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for owner in owners(&ring, 10_000) {
+        *counts.entry(owner).or_default() += 1;
+    }
+    assert_eq!(counts.len(), 5);
+    for (node, count) in &counts {
+        assert!((1000..3000).contains(count), "{node} owns {count}");
+    }
+    assert_eq!(HashRing::default().owner_of("x"), None);
+}
+
+#[test]
+fn adding_a_node_only_moves_keys_to_it() {
+    let mut ring = HashRing::new(&node_ids(4));
+    let before = owners(&ring, 10_000);
+    ring.add("n4");
+    let after = owners(&ring, 10_000);
+    let moved: Vec<_> = before.iter().zip(&after).filter(|(a, b)| a != b).collect();
+    assert!(moved.iter().all(|(_, to)| *to == "n4"));
+    assert!((1000..3000).contains(&moved.len()), "{} moved", moved.len());
+
+    ring.remove("n4");
+    assert_eq!(owners(&ring, 10_000), before);
+}
+
+#[test]
+fn owners_are_distinct_and_start_with_the_owner() {
+    let ring = HashRing::with_vnodes(&node_ids(5), 8);
+    for key in ["a", "b", "c"] {
+        let owners = ring.owners_of(key, 3);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(owners[0], ring.owner_of(key).unwrap());
+        assert!(owners[1..].iter().all(|node| *node != owners[0]));
+        assert_ne!(owners[1], owners[2]);
+    }
+    assert_eq!(ring.owners_of("a", 9).len(), 5);
+}
@@ -0,0 +1,46 @@
+//! Randomized serde roundtrips of every message the crate knows about.
+
+use rand::{rngs::StdRng, SeedableRng};
+use whirlpool::{
+    payload::Payload,
+    testing::arbitrary::{self, Arbitrary},
+    Message,
+};
+
+const CASES: usize = 2000;
+
+#[test]
+fn messages_roundtrip_through_json() {
+    let seed = rand::random();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut seen = [false; arbitrary::VARIANTS];
+    for _ in 0..CASES {
+        let msg = Message::<Payload>::arbitrary(&mut rng);
+        seen[arbitrary::variant(&msg.body.payload)] = true;
+        let json = serde_json::to_string(&msg).unwrap();
+        let back: Message = serde_json::from_str(&json)
+            .unwrap_or_else(|err| panic!("seed {seed}: {json} did not parse back: {err}"));
+        assert_eq!(back, msg, "seed {seed}: {json}");
+    }
+    assert!(
+        seen.iter().all(|&seen| seen),
+        "seed {seed}: only generated variants {:?}",
+        (0..arbitrary::VARIANTS)
+            .filter(|&i| seen[i])
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn payloads_roundtrip_through_values() {
+    let mut rng = StdRng::seed_from_u64(42);
+    for _ in 0..CASES {
+        let payload = Payload::arbitrary(&mut rng);
+        let value = serde_json::to_value(&payload).unwrap();
+        let back: Payload = serde_json::from_value(value.clone())
+            .unwrap_or_else(|err| panic!("{value} did not parse back: {err}"));
+        assert_eq!(back, payload, "{value}");
+        // Every payload names its type, whatever else it flattens in.
+        assert!(value["type"].is_string(), "{value}");
+    }
+}
@@ -0,0 +1,122 @@
+//! Routers dispatch by type, and hand what nobody handles to their
+//! fallback.
+
+use serde_json::{json, Value};
+use whirlpool::{
+    router::{RawPayload, Router},
+    transport, ErrorCode, Message, Node, RpcError,
+};
+
+fn msg(src: &str, body: Value) -> Message<RawPayload> {
+    let Value::Object(payload) = body else {
+        panic!("body is not an object");
+    };
+    let mut msg = Message::new(src, "n1", Some(1), payload);
+    msg.body.in_reply_to = msg
+        .body
+        .payload
+        .get("in_reply_to")
+        .and_then(Value::as_u64)
+        .map(|id| id as usize);
+    msg
+}
+
+fn sent(out: &[u8]) -> Vec<Message<RawPayload>> {
+    transport::parse_lines(out).unwrap()
+}
+
+#[test]
+fn unhandled_messages_go_to_the_fallback() {
+    let mut router = Router::new(Vec::new());
+    router
+        .on("echo", |msg, _ctx| {
+            Ok(json!({ "echo": msg.body.payload["echo"] }))
+        })
+        .on_unhandled(|msg, ctx| {
+            let kind = msg.body.payload["type"].as_str().unwrap_or_default();
+            ctx.state.push(kind.to_string());
+            match kind {
+                "forward_me" => {
+                    ctx.send("n2", kind, json!({}))?;
+                    Ok(Value::Null)
+                }
+                "refuse_me" => Err(RpcError::crash("refused").into()),
+                _ => Ok(Value::Null),
+            }
+        });
+    let mut out = Vec::new();
+    router
+        .handle(
+            msg(
+                "c0",
+                json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2"]}),
+            ),
+            &mut out,
+        )
+        .unwrap();
+    router
+        .handle(msg("c1", json!({"type": "echo", "echo": "hi"})), &mut out)
+        .unwrap();
+    router
+        .handle(msg("c1", json!({"type": "forward_me"})), &mut out)
+        .unwrap();
+    let err = router
+        .handle(msg("c1", json!({"type": "refuse_me"})), &mut out)
+        .unwrap_err();
+    assert_eq!(err.downcast::<RpcError>().unwrap().text, "refused");
+    router
+        .handle(
+            msg("n2", json!({"type": "forward_me_ok", "in_reply_to": 0})),
+            &mut out,
+        )
+        .unwrap();
+
+    assert_eq!(
+        router.state(),
+        &["forward_me", "refuse_me", "forward_me_ok"]
+    );
+    let sent: Vec<_> = sent(&out)
+        .into_iter()
+        .map(|msg| (msg.dest, msg.body.payload["type"].clone()))
+        .collect();
+    assert_eq!(
+        sent,
+        [
+            ("c0".to_string(), json!("init_ok")),
+            ("c1".to_string(), json!("echo_ok")),
+            ("n2".to_string(), json!("forward_me")),
+        ]
+    );
+}
+
+#[test]
+fn without_a_fallback_unhandled_requests_are_not_supported() {
+    let mut router = Router::new(());
+    let mut out = Vec::new();
+    let err = router
+        .handle(msg("c1", json!({"type": "nope"})), &mut out)
+        .unwrap_err();
+    assert_eq!(
+        err.downcast::<RpcError>().unwrap().code,
+        ErrorCode::NotSupported
+    );
+    router
+        .handle(
+            msg("n2", json!({"type": "nope_ok", "in_reply_to": 3})),
+            &mut out,
+        )
+        .unwrap();
+    assert!(out.is_empty());
+}
+
+#[test]
+fn messages_without_a_type_are_malformed() {
+    let mut router = Router::new(());
+    let err = router
+        .handle(msg("c1", json!({"echo": "hi"})), &mut Vec::new())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast::<RpcError>().unwrap().code,
+        ErrorCode::MalformedRequest
+    );
+}
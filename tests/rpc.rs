@@ -0,0 +1,108 @@
+//! Calls that get no reply in time fail with a timeout error.
+
+use std::{thread, time::Duration};
+use whirlpool::{
+    payload::{Payload, ReadValue},
+    rpc::Timeout,
+    transport, ErrorCode, Message, Rpc, RpcError,
+};
+
+fn read() -> Payload {
+    Payload::Read {
+        key: None,
+        consistency: None,
+    }
+}
+
+fn requests(out: &[u8]) -> Vec<Option<usize>> {
+    transport::parse_lines::<Payload>(out)
+        .unwrap()
+        .into_iter()
+        .map(|msg| msg.body.id)
+        .collect()
+}
+
+#[test]
+fn calls_time_out_after_their_retries() {
+    let rpc = Rpc::default();
+    let mut out = Vec::new();
+    let timeout = Timeout::after(Duration::from_millis(20)).retries(2);
+    let err = rpc
+        .call_timeout("n0", "n1", read(), timeout, &mut out)
+        .unwrap_err();
+    assert_eq!(err.downcast::<RpcError>().unwrap().code, ErrorCode::Timeout);
+    assert_eq!(rpc.outstanding(), 0);
+    // The first send and two retries, all under one msg_id.
+    let ids = requests(&out);
+    assert_eq!(ids.len(), 3);
+    assert!(ids.iter().all(|id| *id == ids[0]));
+}
+
+#[test]
+fn a_retry_can_still_get_the_reply() {
+    let rpc = Rpc::default();
+    let replier = rpc.clone();
+    let answer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(80));
+        let request = Message::new("n0", "n1", Some(0), read());
+        let reply = request.into_reply(
+            "n1",
+            None,
+            Payload::ReadOk {
+                value: ReadValue::Messages { messages: vec![1] },
+            },
+        );
+        replier.resolve(reply)
+    });
+    let mut out = Vec::new();
+    let timeout = Timeout::after(Duration::from_millis(50)).retries(3);
+    let reply = rpc
+        .call_timeout("n0", "n1", read(), timeout, &mut out)
+        .unwrap();
+    assert!(answer.join().unwrap().is_none());
+    assert_eq!(reply.body.in_reply_to, Some(0));
+    assert_eq!(requests(&out).len(), 2);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn awaited_calls_time_out_too() {
+    use whirlpool::runtime::block_on;
+
+    let rpc = Rpc::default();
+    let mut out = Vec::new();
+    let call = rpc.call("n0", "n1", read(), &mut out).unwrap();
+    let err = block_on(call.timeout(Duration::from_millis(20))).unwrap_err();
+    assert_eq!(err.code, ErrorCode::Timeout);
+    drop(call);
+
+    let timeout = Timeout::after(Duration::from_millis(20)).retries(1);
+    let err = block_on(rpc.call_timeout_async("n0", "n1", read(), timeout, &mut out)).unwrap_err();
+    assert_eq!(err.downcast::<RpcError>().unwrap().code, ErrorCode::Timeout);
+    assert_eq!(rpc.outstanding(), 0);
+    assert_eq!(requests(&out).len(), 3);
+}
+
+#[test]
+fn cancelled_calls_fail_without_retrying() {
+    let rpc = Rpc::default();
+    let canceller = rpc.clone();
+    let cancel = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(30));
+        canceller.cancel_all(RpcError::crash("shutting down"));
+    });
+    let mut out = Vec::new();
+    let timeout = Timeout::after(Duration::from_millis(200)).retries(3);
+    let err = rpc
+        .call_timeout("n0", "n1", read(), timeout, &mut out)
+        .unwrap_err();
+    cancel.join().unwrap();
+    assert_eq!(err.downcast::<RpcError>().unwrap().code, ErrorCode::Crash);
+    assert_eq!(requests(&out).len(), 1);
+
+    let call = rpc.call("n0", "n1", read(), &mut out).unwrap();
+    rpc.cancel_all(RpcError::crash("shutting down"));
+    assert!(call.wait_timeout(Duration::ZERO).is_none());
+    assert_eq!(call.wait().unwrap_err().code, ErrorCode::Crash);
+    assert_eq!(rpc.outstanding(), 0);
+}
@@ -0,0 +1,230 @@
+//! The async runtime lets handlers wait on calls of their own at the same
+//! time, answering each client as its call's reply comes in.
+#![cfg(feature = "async")]
+
+use std::{
+    future,
+    io::{self, Write},
+    sync::{mpsc, Arc, Mutex},
+    task::{Poll, Waker},
+    thread,
+    time::Duration,
+};
+use whirlpool::{
+    payload::Payload,
+    runtime::{async_run, AsyncNode},
+    Config, ErrorCode, Message, Rpc,
+};
+
+/// Answers each echo with what `n1` echoes back.
+struct Relay {
+    rpc: Rpc,
+}
+
+impl AsyncNode for Relay {
+    async fn handle(&self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let Payload::Echo { echo } = &msg.body.payload else {
+            return Ok(());
+        };
+        let echo = Payload::Echo { echo: echo.clone() };
+        let reply = self.rpc.call("n0", "n1", echo, out)?.await?;
+        msg.into_reply("n0", None, reply.body.payload).send(out)
+    }
+
+    fn rpc(&self) -> Option<Rpc> {
+        Some(self.rpc.clone())
+    }
+}
+
+/// Answers each echo after yielding to the main loop `yields` times,
+/// keeping the waker of the last handler to run.
+struct Yielding {
+    yields: usize,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl AsyncNode for Yielding {
+    async fn handle(&self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        let Payload::Echo { echo } = &msg.body.payload else {
+            return Ok(());
+        };
+        let mut yields = self.yields;
+        future::poll_fn(|cx| {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            if yields == 0 {
+                return Poll::Ready(());
+            }
+            // Woken while it is being polled, so it is due another turn
+            // as soon as this one ends.
+            yields -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        let echo = Payload::EchoOk { echo: echo.clone() };
+        msg.into_reply("n0", None, echo).send(out)
+    }
+}
+
+/// Hands what the node writes over to the test.
+struct Chunks(mpsc::Sender<Vec<u8>>);
+
+impl Write for Chunks {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let _ = self.0.send(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Written {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Written {
+    fn next(&mut self) -> Message {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                return serde_json::from_slice(&line).unwrap();
+            }
+            let chunk = self
+                .chunks
+                .recv_timeout(Duration::from_secs(5))
+                .expect("nothing more was written");
+            self.buf.extend(chunk);
+        }
+    }
+}
+
+/// Runs `node` in the background, with a sender of its input and what it
+/// writes.
+fn start<N: AsyncNode + Send + 'static>(
+    node: N,
+) -> (
+    mpsc::Sender<String>,
+    Written,
+    thread::JoinHandle<anyhow::Result<()>>,
+) {
+    let (input, messages) = mpsc::channel();
+    let (chunks, written) = mpsc::channel();
+    let running =
+        thread::spawn(move || async_run(node, &Config::default(), messages, Chunks(chunks)));
+    let written = Written {
+        chunks: written,
+        buf: Vec::new(),
+    };
+    (input, written, running)
+}
+
+fn relay() -> Relay {
+    Relay {
+        rpc: Rpc::default(),
+    }
+}
+
+fn send(input: &mpsc::Sender<String>, msg: Message) {
+    input.send(serde_json::to_string(&msg).unwrap()).unwrap();
+}
+
+#[test]
+fn handlers_wait_on_their_calls_at_the_same_time() {
+    let (input, mut written, running) = start(relay());
+
+    for (msg_id, echo) in [(1, "a"), (2, "b")] {
+        let echo = Payload::Echo { echo: echo.into() };
+        send(&input, Message::new("c1", "n0", Some(msg_id), echo));
+    }
+    // Both calls go out before either is answered.
+    let calls = [written.next(), written.next()];
+    let echoes: Vec<_> = calls.iter().map(|call| &call.body.payload).collect();
+    assert_eq!(
+        echoes,
+        [
+            &Payload::Echo { echo: "a".into() },
+            &Payload::Echo { echo: "b".into() }
+        ]
+    );
+
+    for call in calls.iter().rev() {
+        let Payload::Echo { echo } = &call.body.payload else {
+            unreachable!()
+        };
+        let echo = Payload::EchoOk { echo: echo.clone() };
+        send(&input, call.into_reply("n1", None, echo));
+    }
+    for (in_reply_to, echo) in [(2, "b"), (1, "a")] {
+        let reply = written.next();
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.in_reply_to, Some(in_reply_to));
+        assert_eq!(reply.body.payload, Payload::EchoOk { echo: echo.into() });
+    }
+
+    drop(input);
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn calls_left_waiting_at_shutdown_fail_their_handlers() {
+    let (input, mut written, running) = start(relay());
+
+    let echo = Payload::Echo { echo: "a".into() };
+    send(&input, Message::new("c1", "n0", Some(1), echo));
+    assert_eq!(written.next().dest, "n1");
+    drop(input);
+
+    let reply = written.next();
+    assert_eq!(reply.dest, "c1");
+    assert!(
+        matches!(reply.body.payload, Payload::Error { code, .. } if code == ErrorCode::Crash),
+        "{reply:?}"
+    );
+    running.join().unwrap().unwrap();
+}
+
+fn echo(input: &mpsc::Sender<String>, written: &mut Written, msg_id: usize) {
+    let echo = Payload::Echo { echo: "a".into() };
+    send(input, Message::new("c1", "n0", Some(msg_id), echo));
+    let reply = written.next();
+    assert_eq!(reply.body.in_reply_to, Some(msg_id));
+    assert_eq!(reply.body.payload, Payload::EchoOk { echo: "a".into() });
+}
+
+#[test]
+fn tasks_that_wake_themselves_run_again() {
+    let node = Yielding {
+        yields: 3,
+        waker: Arc::default(),
+    };
+    let (input, mut written, running) = start(node);
+    for msg_id in 1..=2 {
+        echo(&input, &mut written, msg_id);
+    }
+    drop(input);
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn wakers_outliving_their_task_do_nothing() {
+    let waker = Arc::new(Mutex::new(None));
+    let node = Yielding {
+        yields: 0,
+        waker: Arc::clone(&waker),
+    };
+    let (input, mut written, running) = start(node);
+    echo(&input, &mut written, 1);
+    // The handler is done and its task dropped; the main loop wakes up
+    // for nothing and carries on.
+    let stale = waker.lock().unwrap().take().unwrap();
+    stale.wake_by_ref();
+    echo(&input, &mut written, 2);
+
+    drop(input);
+    running.join().unwrap().unwrap();
+    // Nor does waking it with the main loop gone.
+    stale.wake();
+}
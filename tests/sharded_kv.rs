@@ -0,0 +1,107 @@
+//! The sharded key-value node on the simulated network.
+
+use serde_json::json;
+use std::time::Duration;
+use whirlpool::{
+    payload::{Payload, ReadValue},
+    sim::Sim,
+    ErrorCode, ShardedKvNode,
+};
+
+fn read(key: u64) -> Payload {
+    Payload::Read {
+        key: Some(json!(key)),
+        consistency: None,
+    }
+}
+
+#[test]
+fn any_node_serves_any_key_from_its_owner() {
+    let mut sim = Sim::with_seed(3, |_| ShardedKvNode::default(), 4).unwrap();
+    for key in 0..10 {
+        let node = format!("n{}", key % 3);
+        sim.client_send(
+            &node,
+            Payload::Write {
+                key: json!(key),
+                value: json!(key * 10),
+                consistency: None,
+            },
+        );
+    }
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    assert!(sim
+        .take_replies()
+        .iter()
+        .all(|reply| reply.body.payload == Payload::WriteOk));
+
+    // Read every key back through a node other than the one that wrote it.
+    let mut reads = Vec::new();
+    for key in 0..10 {
+        reads.push((
+            sim.client_send(&format!("n{}", (key + 1) % 3), read(key)),
+            key,
+        ));
+    }
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    let replies = sim.take_replies();
+    for (msg_id, key) in reads {
+        let reply = replies
+            .iter()
+            .find(|reply| reply.body.in_reply_to == Some(msg_id))
+            .unwrap();
+        assert_eq!(
+            reply.body.payload,
+            Payload::ReadOk {
+                value: ReadValue::Value {
+                    value: json!(key * 10)
+                }
+            }
+        );
+    }
+
+    // Each key is stored by its owner only.
+    let n0 = sim.node("n0").unwrap();
+    for key in 0..10 {
+        let owner = n0.owner_of(&json!(key)).unwrap();
+        for node in sim.nodes() {
+            let stored = node.store.read(&json!(key)).is_ok();
+            assert_eq!(stored, node.membership.node_id == owner);
+        }
+    }
+}
+
+#[test]
+fn errors_from_the_owner_reach_the_client() {
+    let mut sim = Sim::with_seed(3, |_| ShardedKvNode::default(), 5).unwrap();
+    let key = (0..)
+        .find(|key| sim.node("n0").unwrap().owner_of(&json!(key)) != Some("n0"))
+        .unwrap();
+    let cas = |from: i64, to: i64, create_if_not_exists| Payload::Cas {
+        key: json!(key),
+        from: json!(from),
+        to: json!(to),
+        create_if_not_exists,
+    };
+    // One at a time, since forwarded requests may overtake each other.
+    for request in [read(key), cas(1, 2, true), cas(1, 3, false)] {
+        sim.client_send("n0", request);
+        sim.run_for(Duration::from_millis(100)).unwrap();
+    }
+    let codes: Vec<_> = sim
+        .take_replies()
+        .into_iter()
+        .map(|reply| match reply.body.payload {
+            Payload::Error { code, .. } => Some(code),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        codes,
+        [
+            Some(ErrorCode::KeyDoesNotExist),
+            None,
+            Some(ErrorCode::PreconditionFailed)
+        ]
+    );
+}
@@ -0,0 +1,78 @@
+//! SIGTERM stops the main loop as if the input had been closed. Signals go
+//! to the whole process, so this runs in a test binary of its own, one test
+//! at a time.
+
+use std::{
+    io::Write,
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+use whirlpool::{payload::Payload, run, signal, transport, Config, EchoNode, Message, Node};
+
+static SIGNALS: Mutex<()> = Mutex::new(());
+
+/// Echoes, and says goodbye on shutdown.
+#[derive(Default)]
+struct Farewell(EchoNode);
+
+impl Node for Farewell {
+    fn handle(&mut self, msg: Message, out: &mut impl Write) -> anyhow::Result<()> {
+        self.0.handle(msg, out)
+    }
+
+    fn shutdown(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        let bye = Payload::Echo { echo: "bye".into() };
+        Message::new("n1", "c1", None, bye).send(out)
+    }
+}
+
+#[test]
+fn sigterm_shuts_down_with_the_input_still_open() {
+    let _signals = SIGNALS.lock().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let node = thread::spawn(move || {
+        let mut out = Vec::new();
+        let config = Config {
+            signals: true,
+            ..Config::default()
+        };
+        run(Farewell::default(), &config, rx, &mut out).map(|()| out)
+    });
+    let echo = Payload::Echo {
+        echo: "hello".into(),
+    };
+    let msg = Message::new("c1", "n1", Some(1), echo);
+    tx.send(serde_json::to_string(&msg).unwrap()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    let out = node.join().unwrap().unwrap();
+    let payloads: Vec<_> = transport::parse_lines::<Payload>(&out)
+        .unwrap()
+        .into_iter()
+        .map(|msg| msg.body.payload)
+        .collect();
+    assert!(matches!(&payloads[0], Payload::EchoOk { echo } if echo == "hello"));
+    assert!(matches!(&payloads[1], Payload::Echo { echo } if echo == "bye"));
+    // Still open: the reader was left blocked on it.
+    drop(tx);
+}
+
+#[test]
+fn dropped_watches_see_no_signals() {
+    let _signals = SIGNALS.lock().unwrap();
+    let (tx, seen) = mpsc::channel();
+    let watch = |name: &'static str| {
+        let tx = tx.clone();
+        signal::on_next(move |signal| tx.send((name, signal)).is_ok()).unwrap()
+    };
+    let dropped = watch("dropped");
+    let _kept = watch("kept");
+    drop(dropped);
+
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    let first = seen.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(first, ("kept", signal::Signal::Term));
+    assert!(seen.recv_timeout(Duration::from_millis(50)).is_err());
+}
@@ -0,0 +1,416 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use whirlpool::{
+    clock::VectorClock,
+    compress::{Encoding, Packed},
+    middleware::{Layered, Next},
+    payload::{AddValue, Payload, ReadValue},
+    raft::RaftNode,
+    sim::Sim,
+    BroadcastMode, BroadcastNode, CounterNode, GossipConfig, Message, TopologyStrategy,
+};
+
+fn broadcast_cluster(mode: BroadcastMode) -> Sim<BroadcastNode> {
+    Sim::with_seed(
+        5,
+        |_| BroadcastNode::new(mode).with_topology(TopologyStrategy::FullMesh),
+        7,
+    )
+    .unwrap()
+}
+
+#[test]
+fn broadcast_converges_despite_drops() {
+    let mut sim = broadcast_cluster(BroadcastMode::Gossip);
+    sim.network().drop_rate = 0.3;
+    for message in 0..20 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            20,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn broadcast_converges_after_partition_heals() {
+    let mut sim = broadcast_cluster(BroadcastMode::Reliable);
+    sim.partition(
+        vec![vec!["n0".into(), "n1".into()]],
+        Duration::ZERO,
+        Duration::from_millis(500),
+    );
+    sim.client_send("n0", Payload::Broadcast { message: 1 });
+    sim.client_send("n4", Payload::Broadcast { message: 2 });
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert!(!sim.node("n4").unwrap().seen.contains(&1));
+
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert!(node.seen.contains(&1) && node.seen.contains(&2));
+    }
+}
+
+#[test]
+fn leader_mode_fans_out_from_the_lowest_id() {
+    let mut sim = broadcast_cluster(BroadcastMode::Leader);
+    // Values take two hops, each retried with backoff, so less loss than
+    // for gossip, which has more paths to take.
+    sim.network().drop_rate = 0.1;
+    for message in 0..20 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(3)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.leader(), Some("n0"));
+        let expected = match node.membership.node_id.as_str() {
+            "n0" => vec!["n1", "n2", "n3", "n4"],
+            _ => vec!["n0"],
+        };
+        assert_eq!(node.neighbors(), expected);
+        assert_eq!(node.seen.len(), 20);
+    }
+}
+
+#[test]
+fn counter_converges() {
+    let mut sim = Sim::with_seed(3, |_| CounterNode::default(), 7).unwrap();
+    for delta in 1..=10 {
+        sim.client_send(
+            &format!("n{}", delta % 3),
+            Payload::Add {
+                value: AddValue::Delta { delta },
+                key: None,
+            },
+        );
+    }
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    sim.take_replies();
+
+    for node in ["n0", "n1", "n2"] {
+        sim.client_send(
+            node,
+            Payload::Read {
+                key: None,
+                consistency: None,
+            },
+        );
+    }
+    sim.run_for(Duration::from_millis(50)).unwrap();
+    let values: Vec<_> = sim
+        .take_replies()
+        .into_iter()
+        .map(|reply| match reply.body.payload {
+            Payload::ReadOk {
+                value: ReadValue::Value { value },
+            } => value,
+            other => panic!("unexpected reply {other:?}"),
+        })
+        .collect();
+    assert_eq!(values, vec![55, 55, 55]);
+}
+
+#[test]
+fn clocks_recover_values_lost_by_forwarding() {
+    let mut sim = Sim::with_seed(
+        5,
+        |_| {
+            BroadcastNode::new(BroadcastMode::Forward)
+                .with_topology(TopologyStrategy::FullMesh)
+                .with_clock()
+        },
+        7,
+    )
+    .unwrap();
+    sim.network().drop_rate = 0.3;
+    for message in 0..20 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            20,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn gossiped_values_count_on_the_receivers_clock() {
+    let catch_ups = Arc::new(AtomicUsize::new(0));
+    let make = |_: &str| {
+        let node = BroadcastNode::new(BroadcastMode::Gossip)
+            .with_topology(TopologyStrategy::FullMesh)
+            .with_clock();
+        let catch_ups = catch_ups.clone();
+        Layered::new(
+            node,
+            move |msg: Message, out: &mut dyn Write, next: Next<'_>| {
+                if let Payload::CatchUp { .. } = msg.body.payload {
+                    catch_ups.fetch_add(1, Ordering::Relaxed);
+                }
+                next.run(msg, out)
+            },
+        )
+    };
+    let mut sim = Sim::with_seed(3, make, 7).unwrap();
+    // Values get to each peer before the stamps that count them.
+    sim.network().delay = Duration::ZERO..Duration::ZERO;
+    for message in 0..20 {
+        sim.client_send(&format!("n{}", message % 3), Payload::Broadcast { message });
+        sim.run_for(Duration::from_millis(50)).unwrap();
+    }
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    let clocks: Vec<_> = sim.nodes().map(|node| node.inner().clock()).collect();
+    assert!(clocks.iter().all(|clock| *clock == clocks[0]));
+    assert_eq!(catch_ups.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn packed_gossip_converges_alongside_nodes_that_dont_pack() {
+    let mut sim = Sim::with_seed(
+        5,
+        |id| {
+            let node =
+                BroadcastNode::new(BroadcastMode::Gossip).with_topology(TopologyStrategy::FullMesh);
+            match id {
+                "n3" | "n4" => node,
+                _ => node.with_compression(16),
+            }
+        },
+        7,
+    )
+    .unwrap();
+    sim.network().drop_rate = 0.1;
+    for message in 0..200 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            200,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn packed_gossip_is_unpacked() {
+    let mut sim = broadcast_cluster(BroadcastMode::Gossip);
+    let gossip = Payload::Gossip {
+        messages: vec![1],
+        clock: None,
+        packed: Some(Packed::encode(Encoding::DeltaVarint, &[2, 3])),
+        origins: Default::default(),
+        accepts: Vec::new(),
+    };
+    sim.inject(Message::new("n1", "n0", Some(1), gossip));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    let seen = &sim.node("n0").unwrap().seen;
+    assert!([1, 2, 3].iter().all(|value| seen.contains(value)));
+}
+
+#[test]
+fn digests_converge_despite_drops() {
+    let mut sim = broadcast_cluster(BroadcastMode::Digest);
+    sim.network().drop_rate = 0.3;
+    for message in 0..100 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            100,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn batches_are_capped_and_the_rest_wait_for_later_rounds() {
+    let gossip = GossipConfig {
+        interval: Duration::from_millis(200),
+        max_batch: Some(5),
+        ..GossipConfig::default()
+    };
+    let mut sim = Sim::with_seed(
+        3,
+        |_| {
+            BroadcastNode::new(BroadcastMode::Gossip)
+                .with_topology(TopologyStrategy::FullMesh)
+                .with_gossip(gossip.clone())
+        },
+        7,
+    )
+    .unwrap();
+    for message in 0..20 {
+        sim.client_send("n0", Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(250)).unwrap();
+    let seen = sim.node("n1").unwrap().seen.len();
+    assert!((1..=10).contains(&seen), "n1 has {seen} values already");
+
+    sim.run_for(Duration::from_secs(1)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(node.seen.len(), 20);
+    }
+}
+
+#[test]
+fn jittered_rounds_to_a_random_few_still_converge() {
+    let gossip = GossipConfig {
+        interval: Duration::from_millis(50),
+        fanout: Some(2),
+        jitter: 0.5,
+        max_batch: Some(10),
+    };
+    let mut sim = Sim::with_seed(
+        5,
+        |_| {
+            BroadcastNode::new(BroadcastMode::Gossip)
+                .with_topology(TopologyStrategy::FullMesh)
+                .with_gossip(gossip.clone())
+        },
+        7,
+    )
+    .unwrap();
+    sim.network().drop_rate = 0.1;
+    for message in 0..50 {
+        sim.client_send(&format!("n{}", message % 5), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_secs(2)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            50,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn pulls_recover_values_every_push_of_which_was_lost() {
+    let mut sim = Sim::with_seed(
+        5,
+        |_| {
+            BroadcastNode::new(BroadcastMode::Forward)
+                .with_topology(TopologyStrategy::FullMesh)
+                .with_pull(Duration::from_millis(50))
+        },
+        7,
+    )
+    .unwrap();
+    sim.partition(
+        vec![vec!["n4".into()]],
+        Duration::ZERO,
+        Duration::from_millis(300),
+    );
+    sim.network().drop_rate = 0.2;
+    for message in 0..20 {
+        sim.client_send(&format!("n{}", message % 4), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(200)).unwrap();
+    assert!(sim.node("n4").unwrap().seen.is_empty());
+
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.seen.len(),
+            20,
+            "{} is missing values",
+            node.membership.node_id
+        );
+    }
+}
+
+#[test]
+fn restarted_nodes_sync_everything_in_chunks() {
+    let node =
+        || BroadcastNode::new(BroadcastMode::Gossip).with_topology(TopologyStrategy::FullMesh);
+    let mut sim = Sim::with_seed(3, |_| node(), 7).unwrap();
+    for message in 0..25 {
+        sim.client_send(&format!("n{}", message % 2), Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert_eq!(sim.node("n2").unwrap().seen.len(), 25);
+
+    let node_ids = vec!["n0".into(), "n1".into(), "n2".into()];
+    sim.add_node("n2", node().with_sync(10), node_ids).unwrap();
+    assert!(sim.node("n2").unwrap().seen.is_empty());
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert_eq!(sim.node("n2").unwrap().seen.len(), 25);
+}
+
+#[test]
+fn sync_leaves_out_what_the_watermark_covers() {
+    let mut sim = Sim::with_seed(2, |_| BroadcastNode::default().with_clock(), 7).unwrap();
+    for message in [5, 1, 9] {
+        sim.client_send("n0", Payload::Broadcast { message });
+    }
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    sim.take_replies();
+
+    let mut clock = VectorClock::new();
+    clock.observe("n0", 2);
+    let request = |clock, after| Payload::SyncRequest { clock, after };
+    sim.client_send("n0", request(Some(clock), None));
+    sim.client_send("n0", request(None, Some(4)));
+    sim.run_for(Duration::from_millis(10)).unwrap();
+    let replies: Vec<Payload> = sim
+        .take_replies()
+        .into_iter()
+        .map(|r| r.body.payload)
+        .collect();
+    let response = |values: Vec<usize>| Payload::SyncResponse { values, done: true };
+    assert_eq!(replies, [response(vec![9]), response(vec![5, 9])]);
+}
+
+#[test]
+fn runs_with_the_same_seed_play_out_the_same() {
+    let run = || {
+        let mut sim: Sim<RaftNode> = Sim::with_seed(5, |_| RaftNode::default(), 11).unwrap();
+        sim.network().drop_rate = 0.2;
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        for key in 0..10 {
+            let write = Payload::Write {
+                key: serde_json::json!(key),
+                value: serde_json::json!(key),
+                consistency: None,
+            };
+            sim.client_send(&format!("n{}", key % 5), write);
+            sim.run_for(Duration::from_millis(50)).unwrap();
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+        let nodes: Vec<_> = sim
+            .nodes()
+            .map(|node| (node.term(), node.leader().map(str::to_string)))
+            .collect();
+        let replies: Vec<_> = sim
+            .take_replies()
+            .into_iter()
+            .map(|reply| serde_json::to_string(&reply).unwrap())
+            .collect();
+        (nodes, replies)
+    };
+    let first = run();
+    assert!(!first.1.is_empty());
+    assert_eq!(first, run());
+}
@@ -0,0 +1,140 @@
+//! Snapshots surviving a restart.
+
+use std::time::{Duration, Instant};
+use whirlpool::{
+    input::Messages,
+    payload::Payload,
+    storage::Persisted,
+    transport,
+    txn::{Op, OpKind},
+    wal::Wal,
+    BroadcastNode, Config, KafkaNode, Message, Node, TxnNode,
+};
+
+fn init_node(node: &mut impl Node) {
+    let init = Payload::Init {
+        node_id: "n1".into(),
+        node_ids: vec!["n1".into()],
+    };
+    node.handle(Message::new("c0", "n1", Some(0), init), &mut Vec::new())
+        .unwrap();
+}
+
+#[test]
+fn broadcast_values_survive_a_restart() {
+    let dir = std::env::temp_dir().join(format!("whirlpool-storage-{}", std::process::id()));
+    let interval = std::time::Duration::from_secs(1);
+
+    let mut node = Persisted::new(BroadcastNode::default(), &dir, interval);
+    init_node(&mut node);
+    let broadcast = Payload::Broadcast { message: 7 };
+    node.handle(
+        Message::new("c1", "n1", Some(1), broadcast),
+        &mut Vec::new(),
+    )
+    .unwrap();
+    node.save().unwrap();
+
+    let mut restarted = Persisted::new(BroadcastNode::default(), &dir, interval);
+    init_node(&mut restarted);
+    assert!(restarted.inner().seen.contains(&7));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn state_is_saved_when_the_input_ends() {
+    let dir = std::env::temp_dir().join(format!("whirlpool-shutdown-{}", std::process::id()));
+    // Long enough that no snapshot is taken on a tick.
+    let interval = std::time::Duration::from_secs(60);
+    let init = Payload::Init {
+        node_id: "n1".into(),
+        node_ids: vec!["n1".into()],
+    };
+    let input = Messages::new([
+        Message::new("c0", "n1", Some(0), init),
+        Message::new("c1", "n1", Some(1), Payload::Broadcast { message: 9 }),
+    ])
+    .unwrap();
+    let node = Persisted::new(BroadcastNode::default(), &dir, interval);
+    whirlpool::run(node, &Config::default(), input, Vec::new()).unwrap();
+
+    let mut restarted = Persisted::new(BroadcastNode::default(), &dir, interval);
+    init_node(&mut restarted);
+    assert!(restarted.inner().seen.contains(&9));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn txns_survive_a_crash_between_snapshots() {
+    let dir = std::env::temp_dir().join(format!("whirlpool-wal-{}", std::process::id()));
+    let interval = std::time::Duration::from_secs(1);
+    let write = |key, value| Payload::Txn {
+        txn: vec![Op(OpKind::Write, key, Some(value))],
+    };
+
+    let mut node = Persisted::new(TxnNode::default(), &dir, interval).with_wal();
+    init_node(&mut node);
+    node.handle(
+        Message::new("c1", "n1", Some(1), write(1, 10)),
+        &mut Vec::new(),
+    )
+    .unwrap();
+    node.save().unwrap();
+    // Only in the log: the node "crashes" before the next snapshot.
+    node.handle(
+        Message::new("c1", "n1", Some(2), write(2, 20)),
+        &mut Vec::new(),
+    )
+    .unwrap();
+    drop(node);
+
+    let mut restarted = Persisted::new(TxnNode::default(), &dir, interval).with_wal();
+    init_node(&mut restarted);
+    let read = Payload::Txn {
+        txn: vec![Op(OpKind::Read, 1, None), Op(OpKind::Read, 2, None)],
+    };
+    let mut out = Vec::new();
+    restarted
+        .handle(Message::new("c1", "n1", Some(3), read), &mut out)
+        .unwrap();
+    let reply = &transport::parse_lines::<Payload>(&out).unwrap()[0];
+    let Payload::TxnOk { txn } = &reply.body.payload else {
+        panic!("expected txn_ok, got {reply:?}");
+    };
+    assert_eq!(
+        txn,
+        &[Op(OpKind::Read, 1, Some(10)), Op(OpKind::Read, 2, Some(20))]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replayed_calls_fail_at_once() {
+    let dir = std::env::temp_dir().join(format!("whirlpool-replay-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Sends that a replicated node appended to lin-kv before it crashed.
+    let mut wal = Wal::open(dir.join("n1.wal"), 0).unwrap();
+    for (msg_id, msg) in [(1, 10), (2, 20)] {
+        let send = Payload::Send {
+            key: "k".into(),
+            msg,
+        };
+        wal.append(&Message::new("c1", "n1", Some(msg_id), send))
+            .unwrap();
+    }
+    drop(wal);
+
+    // Nothing answers lin-kv while the log is replayed, and nobody waits
+    // for it to time out.
+    let started = Instant::now();
+    let mut restarted =
+        Persisted::new(KafkaNode::replicated(), &dir, Duration::from_secs(60)).with_wal();
+    init_node(&mut restarted);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(restarted.inner().rpc().unwrap().outstanding(), 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,83 @@
+//! SWIM failure detection on the simulated network.
+
+use std::time::Duration;
+use whirlpool::{
+    payload::Payload,
+    sim::Sim,
+    swim::{MemberState, SwimConfig},
+    BroadcastMode, BroadcastNode, TopologyStrategy,
+};
+
+fn cluster(topology: TopologyStrategy, seed: u64) -> Sim<BroadcastNode> {
+    let make = |_: &str| {
+        BroadcastNode::new(BroadcastMode::Gossip)
+            .with_topology(topology)
+            .with_failure_detector(SwimConfig::default())
+    };
+    Sim::with_seed(5, make, seed).unwrap()
+}
+
+fn state(sim: &Sim<BroadcastNode>, of: &str, at: &str) -> Option<MemberState> {
+    sim.node(at).unwrap().failure_detector().unwrap().state(of)
+}
+
+#[test]
+fn isolated_node_is_declared_dead_and_comes_back() {
+    let mut sim = cluster(TopologyStrategy::FullMesh, 3);
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+    for node in ["n0", "n1", "n2", "n3"] {
+        assert_eq!(state(&sim, "n4", node), Some(MemberState::Alive));
+    }
+
+    sim.partition(
+        vec![vec!["n4".into()]],
+        Duration::from_millis(1000),
+        Duration::from_millis(4500),
+    );
+    sim.run_for(Duration::from_millis(3000)).unwrap();
+    for node in ["n0", "n1", "n2", "n3"] {
+        assert_eq!(
+            state(&sim, "n4", node),
+            Some(MemberState::Dead),
+            "at {node}"
+        );
+        assert_eq!(state(&sim, "n1", node).is_some(), node != "n1");
+    }
+    assert_eq!(state(&sim, "n0", "n4"), Some(MemberState::Dead));
+
+    // It may take the others nearly two rounds of probes to try n4 again.
+    sim.run_for(Duration::from_millis(4000)).unwrap();
+    for node in sim.nodes() {
+        let detector = node.failure_detector().unwrap();
+        assert!(
+            detector.members().all(|(_, s)| s == MemberState::Alive),
+            "{}: {:?}",
+            node.membership.node_id,
+            detector.members().collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn broadcasts_route_around_a_dead_neighbor() {
+    // n1 is the only link between n0 and its children n3 and n4.
+    let mut sim = cluster(TopologyStrategy::Tree { fanout: 2 }, 5);
+    sim.partition(
+        vec![vec!["n1".into()]],
+        Duration::ZERO,
+        Duration::from_secs(10),
+    );
+    sim.run_for(Duration::from_millis(3000)).unwrap();
+    assert_eq!(state(&sim, "n1", "n0"), Some(MemberState::Dead));
+
+    sim.client_send("n0", Payload::Broadcast { message: 1 });
+    sim.client_send("n3", Payload::Broadcast { message: 2 });
+    sim.run_for(Duration::from_millis(500)).unwrap();
+    for node in ["n0", "n2", "n3", "n4"] {
+        let seen = &sim.node(node).unwrap().seen;
+        assert!(
+            seen.contains(&1) && seen.contains(&2),
+            "{node} saw {seen:?}"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Nodes talking over TCP: clients get replies on their own connection, and
+//! nodes reach each other at the addresses they were given.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+use whirlpool::{
+    payload::{Payload, ReadValue},
+    run,
+    socket::{self, SocketConfig},
+    tcp, BroadcastNode, Config, EchoNode, Message, Node, TopologyStrategy,
+};
+
+/// Serves `node` on a port of its own in the background.
+fn start<N: Node + Send + 'static>(node: N, listener: TcpListener, config: SocketConfig) {
+    let (input, out) = tcp::serve(listener, &config).unwrap();
+    thread::spawn(move || run(node, &Config::default(), input, out));
+}
+
+struct Client {
+    stream: TcpStream,
+    replies: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(listener: &TcpListener) -> Self {
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let replies = BufReader::new(stream.try_clone().unwrap());
+        Self { stream, replies }
+    }
+
+    fn call(&mut self, src: &str, dest: &str, msg_id: usize, payload: Payload) -> Payload {
+        let msg = Message::new(src, dest, Some(msg_id), payload);
+        let line = serde_json::to_string(&msg).unwrap() + "\n";
+        self.stream.write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        self.replies.read_line(&mut reply).unwrap();
+        let reply: Message = serde_json::from_str(&reply).unwrap();
+        assert_eq!(
+            (reply.dest.as_str(), reply.body.in_reply_to),
+            (src, Some(msg_id))
+        );
+        reply.body.payload
+    }
+}
+
+#[test]
+fn clients_get_replies_on_their_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = SocketConfig {
+        node_id: Some("n1".into()),
+        ..SocketConfig::default()
+    };
+    let mut first = Client::connect(&listener);
+    let mut second = Client::connect(&listener);
+    start(EchoNode::default(), listener, config);
+
+    for (client, src) in [(&mut first, "c1"), (&mut second, "c2")] {
+        let echo = Payload::Echo { echo: src.into() };
+        assert_eq!(
+            client.call(src, "n1", 1, echo),
+            Payload::EchoOk { echo: src.into() }
+        );
+    }
+}
+
+#[test]
+fn nodes_gossip_to_each_other() {
+    let listeners: Vec<_> = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let peers: BTreeMap<_, _> = listeners
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (format!("n{i}"), l.local_addr().unwrap().to_string()))
+        .collect();
+    let mut first = Client::connect(&listeners[0]);
+    let mut last = Client::connect(&listeners[2]);
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let config = SocketConfig {
+            peers: peers.clone(),
+            node_id: Some(format!("n{i}")),
+            ..SocketConfig::default()
+        };
+        let node = BroadcastNode::default().with_topology(TopologyStrategy::FullMesh);
+        start(node, listener, config);
+    }
+
+    let broadcast = Payload::Broadcast { message: 42 };
+    assert_eq!(first.call("c1", "n0", 1, broadcast), Payload::BroadcastOk);
+    for msg_id in 1.. {
+        let read = Payload::Read {
+            key: None,
+            consistency: None,
+        };
+        let read = last.call("c2", "n2", msg_id, read);
+        let Payload::ReadOk { value } = read else {
+            panic!("{read:?}");
+        };
+        if value == (ReadValue::Messages { messages: vec![42] }) {
+            break;
+        }
+        assert!(msg_id < 50, "never got there");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn peers_parse_from_a_list() {
+    let peers = socket::parse_peers("n1=127.0.0.1:7000, n2=localhost:7001,").unwrap();
+    assert_eq!(peers["n1"], "127.0.0.1:7000");
+    assert_eq!(peers["n2"], "localhost:7001");
+    assert!(socket::parse_peers("n1").is_err());
+}
@@ -0,0 +1,96 @@
+//! Timer wheels hand back events once their deadlines pass, and the main
+//! loop ticks nodes when their next timer is due.
+
+use std::{
+    io::Write,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use whirlpool::{payload::Payload, run, timer::TimerWheel, transport, Config, Message, Node};
+
+#[test]
+fn events_fall_due_in_deadline_order() {
+    let mut timers = TimerWheel::new(Duration::from_millis(10), 8);
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+    timers.schedule_at(at(250), "lease");
+    timers.schedule_at(at(30), "retry");
+    let gossip = timers.schedule_at(at(35), "gossip");
+    // More than a turn of the wheel away.
+    timers.schedule_at(at(1000), "election");
+    assert_eq!(timers.len(), 4);
+    assert_eq!(timers.next_deadline(), Some(at(30)));
+
+    assert!(timers.expire(at(29)).is_empty());
+    assert_eq!(timers.cancel(gossip), Some("gossip"));
+    assert_eq!(timers.cancel(gossip), None);
+    assert_eq!(timers.expire(at(100)), ["retry"]);
+    assert_eq!(timers.next_deadline(), Some(at(250)));
+    assert_eq!(timers.expire(at(999)), ["lease"]);
+    assert_eq!(timers.expire(at(1000)), ["election"]);
+    assert!(timers.is_empty());
+    assert_eq!(timers.next_deadline(), None);
+}
+
+#[test]
+fn deadlines_already_passed_fall_due_on_the_next_expire() {
+    let mut timers = TimerWheel::default();
+    let now = Instant::now();
+    timers.expire(now + Duration::from_secs(1));
+    timers.schedule_at(now, 1);
+    timers.schedule(Duration::ZERO, 2);
+    assert_eq!(timers.expire(Instant::now()), [1, 2]);
+}
+
+/// Echoes every message back to its sender after a delay, from a timer
+/// rather than a tick interval.
+#[derive(Default)]
+struct Delayed {
+    timers: TimerWheel<Message>,
+}
+
+impl Node for Delayed {
+    fn handle(&mut self, msg: Message, _out: &mut impl Write) -> anyhow::Result<()> {
+        if let Payload::Echo { echo } = &msg.body.payload {
+            let reply =
+                msg.clone()
+                    .into_reply(&msg.dest, None, Payload::EchoOk { echo: echo.clone() });
+            self.timers.schedule(Duration::from_millis(50), reply);
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, out: &mut impl Write) -> anyhow::Result<()> {
+        for reply in self.timers.expire(Instant::now()) {
+            reply.send(out)?;
+        }
+        Ok(())
+    }
+
+    fn next_timer(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+}
+
+#[test]
+fn the_main_loop_ticks_nodes_when_their_timers_are_due() {
+    let (tx, rx) = mpsc::channel();
+    let echo = Payload::Echo {
+        echo: "later".to_string(),
+    };
+    let msg = Message::new("c1", "n0", Some(1), echo);
+    tx.send(serde_json::to_string(&msg).unwrap()).unwrap();
+    let sent = Instant::now();
+    let input = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        drop(tx);
+    });
+
+    let mut out = Vec::new();
+    run(Delayed::default(), &Config::default(), rx, &mut out).unwrap();
+    input.join().unwrap();
+    let replies = transport::parse_lines::<Payload>(&out).unwrap();
+    assert_eq!(replies.len(), 1);
+    assert!(sent.elapsed() >= Duration::from_millis(50));
+}
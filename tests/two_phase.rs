@@ -0,0 +1,126 @@
+//! Two-phase commit across simulated txn nodes.
+
+use std::{collections::HashSet, time::Duration};
+use whirlpool::{
+    payload::Payload,
+    sim::Sim,
+    txn::{
+        two_phase::{Decision, DecisionLog},
+        Op, OpKind,
+    },
+    ErrorCode, TwoPhaseTxnNode,
+};
+
+fn write(key: u64, value: i64) -> Op {
+    Op(OpKind::Write, key, Some(value))
+}
+
+fn read(key: u64) -> Op {
+    Op(OpKind::Read, key, None)
+}
+
+fn txn(ops: Vec<Op>) -> Payload {
+    Payload::Txn { txn: ops }
+}
+
+fn txn_ok(ops: Vec<Op>) -> Payload {
+    Payload::TxnOk { txn: ops }
+}
+
+#[test]
+fn transactions_span_the_owners_of_their_registers() {
+    let mut sim = Sim::with_seed(3, |_| TwoPhaseTxnNode::default(), 1).unwrap();
+    // Keys 0, 1 and 2 belong to n0, n1 and n2.
+    sim.client_send("n0", txn(vec![write(0, 10), write(1, 11), write(2, 12)]));
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    sim.client_send("n1", txn(vec![read(0), read(2), write(2, 22), read(2)]));
+    sim.run_for(Duration::from_millis(1000)).unwrap();
+
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 2, "{replies:?}");
+    assert!(matches!(replies[0].body.payload, Payload::TxnOk { .. }));
+    assert_eq!(
+        replies[1].body.payload,
+        txn_ok(vec![
+            Op(OpKind::Read, 0, Some(10)),
+            Op(OpKind::Read, 2, Some(12)),
+            write(2, 22),
+            Op(OpKind::Read, 2, Some(22)),
+        ])
+    );
+    // Every register is only stored by its owner.
+    let stored = |node: &str, key| sim.node(node).unwrap().participant.store.written_at(key);
+    assert!(stored("n0", 0).is_some() && stored("n1", 0).is_none());
+    assert!(stored("n2", 2).is_some() && stored("n0", 2).is_none());
+}
+
+#[test]
+fn empty_transactions_commit_without_a_decision() {
+    let mut sim = Sim::with_seed(3, |_| TwoPhaseTxnNode::default(), 1).unwrap();
+    sim.client_send("n0", txn(Vec::new()));
+    sim.run_for(Duration::from_millis(100)).unwrap();
+
+    let replies = sim.take_replies();
+    assert_eq!(replies.len(), 1, "{replies:?}");
+    assert_eq!(replies[0].body.payload, txn_ok(Vec::new()));
+    assert_eq!(sim.node("n0").unwrap().decisions.pending().count(), 0);
+}
+
+#[test]
+fn unreachable_participant_aborts_the_transaction() {
+    let mut sim = Sim::with_seed(3, |_| TwoPhaseTxnNode::default(), 2).unwrap();
+    sim.partition(
+        vec![vec!["n2".into()]],
+        Duration::ZERO,
+        Duration::from_millis(700),
+    );
+    sim.client_send("n0", txn(vec![write(0, 1), write(2, 1)]));
+    sim.run_for(Duration::from_millis(1500)).unwrap();
+    let replies = sim.take_replies();
+    assert!(matches!(
+        replies[0].body.payload,
+        Payload::Error {
+            code: ErrorCode::TxnConflict,
+            ..
+        }
+    ));
+    // n0's own write was prepared, and is dropped rather than committed.
+    let n0 = sim.node("n0").unwrap();
+    assert_eq!(n0.participant.store.written_at(0), None);
+    assert_eq!(n0.decisions.pending().count(), 0);
+
+    // And nothing is left locked.
+    sim.client_send("n1", txn(vec![write(0, 2), write(2, 2)]));
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    let replies = sim.take_replies();
+    assert!(matches!(replies[0].body.payload, Payload::TxnOk { .. }));
+}
+
+#[test]
+fn decision_log_recovers_unacknowledged_decisions() {
+    let dir = std::env::temp_dir().join(format!("whirlpool-2pc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("n0.decisions");
+    let _ = std::fs::remove_file(&path);
+    let participants = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+
+    let mut log = DecisionLog::open(&path).unwrap();
+    log.decide("a", Decision::Commit, participants(&["n1", "n2"]))
+        .unwrap();
+    log.decide("b", Decision::Abort, participants(&["n1"]))
+        .unwrap();
+    log.acknowledged("a", "n1").unwrap();
+    log.acknowledged("b", "n1").unwrap();
+    drop(log);
+
+    let mut log = DecisionLog::open(&path).unwrap();
+    let pending: Vec<_> = log.pending().collect();
+    assert_eq!(pending.len(), 1);
+    // Acknowledgements aren't logged until the decision is done with.
+    assert_eq!(pending[0].1, Decision::Commit);
+    assert_eq!(log.get("b"), None);
+    log.acknowledged("a", "n1").unwrap();
+    log.acknowledged("a", "n2").unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
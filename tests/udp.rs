@@ -0,0 +1,140 @@
+//! Messages over UDP: bare ones from clients, and framed ones between
+//! nodes that get through a lossy link once each, even across restarts.
+
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    net::UdpSocket,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use whirlpool::{
+    input::InputSource,
+    payload::Payload,
+    run,
+    socket::SocketConfig,
+    udp::{self, UdpOptions},
+    Config, EchoNode, Message,
+};
+
+#[test]
+fn bare_clients_get_bare_replies() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let config = SocketConfig {
+        node_id: Some("n1".into()),
+        ..SocketConfig::default()
+    };
+    let (input, out) = udp::serve(socket, &config, UdpOptions::default()).unwrap();
+    thread::spawn(move || run(EchoNode::default(), &Config::default(), input, out));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let echo = Message::new("c1", "n1", Some(1), Payload::Echo { echo: "hi".into() });
+    client
+        .send_to(&serde_json::to_vec(&echo).unwrap(), addr)
+        .unwrap();
+    let mut buf = [0; 1024];
+    let len = client.recv(&mut buf).unwrap();
+    let reply: Message = serde_json::from_slice(&buf[..len]).unwrap();
+    assert_eq!(reply.body.in_reply_to, Some(1));
+    assert_eq!(reply.body.payload, Payload::EchoOk { echo: "hi".into() });
+}
+
+#[test]
+fn messages_between_nodes_get_through_loss_once() {
+    let sockets = [(); 2].map(|_| UdpSocket::bind("127.0.0.1:0").unwrap());
+    let peers: BTreeMap<_, _> = ["a", "b"]
+        .iter()
+        .zip(&sockets)
+        .map(|(node, socket)| (node.to_string(), socket.local_addr().unwrap().to_string()))
+        .collect();
+    let config = SocketConfig {
+        peers,
+        ..SocketConfig::default()
+    };
+    let options = UdpOptions {
+        loss: 0.4,
+        ..UdpOptions::default()
+    };
+    let [a, b] = sockets;
+    let (_, mut a_out) = udp::serve(a, &config, options).unwrap();
+    let (mut b_in, _b_out) = udp::serve(b, &config, options).unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        while let Some(Ok(json)) = b_in.next_message() {
+            let msg: Message = serde_json::from_str(&json).unwrap();
+            if tx.send(msg.body.id.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+
+    for msg_id in 0..50 {
+        let msg = Message::new("a", "b", Some(msg_id), Payload::Echo { echo: "x".into() });
+        let line = serde_json::to_string(&msg).unwrap() + "\n";
+        a_out.write_all(line.as_bytes()).unwrap();
+    }
+    let mut received = BTreeSet::new();
+    while received.len() < 50 {
+        let msg_id = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(received.insert(msg_id), "{msg_id} arrived twice");
+    }
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+}
+
+#[test]
+fn frames_from_before_a_restart_are_not_passed_on_again() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let config = SocketConfig {
+        node_id: Some("n1".into()),
+        ..SocketConfig::default()
+    };
+    let (mut input, _out) = udp::serve(socket, &config, UdpOptions::default()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        while let Some(Ok(json)) = input.next_message() {
+            let msg: Message = serde_json::from_str(&json).unwrap();
+            // Past the init the node is given for its id.
+            if msg.src == "c1" && tx.send(msg.body.id.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1024];
+    let mut send = |session: u64, msg_id: usize| {
+        let echo = Message::new("c1", "n1", Some(msg_id), Payload::Echo { echo: "x".into() });
+        let frame = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "udp_data", "msg_id": 0, "session": session, "message": echo},
+        });
+        sender
+            .send_to(&serde_json::to_vec(&frame).unwrap(), addr)
+            .unwrap();
+        // Every frame is acked, stale or not.
+        let len = sender.recv(&mut buf).unwrap();
+        let ack: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(ack["body"]["type"], "udp_ack");
+    };
+
+    send(20, 1);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+    // A straggler from before the sender restarted as session 20, then a
+    // resend of the first frame of session 20.
+    send(10, 2);
+    send(20, 1);
+    send(30, 3);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 3);
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+}
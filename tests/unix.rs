@@ -0,0 +1,106 @@
+//! Nodes talking over unix sockets, in-process and launched by a
+//! supervisor.
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+use whirlpool::{
+    payload::{Payload, ReadValue},
+    run,
+    socket::SocketConfig,
+    unix::{self, Supervisor},
+    Config, EchoNode, Message,
+};
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("whirlpool-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+struct Client {
+    stream: UnixStream,
+    replies: BufReader<UnixStream>,
+}
+
+impl Client {
+    fn connect(path: &Path) -> Self {
+        let stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let replies = BufReader::new(stream.try_clone().unwrap());
+        Self { stream, replies }
+    }
+
+    fn call(&mut self, dest: &str, msg_id: usize, payload: Payload) -> Payload {
+        let msg = Message::new("c1", dest, Some(msg_id), payload);
+        let line = serde_json::to_string(&msg).unwrap() + "\n";
+        self.stream.write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        self.replies.read_line(&mut reply).unwrap();
+        let reply: Message = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        reply.body.payload
+    }
+}
+
+#[test]
+fn clients_get_replies_over_a_socket() {
+    let path = scratch("unix-echo").join("n1.sock");
+    // Left behind by an earlier run.
+    drop(UnixListener::bind(&path));
+    let config = SocketConfig {
+        listen: path.to_str().unwrap().into(),
+        node_id: Some("n1".into()),
+        ..SocketConfig::default()
+    };
+    let (input, out) = unix::listen(&config).unwrap();
+    thread::spawn(move || run(EchoNode::default(), &Config::default(), input, out));
+
+    let echo = Payload::Echo { echo: "hi".into() };
+    assert_eq!(
+        Client::connect(&path).call("n1", 1, echo),
+        Payload::EchoOk { echo: "hi".into() }
+    );
+}
+
+#[test]
+fn supervised_nodes_gossip_to_each_other() {
+    let supervisor = Supervisor::launch(&scratch("unix-cluster"), 3, |_| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_whirlpool"));
+        command
+            .args(["--workload", "broadcast"])
+            .env("WHIRLPOOL_TOPOLOGY", "full-mesh")
+            .env("WHIRLPOOL_LOG", "off");
+        command
+    })
+    .unwrap();
+    let wait = Duration::from_secs(5);
+    let mut first = Client::connect(&supervisor.wait_for("n0", wait).unwrap());
+    let mut last = Client::connect(&supervisor.wait_for("n2", wait).unwrap());
+
+    let broadcast = Payload::Broadcast { message: 42 };
+    assert_eq!(first.call("n0", 1, broadcast), Payload::BroadcastOk);
+    for msg_id in 1.. {
+        let read = Payload::Read {
+            key: None,
+            consistency: None,
+        };
+        let read = last.call("n2", msg_id, read);
+        let Payload::ReadOk { value } = read else {
+            panic!("{read:?}");
+        };
+        if value == (ReadValue::Messages { messages: vec![42] }) {
+            break;
+        }
+        assert!(msg_id < 50, "never got there");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
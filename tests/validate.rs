@@ -0,0 +1,109 @@
+//! Malformed messages are answered or dropped before the node sees them.
+
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::{json, Value};
+use whirlpool::{
+    payload::Payload,
+    testing::arbitrary::Arbitrary,
+    transport,
+    validate::{admit_strictly, unknown_fields, Validator},
+    Body, ErrorCode, Message, MsgIdAllocator, Replier,
+};
+
+/// Has `validator` check `body` from `src` to `dest`, returning whether it
+/// was let through and what was sent back.
+fn admit(validator: &mut Validator, src: &str, dest: &str, body: Value) -> (bool, Vec<Message>) {
+    let json = json!({"src": src, "dest": dest, "body": body}).to_string();
+    let msg: Message = serde_json::from_str(&json).unwrap();
+    let mut out = Vec::new();
+    let admitted = validator.admit(&json, &msg, &mut out).unwrap();
+    (admitted, transport::parse_lines(&out).unwrap())
+}
+
+fn is_malformed(sent: &[Message]) -> bool {
+    matches!(
+        sent,
+        [Message {
+            body: Body {
+                payload: Payload::Error {
+                    code: ErrorCode::MalformedRequest,
+                    ..
+                },
+                ..
+            },
+            ..
+        }]
+    )
+}
+
+#[test]
+fn messages_are_checked_once_the_node_knows_its_id() {
+    let mut validator = Validator::new(true);
+    let echo = |msg_id: Option<usize>| json!({"type": "echo", "echo": "hi", "msg_id": msg_id});
+    // Nothing to check against before init.
+    assert!(admit(&mut validator, "c1", "n7", echo(Some(1))).0);
+    let init = json!({"type": "init", "msg_id": 2, "node_id": "n1", "node_ids": ["n1", "n2"]});
+    assert!(admit(&mut validator, "c1", "n1", init).0);
+
+    assert!(admit(&mut validator, "c1", "n1", echo(Some(3))).0);
+    let (admitted, sent) = admit(&mut validator, "c1", "n7", echo(Some(4)));
+    assert!(!admitted && is_malformed(&sent));
+    assert_eq!(sent[0].dest, "c1");
+    assert_eq!(sent[0].body.in_reply_to, Some(4));
+    // From the node, whatever address the request went to.
+    assert_eq!(sent[0].src, "n1");
+
+    // A client waits for a reply, a node may not.
+    assert_eq!(
+        admit(&mut validator, "c1", "n1", echo(None)),
+        (false, vec![])
+    );
+    assert!(admit(&mut validator, "n2", "n1", echo(None)).0);
+
+    // No call is waiting, so the reply is dropped rather than answered.
+    let reply = json!({"type": "read_ok", "in_reply_to": 9, "msg_id": 5, "messages": []});
+    assert_eq!(
+        admit(&mut validator, "n2", "n1", reply.clone()),
+        (false, vec![])
+    );
+    assert!(admit(&mut Validator::new(false), "n2", "n1", reply).0);
+}
+
+#[test]
+fn strict_parsing_finds_fields_the_payload_lacks() {
+    let read = |body: Value| {
+        let json = json!({"src": "c1", "dest": "n1", "body": body}).to_string();
+        let msg: Message = serde_json::from_str(&json).unwrap();
+        (json, msg)
+    };
+    let (json, msg) = read(json!({"type": "echo", "msg_id": 1, "echo": "hi", "colour": "red"}));
+    assert_eq!(unknown_fields(&json, &msg), ["body.colour"]);
+    let msg_ids = MsgIdAllocator::new();
+    msg_ids.next();
+    let replier = Replier::named("n1", msg_ids);
+    let mut out = Vec::new();
+    assert!(!admit_strictly(&json, &msg, &replier, &mut out).unwrap());
+    let sent = transport::parse_lines(&out).unwrap();
+    assert!(is_malformed(&sent));
+    // Under an id of the node's, after the one it took already.
+    assert_eq!(sent[0].body.id, Some(1));
+
+    // Unset optional fields read the same as missing ones.
+    let (json, msg) = read(json!({"type": "read", "msg_id": 2, "key": null, "in_reply_to": null}));
+    assert!(unknown_fields(&json, &msg).is_empty());
+}
+
+#[test]
+fn every_payload_parses_strictly() {
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..2000 {
+        let msg = Message::<Payload>::arbitrary(&mut rng);
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            unknown_fields(&json, &parsed),
+            Vec::<String>::new(),
+            "{json}"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Nodes talking WebSocket: to clients such as the demo page, which is
+//! served on the same port, and to each other.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+use whirlpool::{
+    payload::{Payload, ReadValue},
+    run,
+    socket::{SocketConfig, Stream},
+    ws::{self, WsStream},
+    BroadcastNode, Config, EchoNode, Message, Node, TopologyStrategy,
+};
+
+fn start<N: Node + Send + 'static>(node: N, listener: TcpListener, config: SocketConfig) {
+    let (input, out) = ws::serve(listener, &config).unwrap();
+    thread::spawn(move || run(node, &Config::default(), input, out));
+}
+
+struct Client {
+    stream: WsStream,
+    replies: BufReader<WsStream>,
+}
+
+impl Client {
+    /// Connects to a node that is already serving, to answer the handshake.
+    fn connect(addr: SocketAddr) -> Self {
+        let stream = ws::connect(&addr.to_string()).unwrap();
+        let replies = BufReader::new(stream.try_clone().unwrap());
+        Self { stream, replies }
+    }
+
+    fn call(&mut self, dest: &str, msg_id: usize, payload: Payload) -> Payload {
+        let msg = Message::new("c1", dest, Some(msg_id), payload);
+        let line = serde_json::to_string(&msg).unwrap() + "\n";
+        self.stream.write_all(line.as_bytes()).unwrap();
+        let mut reply = String::new();
+        self.replies.read_line(&mut reply).unwrap();
+        let reply: Message = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        reply.body.payload
+    }
+}
+
+#[test]
+fn handshakes_are_answered_as_the_rfc_says() {
+    assert_eq!(
+        ws::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[test]
+fn clients_get_replies_of_any_length() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = SocketConfig {
+        node_id: Some("n1".into()),
+        ..SocketConfig::default()
+    };
+    start(EchoNode::default(), listener, config);
+    let mut client = Client::connect(addr);
+
+    for (msg_id, len) in [(1, 5), (2, 300), (3, 70_000)] {
+        let echo = "x".repeat(len);
+        assert_eq!(
+            client.call("n1", msg_id, Payload::Echo { echo: echo.clone() }),
+            Payload::EchoOk { echo }
+        );
+    }
+}
+
+#[test]
+fn frames_claiming_huge_lengths_are_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut request = BufReader::new(stream.try_clone().unwrap());
+        let mut key = String::new();
+        let mut line = String::new();
+        while request.read_line(&mut line).unwrap() > 2 {
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key: ") {
+                key = value.trim().to_string();
+            }
+            line.clear();
+        }
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            ws::accept_key(&key)
+        )
+        .unwrap();
+        // A first fragment, then one whose length would overflow added to it.
+        stream.write_all(&[0x01, 1, b'x', 0x80, 127]).unwrap();
+        stream.write_all(&u64::MAX.to_be_bytes()).unwrap();
+    });
+
+    let mut replies = BufReader::new(ws::connect(&addr.to_string()).unwrap());
+    let err = replies.read_line(&mut String::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn browsers_get_the_demo_page() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = SocketConfig {
+        node_id: Some("n3".into()),
+        ..SocketConfig::default()
+    };
+    start(EchoNode::default(), listener, config);
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let page = get("/");
+    assert!(page.starts_with("HTTP/1.1 200 OK"), "{page}");
+    assert!(page.contains("new WebSocket"));
+    assert!(page.contains(r#"$("dest").value = "n3";"#));
+    assert!(get("/favicon.ico").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn nodes_gossip_to_each_other() {
+    let listeners: Vec<_> = (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let peers: BTreeMap<_, _> = listeners
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (format!("n{i}"), l.local_addr().unwrap().to_string()))
+        .collect();
+    let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let config = SocketConfig {
+            peers: peers.clone(),
+            node_id: Some(format!("n{i}")),
+            ..SocketConfig::default()
+        };
+        let node = BroadcastNode::default().with_topology(TopologyStrategy::FullMesh);
+        start(node, listener, config);
+    }
+    let mut first = Client::connect(addrs[0]);
+    let mut last = Client::connect(addrs[2]);
+
+    let broadcast = Payload::Broadcast { message: 42 };
+    assert_eq!(first.call("n0", 1, broadcast), Payload::BroadcastOk);
+    for msg_id in 1.. {
+        let read = Payload::Read {
+            key: None,
+            consistency: None,
+        };
+        let Payload::ReadOk { value } = last.call("n2", msg_id, read) else {
+            panic!("not a read_ok");
+        };
+        if value == (ReadValue::Messages { messages: vec![42] }) {
+            break;
+        }
+        assert!(msg_id < 50, "never got there");
+        thread::sleep(Duration::from_millis(100));
+    }
+}